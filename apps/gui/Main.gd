@@ -168,6 +168,15 @@ func _tail_log() -> void:
 		processed += 1
 
 func _default_log_path() -> String:
+	# Ask the backend so config.json / FAG_HOME / portable mode are honoured.
+	var exe := ProjectSettings.globalize_path(FAG_EXE)
+	if FileAccess.file_exists(exe):
+		var out: Array = []
+		var code := OS.execute(exe, PackedStringArray(["config", "get", "paths.log"]), out, true, false)
+		if code == 0 and out.size() > 0:
+			var p := str(out[0]).strip_edges()
+			if !p.is_empty():
+				return p
 	var appdata := OS.get_environment("APPDATA")
 	if appdata.is_empty():
 		return ""
@@ -1,23 +1,59 @@
-mod captures;
-mod logging;
-mod rules;
+use fag_core::api::{CheckStatus, ProfileApply};
+use fag_core::control::Command;
+use fag_core::instance::InstanceLock;
+use fag_core::watcher::{Output, WatchEvent, WatchOverrides, Watcher};
+use fag_core::{bundle, config, integrity, processes, rules, schedule, Guard};
 
 fn main() {
-    let mut args = std::env::args().skip(1);
+    let mut args = std::env::args().skip(1).peekable();
+    let mut global = config::GlobalOptions::default();
+    while let Some(arg) = args.next_if(|a| a.starts_with("--")) {
+        match arg.as_str() {
+            "--config" => global.config = args.next().map(std::path::PathBuf::from),
+            "--portable" => global.portable = true,
+            _ => {
+                eprintln!("unknown global option: {}", arg);
+                std::process::exit(2);
+            }
+        }
+    }
     let Some(command) = args.next() else {
         eprintln!(
-            "usage: fag <command> [args]\n\ncommands:\n  read --ext <.ext>\n  progids --ext <.ext>\n  latest --ext <.ext>\n  capture-latest --ext <.ext> --name <label>\n  apply-latest --ext <.ext> --name <label>\n  apply-latest --ext <.ext> --progid <ProgId> --hash <Hash>\n  captures --ext <.ext>\n  rules <list|add|remove> ...\n  check\n  watch-rules [--interval <seconds>] [--monitor-only]\n  watch --ext <.ext> --name <label> [--interval <seconds>] [--monitor-only]\n  sysinfo\n  debug-legacy-hash --ext <.ext> --sid <SID> --progid <ProgId> --regdate-hex <16hex> [--experience <str>]\n  features <status|set> ...\n  win11 disable-userchoicelatest\n  restore --ext <.ext> (--progid <ProgId> | --to <vlc|potplayer>)"
+            "usage: fag [--config <config.json>] [--portable] <command> [args]\n\ncommands:\n  read --ext <.ext>\n  progids --ext <.ext>\n  latest --ext <.ext>\n  capture-latest --ext <.ext> --name <label>\n  apply-latest --ext <.ext> --name <label>\n  apply-latest --ext <.ext> --progid <ProgId> --hash <Hash>\n  captures --ext <.ext>\n  rules <list|add|remove|set|enable|disable|group|schedule|convert> ...\n  profile <list|save|use|delete> ...\n  integrity <status|reseal>\n  config <show|get|set> ...\n  export --out <bundle.json>\n  import <bundle.json> [--merge|--replace] [--dry-run] [--allow-foreign]\n  check\n  watch-rules [--interval <seconds>] [--monitor-only] [--approve]\n  ctl <status|pause [--for <seconds>]|resume|reload|check-now|stop>\n  pending <list|approve <id>|accept <id> --name <label>|dismiss <id>>\n  watch --ext <.ext> --name <label> [--interval <seconds>] [--monitor-only]\n  sysinfo\n  debug-legacy-hash --ext <.ext> --sid <SID> --progid <ProgId> --regdate-hex <16hex> [--experience <str>]\n  features <status|set> ...\n  win11 disable-userchoicelatest\n  restore --ext <.ext> (--progid <ProgId> | --to <vlc|potplayer>)"
         );
         std::process::exit(2);
     };
 
+    let settings = match config::load_settings(&global) {
+        Ok(s) => s,
+        Err(err) if command == "integrity" && integrity::as_tampered(&err).is_some() => {
+            match config::load_settings_unverified(&global) {
+                Ok(s) => s,
+                Err(err) => {
+                    eprintln!("config load failed: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Err(err) => {
+            if let Some(t) = integrity::as_tampered(&err) {
+                println!("{}", WatchEvent::config_tampered("config", t).to_json());
+                std::process::exit(3);
+            }
+            eprintln!("config load failed: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let guard = Guard::new(settings);
+    let settings = guard.settings();
+
     match command.as_str() {
         "read" => {
             let mut ext: Option<String> = None;
             while let Some(arg) = args.next() {
-                match arg.as_str() {
-                    "--ext" => ext = args.next(),
-                    _ => {}
+                if arg == "--ext" {
+                    ext = args.next();
                 }
             }
 
@@ -61,9 +97,8 @@ fn main() {
         "progids" => {
             let mut ext: Option<String> = None;
             while let Some(arg) = args.next() {
-                match arg.as_str() {
-                    "--ext" => ext = args.next(),
-                    _ => {}
+                if arg == "--ext" {
+                    ext = args.next();
                 }
             }
 
@@ -91,9 +126,8 @@ fn main() {
         "latest" => {
             let mut ext: Option<String> = None;
             while let Some(arg) = args.next() {
-                match arg.as_str() {
-                    "--ext" => ext = args.next(),
-                    _ => {}
+                if arg == "--ext" {
+                    ext = args.next();
                 }
             }
 
@@ -162,76 +196,28 @@ fn main() {
                 }
             }
 
-            let Some(ext_raw) = ext else {
-                eprintln!("usage: fag capture-latest --ext <.ext> --name <label>");
-                std::process::exit(2);
-            };
-            let Some(name_raw) = name else {
+            let (Some(ext), Some(name)) = (ext, name) else {
                 eprintln!("usage: fag capture-latest --ext <.ext> --name <label>");
                 std::process::exit(2);
             };
 
-            let ext = match normalize_ext_for_store(&ext_raw) {
-                Ok(e) => e,
-                Err(msg) => {
-                    eprintln!("capture-latest failed: {}", msg);
-                    std::process::exit(2);
-                }
-            };
-            let name = name_raw.trim().to_ascii_lowercase();
-            if name.is_empty() {
-                eprintln!("capture-latest failed: --name is empty");
-                std::process::exit(2);
-            }
-
-            match fag_core::registry::read_user_choice_latest(&ext) {
-                Ok(Some(uc)) => {
-                    let Some(prog_id) = uc.prog_id else {
-                        eprintln!("capture-latest failed: ProgId missing in UserChoiceLatest");
-                        std::process::exit(1);
-                    };
-                    let Some(hash) = uc.hash else {
-                        eprintln!("capture-latest failed: Hash missing in UserChoiceLatest");
-                        std::process::exit(1);
-                    };
-
-                    let cap = captures::LatestCapture {
-                        prog_id: prog_id.clone(),
-                        hash: hash.clone(),
-                        last_write_time_filetime: uc.last_write_time.map(|ft| ft.as_u64()),
-                        prog_id_last_write_time_filetime: uc
-                            .prog_id_last_write_time
-                            .map(|ft| ft.as_u64()),
-                    };
-
-                    let path = captures::default_store_path();
-                    if let Err(err) = captures::upsert_latest_capture(&path, &ext, &name, cap) {
-                        eprintln!("capture-latest failed: store write error: {}", err);
-                        std::process::exit(1);
-                    }
-
+            match guard.capture(&ext, &name) {
+                Ok(captured) => {
                     println!(
                         "{{\"ext\":{},\"name\":{},\"prog_id\":{},\"hash\":{},\"store_path\":{}}}",
-                        json_string(&ext),
-                        json_string(&name),
-                        json_string(&prog_id),
-                        json_string(&hash),
-                        json_string(path.to_string_lossy().as_ref())
+                        json_string(&captured.ext),
+                        json_string(&captured.label),
+                        json_string(&captured.capture.prog_id),
+                        json_string(&captured.capture.hash),
+                        json_string(settings.captures_path().to_string_lossy().as_ref())
                     );
-                    eprintln!("next: fag apply-latest --ext {} --name {}", ext, name);
-                    std::process::exit(0);
-                }
-                Ok(None) => {
                     eprintln!(
-                        "capture-latest failed: UserChoiceLatest not set for {}",
-                        ext
+                        "next: fag apply-latest --ext {} --name {}",
+                        captured.ext, captured.label
                     );
-                    std::process::exit(1);
-                }
-                Err(err) => {
-                    eprintln!("capture-latest failed: {}", err);
-                    std::process::exit(1);
+                    std::process::exit(0);
                 }
+                Err(err) => exit_with(&guard, "capture-latest", &err),
             }
         }
         "apply-latest" => {
@@ -249,518 +235,1527 @@ fn main() {
                 }
             }
 
-            let Some(ext_raw) = ext else {
+            let usage = || {
                 eprintln!("usage: fag apply-latest --ext <.ext> --name <label>");
                 eprintln!("   or: fag apply-latest --ext <.ext> --progid <ProgId> --hash <Hash>");
                 std::process::exit(2);
             };
-            let ext = match normalize_ext_for_store(&ext_raw) {
+            let Some(ext) = ext else { usage() };
+            let (applied, source) = match (name, progid, hash) {
+                (Some(n), None, None) => (
+                    guard.apply(&ext, &n),
+                    format!("store:{}", n.trim().to_ascii_lowercase()),
+                ),
+                (None, Some(p), Some(h)) => {
+                    (guard.apply_progid(&ext, &p, &h), "inline".to_string())
+                }
+                _ => usage(),
+            };
+            let applied = applied.unwrap_or_else(|err| exit_with(&guard, "apply-latest", &err));
+            if let Some(err) = &applied.query_error {
+                eprintln!("warning: effective progid query failed: {}", err);
+            }
+            let ok = applied.accepted();
+            println!(
+                "{{\"ext\":{},\"status\":{},\"prog_id\":{},\"effective_progid\":{},\"source\":{},\"hint\":{}}}",
+                json_string(&applied.ext),
+                json_string(if ok { "APPLIED" } else { "REJECTED" }),
+                json_string(&applied.prog_id),
+                applied
+                    .effective
+                    .as_deref()
+                    .map(json_string)
+                    .unwrap_or("null".into()),
+                json_string(&source),
+                json_string(if ok {
+                    ""
+                } else {
+                    "系统可能拒绝/回滚了这次写入：请去 Windows 设置里手动改回默认程序；本工具会记录/提醒篡改事件。"
+                })
+            );
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        "captures" => {
+            let mut ext: Option<String> = None;
+            while let Some(arg) = args.next() {
+                if arg == "--ext" {
+                    ext = args.next();
+                }
+            }
+
+            let Some(ext_raw) = ext else {
+                eprintln!("usage: fag captures --ext <.ext>");
+                std::process::exit(2);
+            };
+            let ext = match rules::normalize_ext(&ext_raw) {
                 Ok(e) => e,
                 Err(msg) => {
-                    eprintln!("apply-latest failed: {}", msg);
+                    eprintln!("captures failed: {}", msg);
                     std::process::exit(2);
                 }
             };
 
-            let (progid, hash, source) = match (name, progid, hash) {
-                (Some(n), None, None) => {
-                    let label = n.trim().to_ascii_lowercase();
-                    if label.is_empty() {
-                        eprintln!("apply-latest failed: --name is empty");
+            let names = guard
+                .captures(&ext)
+                .unwrap_or_else(|err| exit_with(&guard, "captures", &err));
+            let joined = names
+                .into_iter()
+                .map(|s| json_string(&s))
+                .collect::<Vec<_>>()
+                .join(",");
+            println!(
+                "{{\"ext\":{},\"names\":[{}],\"store_path\":{}}}",
+                json_string(&ext),
+                joined,
+                json_string(settings.captures_path().to_string_lossy().as_ref())
+            );
+            std::process::exit(0);
+        }
+        "rules" => {
+            let Some(action) = args.next() else {
+                eprintln!(
+                    "usage: fag rules <list|add|remove|set|enable|disable|group|schedule|convert> ..."
+                );
+                std::process::exit(2);
+            };
+
+            match action.as_str() {
+                "list" => {
+                    let expand = args.any(|a| a == "--expand");
+                    let path = settings.rules_path().to_path_buf();
+                    let layered = guard
+                        .rules()
+                        .unwrap_or_else(|err| exit_with(&guard, "rules list", &err));
+                    let joined = if expand {
+                        layered
+                            .expand()
+                            .iter()
+                            .map(effective_rule_json)
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    } else {
+                        let listed = |set: &rules::RuleSet, source: &str| {
+                            set.by_ext
+                                .iter()
+                                .chain(set.by_group.iter())
+                                .map(|(key, rule)| sourced_rule_json(key, rule, source))
+                                .collect::<Vec<_>>()
+                        };
+                        let mut all = listed(&layered.policy, "policy");
+                        all.extend(listed(&layered.user, "user"));
+                        all.join(",")
+                    };
+                    let set = &layered.user;
+                    let groups = set
+                        .all_groups()
+                        .into_iter()
+                        .map(|(name, exts, builtin)| group_json(&name, &exts, builtin))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    println!(
+                        "{{\"rules\":[{}],\"expanded\":{},\"groups\":[{}],\"rules_path\":{},\"policy_path\":{}}}",
+                        joined,
+                        expand,
+                        groups,
+                        json_string(path.to_string_lossy().as_ref()),
+                        settings
+                            .policy_path
+                            .as_deref()
+                            .map(|p| json_string(p.to_string_lossy().as_ref()))
+                            .unwrap_or_else(|| "null".into())
+                    );
+                    std::process::exit(0);
+                }
+                "add" => {
+                    let mut ext: Option<String> = None;
+                    let mut group: Option<String> = None;
+                    let mut name: Option<String> = None;
+                    let mut follow: Option<String> = None;
+                    while let Some(arg) = args.next() {
+                        match arg.as_str() {
+                            "--ext" => ext = args.next(),
+                            "--group" => group = args.next(),
+                            "--name" => name = args.next(),
+                            "--follow" => follow = args.next(),
+                            _ => {}
+                        }
+                    }
+
+                    let usage = "usage: fag rules add (--ext <.ext> | --group <group>) (--name <label> | --follow <.leader-ext>)";
+                    let key = match rule_key(ext, group) {
+                        Ok(k) => k,
+                        Err(msg) => {
+                            eprintln!("rules add failed: {}", msg);
+                            eprintln!("{}", usage);
+                            std::process::exit(2);
+                        }
+                    };
+                    let path = settings.rules_path();
+                    let kind = if rules::is_group_key(&key) {
+                        "group"
+                    } else {
+                        "ext"
+                    };
+                    let name = match (name, follow) {
+                        (Some(n), None) => n,
+                        (None, Some(leader)) => {
+                            let leader = guard
+                                .add_follow_rule(&key, &leader)
+                                .unwrap_or_else(|err| exit_with(&guard, "rules add", &err));
+                            println!(
+                                "{{\"status\":\"ADDED\",{}:{},\"follow\":{},\"rules_path\":{}}}",
+                                json_string(kind),
+                                json_string(&key),
+                                json_string(&leader),
+                                json_string(path.to_string_lossy().as_ref())
+                            );
+                            std::process::exit(0);
+                        }
+                        _ => {
+                            eprintln!("{}", usage);
+                            std::process::exit(2);
+                        }
+                    };
+
+                    // A group rule is accepted before every member is captured; the missing ones
+                    // are listed here and skipped by check/watch until captured.
+                    let added = guard
+                        .add_rule(&key, &name)
+                        .unwrap_or_else(|err| exit_with(&guard, "rules add", &err));
+                    if !added.managed_members.is_empty() {
+                        eprintln!(
+                            "warning: {} managed by machine policy; this group rule does not apply there",
+                            added.managed_members.join(" ")
+                        );
+                    }
+                    if rules::is_group_key(&key) {
+                        println!(
+                            "{{\"status\":\"ADDED\",\"group\":{},\"name\":{},\"missing_captures\":[{}],\"rules_path\":{}}}",
+                            json_string(&key),
+                            json_string(&added.label),
+                            added
+                                .missing_captures
+                                .iter()
+                                .map(|e| json_string(e))
+                                .collect::<Vec<_>>()
+                                .join(","),
+                            json_string(path.to_string_lossy().as_ref())
+                        );
+                    } else {
+                        println!(
+                            "{{\"status\":\"ADDED\",\"ext\":{},\"name\":{},\"rules_path\":{}}}",
+                            json_string(&key),
+                            json_string(&added.label),
+                            json_string(path.to_string_lossy().as_ref())
+                        );
+                    }
+                    std::process::exit(0);
+                }
+                "remove" => {
+                    let mut ext: Option<String> = None;
+                    let mut group: Option<String> = None;
+                    while let Some(arg) = args.next() {
+                        match arg.as_str() {
+                            "--ext" => ext = args.next(),
+                            "--group" => group = args.next(),
+                            _ => {}
+                        }
+                    }
+                    let key = match rule_key(ext, group) {
+                        Ok(k) => k,
+                        Err(msg) => {
+                            eprintln!("rules remove failed: {}", msg);
+                            eprintln!("usage: fag rules remove (--ext <.ext> | --group <group>)");
+                            std::process::exit(2);
+                        }
+                    };
+                    if let Err(err) = guard.remove_rule(&key) {
+                        exit_with(&guard, "rules remove", &err);
+                    }
+                    println!(
+                        "{{\"status\":\"REMOVED\",{}:{},\"rules_path\":{}}}",
+                        json_string(if rules::is_group_key(&key) {
+                            "group"
+                        } else {
+                            "ext"
+                        }),
+                        json_string(&key),
+                        json_string(settings.rules_path().to_string_lossy().as_ref())
+                    );
+                    std::process::exit(0);
+                }
+                "group" => {
+                    let usage = "usage: fag rules group <list|set <group> <.ext> [<.ext>...]|remove <group>>";
+                    let path = settings.rules_path().to_path_buf();
+                    let sub = args.next().unwrap_or_default();
+                    match sub.as_str() {
+                        "list" => {
+                            let set = guard
+                                .rules()
+                                .unwrap_or_else(|err| exit_with(&guard, "rules group list", &err))
+                                .user;
+                            let groups = set
+                                .all_groups()
+                                .into_iter()
+                                .map(|(name, exts, builtin)| group_json(&name, &exts, builtin))
+                                .collect::<Vec<_>>()
+                                .join(",");
+                            println!("{{\"groups\":[{}]}}", groups);
+                            std::process::exit(0);
+                        }
+                        "set" | "remove" => {
+                            let Some(group) = args.next() else {
+                                eprintln!("{}", usage);
+                                std::process::exit(2);
+                            };
+                            let exts = if sub == "set" {
+                                let mut exts = Vec::new();
+                                for raw in args.by_ref() {
+                                    match rules::normalize_ext(&raw) {
+                                        Ok(e) if !exts.contains(&e) => exts.push(e),
+                                        Ok(_) => {}
+                                        Err(msg) => {
+                                            eprintln!("rules group set failed: {}: {}", raw, msg);
+                                            std::process::exit(2);
+                                        }
+                                    }
+                                }
+                                if exts.is_empty() {
+                                    eprintln!("{}", usage);
+                                    std::process::exit(2);
+                                }
+                                Some(exts)
+                            } else {
+                                None
+                            };
+                            match guard.set_group(&group, exts.clone()) {
+                                Ok(group) => {
+                                    let set = guard
+                                        .rules()
+                                        .unwrap_or_else(|err| {
+                                            exit_with(&guard, &format!("rules group {}", sub), &err)
+                                        })
+                                        .user;
+                                    let effective = set.group_members(&group);
+                                    if effective.is_none() && set.by_group.contains_key(&group) {
+                                        eprintln!(
+                                            "warning: rule for group {} now matches nothing (fag rules remove --group {})",
+                                            group, group
+                                        );
+                                    }
+                                    println!(
+                                        "{{\"status\":{},\"group\":{},\"exts\":{},\"rules_path\":{}}}",
+                                        json_string(if exts.is_some() { "UPDATED" } else { "REMOVED" }),
+                                        json_string(&group),
+                                        effective
+                                            .map(|e| format!(
+                                                "[{}]",
+                                                e.iter().map(|x| json_string(x)).collect::<Vec<_>>().join(",")
+                                            ))
+                                            .unwrap_or_else(|| "null".into()),
+                                        json_string(path.to_string_lossy().as_ref())
+                                    );
+                                    std::process::exit(0);
+                                }
+                                Err(fag_core::Error::UnknownGroup(group)) => {
+                                    eprintln!(
+                                        "rules group remove: no user-defined group {}",
+                                        group
+                                    );
+                                    std::process::exit(2);
+                                }
+                                Err(err) => {
+                                    exit_with(&guard, &format!("rules group {}", sub), &err)
+                                }
+                            }
+                        }
+                        _ => {
+                            eprintln!("{}", usage);
+                            std::process::exit(2);
+                        }
+                    }
+                }
+                "convert" => {
+                    let usage = "usage: fag rules convert --to <toml|json>";
+                    let mut to: Option<String> = None;
+                    while let Some(arg) = args.next() {
+                        if arg == "--to" {
+                            to = args.next().map(|t| t.trim().to_ascii_lowercase());
+                        }
+                    }
+                    let Some(to) = to.filter(|t| t == "toml" || t == "json") else {
+                        eprintln!("{}", usage);
                         std::process::exit(2);
+                    };
+                    let converted = guard
+                        .convert_rules(to == "toml")
+                        .unwrap_or_else(|err| exit_with(&guard, "rules convert", &err));
+                    for warning in &converted.warnings {
+                        eprintln!("warning: {}", warning);
                     }
-                    let path = captures::default_store_path();
-                    let cap = match captures::get_latest_capture(&path, &ext, &label) {
-                        Ok(Some(c)) => c,
-                        Ok(None) => {
+                    println!(
+                        "{{\"status\":\"CONVERTED\",\"from\":{},\"to\":{},\"backup\":{}}}",
+                        json_string(converted.from.to_string_lossy().as_ref()),
+                        json_string(converted.to.to_string_lossy().as_ref()),
+                        json_string(converted.backup.to_string_lossy().as_ref())
+                    );
+                    std::process::exit(0);
+                }
+                "schedule" => {
+                    let usage ="usage: fag rules schedule <add (--ext <.ext> | --group <group>) --days <mon-fri|sat,sun|*> --from <HH:MM> --to <HH:MM> --name <label>|clear (--ext <.ext> | --group <group>)|preview [--ext <.ext>] [--days <n>]>";
+                    let sub = args.next().unwrap_or_default();
+                    let mut ext: Option<String> = None;
+                    let mut group: Option<String> = None;
+                    let mut days: Option<String> = None;
+                    let mut from: Option<String> = None;
+                    let mut to: Option<String> = None;
+                    let mut name: Option<String> = None;
+                    while let Some(arg) = args.next() {
+                        match arg.as_str() {
+                            "--ext" => ext = args.next(),
+                            "--group" => group = args.next(),
+                            "--days" => days = args.next(),
+                            "--from" => from = args.next(),
+                            "--to" => to = args.next(),
+                            "--name" => name = args.next(),
+                            _ => {}
+                        }
+                    }
+                    let path = settings.rules_path().to_path_buf();
+                    match sub.as_str() {
+                        "add" | "clear" => {
+                            let key = match rule_key(ext, group) {
+                                Ok(k) => k,
+                                Err(msg) => {
+                                    eprintln!("rules schedule {} failed: {}", sub, msg);
+                                    eprintln!("{}", usage);
+                                    std::process::exit(2);
+                                }
+                            };
+                            refuse_if_managed(&guard, &key, "schedule");
+                            let window = if sub == "add" {
+                                let (Some(days), Some(from), Some(to), Some(name)) =
+                                    (days, from, to, name)
+                                else {
+                                    eprintln!("{}", usage);
+                                    std::process::exit(2);
+                                };
+                                Some(schedule::ScheduleWindow {
+                                    days: days.trim().to_ascii_lowercase(),
+                                    from: from.trim().to_string(),
+                                    to: to.trim().to_string(),
+                                    name: name.trim().to_ascii_lowercase(),
+                                })
+                            } else {
+                                None
+                            };
+                            match guard.set_schedule(&key, window) {
+                                Ok(rule) => {
+                                    println!(
+                                        "{{\"status\":\"UPDATED\",\"rule\":{},\"rules_path\":{}}}",
+                                        rule_json(&key, &rule),
+                                        json_string(path.to_string_lossy().as_ref())
+                                    );
+                                    std::process::exit(0);
+                                }
+                                Err(fag_core::Error::NotFound(_)) => {
+                                    eprintln!(
+                                        "rules schedule {}: not found for {}. Add it first: fag rules add --{} {} --name <label>",
+                                        sub,
+                                        key,
+                                        if rules::is_group_key(&key) { "group" } else { "ext" },
+                                        key
+                                    );
+                                    std::process::exit(2);
+                                }
+                                Err(err) => {
+                                    exit_with(&guard, &format!("rules schedule {}", sub), &err)
+                                }
+                            }
+                        }
+                        "preview" => {
+                            let horizon_days = match days.as_deref().map(str::parse::<u32>) {
+                                None => 7,
+                                Some(Ok(n)) if (1..=31).contains(&n) => n,
+                                _ => {
+                                    eprintln!(
+                                        "rules schedule preview failed: --days must be 1..31"
+                                    );
+                                    std::process::exit(2);
+                                }
+                            };
+                            let only = match ext.map(|e| rules::normalize_ext(&e)).transpose() {
+                                Ok(e) => e,
+                                Err(msg) => {
+                                    eprintln!("rules schedule preview failed: {}", msg);
+                                    std::process::exit(2);
+                                }
+                            };
+                            let set = guard
+                                .rules()
+                                .unwrap_or_else(|err| {
+                                    exit_with(&guard, "rules schedule preview", &err)
+                                })
+                                .user;
+                            let now = schedule::Clock::now(&schedule::SystemClock);
+                            let mut entries = Vec::new();
+                            for eff in set.expand() {
+                                if eff.rule.schedule.is_empty()
+                                    || only.as_deref().is_some_and(|e| e != eff.ext)
+                                {
+                                    continue;
+                                }
+                                let current = schedule::active_label(&eff.rule, now).to_string();
+                                let upcoming = schedule::transitions(
+                                    &eff.rule,
+                                    now,
+                                    horizon_days * schedule::MINUTES_PER_DAY,
+                                )
+                                .into_iter()
+                                .map(|(at, from, to)| {
+                                    format!(
+                                        "{{\"at_local\":{},\"from\":{},\"to\":{}}}",
+                                        json_string(&at.format()),
+                                        json_string(&from),
+                                        json_string(&to)
+                                    )
+                                })
+                                .collect::<Vec<_>>()
+                                .join(",");
+                                entries.push(format!(
+                                    "{{\"ext\":{},\"current\":{},\"transitions\":[{}]}}",
+                                    json_string(&eff.ext),
+                                    json_string(&current),
+                                    upcoming
+                                ));
+                            }
+                            println!(
+                                "{{\"now_local\":{},\"days\":{},\"rules\":[{}]}}",
+                                json_string(&now.format()),
+                                horizon_days,
+                                entries.join(",")
+                            );
+                            std::process::exit(0);
+                        }
+                        _ => {
+                            eprintln!("{}", usage);
+                            std::process::exit(2);
+                        }
+                    }
+                }
+                "set" | "enable" | "disable" => {
+                    let usage = if action == "set" {
+                        "usage: fag rules set (--ext <.ext> | --group <group>) [--name <label>] [--mode <auto|monitor|approve|inherit>] [--interval <seconds>] [--backoff-base <seconds>] [--backoff-max <seconds>] [--backoff-policy <exponential|linear|fixed|never|inherit>] [--backoff-retries <n|inherit>] [--backoff-cooldown <seconds|inherit>] [--grace <seconds|inherit>] [--pause-for <process>,...] [--description <text>] [--allow <label|ProgId>,...] [--fallback <label>,...]   (0 / empty clears; for retries, cool-down and grace 0 is a value and inherit clears)"
+                    } else {
+                        "usage: fag rules <enable|disable> (--ext <.ext> | --group <group>)"
+                    };
+                    let mut ext: Option<String> = None;
+                    let mut group: Option<String> = None;
+                    let mut name: Option<String> = None;
+                    let mut mode: Option<Option<rules::RuleMode>> = None;
+                    let mut interval: Option<u64> = None;
+                    let mut backoff_base: Option<u64> = None;
+                    let mut backoff_max: Option<u64> = None;
+                    let mut backoff_policy: Option<Option<rules::BackoffPolicy>> = None;
+                    let mut backoff_retries: Option<Option<u32>> = None;
+                    let mut backoff_cooldown: Option<Option<u64>> = None;
+                    let mut grace: Option<Option<u64>> = None;
+                    let mut pause_for: Option<Vec<String>> = None;
+                    let mut description: Option<String> = None;
+                    let mut allow: Option<Vec<String>> = None;
+                    let mut fallback: Option<Vec<String>> = None;
+                    while let Some(arg) = args.next() {
+                        let parse_secs = |v: Option<String>| -> u64 {
+                            match v.as_deref().map(str::parse::<u64>) {
+                                Some(Ok(n)) => n,
+                                _ => {
+                                    eprintln!("{}", usage);
+                                    std::process::exit(2);
+                                }
+                            }
+                        };
+                        match arg.as_str() {
+                            "--ext" => ext = args.next(),
+                            "--group" => group = args.next(),
+                            "--name" if action == "set" => name = args.next(),
+                            "--mode" if action == "set" => {
+                                let v = args.next().unwrap_or_default();
+                                mode = Some(if v.eq_ignore_ascii_case("inherit") {
+                                    None
+                                } else {
+                                    match rules::RuleMode::parse(&v) {
+                                        Some(m) => Some(m),
+                                        None => {
+                                            eprintln!("{}", usage);
+                                            std::process::exit(2);
+                                        }
+                                    }
+                                });
+                            }
+                            "--interval" if action == "set" => {
+                                interval = Some(parse_secs(args.next()))
+                            }
+                            "--backoff-base" if action == "set" => {
+                                backoff_base = Some(parse_secs(args.next()))
+                            }
+                            "--backoff-max" if action == "set" => {
+                                backoff_max = Some(parse_secs(args.next()))
+                            }
+                            "--backoff-policy" if action == "set" => {
+                                let v = args.next().unwrap_or_default();
+                                backoff_policy = Some(if v.eq_ignore_ascii_case("inherit") {
+                                    None
+                                } else {
+                                    match rules::BackoffPolicy::parse(&v) {
+                                        Some(p) => Some(p),
+                                        None => {
+                                            eprintln!("{}", usage);
+                                            std::process::exit(2);
+                                        }
+                                    }
+                                });
+                            }
+                            "--backoff-retries" if action == "set" => {
+                                let v = args.next();
+                                backoff_retries = Some(match v.as_deref() {
+                                    Some(i) if i.eq_ignore_ascii_case("inherit") => None,
+                                    _ => match u32::try_from(parse_secs(v)) {
+                                        Ok(n) => Some(n),
+                                        Err(_) => {
+                                            eprintln!("{}", usage);
+                                            std::process::exit(2);
+                                        }
+                                    },
+                                });
+                            }
+                            "--backoff-cooldown" if action == "set" => {
+                                let v = args.next();
+                                backoff_cooldown = Some(match v.as_deref() {
+                                    Some(i) if i.eq_ignore_ascii_case("inherit") => None,
+                                    _ => Some(parse_secs(v)),
+                                });
+                            }
+                            "--grace" if action == "set" => {
+                                let v = args.next();
+                                grace = Some(match v.as_deref() {
+                                    Some(i) if i.eq_ignore_ascii_case("inherit") => None,
+                                    _ => Some(parse_secs(v)),
+                                });
+                            }
+                            "--pause-for" if action == "set" => {
+                                let v = args.next().unwrap_or_default();
+                                let names: Result<Vec<String>, String> = v
+                                    .split(',')
+                                    .filter(|p| !p.trim().is_empty())
+                                    .map(processes::normalize_pattern)
+                                    .collect();
+                                match names {
+                                    Ok(names) => pause_for = Some(names),
+                                    Err(msg) => {
+                                        eprintln!("rules set failed: --pause-for {}", msg);
+                                        std::process::exit(2);
+                                    }
+                                }
+                            }
+                            "--description" if action == "set" => description = args.next(),
+                            "--fallback" if action == "set" => {
+                                fallback = Some(
+                                    args.next()
+                                        .unwrap_or_default()
+                                        .split(',')
+                                        .map(|a| a.trim().to_ascii_lowercase())
+                                        .filter(|a| !a.is_empty())
+                                        .collect(),
+                                )
+                            }
+                            "--allow" if action == "set" => {
+                                allow = Some(
+                                    args.next()
+                                        .unwrap_or_default()
+                                        .split(',')
+                                        .map(str::trim)
+                                        .filter(|a| !a.is_empty())
+                                        .map(str::to_string)
+                                        .collect(),
+                                )
+                            }
+                            _ => {}
+                        }
+                    }
+                    let ext = match rule_key(ext, group) {
+                        Ok(k) => k,
+                        Err(msg) => {
+                            eprintln!("rules {} failed: {}", action, msg);
+                            eprintln!("{}", usage);
+                            std::process::exit(2);
+                        }
+                    };
+                    refuse_if_managed(&guard, &ext, &action);
+                    let label = name.map(|n| n.trim().to_ascii_lowercase());
+                    if label.as_deref() == Some("") {
+                        eprintln!("rules set failed: --name is empty");
+                        std::process::exit(2);
+                    }
+                    if let Some(label) = label.as_deref().filter(|_| !rules::is_group_key(&ext)) {
+                        if !matches!(guard.capture_for(&ext, label), Ok(Some(_))) {
                             eprintln!(
-                                "apply-latest failed: no capture found for ext={} name={}. Run capture first: fag capture-latest --ext {} --name {}",
+                                "rules set failed: capture missing for ext={} name={}. Run: fag capture-latest --ext {} --name {}",
                                 ext, label, ext, label
                             );
                             std::process::exit(1);
                         }
-                        Err(err) => {
-                            eprintln!("apply-latest failed: store read error: {}", err);
-                            std::process::exit(1);
+                    }
+
+                    let path = settings.rules_path().to_path_buf();
+                    let mut updated: Option<rules::Rule> = None;
+                    let res = guard.update_rule(&ext, |rule| {
+                        match action.as_str() {
+                            "enable" => rule.enabled = true,
+                            "disable" => rule.enabled = false,
+                            _ => {}
                         }
-                    };
-                    (cap.prog_id, cap.hash, format!("store:{}", label))
+                        if let Some(label) = label {
+                            rule.name = label;
+                            rule.follow = None;
+                        }
+                        if let Some(mode) = mode {
+                            rule.mode = mode;
+                        }
+                        if let Some(n) = interval {
+                            rule.interval_secs = (n > 0).then_some(n);
+                        }
+                        if backoff_base.is_some()
+                            || backoff_max.is_some()
+                            || backoff_policy.is_some()
+                            || backoff_retries.is_some()
+                            || backoff_cooldown.is_some()
+                        {
+                            let mut b = rule.backoff.take().unwrap_or_default();
+                            if let Some(n) = backoff_base {
+                                b.base_secs = (n > 0).then_some(n);
+                            }
+                            if let Some(n) = backoff_max {
+                                b.max_secs = (n > 0).then_some(n);
+                            }
+                            if let Some(p) = backoff_policy {
+                                b.policy = p;
+                            }
+                            if let Some(n) = backoff_retries {
+                                b.max_retries = n;
+                            }
+                            if let Some(n) = backoff_cooldown {
+                                b.cooldown_secs = n;
+                            }
+                            rule.backoff = (b != rules::BackoffOverride::default()).then_some(b);
+                        }
+                        if let Some(g) = grace {
+                            rule.grace_secs = g;
+                        }
+                        if let Some(p) = pause_for {
+                            rule.pause_for = p;
+                        }
+                        if let Some(a) = allow {
+                            rule.allow = a;
+                        }
+                        if let Some(f) = fallback {
+                            rule.fallback = f;
+                        }
+                        if let Some(d) = description {
+                            let d = d.trim();
+                            rule.description = (!d.is_empty()).then(|| d.to_string());
+                        }
+                        updated = Some(rule.clone());
+                    });
+                    match res {
+                        Ok(()) => {
+                            let rule = updated.unwrap_or_else(|| rules::Rule::new(""));
+                            println!(
+                                "{{\"status\":\"UPDATED\",\"rule\":{},\"rules_path\":{}}}",
+                                rule_json(&ext, &rule),
+                                json_string(path.to_string_lossy().as_ref())
+                            );
+                            std::process::exit(0);
+                        }
+                        Err(fag_core::Error::NotFound(_)) => {
+                            eprintln!(
+                                "rules {}: not found for {}. Add it first: fag rules add --{} {} --name <label>",
+                                action,
+                                ext,
+                                if rules::is_group_key(&ext) { "group" } else { "ext" },
+                                ext
+                            );
+                            std::process::exit(2);
+                        }
+                        Err(err) => exit_with(&guard, &format!("rules {}", action), &err),
+                    }
                 }
-                (None, Some(p), Some(h)) => (p, h, "inline".to_string()),
                 _ => {
-                    eprintln!("usage: fag apply-latest --ext <.ext> --name <label>");
                     eprintln!(
-                        "   or: fag apply-latest --ext <.ext> --progid <ProgId> --hash <Hash>"
+                        "usage: fag rules <list|add|remove|set|enable|disable|group|schedule|convert> ..."
                     );
                     std::process::exit(2);
                 }
+            }
+        }
+        "profile" => {
+            let usage =
+                "usage: fag profile <list|save <name>|use <name> [--no-apply]|delete <name>>";
+            let Some(action) = args.next() else {
+                eprintln!("{}", usage);
+                std::process::exit(2);
             };
+            let path = settings.rules_path().to_path_buf();
+            if action == "list" {
+                let set = guard
+                    .rules()
+                    .unwrap_or_else(|err| exit_with(&guard, "profile list", &err))
+                    .user;
+                let profiles = set
+                    .profile_summaries()
+                    .into_iter()
+                    .map(|(name, count, active)| {
+                        format!(
+                            "{{\"name\":{},\"rule_count\":{},\"active\":{}}}",
+                            json_string(&name),
+                            count,
+                            active
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                println!(
+                    "{{\"active\":{},\"profiles\":[{}],\"rules_path\":{}}}",
+                    json_string(set.active_profile_name()),
+                    profiles,
+                    json_string(path.to_string_lossy().as_ref())
+                );
+                std::process::exit(0);
+            }
 
-            match fag_core::registry::set_user_choice_latest_replay(&ext, &progid, &hash) {
-                Ok(()) => {
-                    let effective_raw = match fag_core::registry::effective_progid_for_ext(&ext) {
-                        Ok(v) => v,
-                        Err(err) => {
-                            eprintln!("warning: effective progid query failed: {}", err);
-                            None
+            let Some(name) = args.next() else {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            };
+            let no_apply = args.any(|a| a == "--no-apply");
+
+            match action.as_str() {
+                "save" => {
+                    let name = guard
+                        .save_profile(&name)
+                        .unwrap_or_else(|err| exit_with(&guard, "profile save", &err));
+                    println!(
+                        "{{\"status\":\"SAVED\",\"profile\":{},\"rules_path\":{}}}",
+                        json_string(&name),
+                        json_string(path.to_string_lossy().as_ref())
+                    );
+                    std::process::exit(0);
+                }
+                "delete" => match guard.delete_profile(&name) {
+                    Ok(name) => {
+                        println!(
+                            "{{\"status\":\"DELETED\",\"profile\":{}}}",
+                            json_string(&name)
+                        );
+                        std::process::exit(0);
+                    }
+                    Err(fag_core::Error::UnknownProfile(name)) => {
+                        eprintln!(
+                            "profile delete: no inactive profile named {} (the active profile cannot be deleted)",
+                            name
+                        );
+                        std::process::exit(2);
+                    }
+                    Err(err) => exit_with(&guard, "profile delete", &err),
+                },
+                "use" => {
+                    let switch = match guard.use_profile(&name, !no_apply) {
+                        Ok(switch) => switch,
+                        Err(fag_core::Error::UnknownProfile(name)) => {
+                            eprintln!(
+                                "profile use: unknown profile {} (create it with: fag profile save {})",
+                                name, name
+                            );
+                            std::process::exit(2);
                         }
+                        Err(err) => exit_with(&guard, "profile use", &err),
                     };
-                    let ok = effective_raw.as_deref() == Some(progid.as_str());
-                    let effective = effective_raw
-                        .as_deref()
-                        .map(json_string)
-                        .unwrap_or("null".into());
-                    println!(
-                        "{{\"ext\":{},\"status\":{},\"prog_id\":{},\"effective_progid\":{},\"source\":{},\"hint\":{}}}",
-                        json_string(&ext),
-                        json_string(if ok { "APPLIED" } else { "REJECTED" }),
-                        json_string(&progid),
-                        effective,
-                        json_string(&source),
-                        json_string(if ok {
-                            ""
-                        } else {
-                            "系统可能拒绝/回滚了这次写入：请去 Windows 设置里手动改回默认程序；本工具会记录/提醒篡改事件。"
+
+                    let (mut applied, mut failed, mut skipped) = (0usize, 0usize, 0usize);
+                    for outcome in &switch.outcomes {
+                        let line = match outcome {
+                            ProfileApply::FollowUnresolved { ext, unresolved } => {
+                                skipped += 1;
+                                println!(
+                                    "{}",
+                                    WatchEvent::follow_unresolved(ext, unresolved).to_json()
+                                );
+                                continue;
+                            }
+                            ProfileApply::TargetUnavailable { ext, rule } => {
+                                skipped += 1;
+                                println!(
+                                    "{}",
+                                    WatchEvent::target_unavailable(ext, rule, None).to_json()
+                                );
+                                continue;
+                            }
+                            ProfileApply::NoCapture { ext, label } => {
+                                skipped += 1;
+                                println!(
+                                    "{{\"ext\":{},\"name\":{},\"status\":\"NO_CAPTURE\",\"hint\":{}}}",
+                                    json_string(ext),
+                                    json_string(label),
+                                    json_string(&format!(
+                                        "fag capture-latest --ext {} --name {}",
+                                        ext, label
+                                    ))
+                                );
+                                continue;
+                            }
+                            ProfileApply::Applied { label, applied: a } => {
+                                let ok = a.accepted();
+                                if ok {
+                                    applied += 1;
+                                } else {
+                                    failed += 1;
+                                }
+                                format!(
+                                    "{{\"time_unix_ms\":{},\"ext\":{},\"name\":{},\"status\":{},\"effective_progid\":{},\"target_progid\":{},\"profile\":{}}}",
+                                    unix_time_ms(),
+                                    json_string(&a.ext),
+                                    json_string(label),
+                                    json_string(if ok { "APPLIED" } else { "REJECTED" }),
+                                    a.effective.as_deref().map(json_string).unwrap_or("null".into()),
+                                    json_string(&a.prog_id),
+                                    json_string(&switch.profile)
+                                )
+                            }
+                            ProfileApply::Failed { ext, label, error } => {
+                                failed += 1;
+                                format!(
+                                    "{{\"time_unix_ms\":{},\"ext\":{},\"name\":{},\"status\":\"ERROR\",\"error\":{},\"profile\":{}}}",
+                                    unix_time_ms(),
+                                    json_string(ext),
+                                    json_string(label),
+                                    json_string(error),
+                                    json_string(&switch.profile)
+                                )
+                            }
+                        };
+                        println!("{}", line);
+                        let _ = guard.record(&line);
+                    }
+
+                    let line = format!(
+                        "{{\"time_unix_ms\":{},\"status\":\"PROFILE_ACTIVE\",\"profile\":{},\"from\":{},\"applied\":{},\"failed\":{},\"skipped\":{}}}",
+                        unix_time_ms(),
+                        json_string(&switch.profile),
+                        json_string(&switch.from),
+                        applied,
+                        failed,
+                        skipped
+                    );
+                    println!("{}", line);
+                    let _ = guard.record(&line);
+                    std::process::exit(if failed > 0 { 1 } else { 0 });
+                }
+                _ => {
+                    eprintln!("{}", usage);
+                    std::process::exit(2);
+                }
+            }
+        }
+        "config" => {
+            let Some(action) = args.next() else {
+                eprintln!("usage: fag config <show|get <key>|set <key> <value>>");
+                std::process::exit(2);
+            };
+
+            match action.as_str() {
+                "show" => {
+                    let entries = config::KEYS
+                        .iter()
+                        .filter_map(|key| {
+                            let (value, source) = config::get_value(settings, key)?;
+                            Some(format!(
+                                "{}:{{\"value\":{},\"source\":{}}}",
+                                json_string(key),
+                                json_string(&value),
+                                json_string(source.as_str())
+                            ))
                         })
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    println!(
+                        "{{\"config_path\":{},\"home\":{},\"portable\":{},\"key_path\":{},\"policy_path\":{},\"settings\":{{{}}}}}",
+                        json_string(settings.config_path.to_string_lossy().as_ref()),
+                        json_string(settings.home.to_string_lossy().as_ref()),
+                        settings.portable.0,
+                        json_string(settings.key_path.to_string_lossy().as_ref()),
+                        settings
+                            .policy_path
+                            .as_deref()
+                            .map(|p| json_string(p.to_string_lossy().as_ref()))
+                            .unwrap_or_else(|| "null".into()),
+                        entries
                     );
-                    std::process::exit(if ok { 0 } else { 1 });
+                    std::process::exit(0);
                 }
-                Err(err) => {
-                    eprintln!("apply-latest failed: {}", err);
-                    std::process::exit(1);
+                "get" => {
+                    let Some(key) = args.next() else {
+                        eprintln!("usage: fag config get <key>");
+                        std::process::exit(2);
+                    };
+                    let Some((value, _)) = config::get_value(settings, &key) else {
+                        eprintln!(
+                            "config get failed: unknown key '{}'. known keys: {}",
+                            key,
+                            config::KEYS.join(", ")
+                        );
+                        std::process::exit(2);
+                    };
+                    println!("{}", value);
+                    std::process::exit(0);
+                }
+                "set" => {
+                    let (Some(key), Some(value)) = (args.next(), args.next()) else {
+                        eprintln!("usage: fag config set <key> <value>   (empty value unsets)");
+                        std::process::exit(2);
+                    };
+                    let mut cfg =
+                        match config::read_config_file(&settings.config_path, &settings.key_path) {
+                            Ok(c) => c.unwrap_or_default(),
+                            Err(err) => {
+                                eprintln!("config set failed: config read error: {}", err);
+                                std::process::exit(1);
+                            }
+                        };
+                    if let Err(msg) = config::set_value(&mut cfg, &key, &value) {
+                        eprintln!("config set failed: {}", msg);
+                        std::process::exit(2);
+                    }
+                    if let Err(err) =
+                        config::save_config_file(&settings.config_path, &settings.key_path, &cfg)
+                    {
+                        eprintln!("config set failed: config write error: {}", err);
+                        std::process::exit(1);
+                    }
+                    println!(
+                        "{{\"status\":\"SET\",\"key\":{},\"value\":{},\"config_path\":{}}}",
+                        json_string(&key),
+                        json_string(value.trim()),
+                        json_string(settings.config_path.to_string_lossy().as_ref())
+                    );
+                    std::process::exit(0);
+                }
+                _ => {
+                    eprintln!("usage: fag config <show|get <key>|set <key> <value>>");
+                    std::process::exit(2);
                 }
             }
         }
-        "captures" => {
-            let mut ext: Option<String> = None;
+        "export" => {
+            let mut out: Option<String> = None;
             while let Some(arg) = args.next() {
+                if arg == "--out" {
+                    out = args.next();
+                }
+            }
+            let Some(out) = out else {
+                eprintln!("usage: fag export --out <bundle.json>");
+                std::process::exit(2);
+            };
+
+            let out_path = std::path::PathBuf::from(out);
+            let b = guard
+                .export(&out_path)
+                .unwrap_or_else(|err| exit_with(&guard, "export", &err));
+            println!(
+                "{{\"status\":\"EXPORTED\",\"path\":{},\"rules\":{},\"captures\":{},\"config\":{},\"sid\":{},\"machine\":{},\"checksum\":{}}}",
+                json_string(out_path.to_string_lossy().as_ref()),
+                b.manifest.rule_count,
+                b.manifest.capture_count,
+                b.config.is_some(),
+                b.manifest.source.sid.as_deref().map(json_string).unwrap_or("null".into()),
+                b.manifest.source.machine.as_deref().map(json_string).unwrap_or("null".into()),
+                json_string(&b.checksum)
+            );
+            std::process::exit(0);
+        }
+        "import" => {
+            let mut file: Option<String> = None;
+            let mut mode = bundle::ImportMode::Merge;
+            let mut dry_run = false;
+            let mut allow_foreign = false;
+            for arg in args.by_ref() {
                 match arg.as_str() {
-                    "--ext" => ext = args.next(),
+                    "--merge" => mode = bundle::ImportMode::Merge,
+                    "--replace" => mode = bundle::ImportMode::Replace,
+                    "--dry-run" => dry_run = true,
+                    "--allow-foreign" => allow_foreign = true,
+                    _ if file.is_none() => file = Some(arg),
                     _ => {}
                 }
             }
-
-            let Some(ext_raw) = ext else {
-                eprintln!("usage: fag captures --ext <.ext>");
+            let Some(file) = file else {
+                eprintln!("usage: fag import <bundle.json> [--merge|--replace] [--dry-run] [--allow-foreign]");
                 std::process::exit(2);
             };
-            let ext = match normalize_ext_for_store(&ext_raw) {
-                Ok(e) => e,
-                Err(msg) => {
-                    eprintln!("captures failed: {}", msg);
-                    std::process::exit(2);
+
+            let b = match bundle::read_bundle(std::path::Path::new(&file)) {
+                Ok(b) => b,
+                Err(err) => {
+                    eprintln!("import failed: {}", err);
+                    std::process::exit(1);
                 }
             };
+            let plan = guard
+                .plan_import(&b, mode, allow_foreign)
+                .unwrap_or_else(|err| exit_with(&guard, "import", &err));
+            if !dry_run {
+                if let Err(err) = guard.import(&b, &plan) {
+                    exit_with(&guard, "import", &err);
+                }
+            }
 
-            let path = captures::default_store_path();
-            let names = captures::list_capture_names(&path, &ext).unwrap_or_default();
-            let joined = names
-                .into_iter()
-                .map(|s| json_string(&s))
+            let conflicts = plan
+                .conflicts
+                .iter()
+                .map(|c| match c {
+                    bundle::Conflict::Rule {
+                        ext,
+                        existing,
+                        incoming,
+                    } => format!(
+                        "{{\"kind\":\"rule\",\"ext\":{},\"existing\":{},\"incoming\":{}}}",
+                        json_string(ext),
+                        rule_json(ext, existing),
+                        rule_json(ext, incoming)
+                    ),
+                    bundle::Conflict::Capture {
+                        ext,
+                        name,
+                        existing_prog_id,
+                        incoming_prog_id,
+                        hash_differs,
+                    } => format!(
+                        "{{\"kind\":\"capture\",\"ext\":{},\"name\":{},\"existing_prog_id\":{},\"incoming_prog_id\":{},\"hash_differs\":{}}}",
+                        json_string(ext),
+                        json_string(name),
+                        json_string(existing_prog_id),
+                        json_string(incoming_prog_id),
+                        hash_differs
+                    ),
+                })
                 .collect::<Vec<_>>()
                 .join(",");
+            let pairs = |items: &[(String, String)]| {
+                items
+                    .iter()
+                    .map(|(ext, name)| {
+                        format!(
+                            "{{\"ext\":{},\"name\":{}}}",
+                            json_string(ext),
+                            json_string(name)
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
             println!(
-                "{{\"ext\":{},\"names\":[{}],\"store_path\":{}}}",
-                json_string(&ext),
-                joined,
-                json_string(path.to_string_lossy().as_ref())
+                "{{\"status\":{},\"mode\":{},\"source_sid\":{},\"source_machine\":{},\"target_sid\":{},\"added_rules\":{},\"added_captures\":{},\"conflicts\":[{}],\"foreign_captures\":[{}],\"foreign_captures_imported\":{},\"needs_capture\":[{}]}}",
+                json_string(if dry_run { "DRY_RUN" } else { "IMPORTED" }),
+                json_string(match mode {
+                    bundle::ImportMode::Merge => "merge",
+                    bundle::ImportMode::Replace => "replace",
+                }),
+                b.manifest.source.sid.as_deref().map(json_string).unwrap_or("null".into()),
+                b.manifest.source.machine.as_deref().map(json_string).unwrap_or("null".into()),
+                plan.target_sid.as_deref().map(json_string).unwrap_or("null".into()),
+                plan.added_rules,
+                plan.added_captures,
+                conflicts,
+                pairs(&plan.foreign_captures),
+                allow_foreign,
+                pairs(&plan.needs_capture)
             );
+            if !plan.foreign_captures.is_empty() && !allow_foreign {
+                eprintln!(
+                    "note: {} capture(s) come from another user/machine; their hash cannot be valid here. Re-capture with: fag capture-latest --ext <.ext> --name <label>",
+                    plan.foreign_captures.len()
+                );
+            }
             std::process::exit(0);
         }
-        "rules" => {
+        "integrity" => {
             let Some(action) = args.next() else {
-                eprintln!("usage: fag rules <list|add|remove> ...");
+                eprintln!("usage: fag integrity <status|reseal>");
                 std::process::exit(2);
             };
+            let stores = [
+                ("config", settings.config_path.clone()),
+                ("rules", settings.rules_path().to_path_buf()),
+                ("captures", settings.captures_path().to_path_buf()),
+            ];
 
             match action.as_str() {
-                "list" => {
-                    let path = rules::default_rules_path();
-                    let items = rules::list_rules(&path).unwrap_or_default();
-                    let joined = items
-                        .into_iter()
-                        .map(|(ext, name)| {
+                "status" => {
+                    let mut any_tampered = false;
+                    let items = stores
+                        .iter()
+                        .map(|(store, path)| {
+                            let (status, reason) = match std::fs::read(path) {
+                                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                                    ("MISSING", None)
+                                }
+                                Err(e) => ("ERROR", Some(e.to_string())),
+                                Ok(bytes) => match integrity::verify_store_bytes(
+                                    path,
+                                    &settings.key_path,
+                                    &bytes,
+                                ) {
+                                    Ok(_) => ("OK", None),
+                                    Err(e) => match integrity::as_tampered(&e) {
+                                        Some(t) => {
+                                            any_tampered = true;
+                                            ("TAMPERED", Some(t.reason.to_string()))
+                                        }
+                                        None => ("ERROR", Some(e.to_string())),
+                                    },
+                                },
+                            };
                             format!(
-                                "{{\"ext\":{},\"name\":{}}}",
-                                json_string(&ext),
-                                json_string(&name)
+                                "{{\"store\":{},\"path\":{},\"status\":{},\"reason\":{}}}",
+                                json_string(store),
+                                json_string(path.to_string_lossy().as_ref()),
+                                json_string(status),
+                                reason.as_deref().map(json_string).unwrap_or("null".into())
                             )
                         })
                         .collect::<Vec<_>>()
                         .join(",");
+                    let key_path = &settings.key_path;
                     println!(
-                        "{{\"rules\":[{}],\"rules_path\":{}}}",
-                        joined,
-                        json_string(path.to_string_lossy().as_ref())
+                        "{{\"stores\":[{}],\"key_path\":{}}}",
+                        items,
+                        json_string(key_path.to_string_lossy().as_ref())
                     );
-                    std::process::exit(0);
+                    std::process::exit(if any_tampered { 3 } else { 0 });
                 }
-                "add" => {
-                    let mut ext: Option<String> = None;
-                    let mut name: Option<String> = None;
-                    while let Some(arg) = args.next() {
-                        match arg.as_str() {
-                            "--ext" => ext = args.next(),
-                            "--name" => name = args.next(),
-                            _ => {}
-                        }
-                    }
-
-                    let (Some(ext_raw), Some(name_raw)) = (ext, name) else {
-                        eprintln!("usage: fag rules add --ext <.ext> --name <label>");
-                        std::process::exit(2);
-                    };
-                    let ext = match normalize_ext_for_store(&ext_raw) {
-                        Ok(e) => e,
-                        Err(msg) => {
-                            eprintln!("rules add failed: {}", msg);
-                            std::process::exit(2);
+                "reseal" => {
+                    for (store, path) in stores.iter() {
+                        match integrity::reseal_file(path, &settings.key_path) {
+                            Ok(resealed) => println!(
+                                "{{\"store\":{},\"path\":{},\"status\":{}}}",
+                                json_string(store),
+                                json_string(path.to_string_lossy().as_ref()),
+                                json_string(if resealed { "RESEALED" } else { "MISSING" })
+                            ),
+                            Err(err) => {
+                                eprintln!("integrity reseal failed for {}: {}", store, err);
+                                std::process::exit(1);
+                            }
                         }
-                    };
-                    let label = name_raw.trim().to_ascii_lowercase();
-                    if label.is_empty() {
-                        eprintln!("rules add failed: --name is empty");
-                        std::process::exit(2);
-                    }
-
-                    let cap_path = captures::default_store_path();
-                    let cap_ok = matches!(
-                        captures::get_latest_capture(&cap_path, &ext, &label),
-                        Ok(Some(_))
-                    );
-                    if !cap_ok {
-                        eprintln!(
-                            "rules add failed: capture missing for ext={} name={}. Run: fag capture-latest --ext {} --name {}",
-                            ext, label, ext, label
-                        );
-                        std::process::exit(1);
-                    }
-
-                    let path = rules::default_rules_path();
-                    if let Err(err) = rules::upsert_rule(&path, &ext, &label) {
-                        eprintln!("rules add failed: store write error: {}", err);
-                        std::process::exit(1);
                     }
-                    println!(
-                        "{{\"status\":\"ADDED\",\"ext\":{},\"name\":{},\"rules_path\":{}}}",
-                        json_string(&ext),
-                        json_string(&label),
-                        json_string(path.to_string_lossy().as_ref())
-                    );
                     std::process::exit(0);
                 }
-                "remove" => {
-                    let mut ext: Option<String> = None;
-                    while let Some(arg) = args.next() {
-                        match arg.as_str() {
-                            "--ext" => ext = args.next(),
-                            _ => {}
-                        }
-                    }
-                    let Some(ext_raw) = ext else {
-                        eprintln!("usage: fag rules remove --ext <.ext>");
-                        std::process::exit(2);
-                    };
-                    let ext = match normalize_ext_for_store(&ext_raw) {
-                        Ok(e) => e,
-                        Err(msg) => {
-                            eprintln!("rules remove failed: {}", msg);
-                            std::process::exit(2);
-                        }
-                    };
-                    let path = rules::default_rules_path();
-                    match rules::remove_rule(&path, &ext) {
-                        Ok(true) => {
-                            println!(
-                                "{{\"status\":\"REMOVED\",\"ext\":{},\"rules_path\":{}}}",
-                                json_string(&ext),
-                                json_string(path.to_string_lossy().as_ref())
-                            );
-                            std::process::exit(0);
-                        }
-                        Ok(false) => {
-                            eprintln!("rules remove: not found for {}", ext);
-                            std::process::exit(2);
-                        }
-                        Err(err) => {
-                            eprintln!("rules remove failed: store write error: {}", err);
-                            std::process::exit(1);
-                        }
-                    }
-                }
                 _ => {
-                    eprintln!("usage: fag rules <list|add|remove> ...");
+                    eprintln!("usage: fag integrity <status|reseal>");
                     std::process::exit(2);
                 }
             }
         }
         "check" => {
-            let rules_path = rules::default_rules_path();
-            let rules_items = match rules::list_rules(&rules_path) {
-                Ok(v) => v,
-                Err(err) => {
-                    eprintln!("check failed: rules read error: {}", err);
-                    std::process::exit(1);
-                }
-            };
-
-            if rules_items.is_empty() {
+            let items = guard
+                .check()
+                .unwrap_or_else(|err| exit_with(&guard, "check", &err));
+            if items.is_empty() {
                 eprintln!(
                     "check: no rules found (add one with: fag rules add --ext .mp4 --name <label>)"
                 );
                 std::process::exit(2);
             }
 
-            let cap_path = captures::default_store_path();
-            let log_path = logging::default_log_path();
             let mut has_tampered = false;
-            for (ext, label) in rules_items {
-                let cap = match captures::get_latest_capture(&cap_path, &ext, &label) {
-                    Ok(Some(c)) => c,
-                    Ok(None) => {
-                        eprintln!(
-                            "check failed: capture missing for ext={} name={}. Re-capture: fag capture-latest --ext {} --name {}",
-                            ext, label, ext, label
+            for item in &items {
+                let (ext, label) = (&item.ext, &item.rule.name);
+                if item.target_unavailable() {
+                    let line =
+                        WatchEvent::target_unavailable(ext, &item.rule, item.resolved.as_ref())
+                            .to_json();
+                    println!("{}", line);
+                    let _ = guard.record(&line);
+                }
+                let (ok, effective, target, target_progid, matched) = match &item.status {
+                    CheckStatus::Disabled => {
+                        println!(
+                            "{{\"ext\":{},\"name\":{},\"status\":\"DISABLED\"}}",
+                            json_string(ext),
+                            json_string(label)
                         );
-                        std::process::exit(1);
+                        continue;
                     }
-                    Err(err) => {
-                        eprintln!("check failed: capture store read error: {}", err);
-                        std::process::exit(1);
+                    CheckStatus::FollowUnresolved(u) => {
+                        let line = WatchEvent::follow_unresolved(ext, u).to_json();
+                        println!("{}", line);
+                        let _ = guard.record(&line);
+                        continue;
                     }
-                };
-
-                let effective = match fag_core::registry::effective_progid_for_ext(&ext) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("check failed: effective progid query failed: {}", err);
-                        std::process::exit(1);
+                    CheckStatus::NoCapture => {
+                        // Group members are guarded once captured; not having one yet is not an error.
+                        println!(
+                            "{{\"ext\":{},\"name\":{},\"status\":\"NO_CAPTURE\",\"group\":{},\"hint\":{}}}",
+                            json_string(ext),
+                            json_string(label),
+                            json_string(item.group.as_deref().unwrap_or_default()),
+                            json_string(&format!("fag capture-latest --ext {} --name {}", ext, label))
+                        );
+                        continue;
                     }
+                    CheckStatus::Ok {
+                        effective,
+                        target,
+                        target_progid,
+                        matched,
+                    } => (true, effective, target, target_progid, Some(matched)),
+                    CheckStatus::Tampered {
+                        effective,
+                        target,
+                        target_progid,
+                    } => (false, effective, target, target_progid, None),
                 };
-                let ok = effective.as_deref() == Some(cap.prog_id.as_str());
                 if !ok {
                     has_tampered = true;
                 }
                 let line = format!(
-                    "{{\"ext\":{},\"name\":{},\"status\":{},\"effective_progid\":{},\"target_progid\":{}}}",
-                    json_string(&ext),
-                    json_string(&label),
+                    "{{\"ext\":{},\"name\":{},\"status\":{},\"effective_progid\":{},\"target\":{},\"target_progid\":{},\"matched\":{}}}",
+                    json_string(ext),
+                    json_string(label),
                     json_string(if ok { "OK" } else { "TAMPERED" }),
-                    effective.map(|s| json_string(&s)).unwrap_or("null".into()),
-                    json_string(&cap.prog_id)
+                    effective.as_deref().map(json_string).unwrap_or("null".into()),
+                    json_string(target),
+                    json_string(target_progid),
+                    matched.map(|s| json_string(s)).unwrap_or("null".into())
                 );
                 println!("{}", line);
                 if !ok {
-                    let _ = logging::append_line(&log_path, &line);
+                    let _ = guard.record(&line);
                 }
             }
 
             std::process::exit(if has_tampered { 2 } else { 0 });
         }
         "watch-rules" => {
-            let mut interval_secs: u64 = 5;
-            let mut monitor_only: bool = false;
+            let mut overrides = WatchOverrides::default();
             while let Some(arg) = args.next() {
                 match arg.as_str() {
+                    // Pins the interval; otherwise it follows config.json, reloaded live.
                     "--interval" => {
                         let Some(v) = args.next() else {
-                            eprintln!("usage: fag watch-rules [--interval <seconds>] [--monitor-only]");
+                            eprintln!(
+                                "usage: fag watch-rules [--interval <seconds>] [--monitor-only] [--approve]"
+                            );
                             std::process::exit(2);
                         };
-                        interval_secs = match v.parse::<u64>() {
-                            Ok(n) if n > 0 => n,
+                        overrides.interval_secs = match v.parse::<u64>() {
+                            Ok(n) if n > 0 => Some(n),
                             _ => {
                                 eprintln!("watch-rules failed: --interval must be a positive integer (seconds)");
                                 std::process::exit(2);
                             }
                         };
                     }
-                    "--monitor-only" => monitor_only = true,
+                    "--monitor-only" => overrides.monitor_only = true,
+                    "--approve" => overrides.approve = true,
                     _ => {}
                 }
             }
-
-            let rules_path = rules::default_rules_path();
-            let cap_path = captures::default_store_path();
-            let log_path = logging::default_log_path();
+            // Held until the end of the command; a second watcher exits here.
+            let lock = acquire_instance(settings, &guard, "watch-rules");
+            let interval = overrides.interval_secs;
+            let mut watcher = Watcher::rules(&guard, overrides);
             eprintln!(
-                "watch-rules interval={}s rules={} captures={} log={} (Ctrl+C to stop)",
-                interval_secs,
-                rules_path.to_string_lossy(),
-                cap_path.to_string_lossy(),
-                log_path.to_string_lossy()
+                "watch-rules interval={}s notify={} control={} rules={} captures={} log={} (Ctrl+C to stop)",
+                interval.unwrap_or(settings.interval_secs.0),
+                watcher.notify_name(),
+                watcher
+                    .control_endpoint()
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "none".into()),
+                settings.rules_path().to_string_lossy(),
+                settings.captures_path().to_string_lossy(),
+                settings.log_path().to_string_lossy()
             );
-
-            #[derive(Debug, Copy, Clone)]
-            struct BackoffState {
-                failures: u32,
-                next_allowed_ms: u128,
-                manual_only: bool,
+            watcher.run(|out| print_output("watch-rules", out));
+            drop(watcher);
+            drop(lock);
+            fag_core::shutdown::finished();
+        }
+        "ctl" => {
+            let usage =
+                "usage: fag ctl <status|pause [--for <seconds>]|resume|reload|check-now|stop>";
+            let Some(verb) = args.next() else {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            };
+            let command = match verb.as_str() {
+                "status" => Command::Status,
+                "pause" => {
+                    let mut for_secs = None;
+                    while let Some(arg) = args.next() {
+                        if arg == "--for" {
+                            for_secs = match args.next().map(|v| v.parse::<u64>()) {
+                                Some(Ok(n)) if n > 0 => Some(n),
+                                _ => {
+                                    eprintln!(
+                                        "ctl failed: --for must be a positive integer (seconds)"
+                                    );
+                                    std::process::exit(2);
+                                }
+                            };
+                        }
+                    }
+                    Command::Pause { for_secs }
+                }
+                "resume" => Command::Resume,
+                "reload" => Command::Reload,
+                "check-now" => Command::CheckNow,
+                "stop" => Command::Stop,
+                _ => {
+                    eprintln!("{}", usage);
+                    std::process::exit(2);
+                }
+            };
+            let endpoint = fag_core::control::endpoint(settings);
+            match fag_core::control::send(&endpoint, &command) {
+                Ok(reply) => {
+                    println!("{}", reply);
+                    let ok = serde_json::from_str::<serde_json::Value>(&reply)
+                        .is_ok_and(|v| v["ok"] == serde_json::Value::Bool(true));
+                    std::process::exit(if ok { 0 } else { 1 });
+                }
+                Err(err) => {
+                    eprintln!(
+                        "ctl failed: no watcher reachable at {} ({}). Is fag watch-rules running?",
+                        endpoint.to_string_lossy(),
+                        err
+                    );
+                    std::process::exit(1);
+                }
             }
-
-            fn backoff_seconds(failures: u32) -> u64 {
-                if failures == 0 {
-                    return 0;
+        }
+        "pending" => {
+            let usage =
+                "usage: fag pending <list|approve <id>|accept <id> --name <label>|dismiss <id>>";
+            let verb = args.next().unwrap_or_default();
+            let path = settings.pending_path();
+            let failed = |err: std::io::Error| -> ! {
+                if let Some(t) = integrity::as_tampered(&err) {
+                    let line = WatchEvent::config_tampered("pending", t).to_json();
+                    println!("{}", line);
+                    let _ = guard.record(&line);
+                    std::process::exit(3);
                 }
-                let shift = failures.saturating_sub(1).min(4);
-                let secs = 30u64.saturating_mul(1u64 << shift);
-                secs.min(600)
+                eprintln!("pending {} failed: {}", verb, err);
+                std::process::exit(1);
+            };
+            if verb == "list" {
+                let queue = fag_core::pending::read_queue(&path, &settings.key_path)
+                    .unwrap_or_else(|e| failed(e));
+                println!(
+                    "{{\"pending\":[{}],\"pending_path\":{}}}",
+                    queue
+                        .items
+                        .iter()
+                        .map(pending_item_json)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    json_string(path.to_string_lossy().as_ref())
+                );
+                std::process::exit(0);
             }
-
-            let mut backoff: std::collections::BTreeMap<String, BackoffState> =
-                std::collections::BTreeMap::new();
-            let mut last_emitted: std::collections::BTreeMap<String, (String, Option<String>)> =
-                std::collections::BTreeMap::new();
-
-            fn should_emit(
-                last: &mut std::collections::BTreeMap<String, (String, Option<String>)>,
-                key: &str,
-                status: &str,
-                effective: &Option<String>,
-            ) -> bool {
-                match last.get(key) {
-                    Some((prev_status, prev_effective))
-                        if prev_status == status && prev_effective == effective =>
-                    {
-                        false
-                    }
-                    _ => {
-                        last.insert(key.to_string(), (status.to_string(), effective.clone()));
-                        true
-                    }
+            if !matches!(verb.as_str(), "approve" | "accept" | "dismiss") {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            }
+            let Some(id) = args.next().and_then(|v| v.parse::<u64>().ok()) else {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            };
+            let mut name: Option<String> = None;
+            while let Some(arg) = args.next() {
+                if arg == "--name" {
+                    name = args.next();
                 }
             }
-
-            let interval = std::time::Duration::from_secs(interval_secs);
-            loop {
-                let now_ms = unix_time_ms();
-                let rules_items = rules::list_rules(&rules_path).unwrap_or_default();
-                if rules_items.is_empty() {
-                    eprintln!("watch-rules: no rules found");
-                    std::thread::sleep(interval);
-                    continue;
+            let item = match fag_core::pending::read_queue(&path, &settings.key_path) {
+                Ok(queue) => queue.get(id).cloned(),
+                Err(err) => failed(err),
+            };
+            let Some(item) = item else {
+                eprintln!(
+                    "pending {} failed: no pending item {} (see: fag pending list)",
+                    verb, id
+                );
+                std::process::exit(2);
+            };
+            let status = match verb.as_str() {
+                "approve" => {
+                    fag_core::pending::update(&path, &settings.key_path, |q| q.approve(id))
+                        .unwrap_or_else(|e| failed(e));
+                    "APPROVED"
                 }
-
-                for (ext, label) in rules_items.iter() {
-                    let key = format!("{}|{}", ext, label);
-                    let cap = match captures::get_latest_capture(&cap_path, ext, label) {
-                        Ok(Some(c)) => c,
-                        _ => {
-                            eprintln!(
-                                "watch-rules: capture missing ext={} name={} (skip)",
-                                ext, label
-                            );
-                            continue;
-                        }
+                "accept" => {
+                    let Some(name) = name else {
+                        eprintln!("{}", usage);
+                        std::process::exit(2);
                     };
-
-                    if let Some(st) = backoff.get(&key) {
-                        if st.manual_only {
-                            // still allow "ok" check below to clear state, but don't attempt apply.
-                        } else if now_ms < st.next_allowed_ms {
-                            continue;
-                        }
-                    }
-
-                    let effective = fag_core::registry::effective_progid_for_ext(ext)
-                        .ok()
-                        .flatten();
-                    let ok = effective.as_deref() == Some(cap.prog_id.as_str());
-                    if ok {
-                        backoff.remove(&key);
-                        if should_emit(&mut last_emitted, &key, "OK", &effective) {
-                            let line = format!(
-                                "{{\"time_unix_ms\":{},\"ext\":{},\"name\":{},\"status\":\"OK\",\"effective_progid\":{},\"target_progid\":{}}}",
-                                unix_time_ms(),
-                                json_string(ext),
-                                json_string(label),
-                                effective.map(|s| json_string(&s)).unwrap_or("null".into()),
-                                json_string(&cap.prog_id)
-                            );
-                            println!("{}", line);
-                        }
-                        continue;
-                    }
-
-                    let st = backoff.get(&key).copied();
-                    let manual_only_for_key = monitor_only || st.map(|s| s.manual_only).unwrap_or(false);
-
-                    if should_emit(&mut last_emitted, &key, "TAMPERED", &effective) {
-                        let line = format!(
-                            "{{\"time_unix_ms\":{},\"ext\":{},\"name\":{},\"status\":\"TAMPERED\",\"effective_progid\":{},\"target_progid\":{},\"mode\":{}}}",
-                            unix_time_ms(),
-                            json_string(ext),
-                            json_string(label),
-                            effective.map(|s| json_string(&s)).unwrap_or("null".into()),
-                            json_string(&cap.prog_id),
-                            json_string(if manual_only_for_key { "MONITOR_ONLY" } else { "AUTO_RESTORE" })
-                        );
-                        println!("{}", line);
-                        let _ = logging::append_line(&log_path, &line);
-                    }
-
-                    if manual_only_for_key {
-                        continue;
-                    }
-
-                    if let Err(err) = fag_core::registry::set_user_choice_latest_replay(
-                        ext,
-                        &cap.prog_id,
-                        &cap.hash,
-                    ) {
-                        eprintln!(
-                            "watch-rules apply failed ext={} name={}: {}",
-                            ext, label, err
-                        );
-                        continue;
-                    }
-
-                    let after = fag_core::registry::effective_progid_for_ext(ext)
-                        .ok()
-                        .flatten();
-                    if after.as_deref() == Some(cap.prog_id.as_str()) {
-                        backoff.remove(&key);
-                        if should_emit(&mut last_emitted, &key, "APPLIED", &after) {
-                            let line = format!(
-                                "{{\"time_unix_ms\":{},\"ext\":{},\"name\":{},\"status\":\"APPLIED\",\"effective_progid\":{},\"target_progid\":{}}}",
-                                unix_time_ms(),
-                                json_string(ext),
-                                json_string(label),
-                                after.map(|s| json_string(&s)).unwrap_or("null".into()),
-                                json_string(&cap.prog_id)
-                            );
-                            println!("{}", line);
-                            let _ = logging::append_line(&log_path, &line);
-                        }
-                    } else {
-                        let failures = backoff.get(&key).map(|s| s.failures).unwrap_or(0) + 1;
-                        let secs = backoff_seconds(failures);
-                        backoff.insert(
-                            key.clone(),
-                            BackoffState {
-                                failures,
-                                next_allowed_ms: now_ms.saturating_add(u128::from(secs) * 1000),
-                                manual_only: true,
-                            },
-                        );
-                        if should_emit(&mut last_emitted, &key, "REJECTED", &after) {
-                            let line = format!(
-                                "{{\"time_unix_ms\":{},\"ext\":{},\"name\":{},\"status\":\"REJECTED\",\"effective_progid\":{},\"target_progid\":{},\"backoff_seconds\":{},\"next_mode\":\"MONITOR_ONLY\",\"hint\":\"系统拒绝/回滚了写入：后续改为只提示不自动改。建议去 Windows 设置里手动改回默认程序，然后再运行 fag capture-latest（可更新抓取）\"}}",
-                                unix_time_ms(),
-                                json_string(ext),
-                                json_string(label),
-                                after.map(|s| json_string(&s)).unwrap_or("null".into()),
-                                json_string(&cap.prog_id),
-                                secs
-                            );
-                            println!("{}", line);
-                            let _ = logging::append_line(&log_path, &line);
-                        }
-                    }
+                    let captured = guard
+                        .accept_change(&item.ext, &name, item.effective_progid.as_deref())
+                        .unwrap_or_else(|err| exit_with(&guard, "pending accept", &err));
+                    fag_core::pending::update(&path, &settings.key_path, |q| q.remove(id))
+                        .unwrap_or_else(|e| failed(e));
+                    let line = format!(
+                        "{{\"time_unix_ms\":{},\"status\":\"ACCEPTED\",\"id\":{},\"ext\":{},\"name\":{},\"prog_id\":{},\"previous_name\":{}}}",
+                        unix_time_ms(),
+                        id,
+                        json_string(&captured.ext),
+                        json_string(&captured.label),
+                        json_string(&captured.capture.prog_id),
+                        json_string(&item.target)
+                    );
+                    println!("{}", line);
+                    let _ = guard.record(&line);
+                    std::process::exit(0);
+                }
+                _ => {
+                    fag_core::pending::update(&path, &settings.key_path, |q| q.remove(id))
+                        .unwrap_or_else(|e| failed(e));
+                    "DISMISSED"
+                }
+            };
+            let line = format!(
+                "{{\"time_unix_ms\":{},\"status\":{},\"id\":{},\"ext\":{},\"name\":{},\"effective_progid\":{}}}",
+                unix_time_ms(),
+                json_string(status),
+                id,
+                json_string(&item.ext),
+                json_string(&item.target),
+                item.effective_progid
+                    .as_deref()
+                    .map(json_string)
+                    .unwrap_or_else(|| "null".into())
+            );
+            println!("{}", line);
+            let _ = guard.record(&line);
+            if status == "APPROVED" {
+                // Wake the watcher so the restore happens now rather than at the rule's next check.
+                let endpoint = fag_core::control::endpoint(settings);
+                if fag_core::control::send(&endpoint, &Command::CheckNow).is_err() {
+                    eprintln!("note: no watcher is running; the restore happens once fag watch-rules starts");
                 }
-
-                std::thread::sleep(interval);
             }
+            std::process::exit(0);
         }
         "sysinfo" => match fag_core::sysinfo::read_sysinfo() {
             Ok(si) => {
@@ -850,7 +1845,7 @@ fn main() {
                 json_string(&hash)
             );
             std::process::exit(0);
-        },
+        }
         "features" => {
             let Some(sub) = args.next() else {
                 eprintln!("usage: fag features <status|set> ...");
@@ -877,7 +1872,9 @@ fn main() {
                                 };
                                 ty = match v.as_str() {
                                     "boot" => fag_core::features::FeatureConfigurationType::Boot,
-                                    "runtime" => fag_core::features::FeatureConfigurationType::Runtime,
+                                    "runtime" => {
+                                        fag_core::features::FeatureConfigurationType::Runtime
+                                    }
                                     _ => {
                                         eprintln!("features status failed: --type must be boot or runtime");
                                         std::process::exit(2);
@@ -889,7 +1886,9 @@ fn main() {
                     }
 
                     let Some(id) = id else {
-                        eprintln!("usage: fag features status --id <number> [--type <boot|runtime>]");
+                        eprintln!(
+                            "usage: fag features status --id <number> [--type <boot|runtime>]"
+                        );
                         std::process::exit(2);
                     };
 
@@ -942,9 +1941,13 @@ fn main() {
                                 };
                                 ty = match v.as_str() {
                                     "boot" => fag_core::features::FeatureConfigurationType::Boot,
-                                    "runtime" => fag_core::features::FeatureConfigurationType::Runtime,
+                                    "runtime" => {
+                                        fag_core::features::FeatureConfigurationType::Runtime
+                                    }
                                     _ => {
-                                        eprintln!("features set failed: --type must be boot or runtime");
+                                        eprintln!(
+                                            "features set failed: --type must be boot or runtime"
+                                        );
                                         std::process::exit(2);
                                     }
                                 };
@@ -1065,12 +2068,12 @@ fn main() {
             } else {
                 std::process::exit(1);
             }
-        },
+        }
         "watch" => {
             let mut ext: Option<String> = None;
             let mut name: Option<String> = None;
-            let mut interval_secs: u64 = 5;
-            let mut monitor_only: bool = false;
+            // Command-line values win over config.json, which is otherwise reloaded live.
+            let mut overrides = WatchOverrides::default();
 
             while let Some(arg) = args.next() {
                 match arg.as_str() {
@@ -1083,8 +2086,8 @@ fn main() {
                             );
                             std::process::exit(2);
                         };
-                        interval_secs = match v.parse::<u64>() {
-                            Ok(n) if n > 0 => n,
+                        overrides.interval_secs = match v.parse::<u64>() {
+                            Ok(n) if n > 0 => Some(n),
                             _ => {
                                 eprintln!(
                                     "watch failed: --interval must be a positive integer (seconds)"
@@ -1093,7 +2096,7 @@ fn main() {
                             }
                         };
                     }
-                    "--monitor-only" => monitor_only = true,
+                    "--monitor-only" => overrides.monitor_only = true,
                     _ => {}
                 }
             }
@@ -1103,7 +2106,7 @@ fn main() {
                 std::process::exit(2);
             };
 
-            let ext = match normalize_ext_for_store(&ext_raw) {
+            let ext = match rules::normalize_ext(&ext_raw) {
                 Ok(e) => e,
                 Err(msg) => {
                     eprintln!("watch failed: {}", msg);
@@ -1116,148 +2119,32 @@ fn main() {
                 std::process::exit(2);
             }
 
-            let path = captures::default_store_path();
-            let cap = match captures::get_latest_capture(&path, &ext, &label) {
+            let cap = match guard.capture_for(&ext, &label) {
                 Ok(Some(c)) => c,
                 Ok(None) => {
-                    eprintln!(
-                        "watch failed: no capture found for ext={} name={}. Run: fag capture-latest --ext {} --name {}",
-                        ext, label, ext, label
-                    );
-                    std::process::exit(1);
-                }
-                Err(err) => {
-                    eprintln!("watch failed: store read error: {}", err);
-                    std::process::exit(1);
+                    let err = fag_core::Error::NoCapture { ext, label };
+                    exit_with(&guard, "watch", &err)
                 }
+                Err(err) => exit_with(&guard, "watch", &err),
             };
 
-            let target = cap.prog_id.clone();
-            let log_path = logging::default_log_path();
+            let lock = acquire_instance(settings, &guard, "watch");
+            let interval = overrides.interval_secs;
+            let mut watcher = Watcher::single(&guard, &ext, &label, overrides);
             eprintln!(
-                "watching ext={} target={} label={} interval={}s store={} log={} (Ctrl+C to stop)",
+                "watching ext={} target={} label={} interval={}s notify={} store={} log={} (Ctrl+C to stop)",
                 ext,
-                target,
+                cap.prog_id,
                 label,
-                interval_secs,
-                path.to_string_lossy(),
-                log_path.to_string_lossy()
+                interval.unwrap_or(settings.interval_secs.0),
+                watcher.notify_name(),
+                settings.captures_path().to_string_lossy(),
+                settings.log_path().to_string_lossy()
             );
-
-            let interval = std::time::Duration::from_secs(interval_secs);
-            let mut failures: u32 = 0;
-            let mut next_allowed_ms: u128 = 0;
-            let mut manual_only = monitor_only;
-            let mut last_emitted: Option<(String, Option<String>)> = None;
-            loop {
-                let now_ms = unix_time_ms();
-                if !manual_only && next_allowed_ms != 0 && now_ms < next_allowed_ms {
-                    std::thread::sleep(interval);
-                    continue;
-                }
-                let effective = match fag_core::registry::effective_progid_for_ext(&ext) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        eprintln!("warning: effective progid query failed: {}", err);
-                        None
-                    }
-                };
-
-                let needs_fix = effective.as_deref() != Some(target.as_str());
-                if !needs_fix {
-                    failures = 0;
-                    next_allowed_ms = 0;
-                    manual_only = monitor_only;
-                    let status = "OK".to_string();
-                    if last_emitted.as_ref().map(|(s, e)| (s, e)) != Some((&status, &effective))
-                    {
-                        let line = format!(
-                            "{{\"time_unix_ms\":{},\"ext\":{},\"status\":\"OK\",\"effective_progid\":{},\"target_progid\":{}}}",
-                            now_ms,
-                            json_string(&ext),
-                            effective
-                                .as_deref()
-                                .map(json_string)
-                                .unwrap_or("null".into()),
-                            json_string(&target)
-                        );
-                        println!("{}", line);
-                        last_emitted = Some((status, effective.clone()));
-                    }
-                } else {
-                    let status = "TAMPERED".to_string();
-                    if last_emitted.as_ref().map(|(s, e)| (s, e)) != Some((&status, &effective))
-                    {
-                        let line = format!(
-                            "{{\"time_unix_ms\":{},\"ext\":{},\"status\":\"TAMPERED\",\"effective_progid\":{},\"target_progid\":{},\"mode\":{}}}",
-                            now_ms,
-                            json_string(&ext),
-                            effective
-                                .as_deref()
-                                .map(json_string)
-                                .unwrap_or("null".into()),
-                            json_string(&target),
-                            json_string(if manual_only { "MONITOR_ONLY" } else { "AUTO_RESTORE" })
-                        );
-                        println!("{}", line);
-                        let _ = logging::append_line(&log_path, &line);
-                        last_emitted = Some((status, effective.clone()));
-                    }
-
-                    if manual_only {
-                        std::thread::sleep(interval);
-                        continue;
-                    }
-
-                    match fag_core::registry::set_user_choice_latest_replay(
-                        &ext,
-                        &cap.prog_id,
-                        &cap.hash,
-                    ) {
-                        Ok(()) => {
-                            let after = fag_core::registry::effective_progid_for_ext(&ext)
-                                .ok()
-                                .flatten();
-                            if after.as_deref() == Some(target.as_str()) {
-                                failures = 0;
-                                next_allowed_ms = 0;
-                                let line = format!(
-                                    "{{\"time_unix_ms\":{},\"ext\":{},\"status\":\"APPLIED\",\"effective_progid\":{},\"target_progid\":{}}}",
-                                    unix_time_ms(),
-                                    json_string(&ext),
-                                    after.as_deref().map(json_string).unwrap_or("null".into()),
-                                    json_string(&target)
-                                );
-                                println!("{}", line);
-                                let _ = logging::append_line(&log_path, &line);
-                                last_emitted = Some(("APPLIED".to_string(), after));
-                            } else {
-                                failures += 1;
-                                let shift = failures.saturating_sub(1).min(4);
-                                let secs = (30u64.saturating_mul(1u64 << shift)).min(600);
-                                next_allowed_ms = now_ms.saturating_add(u128::from(secs) * 1000);
-                                let line = format!(
-                                    "{{\"time_unix_ms\":{},\"ext\":{},\"status\":\"REJECTED\",\"effective_progid\":{},\"target_progid\":{},\"backoff_seconds\":{},\"next_mode\":\"MONITOR_ONLY\",\"hint\":\"系统拒绝/回滚了写入：后续改为只提示不自动改。建议去 Windows 设置里手动改回默认程序，然后再运行 fag capture-latest（可更新抓取）\"}}",
-                                    unix_time_ms(),
-                                    json_string(&ext),
-                                    after.as_deref().map(json_string).unwrap_or("null".into()),
-                                    json_string(&target),
-                                    secs
-                                );
-                                println!("{}", line);
-                                let _ = logging::append_line(&log_path, &line);
-                                manual_only = true;
-                                last_emitted = Some(("REJECTED".to_string(), after));
-                            }
-                        }
-                        Err(err) => {
-                            eprintln!("watch apply failed: {}", err);
-                        }
-                    }
-                }
-
-                std::thread::sleep(interval);
-            }
+            watcher.run(|out| print_output("watch", out));
+            drop(watcher);
+            drop(lock);
+            fag_core::shutdown::finished();
         }
         "restore" => {
             let mut ext: Option<String> = None;
@@ -1360,16 +2247,200 @@ fn json_string(s: &str) -> String {
     out
 }
 
-fn normalize_ext_for_store(ext: &str) -> Result<String, String> {
-    let ext = ext.trim();
-    if ext.is_empty() || ext == "." {
-        return Err("invalid extension".to_string());
+fn pending_item_json(item: &fag_core::pending::PendingItem) -> String {
+    format!(
+        "{{\"id\":{},\"key\":{},\"ext\":{},\"name\":{},\"target_progid\":{},\"effective_progid\":{},\"detected_unix_ms\":{},\"approved\":{}}}",
+        item.id,
+        json_string(&item.key),
+        json_string(&item.ext),
+        json_string(&item.target),
+        json_string(&item.target_progid),
+        item.effective_progid
+            .as_deref()
+            .map(json_string)
+            .unwrap_or_else(|| "null".into()),
+        item.detected_unix_ms,
+        item.approved
+    )
+}
+
+fn opt_u64_json(v: Option<u64>) -> String {
+    v.map(|n| n.to_string()).unwrap_or_else(|| "null".into())
+}
+
+/// `--ext` or `--group` (exactly one) as a rules.json key.
+fn rule_key(ext: Option<String>, group: Option<String>) -> Result<String, String> {
+    match (ext, group) {
+        (Some(ext), None) => rules::normalize_ext(&ext),
+        (None, Some(group)) => rules::normalize_name(&group),
+        _ => Err("pass exactly one of --ext / --group".to_string()),
+    }
+}
+
+fn group_json(name: &str, exts: &[String], builtin: bool) -> String {
+    format!(
+        "{{\"name\":{},\"builtin\":{},\"exts\":[{}]}}",
+        json_string(name),
+        builtin,
+        exts.iter()
+            .map(|e| json_string(e))
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+fn effective_rule_json(r: &rules::EffectiveRule) -> String {
+    format!(
+        "{{\"ext\":{},\"from_group\":{},\"source\":{},{}}}",
+        json_string(&r.ext),
+        r.group
+            .as_deref()
+            .map(json_string)
+            .unwrap_or_else(|| "null".into()),
+        json_string(if r.managed { "policy" } else { "user" }),
+        rule_fields_json(&r.rule)
+    )
+}
+
+/// `rule_json` plus where the rule comes from (`user` or `policy`).
+fn sourced_rule_json(key: &str, rule: &rules::Rule, source: &str) -> String {
+    let json = rule_json(key, rule);
+    format!(
+        "{},\"source\":{}}}",
+        &json[..json.len() - 1],
+        json_string(source)
+    )
+}
+
+/// Exits when `key` belongs to the machine policy: those rules cannot be changed per user.
+fn refuse_if_managed(guard: &Guard, key: &str, action: &str) {
+    if let Err(err) = guard.ensure_editable(key) {
+        exit_with(guard, &format!("rules {}", action), &err);
     }
-    let ext = ext.strip_prefix('.').unwrap_or(ext);
-    if ext.is_empty() || ext.contains(['\\', '/', '\0']) {
-        return Err("invalid extension".to_string());
+}
+
+/// Group rules are keyed `"group"`, extension rules `"ext"`.
+fn rule_json(key: &str, rule: &rules::Rule) -> String {
+    format!(
+        "{{{}:{},{}}}",
+        json_string(if rules::is_group_key(key) {
+            "group"
+        } else {
+            "ext"
+        }),
+        json_string(key),
+        rule_fields_json(rule)
+    )
+}
+
+fn rule_fields_json(rule: &rules::Rule) -> String {
+    let backoff = rule.backoff.clone().unwrap_or_default();
+    format!(
+        "\"name\":{},\"enabled\":{},\"mode\":{},\"interval_secs\":{},\"backoff\":{{\"policy\":{},\"base_secs\":{},\"max_secs\":{},\"max_retries\":{},\"cooldown_secs\":{}}},\"grace_secs\":{},\"pause_for\":[{}],\"description\":{},\"allow\":[{}],\"fallback\":[{}],\"follow\":{},\"schedule\":[{}]",
+        json_string(&rule.name),
+        rule.enabled,
+        rule.mode
+            .map(|m| json_string(m.as_str()))
+            .unwrap_or_else(|| "null".into()),
+        opt_u64_json(rule.interval_secs),
+        backoff
+            .policy
+            .map(|p| json_string(p.as_str()))
+            .unwrap_or_else(|| "null".into()),
+        opt_u64_json(backoff.base_secs),
+        opt_u64_json(backoff.max_secs),
+        opt_u64_json(backoff.max_retries.map(u64::from)),
+        opt_u64_json(backoff.cooldown_secs),
+        opt_u64_json(rule.grace_secs),
+        rule.pause_for
+            .iter()
+            .map(|p| json_string(p))
+            .collect::<Vec<_>>()
+            .join(","),
+        rule.description
+            .as_deref()
+            .map(json_string)
+            .unwrap_or_else(|| "null".into()),
+        rule.allow
+            .iter()
+            .map(|a| json_string(a))
+            .collect::<Vec<_>>()
+            .join(","),
+        rule.fallback
+            .iter()
+            .map(|a| json_string(a))
+            .collect::<Vec<_>>()
+            .join(","),
+        rule.follow
+            .as_deref()
+            .map(json_string)
+            .unwrap_or_else(|| "null".into()),
+        rule.schedule
+            .iter()
+            .map(|w| format!(
+                "{{\"days\":{},\"from\":{},\"to\":{},\"name\":{}}}",
+                json_string(&w.days),
+                json_string(&w.from),
+                json_string(&w.to),
+                json_string(&w.name)
+            ))
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// Reports a failed `Guard` call and exits: 3 with a logged CONFIG_TAMPERED line for a tampered
+/// store, 2 for bad input, 1 otherwise.
+fn exit_with(guard: &Guard, command: &str, err: &fag_core::Error) -> ! {
+    if let Some((store, t)) = err.tampered() {
+        let line = WatchEvent::config_tampered(store, t).to_json();
+        println!("{}", line);
+        let _ = guard.record(&line);
+        std::process::exit(3);
+    }
+    let hint = match err {
+        fag_core::Error::NoCapture { ext, label } => {
+            format!(". Run: fag capture-latest --ext {} --name {}", ext, label)
+        }
+        fag_core::Error::ProgIdGone(_) => ". Reinstall it or capture another app.".to_string(),
+        fag_core::Error::UnknownGroup(_) => " (see: fag rules group list)".to_string(),
+        _ => String::new(),
+    };
+    eprintln!("{} failed: {}{}", command, err, hint);
+    std::process::exit(match err {
+        fag_core::Error::Invalid(_)
+        | fag_core::Error::UnknownGroup(_)
+        | fag_core::Error::UnknownProfile(_)
+        | fag_core::Error::NotFound(_) => 2,
+        _ => 1,
+    });
+}
+
+/// Prints what a watcher reports: events on stdout, everything else on stderr.
+fn print_output(command: &str, out: Output) {
+    match out {
+        Output::Event(event) => println!("{}", event.to_json()),
+        Output::Warning(message) => eprintln!("warning: {}: {}", command, message),
+        Output::Notice(message) => eprintln!("{}: {}", command, message),
+    }
+}
+
+/// Takes the single-watcher lock, or exits with the running watcher's details. Taking over the
+/// lock of a watcher that crashed is logged as `INSTANCE_TAKEN_OVER`.
+fn acquire_instance(settings: &config::Settings, guard: &Guard, command: &str) -> InstanceLock {
+    let lock = match InstanceLock::acquire(&settings.lock_path(), command) {
+        Ok(lock) => lock,
+        Err(err) => {
+            eprintln!("{} failed: {}", command, err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(event) = WatchEvent::instance_taken_over(&lock) {
+        let line = event.to_json();
+        println!("{}", line);
+        let _ = guard.record(&line);
     }
-    Ok(format!(".{}", ext))
+    lock
 }
 
 fn unix_time_ms() -> u128 {
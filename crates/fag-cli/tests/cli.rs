@@ -0,0 +1,127 @@
+//! `fag` run as a process against a throwaway home.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn temp_home(name: &str) -> PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let home = std::env::temp_dir().join(format!("fag-cli-{}-{}", name, nanos));
+    std::fs::create_dir_all(&home).unwrap();
+    home
+}
+
+/// Portable, so the integrity key lands in `home` and not in the real LocalAppData.
+fn fag(home: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fag"))
+        .arg("--portable")
+        .args(args)
+        .env("FAG_HOME", home)
+        .output()
+        .unwrap()
+}
+
+/// A rules.json with one `.mp4` rule and its capture, sealed by `fag integrity reseal`.
+fn sealed_home(name: &str) -> PathBuf {
+    let home = temp_home(name);
+    std::fs::write(
+        home.join("captures.json"),
+        r#"{"version":1,"by_ext":{".mp4":{"vlc":{"prog_id":"VLC.mp4","hash":"abc"}}}}"#,
+    )
+    .unwrap();
+    std::fs::write(
+        home.join("rules.json"),
+        r#"{"version":2,"by_ext":{".mp4":{"name":"vlc"}}}"#,
+    )
+    .unwrap();
+    assert!(fag(&home, &["integrity", "reseal"]).status.success());
+    home
+}
+
+/// Rewrites a sealed store the way a hand edit would, leaving its `mac` stale.
+fn tamper(path: &Path) {
+    let edited = std::fs::read_to_string(path)
+        .unwrap()
+        .replace("\"vlc\"", "\"evil\"");
+    std::fs::write(path, edited).unwrap();
+}
+
+#[test]
+fn rules_list_fails_closed_on_an_edited_store() {
+    let home = sealed_home("rules-list");
+    let listed = fag(&home, &["rules", "list"]);
+    assert!(listed.status.success());
+    assert!(String::from_utf8_lossy(&listed.stdout).contains("\"ext\":\".mp4\""));
+
+    tamper(&home.join("rules.json"));
+    let listed = fag(&home, &["rules", "list"]);
+    let stdout = String::from_utf8_lossy(&listed.stdout);
+    assert_eq!(listed.status.code(), Some(3));
+    assert!(
+        stdout.contains("\"status\":\"CONFIG_TAMPERED\""),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("\"rules\":["), "{}", stdout);
+    let _ = std::fs::remove_dir_all(&home);
+}
+
+#[test]
+fn reads_of_a_tampered_store_exit_3_instead_of_reading_empty() {
+    let home = sealed_home("tampered-reads");
+    tamper(&home.join("rules.json"));
+    tamper(&home.join("captures.json"));
+    for args in [
+        &["captures", "--ext", ".mp4"][..],
+        &["rules", "group", "list"],
+        &["rules", "schedule", "preview"],
+        &["profile", "use", "default", "--no-apply"],
+    ] {
+        let out = fag(&home, args);
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert_eq!(out.status.code(), Some(3), "{:?}: {}", args, stdout);
+        assert!(
+            stdout.contains("\"status\":\"CONFIG_TAMPERED\""),
+            "{:?}: {}",
+            args,
+            stdout
+        );
+    }
+    let _ = std::fs::remove_dir_all(&home);
+}
+
+#[test]
+fn import_stops_untouched_when_the_config_is_tampered() {
+    let home = sealed_home("import-config");
+    assert!(fag(&home, &["config", "set", "watch.interval_secs", "10"])
+        .status
+        .success());
+    let bundle = home.join("bundle.json");
+    let exported = fag(&home, &["export", "--out", bundle.to_str().unwrap()]);
+    assert!(exported.status.success());
+    assert!(fag(&home, &["rules", "remove", "--ext", ".mp4"])
+        .status
+        .success());
+
+    let config = home.join("config.json");
+    let edited = std::fs::read_to_string(&config)
+        .unwrap()
+        .replace("10", "99");
+    std::fs::write(&config, edited).unwrap();
+    let rules_before = std::fs::read(home.join("rules.json")).unwrap();
+    let imported = fag(&home, &["import", bundle.to_str().unwrap()]);
+    let stdout = String::from_utf8_lossy(&imported.stdout);
+    assert_eq!(imported.status.code(), Some(3), "{}", stdout);
+    assert!(
+        stdout.contains("\"status\":\"CONFIG_TAMPERED\""),
+        "{}",
+        stdout
+    );
+    assert_eq!(
+        std::fs::read(home.join("rules.json")).unwrap(),
+        rules_before
+    );
+    let _ = std::fs::remove_dir_all(&home);
+}
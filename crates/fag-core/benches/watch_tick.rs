@@ -0,0 +1,222 @@
+//! Per-tick cost of a `watch-rules` pass over 100 rules, before and after change detection.
+//!
+//! `cargo bench -p fag-core --bench watch_tick`
+//!
+//! The registry is a stand-in: each effective-ProgId query burns `QUERY_COST` to model
+//! `AssocQueryStringW`, and only `.x0`'s stamp moves, by one per write. Stores are real files
+//! in a temp directory, read through `Guard` exactly as the watch loop does.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use fag_core::captures::{self, LatestCapture};
+use fag_core::config::{Settings, Source};
+use fag_core::guard::{Backoff, CaptureStore, Check, Clock, Engine, FlapLimit, Registry};
+use fag_core::registry::AssocStamp;
+use fag_core::rules::{self, Rule, RuleSet};
+use fag_core::Guard;
+
+const RULES: usize = 100;
+const TICKS: u32 = 50;
+const QUERY_COST: Duration = Duration::from_micros(200);
+
+struct FakeRegistry {
+    stamped: bool,
+    writes: Cell<u64>,
+    queries: Cell<u64>,
+}
+
+impl Registry for &FakeRegistry {
+    fn effective_progid(&self, ext: &str) -> Result<Option<String>, String> {
+        self.queries.set(self.queries.get() + 1);
+        let started = Instant::now();
+        while started.elapsed() < QUERY_COST {
+            std::hint::spin_loop();
+        }
+        Ok(Some(format!("App{}", ext)))
+    }
+
+    fn apply(&self, _ext: &str, _prog_id: &str, _hash: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn stamp(&self, ext: &str) -> Option<AssocStamp> {
+        self.stamped.then(|| AssocStamp {
+            // Only `.x0` is ever written.
+            user_choice_latest: Some(if ext == ".x0" { self.writes.get() } else { 0 }),
+            user_choice_latest_prog_id: None,
+            user_choice: None,
+        })
+    }
+}
+
+/// Time stands still: nothing in a steady-state tick depends on it.
+struct ZeroClock;
+
+impl Clock for ZeroClock {
+    fn now_ms(&self) -> u128 {
+        0
+    }
+}
+
+/// Re-reads captures.json on every lookup, as the loop did before the store cache.
+struct UncachedStore(PathBuf, PathBuf);
+
+impl CaptureStore for UncachedStore {
+    fn prog_id(&self, ext: &str, label: &str) -> Option<String> {
+        captures::get_latest_capture(&self.0, &self.1, ext, label)
+            .ok()
+            .flatten()
+            .map(|c| c.prog_id)
+    }
+}
+
+fn setup() -> Guard {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let home = std::env::temp_dir().join(format!("fag-bench-{}", nanos));
+    let at = |file: &str| (home.join(file), Source::Default);
+    let settings = Settings {
+        config_path: home.join("config.json"),
+        home: home.clone(),
+        portable: (false, Source::Default),
+        rules_path: at("rules.json"),
+        captures_path: at("captures.json"),
+        log_path: at("guard.log"),
+        key_path: home.join("integrity.key"),
+        policy_path: None,
+        interval_secs: (5, Source::Default),
+        monitor_only: (false, Source::Default),
+        backoff_base_secs: (30, Source::Default),
+        backoff_max_secs: (600, Source::Default),
+        backoff_policy: (fag_core::rules::BackoffPolicy::Exponential, Source::Default),
+        backoff_max_retries: (0, Source::Default),
+        backoff_cooldown_secs: (0, Source::Default),
+        flap_cycles: (5, Source::Default),
+        flap_window_secs: (600, Source::Default),
+        flap_quiet_secs: (900, Source::Default),
+        grace_secs: (0, Source::Default),
+        pause_processes: (Vec::new(), Source::Default),
+    };
+
+    let mut by_ext = BTreeMap::new();
+    let mut set = RuleSet::default();
+    for i in 0..RULES {
+        let ext = format!(".x{}", i);
+        let cap = LatestCapture {
+            prog_id: format!("App{}", ext),
+            hash: "h".to_string(),
+            last_write_time_filetime: None,
+            prog_id_last_write_time_filetime: None,
+        };
+        by_ext.insert(
+            ext.clone(),
+            BTreeMap::from([("app".to_string(), cap.clone()), ("alt".to_string(), cap)]),
+        );
+        let mut rule = Rule::new("app");
+        rule.allow = vec!["alt".to_string()];
+        set.by_ext.insert(ext, rule);
+    }
+    captures::save_store(settings.captures_path(), &settings.key_path, &by_ext).unwrap();
+    rules::save_rules(settings.rules_path(), &settings.key_path, &set).unwrap();
+    Guard::new(settings)
+}
+
+fn check<'a>(ext: &'a str, key: &'a str, cap: &'a LatestCapture, allow: &'a [String]) -> Check<'a> {
+    Check {
+        key,
+        ext,
+        target: "app",
+        prog_id: &cap.prog_id,
+        hash: &cap.hash,
+        allow,
+        monitor_only: false,
+        backoff: Backoff::default(),
+        flapping: FlapLimit::default(),
+        grace_secs: 0,
+    }
+}
+
+/// Every tick reloads both stores per rule and queries the registry for every rule.
+fn tick_uncached(guard: &Guard, engine: &mut Engine<&FakeRegistry, ZeroClock, UncachedStore>) {
+    let s = guard.settings();
+    let layered = rules::load_layered(s.rules_path(), &s.key_path, None).unwrap();
+    for r in layered.expand() {
+        let key = format!("{}|{}", r.ext, r.rule.name);
+        let cap =
+            captures::get_latest_capture(s.captures_path(), &s.key_path, &r.ext, &r.rule.name)
+                .unwrap()
+                .unwrap();
+        engine.step(&check(&r.ext, &key, &cap, &r.rule.allow));
+    }
+}
+
+fn tick_cached(guard: &Guard, engine: &mut Engine<&FakeRegistry, ZeroClock, &Guard>) {
+    for r in guard.rules().unwrap().expand() {
+        let key = format!("{}|{}", r.ext, r.rule.name);
+        let cap = guard.capture_for(&r.ext, &r.rule.name).unwrap().unwrap();
+        engine.step(&check(&r.ext, &key, &cap, &r.rule.allow));
+    }
+}
+
+fn report(name: &str, registry: &FakeRegistry, run: impl FnMut()) {
+    let mut run = run;
+    registry.queries.set(0);
+    let started = Instant::now();
+    for _ in 0..TICKS {
+        run();
+    }
+    let per_tick = started.elapsed() / TICKS;
+    println!(
+        "{:<34} {:>9.3} ms/tick  {:>6.1} queries/tick",
+        name,
+        per_tick.as_secs_f64() * 1000.0,
+        registry.queries.get() as f64 / f64::from(TICKS)
+    );
+}
+
+fn main() {
+    let guard = setup();
+    println!(
+        "{} rules, {} ticks, {:?} per effective-ProgId query",
+        RULES, TICKS, QUERY_COST
+    );
+
+    let plain = FakeRegistry {
+        stamped: false,
+        writes: Cell::new(0),
+        queries: Cell::new(0),
+    };
+    let mut engine = Engine::new(
+        &plain,
+        ZeroClock,
+        UncachedStore(
+            guard.settings().captures_path().to_path_buf(),
+            guard.settings().key_path.clone(),
+        ),
+    );
+    report("before: reload + query every rule", &plain, || {
+        tick_uncached(&guard, &mut engine)
+    });
+
+    let stamped = FakeRegistry {
+        stamped: true,
+        writes: Cell::new(0),
+        queries: Cell::new(0),
+    };
+    let mut engine = Engine::new(&stamped, ZeroClock, &guard);
+    tick_cached(&guard, &mut engine);
+    report("after: nothing changed", &stamped, || {
+        tick_cached(&guard, &mut engine)
+    });
+    report("after: one extension written", &stamped, || {
+        stamped.writes.set(stamped.writes.get() + 1);
+        tick_cached(&guard, &mut engine)
+    });
+
+    let _ = std::fs::remove_dir_all(&guard.settings().home);
+}
@@ -0,0 +1,1204 @@
+//! The stores and checks behind `fag`, for hosts that link fag-core instead of running `fag.exe`.
+//!
+//! [`Guard`] wraps resolved [`Settings`] and offers capture, apply, check, editing of rules,
+//! groups, schedules and profiles, export/import and the event log with typed results; [`crate::Watcher`] runs the watch loop on top of it and reports
+//! typed events. Other output formats (the JSON lines of the one-shot commands) stay with the host.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::bundle::{self, Bundle, ImportMode, ImportPlan, SourceInfo};
+use crate::cache::Cached;
+use crate::captures::{self, FollowTarget, LatestCapture};
+use crate::config::{self, GlobalOptions, Settings};
+use crate::integrity::{self, StoreTampered};
+use crate::rules::{self, EffectiveRule, LayeredRules, Rule};
+use crate::schedule::ScheduleWindow;
+use crate::{logging, registry, schedule, toml_store};
+
+#[derive(Debug)]
+pub enum Error {
+    /// An extension, label or rule key that cannot be used.
+    Invalid(String),
+    /// Reading or writing a store failed; `store` is `config`, `captures`, `rules`, `key` or
+    /// `bundle` (an export being written).
+    Store {
+        store: &'static str,
+        source: io::Error,
+    },
+    /// The machine policy file exists but cannot be read.
+    Policy {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Registry(String),
+    NoCapture {
+        ext: String,
+        label: String,
+    },
+    /// The captured ProgId is no longer registered under HKCR.
+    ProgIdGone(String),
+    UnknownGroup(String),
+    UnknownProfile(String),
+    NotFound(String),
+    /// The rule belongs to the machine policy and cannot be changed per user.
+    Managed {
+        key: String,
+        policy_path: PathBuf,
+    },
+}
+
+impl Error {
+    /// The store and reason when a store failed its integrity check.
+    pub fn tampered(&self) -> Option<(&'static str, &StoreTampered)> {
+        match self {
+            Self::Store { store, source } => integrity::as_tampered(source).map(|t| (*store, t)),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(msg) | Self::Registry(msg) => f.write_str(msg),
+            Self::Store { store, source } => write!(f, "{} store error: {}", store, source),
+            Self::Policy { path, source } => write!(
+                f,
+                "policy read error ({}): {}",
+                path.to_string_lossy(),
+                source
+            ),
+            Self::NoCapture { ext, label } => {
+                write!(f, "no capture found for ext={} name={}", ext, label)
+            }
+            Self::ProgIdGone(prog_id) => write!(
+                f,
+                "ProgId {} is no longer registered under HKCR (application uninstalled?)",
+                prog_id
+            ),
+            Self::UnknownGroup(group) => write!(f, "unknown group {}", group),
+            Self::UnknownProfile(name) => write!(f, "unknown profile {}", name),
+            Self::NotFound(key) => write!(f, "no rule for {}", key),
+            Self::Managed { key, policy_path } => write!(
+                f,
+                "{} is managed by machine policy ({}) and cannot be changed here",
+                key,
+                policy_path.to_string_lossy()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn store(store: &'static str) -> impl Fn(io::Error) -> Error {
+    move |source| Error::Store { store, source }
+}
+
+/// A capture just taken from UserChoiceLatest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captured {
+    pub ext: String,
+    pub label: String,
+    pub capture: LatestCapture,
+}
+
+/// The result of replaying a capture. Windows may roll the write back, so `effective` is read
+/// again afterwards; `query_error` is set when that read failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Applied {
+    pub ext: String,
+    pub prog_id: String,
+    pub effective: Option<String>,
+    pub query_error: Option<String>,
+}
+
+impl Applied {
+    pub fn accepted(&self) -> bool {
+        self.effective.as_deref() == Some(self.prog_id.as_str())
+    }
+}
+
+/// The restore target picked from a rule's chain (`name`, then `fallback`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    /// 0 for the rule's own label, 1.. for fallbacks.
+    pub position: usize,
+    pub label: String,
+    pub capture: LatestCapture,
+}
+
+/// Why a follow rule has no label right now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowUnresolved {
+    pub leader: String,
+    pub leader_progid: Option<String>,
+    pub reason: String,
+    pub hint: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckStatus {
+    Disabled,
+    /// A group member that has not been captured yet; not an error.
+    NoCapture,
+    FollowUnresolved(FollowUnresolved),
+    Ok {
+        effective: Option<String>,
+        target: String,
+        target_progid: String,
+        matched: String,
+    },
+    Tampered {
+        effective: Option<String>,
+        target: String,
+        target_progid: String,
+    },
+}
+
+/// One expanded rule as seen by [`Guard::check`]. `rule` has its schedule and follow applied;
+/// `resolved` is `None` or a fallback when the rule's own target is unavailable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckItem {
+    pub ext: String,
+    pub group: Option<String>,
+    pub rule: Rule,
+    pub resolved: Option<Resolved>,
+    pub status: CheckStatus,
+}
+
+impl CheckItem {
+    /// Checked against a fallback (or the unregistered primary) rather than the rule's own label.
+    pub fn target_unavailable(&self) -> bool {
+        matches!(
+            self.status,
+            CheckStatus::Ok { .. } | CheckStatus::Tampered { .. }
+        ) && self.resolved.as_ref().map(|r| r.position) != Some(0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedRule {
+    pub key: String,
+    pub label: String,
+    /// Group members covered by the machine policy; the group rule does not apply there.
+    pub managed_members: Vec<String>,
+    /// Group members without a capture for `label`; skipped until captured.
+    pub missing_captures: Vec<String>,
+}
+
+/// The outcome of [`Guard::use_profile`] for one expanded rule of the new profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileApply {
+    /// Replayed; `applied.accepted()` tells whether Windows kept it.
+    Applied {
+        label: String,
+        applied: Applied,
+    },
+    /// The replay itself failed.
+    Failed {
+        ext: String,
+        label: String,
+        error: String,
+    },
+    FollowUnresolved {
+        ext: String,
+        unresolved: FollowUnresolved,
+    },
+    /// Captured, but neither the label nor a fallback is installed.
+    TargetUnavailable {
+        ext: String,
+        rule: Rule,
+    },
+    NoCapture {
+        ext: String,
+        label: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileSwitch {
+    pub from: String,
+    pub profile: String,
+    /// Empty when the switch was made without applying.
+    pub outcomes: Vec<ProfileApply>,
+}
+
+/// Where [`Guard::convert_rules`] moved the rules. `warnings` lists the follow-up steps that
+/// failed (moving the old file aside, updating `paths.rules`); the conversion itself happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Converted {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub backup: PathBuf,
+    pub warnings: Vec<String>,
+}
+
+/// One line of the event log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedEvent {
+    pub status: Option<String>,
+    pub ext: Option<String>,
+    pub time_unix_ms: Option<u64>,
+    pub line: String,
+}
+
+/// Stores are read through in-memory caches that reload when a file's size or mtime changes, so
+/// a watch loop can ask for every rule each tick without re-parsing them. Once a store has loaded,
+/// a later version that fails to parse is ignored in favour of the last good one (see
+/// [`crate::reload`] for how that is reported); a tampered store is always an error.
+pub struct Guard {
+    settings: Settings,
+    captures: Cached<captures::ByExt>,
+    rules: Cached<LayeredRules>,
+}
+
+impl Guard {
+    /// Resolves settings the way `fag` does.
+    pub fn open(opts: &GlobalOptions) -> Result<Self, Error> {
+        Ok(Self::new(
+            config::load_settings(opts).map_err(store("config"))?,
+        ))
+    }
+
+    pub fn new(settings: Settings) -> Self {
+        let captures = Cached::new(vec![settings.captures_path().to_path_buf()]);
+        let rules = Cached::new(
+            std::iter::once(settings.rules_path())
+                .chain(settings.policy_path.as_deref())
+                .map(|p| p.to_path_buf())
+                .collect(),
+        );
+        Self {
+            settings,
+            captures,
+            rules,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Makes the next read of every store go to disk, even if the files look unchanged.
+    pub fn invalidate(&self) {
+        self.captures.invalidate();
+        self.rules.invalidate();
+    }
+
+    /// Stores the current UserChoiceLatest of `ext` under `label`.
+    pub fn capture(&self, ext: &str, label: &str) -> Result<Captured, Error> {
+        let ext = rules::normalize_ext(ext).map_err(Error::Invalid)?;
+        let label = normalize_label(label)?;
+        let uc = registry::read_user_choice_latest(&ext)
+            .map_err(|e| Error::Registry(e.to_string()))?
+            .ok_or_else(|| Error::Registry(format!("UserChoiceLatest not set for {}", ext)))?;
+        let missing = |what: &str| Error::Registry(format!("{} missing in UserChoiceLatest", what));
+        let capture = LatestCapture {
+            prog_id: uc.prog_id.ok_or_else(|| missing("ProgId"))?,
+            hash: uc.hash.ok_or_else(|| missing("Hash"))?,
+            last_write_time_filetime: uc.last_write_time.map(|ft| ft.as_u64()),
+            prog_id_last_write_time_filetime: uc.prog_id_last_write_time.map(|ft| ft.as_u64()),
+        };
+        captures::upsert_latest_capture(
+            self.settings.captures_path(),
+            &self.settings.key_path,
+            &ext,
+            &label,
+            capture.clone(),
+        )
+        .map_err(store("captures"))?;
+        self.captures.invalidate();
+        Ok(Captured {
+            ext,
+            label,
+            capture,
+        })
+    }
+
+    /// Capture labels stored for `ext`.
+    pub fn captures(&self, ext: &str) -> Result<Vec<String>, Error> {
+        let ext = rules::normalize_ext(ext).map_err(Error::Invalid)?;
+        Ok(self
+            .capture_store()?
+            .get(&ext)
+            .map(|m| m.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    pub fn capture_for(&self, ext: &str, label: &str) -> Result<Option<LatestCapture>, Error> {
+        Ok(self
+            .capture_store()?
+            .get(ext)
+            .and_then(|m| m.get(label))
+            .cloned())
+    }
+
+    fn capture_store(&self) -> Result<Arc<captures::ByExt>, Error> {
+        last_good(self.load_captures(), &self.captures)
+    }
+
+    /// captures.json as it is on disk now, without falling back to the last good contents.
+    pub(crate) fn load_captures(&self) -> Result<Arc<captures::ByExt>, Error> {
+        self.captures
+            .get(|| captures::load_store(self.settings.captures_path(), &self.settings.key_path))
+            .map_err(store("captures"))
+    }
+
+    /// Replays the capture stored under `label`.
+    pub fn apply(&self, ext: &str, label: &str) -> Result<Applied, Error> {
+        let ext = rules::normalize_ext(ext).map_err(Error::Invalid)?;
+        let label = normalize_label(label)?;
+        let cap = self
+            .capture_for(&ext, &label)?
+            .ok_or_else(|| Error::NoCapture {
+                ext: ext.clone(),
+                label,
+            })?;
+        if let Ok(false) = registry::progid_registered(&cap.prog_id) {
+            return Err(Error::ProgIdGone(cap.prog_id));
+        }
+        self.apply_progid(&ext, &cap.prog_id, &cap.hash)
+    }
+
+    pub fn apply_progid(&self, ext: &str, prog_id: &str, hash: &str) -> Result<Applied, Error> {
+        let ext = rules::normalize_ext(ext).map_err(Error::Invalid)?;
+        registry::set_user_choice_latest_replay(&ext, prog_id, hash)
+            .map_err(|e| Error::Registry(e.to_string()))?;
+        let (effective, query_error) = match registry::effective_progid_for_ext(&ext) {
+            Ok(v) => (v, None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        Ok(Applied {
+            ext,
+            prog_id: prog_id.to_string(),
+            effective,
+            query_error,
+        })
+    }
+
+    /// Compares every enabled rule with the registry. Stops at the first rule that cannot be
+    /// checked at all (missing capture, unreadable store, failing registry query).
+    pub fn check(&self) -> Result<Vec<CheckItem>, Error> {
+        let now = schedule::Clock::now(&schedule::SystemClock);
+        let mut items = Vec::new();
+        for EffectiveRule {
+            ext, rule, group, ..
+        } in self.rules()?.expand()
+        {
+            let mut item = CheckItem {
+                ext,
+                group,
+                rule,
+                resolved: None,
+                status: CheckStatus::Disabled,
+            };
+            if !item.rule.enabled {
+                items.push(item);
+                continue;
+            }
+            let ext = item.ext.clone();
+            item.rule = match self.resolve_follow(&ext, &schedule::apply(&item.rule, now)) {
+                Ok(r) => r,
+                Err(unresolved) => {
+                    item.status = CheckStatus::FollowUnresolved(unresolved);
+                    items.push(item);
+                    continue;
+                }
+            };
+            let label = item.rule.name.clone();
+            let cap = match self.capture_for(&ext, &label)? {
+                Some(c) => c,
+                None if item.group.is_some() => {
+                    item.status = CheckStatus::NoCapture;
+                    items.push(item);
+                    continue;
+                }
+                None => return Err(Error::NoCapture { ext, label }),
+            };
+            item.resolved = self.resolve_target(&ext, &item.rule);
+            let (target, cap) = match &item.resolved {
+                Some(r) => (r.label.clone(), r.capture.clone()),
+                None => (label, cap),
+            };
+            let effective = registry::effective_progid_for_ext(&ext)
+                .map_err(|e| Error::Registry(format!("effective progid query failed: {}", e)))?;
+            let matched = item
+                .rule
+                .matched_target(&target, effective.as_deref(), |l| {
+                    if l == target {
+                        return Some(cap.prog_id.clone());
+                    }
+                    self.capture_for(&ext, l).ok().flatten().map(|c| c.prog_id)
+                });
+            item.status = match matched {
+                Some(matched) => CheckStatus::Ok {
+                    effective,
+                    target,
+                    target_progid: cap.prog_id,
+                    matched,
+                },
+                None => CheckStatus::Tampered {
+                    effective,
+                    target,
+                    target_progid: cap.prog_id,
+                },
+            };
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    /// The rule's restore target: the first label in its chain whose capture exists and whose
+    /// ProgId is still registered under HKCR. Registry errors count as registered, so a failing
+    /// query never pushes a rule down the chain.
+    pub fn resolve_target(&self, ext: &str, rule: &Rule) -> Option<Resolved> {
+        let lookup = |label: &str| self.capture_for(ext, label).ok().flatten();
+        let (position, label) = rule.select_target(|label| {
+            lookup(label).is_some_and(|c| registry::progid_registered(&c.prog_id).unwrap_or(true))
+        })?;
+        Some(Resolved {
+            position,
+            label: label.to_string(),
+            capture: lookup(label)?,
+        })
+    }
+
+    /// Turns a follow rule into a plain rule for the label the leader extension currently uses.
+    /// Plain rules pass through.
+    pub fn resolve_follow(&self, ext: &str, rule: &Rule) -> Result<Rule, FollowUnresolved> {
+        let Some(leader) = rule.follow.as_deref() else {
+            return Ok(rule.clone());
+        };
+        let unresolved =
+            |leader_progid: Option<String>, reason: &str, hint: String| FollowUnresolved {
+                leader: leader.to_string(),
+                leader_progid,
+                reason: reason.to_string(),
+                hint,
+            };
+
+        let leader_progid = match registry::effective_progid_for_ext(leader) {
+            Ok(Some(p)) => p,
+            Ok(None) => {
+                return Err(unresolved(
+                    None,
+                    "leader has no default app",
+                    format!("先在 Windows 设置里给 {} 选一个默认程序", leader),
+                ))
+            }
+            Err(err) => return Err(unresolved(None, &err.to_string(), String::new())),
+        };
+        // A store read error is reported by the regular capture lookup that follows.
+        let Ok(store) = self.capture_store() else {
+            return Ok(rule.clone());
+        };
+        match captures::follow_target(&store, leader, &leader_progid, ext) {
+            FollowTarget::Found(label, _) => {
+                let mut resolved = rule.clone();
+                resolved.name = label;
+                resolved.fallback.clear();
+                Ok(resolved)
+            }
+            FollowTarget::LeaderUncaptured => Err(unresolved(
+                Some(leader_progid),
+                "leader's current app was never captured",
+                format!(
+                    "fag capture-latest --ext {} --name <label>（然后对 {} 用同一个 label 再 capture 一次）",
+                    leader, ext
+                ),
+            )),
+            FollowTarget::FollowerUncaptured(label) => Err(unresolved(
+                Some(leader_progid),
+                "follower has no capture for the leader's app",
+                format!(
+                    "先在 Windows 设置里把 {} 切到同一个程序，然后 fag capture-latest --ext {} --name {}",
+                    ext, ext, label
+                ),
+            )),
+        }
+    }
+
+    /// User rules with the machine policy layered on top.
+    pub fn rules(&self) -> Result<LayeredRules, Error> {
+        last_good(self.load_rules(), &self.rules).map(|r| (*r).clone())
+    }
+
+    /// The rules as they are on disk now, without falling back to the last good contents.
+    pub(crate) fn load_rules(&self) -> Result<Arc<LayeredRules>, Error> {
+        self.rules
+            .get(|| {
+                rules::load_layered(
+                    self.settings.rules_path(),
+                    &self.settings.key_path,
+                    self.settings.policy_path.as_deref(),
+                )
+            })
+            .map_err(store("rules"))
+    }
+
+    /// Fails with [`Error::Managed`] when `key` belongs to the machine policy.
+    pub fn ensure_editable(&self, key: &str) -> Result<(), Error> {
+        let Some(policy_path) = self.settings.policy_path.as_deref() else {
+            return Ok(());
+        };
+        let policy = rules::load_policy(policy_path).map_err(|source| Error::Policy {
+            path: policy_path.to_path_buf(),
+            source,
+        })?;
+        let layered = LayeredRules {
+            policy,
+            ..Default::default()
+        };
+        if layered.is_managed(key) {
+            return Err(Error::Managed {
+                key: key.to_string(),
+                policy_path: policy_path.to_path_buf(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds or relabels the rule for `key` (an extension or a group). An extension rule needs a
+    /// capture for `label`; a group rule is accepted with members still uncaptured.
+    pub fn add_rule(&self, key: &str, label: &str) -> Result<AddedRule, Error> {
+        self.ensure_editable(key)?;
+        let label = normalize_label(label)?;
+        let path = self.settings.rules_path();
+        let has_capture = |ext: &str| matches!(self.capture_for(ext, &label), Ok(Some(_)));
+        let mut added = AddedRule {
+            key: key.to_string(),
+            label: label.clone(),
+            managed_members: Vec::new(),
+            missing_captures: Vec::new(),
+        };
+        if rules::is_group_key(key) {
+            let members = self
+                .rules()
+                .ok()
+                .and_then(|layered| layered.user.group_members(key))
+                .ok_or_else(|| Error::UnknownGroup(key.to_string()))?;
+            if let Ok(layered) = self.rules() {
+                added.managed_members = members
+                    .iter()
+                    .filter(|e| layered.is_managed(e))
+                    .cloned()
+                    .collect();
+            }
+            added.missing_captures = members
+                .into_iter()
+                .filter(|e| !added.managed_members.contains(e) && !has_capture(e))
+                .collect();
+        } else if !has_capture(key) {
+            return Err(Error::NoCapture {
+                ext: key.to_string(),
+                label,
+            });
+        }
+        rules::upsert_rule(path, &self.settings.key_path, key, &label).map_err(store("rules"))?;
+        self.rules.invalidate();
+        Ok(added)
+    }
+
+    /// Makes `key` follow whatever app `leader` currently opens with.
+    pub fn add_follow_rule(&self, key: &str, leader: &str) -> Result<String, Error> {
+        self.ensure_editable(key)?;
+        let leader = rules::normalize_ext(leader).map_err(Error::Invalid)?;
+        if leader == key {
+            return Err(Error::Invalid(
+                "an extension cannot follow itself".to_string(),
+            ));
+        }
+        rules::upsert_follow_rule(
+            self.settings.rules_path(),
+            &self.settings.key_path,
+            key,
+            &leader,
+        )
+        .map_err(store("rules"))?;
+        self.rules.invalidate();
+        Ok(leader)
+    }
+
+    /// Keeps the association `ext` was changed to: captures it as `label` and points the
+    /// extension's rule at it (adding an extension rule over a group rule). `expected` is the
+    /// ProgId the user was shown; a later change is refused rather than captured unseen.
+    pub fn accept_change(
+        &self,
+        ext: &str,
+        label: &str,
+        expected: Option<&str>,
+    ) -> Result<Captured, Error> {
+        let ext = rules::normalize_ext(ext).map_err(Error::Invalid)?;
+        self.ensure_editable(&ext)?;
+        let now = registry::read_user_choice_latest(&ext)
+            .map_err(|e| Error::Registry(e.to_string()))?
+            .and_then(|uc| uc.prog_id);
+        if now.as_deref() != expected {
+            return Err(Error::Invalid(format!(
+                "{} changed again since (now {}); nothing was captured",
+                ext,
+                now.as_deref().unwrap_or("unset")
+            )));
+        }
+        let captured = self.capture(&ext, label)?;
+        let label = captured.label.clone();
+        match self.update_rule(&ext, |rule| {
+            rule.name = label.clone();
+            rule.follow = None;
+        }) {
+            Err(Error::NotFound(_)) => {
+                self.add_rule(&ext, &label)?;
+            }
+            other => other?,
+        }
+        Ok(captured)
+    }
+
+    pub fn update_rule(&self, key: &str, f: impl FnOnce(&mut Rule)) -> Result<(), Error> {
+        self.ensure_editable(key)?;
+        let updated =
+            rules::update_rule(self.settings.rules_path(), &self.settings.key_path, key, f);
+        self.rules.invalidate();
+        match updated.map_err(store("rules"))? {
+            true => Ok(()),
+            false => Err(Error::NotFound(key.to_string())),
+        }
+    }
+
+    pub fn remove_rule(&self, key: &str) -> Result<(), Error> {
+        self.ensure_editable(key)?;
+        let removed = rules::remove_rule(self.settings.rules_path(), &self.settings.key_path, key);
+        self.rules.invalidate();
+        match removed.map_err(store("rules"))? {
+            true => Ok(()),
+            false => Err(Error::NotFound(key.to_string())),
+        }
+    }
+
+    /// Adds `window` to the rule for `key`, or with `None` clears its schedule. Returns the rule
+    /// as saved.
+    pub fn set_schedule(&self, key: &str, window: Option<ScheduleWindow>) -> Result<Rule, Error> {
+        if let Some(window) = &window {
+            window.validate().map_err(Error::Invalid)?;
+            if window.name.is_empty() {
+                return Err(Error::Invalid("--name is empty".to_string()));
+            }
+            if !rules::is_group_key(key) && self.capture_for(key, &window.name)?.is_none() {
+                return Err(Error::NoCapture {
+                    ext: key.to_string(),
+                    label: window.name.clone(),
+                });
+            }
+        }
+        let mut updated = None;
+        self.update_rule(key, |rule| {
+            if rule.follow.is_some() {
+                return;
+            }
+            match window {
+                Some(w) => rule.schedule.push(w),
+                None => rule.schedule.clear(),
+            }
+            updated = Some(rule.clone());
+        })?;
+        updated.ok_or_else(|| {
+            Error::Invalid(format!(
+                "{} is a follow rule; schedules apply to labelled rules only",
+                key
+            ))
+        })
+    }
+
+    /// Defines the user group `group`, or with `None` deletes it. Returns the normalized name.
+    pub fn set_group(&self, group: &str, exts: Option<Vec<String>>) -> Result<String, Error> {
+        let group = rules::normalize_name(group).map_err(Error::Invalid)?;
+        let changed = rules::set_group(
+            self.settings.rules_path(),
+            &self.settings.key_path,
+            &group,
+            exts,
+        );
+        self.rules.invalidate();
+        match changed.map_err(store("rules"))? {
+            true => Ok(group),
+            false => Err(Error::UnknownGroup(group)),
+        }
+    }
+
+    /// Snapshots the active rules as profile `name`. Returns the normalized name.
+    pub fn save_profile(&self, name: &str) -> Result<String, Error> {
+        let name = rules::normalize_name(name).map_err(Error::Invalid)?;
+        rules::save_profile(self.settings.rules_path(), &self.settings.key_path, &name)
+            .map_err(store("rules"))?;
+        self.rules.invalidate();
+        Ok(name)
+    }
+
+    /// Deletes an inactive profile; the active one is [`Error::UnknownProfile`] too.
+    pub fn delete_profile(&self, name: &str) -> Result<String, Error> {
+        let name = rules::normalize_name(name).map_err(Error::Invalid)?;
+        let removed =
+            rules::delete_profile(self.settings.rules_path(), &self.settings.key_path, &name);
+        self.rules.invalidate();
+        match removed.map_err(store("rules"))? {
+            true => Ok(name),
+            false => Err(Error::UnknownProfile(name)),
+        }
+    }
+
+    /// Makes `name` the active profile and, with `apply`, replays what each of its enabled rules
+    /// points at right now (schedule, follow and fallbacks resolved as [`Self::check`] does).
+    pub fn use_profile(&self, name: &str, apply: bool) -> Result<ProfileSwitch, Error> {
+        let name = rules::normalize_name(name).map_err(Error::Invalid)?;
+        let from = self.rules()?.user.active_profile_name().to_string();
+        // Read before switching, so an unreadable policy leaves the active profile alone.
+        let policy = match self.settings.policy_path.as_deref() {
+            Some(path) => rules::load_policy(path).map_err(|source| Error::Policy {
+                path: path.to_path_buf(),
+                source,
+            })?,
+            None => Default::default(),
+        };
+        let switched =
+            rules::use_profile(self.settings.rules_path(), &self.settings.key_path, &name);
+        self.rules.invalidate();
+        let user = switched
+            .map_err(store("rules"))?
+            .ok_or_else(|| Error::UnknownProfile(name.clone()))?;
+        let mut outcomes = Vec::new();
+        let now = schedule::Clock::now(&schedule::SystemClock);
+        for EffectiveRule { ext, rule, .. } in (LayeredRules { user, policy }).expand() {
+            if !apply || !rule.enabled {
+                continue;
+            }
+            outcomes.push(self.apply_rule(ext, &schedule::apply(&rule, now)));
+        }
+        Ok(ProfileSwitch {
+            from,
+            profile: name,
+            outcomes,
+        })
+    }
+
+    fn apply_rule(&self, ext: String, rule: &Rule) -> ProfileApply {
+        let rule = match self.resolve_follow(&ext, rule) {
+            Ok(rule) => rule,
+            Err(unresolved) => return ProfileApply::FollowUnresolved { ext, unresolved },
+        };
+        let label = rule.name.clone();
+        let capture = match self.resolve_target(&ext, &rule) {
+            Some(resolved) => resolved.capture,
+            None if matches!(self.capture_for(&ext, &label), Ok(Some(_))) => {
+                return ProfileApply::TargetUnavailable { ext, rule };
+            }
+            None => return ProfileApply::NoCapture { ext, label },
+        };
+        match self.apply_progid(&ext, &capture.prog_id, &capture.hash) {
+            Ok(applied) => ProfileApply::Applied { label, applied },
+            Err(err) => ProfileApply::Failed {
+                ext,
+                label,
+                error: err.to_string(),
+            },
+        }
+    }
+
+    /// Rewrites rules.json as rules.toml or back (`to_toml`), keeps the old file as `*.bak`, and
+    /// points a configured `paths.rules` at the new file. This `Guard` still reads the old path;
+    /// open a new one to use the converted rules.
+    pub fn convert_rules(&self, to_toml: bool) -> Result<Converted, Error> {
+        let from = self.settings.rules_path().to_path_buf();
+        let to_ext = if to_toml { "toml" } else { "json" };
+        if toml_store::is_toml(&from) == to_toml {
+            return Err(Error::Invalid(format!(
+                "{} is already {}",
+                from.to_string_lossy(),
+                to_ext
+            )));
+        }
+        let to = from.with_extension(to_ext);
+        if to.exists() {
+            return Err(Error::Invalid(format!(
+                "{} already exists; move it away first",
+                to.to_string_lossy()
+            )));
+        }
+        let set = rules::load_rules(&from, &self.settings.key_path).map_err(store("rules"))?;
+        rules::save_rules(&to, &self.settings.key_path, &set).map_err(store("rules"))?;
+        // Keep the old file as a backup; leaving it in place would shadow (or be shadowed by)
+        // the new one.
+        let mut backup = from.clone().into_os_string();
+        backup.push(".bak");
+        let backup = PathBuf::from(backup);
+        let mut warnings = Vec::new();
+        if from.exists() {
+            if let Err(err) = std::fs::rename(&from, &backup) {
+                warnings.push(format!(
+                    "could not move {} aside: {}",
+                    from.to_string_lossy(),
+                    err
+                ));
+            }
+        }
+        if self.settings.rules_path.1 == config::Source::Config {
+            let (config_path, key_path) = (&self.settings.config_path, &self.settings.key_path);
+            let updated = config::read_config_file(config_path, key_path).and_then(|cfg| {
+                let mut cfg = cfg.unwrap_or_default();
+                cfg.paths.rules = cfg.paths.rules.map(|p| p.with_extension(to_ext));
+                config::save_config_file(config_path, key_path, &cfg)
+            });
+            if let Err(err) = updated {
+                warnings.push(format!(
+                    "update paths.rules in {} by hand: {}",
+                    config_path.to_string_lossy(),
+                    err
+                ));
+            }
+        }
+        Ok(Converted {
+            from,
+            to,
+            backup,
+            warnings,
+        })
+    }
+
+    /// Writes the user rules, captures and portable config settings to a bundle at `out`.
+    pub fn export(&self, out: &Path) -> Result<Bundle, Error> {
+        let (rules, captures) = self.load_for_bundle()?;
+        let config = config::read_config_file(&self.settings.config_path, &self.settings.key_path)
+            .map_err(store("config"))?;
+        let created_unix_ms = crate::guard::Clock::now_ms(&crate::guard::SystemClock) as u64;
+        let bundle = bundle::build(
+            rules,
+            captures,
+            config,
+            SourceInfo::current(),
+            created_unix_ms,
+        );
+        bundle::write_bundle(out, &bundle).map_err(store("bundle"))?;
+        Ok(bundle)
+    }
+
+    /// What importing `bundle` would do to the stores here; nothing is written.
+    pub fn plan_import(
+        &self,
+        bundle: &Bundle,
+        mode: ImportMode,
+        allow_foreign: bool,
+    ) -> Result<ImportPlan, Error> {
+        let (rules, captures) = self.load_for_bundle()?;
+        Ok(bundle::plan_import(
+            bundle,
+            &rules,
+            &captures,
+            SourceInfo::current().sid.as_deref(),
+            mode,
+            allow_foreign,
+        ))
+    }
+
+    /// Writes a plan from [`Self::plan_import`] and merges the bundle's watch settings into the
+    /// config. The config is read first, so one that fails to load stops the import untouched.
+    pub fn import(&self, bundle: &Bundle, plan: &ImportPlan) -> Result<(), Error> {
+        let (config_path, key_path) = (&self.settings.config_path, &self.settings.key_path);
+        let config = match bundle.config.as_ref() {
+            Some(incoming) => {
+                let mut cfg = config::read_config_file(config_path, key_path)
+                    .map_err(store("config"))?
+                    .unwrap_or_default();
+                cfg.watch = bundle::merge_watch(cfg.watch, &incoming.watch, plan.mode);
+                Some(cfg)
+            }
+            None => None,
+        };
+        let written = captures::save_store(self.settings.captures_path(), key_path, &plan.captures)
+            .map_err(store("captures"))
+            .and_then(|()| {
+                rules::save_rules(self.settings.rules_path(), key_path, &plan.rules)
+                    .map_err(store("rules"))
+            });
+        self.invalidate();
+        written?;
+        if let Some(cfg) = config {
+            config::save_config_file(config_path, key_path, &cfg).map_err(store("config"))?;
+        }
+        Ok(())
+    }
+
+    /// The user rules and captures as they are on disk, for export and import.
+    fn load_for_bundle(&self) -> Result<(rules::RuleSet, captures::ByExt), Error> {
+        let rules = rules::load_rules(self.settings.rules_path(), &self.settings.key_path)
+            .map_err(store("rules"))?;
+        let captures = captures::load_store(self.settings.captures_path(), &self.settings.key_path)
+            .map_err(store("captures"))?;
+        Ok((rules, captures))
+    }
+
+    /// Appends one JSON line to the event log.
+    pub fn record(&self, line: &str) -> io::Result<()> {
+        logging::append_line(self.settings.log_path(), line)
+    }
+
+    /// Log lines written after byte `offset`, and the offset to pass next time. A log that
+    /// shrank (rotated or cleared) is read from the start.
+    pub fn events_since(&self, offset: u64) -> io::Result<(Vec<LoggedEvent>, u64)> {
+        let (lines, next) = logging::read_since(self.settings.log_path(), offset)?;
+        let events = lines
+            .into_iter()
+            .map(|line| {
+                let v: serde_json::Value = serde_json::from_str(&line).unwrap_or_default();
+                let field = |k: &str| v.get(k).and_then(|s| s.as_str()).map(str::to_string);
+                LoggedEvent {
+                    status: field("status"),
+                    ext: field("ext"),
+                    time_unix_ms: v.get("time_unix_ms").and_then(|t| t.as_u64()),
+                    line,
+                }
+            })
+            .collect();
+        Ok((events, next))
+    }
+}
+
+/// Allow-list labels for [`crate::guard::Engine`], from the cached capture store.
+impl crate::guard::CaptureStore for &Guard {
+    fn prog_id(&self, ext: &str, label: &str) -> Option<String> {
+        self.capture_for(ext, label)
+            .ok()
+            .flatten()
+            .map(|c| c.prog_id)
+    }
+}
+
+/// `loaded`, or the cache's previous value when the new contents do not load for any reason other
+/// than tampering.
+fn last_good<T>(loaded: Result<Arc<T>, Error>, cache: &Cached<T>) -> Result<Arc<T>, Error> {
+    match loaded {
+        Err(err) if err.tampered().is_none() => cache.last().ok_or(err),
+        other => other,
+    }
+}
+
+fn normalize_label(label: &str) -> Result<String, Error> {
+    let label = label.trim().to_ascii_lowercase();
+    if label.is_empty() {
+        return Err(Error::Invalid("--name is empty".to_string()));
+    }
+    Ok(label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+    use std::path::Path;
+
+    fn temp_home(name: &str) -> PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("fag-api-{}-{}", name, nanos))
+    }
+
+    fn guard_at(home: &Path, policy: Option<PathBuf>) -> Guard {
+        let at = |file: &str| (home.join(file), Source::Default);
+        Guard::new(Settings {
+            config_path: home.join("config.json"),
+            home: home.to_path_buf(),
+            portable: (false, Source::Default),
+            rules_path: at("rules.json"),
+            captures_path: at("captures.json"),
+            log_path: at("guard.log"),
+            key_path: home.join("integrity.key"),
+            policy_path: policy,
+            interval_secs: (5, Source::Default),
+            monitor_only: (false, Source::Default),
+            backoff_base_secs: (30, Source::Default),
+            backoff_max_secs: (600, Source::Default),
+            backoff_policy: (crate::rules::BackoffPolicy::Exponential, Source::Default),
+            backoff_max_retries: (0, Source::Default),
+            backoff_cooldown_secs: (0, Source::Default),
+            flap_cycles: (5, Source::Default),
+            flap_window_secs: (600, Source::Default),
+            flap_quiet_secs: (900, Source::Default),
+            grace_secs: (0, Source::Default),
+            pause_processes: (Vec::new(), Source::Default),
+        })
+    }
+
+    fn capture(guard: &Guard, ext: &str, label: &str, prog_id: &str) {
+        let cap = LatestCapture {
+            prog_id: prog_id.to_string(),
+            hash: "h".to_string(),
+            last_write_time_filetime: None,
+            prog_id_last_write_time_filetime: None,
+        };
+        captures::upsert_latest_capture(
+            guard.settings().captures_path(),
+            &guard.settings().key_path,
+            ext,
+            label,
+            cap,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rule_editing_respects_captures_and_policy() {
+        let home = temp_home("rules");
+        std::fs::create_dir_all(&home).unwrap();
+        let policy = home.join("policy.json");
+        std::fs::write(
+            &policy,
+            r#"{"version":2,"by_ext":{".pdf":{"name":"acrobat"}}}"#,
+        )
+        .unwrap();
+        let guard = guard_at(&home, Some(policy.clone()));
+        capture(&guard, ".mp4", "vlc", "VLC.mp4");
+
+        assert!(matches!(
+            guard.add_rule(".mkv", "vlc"),
+            Err(Error::NoCapture { .. })
+        ));
+        assert!(matches!(
+            guard.add_rule(".pdf", "edge"),
+            Err(Error::Managed { .. })
+        ));
+        assert_eq!(guard.add_rule(".mp4", " VLC ").unwrap().label, "vlc");
+        let added = guard.add_rule("video", "vlc").unwrap();
+        assert!(added.missing_captures.contains(&".mkv".to_string()));
+        assert!(!added.missing_captures.contains(&".mp4".to_string()));
+        assert!(matches!(
+            guard.add_rule("nosuch", "vlc"),
+            Err(Error::UnknownGroup(_))
+        ));
+
+        guard.update_rule(".mp4", |r| r.enabled = false).unwrap();
+        let layered = guard.rules().unwrap();
+        assert!(!layered.user.by_ext[".mp4"].enabled);
+        assert!(layered.is_managed(".pdf"));
+
+        guard.remove_rule(".mp4").unwrap();
+        assert!(matches!(guard.remove_rule(".mp4"), Err(Error::NotFound(_))));
+
+        // A policy that cannot be read is not "no policy": edits stop, the managed rules stay.
+        std::fs::write(&policy, "{ not json").unwrap();
+        assert!(matches!(
+            guard.add_rule(".mp4", "vlc"),
+            Err(Error::Policy { .. })
+        ));
+        assert!(guard.rules().unwrap().is_managed(".pdf"));
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn groups_profiles_schedules_and_convert() {
+        let home = temp_home("edits");
+        std::fs::create_dir_all(&home).unwrap();
+        let guard = guard_at(&home, None);
+        capture(&guard, ".mp4", "vlc", "VLC.mp4");
+        capture(&guard, ".mp4", "potplayer", "PotPlayer.mp4");
+
+        assert_eq!(
+            guard
+                .set_group("Clips", Some(vec![".mp4".to_string()]))
+                .unwrap(),
+            "clips"
+        );
+        assert!(matches!(
+            guard.set_group("nosuch", None),
+            Err(Error::UnknownGroup(_))
+        ));
+
+        guard.add_rule(".mp4", "vlc").unwrap();
+        assert_eq!(guard.save_profile("Work").unwrap(), "work");
+        guard
+            .update_rule(".mp4", |r| r.name = "potplayer".to_string())
+            .unwrap();
+        let switch = guard.use_profile("work", false).unwrap();
+        assert_eq!(
+            (switch.from.as_str(), switch.profile.as_str()),
+            ("default", "work")
+        );
+        assert!(switch.outcomes.is_empty());
+        assert_eq!(guard.rules().unwrap().user.by_ext[".mp4"].name, "vlc");
+        assert!(matches!(
+            guard.use_profile("nosuch", false),
+            Err(Error::UnknownProfile(_))
+        ));
+        assert!(matches!(
+            guard.delete_profile("work"),
+            Err(Error::UnknownProfile(_))
+        ));
+        assert_eq!(guard.delete_profile("default").unwrap(), "default");
+
+        let window = |days: &str, name: &str| ScheduleWindow {
+            days: days.to_string(),
+            from: "09:00".to_string(),
+            to: "17:00".to_string(),
+            name: name.to_string(),
+        };
+        let rule = guard
+            .set_schedule(".mp4", Some(window("mon-fri", "potplayer")))
+            .unwrap();
+        assert_eq!(rule.schedule.len(), 1);
+        assert!(matches!(
+            guard.set_schedule(".mp4", Some(window("mon-fri", "nope"))),
+            Err(Error::NoCapture { .. })
+        ));
+        assert!(matches!(
+            guard.set_schedule(".mp4", Some(window("someday", "vlc"))),
+            Err(Error::Invalid(_))
+        ));
+        assert!(guard
+            .set_schedule(".mp4", None)
+            .unwrap()
+            .schedule
+            .is_empty());
+        guard.add_follow_rule(".mkv", ".mp4").unwrap();
+        assert!(matches!(
+            guard.set_schedule(".mkv", None),
+            Err(Error::Invalid(_))
+        ));
+
+        let converted = guard.convert_rules(true).unwrap();
+        assert_eq!(converted.to, home.join("rules.toml"));
+        assert!(converted.backup.exists() && !converted.from.exists());
+        assert!(converted.warnings.is_empty());
+        let toml = rules::load_rules(&converted.to, &guard.settings().key_path).unwrap();
+        assert_eq!(toml.by_ext[".mp4"].name, "vlc");
+        assert!(matches!(guard.convert_rules(false), Err(Error::Invalid(_))));
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn events_are_read_incrementally() {
+        let home = temp_home("events");
+        let guard = guard_at(&home, None);
+        assert_eq!(guard.events_since(0).unwrap(), (Vec::new(), 0));
+
+        guard
+            .record(r#"{"time_unix_ms":1,"ext":".mp4","status":"TAMPERED"}"#)
+            .unwrap();
+        let (events, offset) = guard.events_since(0).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].status.as_deref(), Some("TAMPERED"));
+        assert_eq!(events[0].ext.as_deref(), Some(".mp4"));
+        assert_eq!(events[0].time_unix_ms, Some(1));
+
+        guard.record(r#"{"status":"APPLIED"}"#).unwrap();
+        let (events, _) = guard.events_since(offset).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].status.as_deref(), Some("APPLIED"));
+
+        // A log cleared behind our back starts over.
+        std::fs::write(guard.settings().log_path(), "").unwrap();
+        assert_eq!(guard.events_since(offset).unwrap(), (Vec::new(), 0));
+        let _ = std::fs::remove_dir_all(&home);
+    }
+}
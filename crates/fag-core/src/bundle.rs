@@ -0,0 +1,456 @@
+//! Export bundles: rules, captures and the portable part of the config in one checksummed file,
+//! and the plan for importing one into the stores here. [`crate::Guard`] reads and writes the
+//! stores around them.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::captures::LatestCapture;
+use crate::config::{ConfigFile, WatchConfig};
+use crate::rules::{Rule, RuleSet};
+
+pub const BUNDLE_FORMAT: &str = "fileassocguard-bundle";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bundle {
+    pub manifest: Manifest,
+    #[serde(default)]
+    pub rules: BTreeMap<String, Rule>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub group_rules: BTreeMap<String, Rule>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub captures: BTreeMap<String, BTreeMap<String, LatestCapture>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ConfigFile>,
+    #[serde(default)]
+    pub checksum: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    pub created_unix_ms: u64,
+    pub source: SourceInfo,
+    pub rule_count: usize,
+    pub capture_count: usize,
+}
+
+/// Where the bundle was exported. Captured hashes are only valid for the same user SID.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceInfo {
+    pub sid: Option<String>,
+    pub machine: Option<String>,
+    pub hash_version: Option<u32>,
+}
+
+impl SourceInfo {
+    pub fn current() -> Self {
+        let si = crate::sysinfo::read_sysinfo().ok();
+        Self {
+            sid: si.as_ref().and_then(|s| s.sid.clone()),
+            machine: std::env::var("COMPUTERNAME")
+                .or_else(|_| std::env::var("HOSTNAME"))
+                .ok(),
+            hash_version: si.and_then(|s| s.hash_version),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Add what is missing; on conflicts keep the existing entry.
+    #[default]
+    Merge,
+    /// Replace the target rules and captures with the bundle contents.
+    Replace,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// `ext` is a group name for group rules and group definitions.
+    Rule {
+        ext: String,
+        existing: Box<Rule>,
+        incoming: Box<Rule>,
+    },
+    Capture {
+        ext: String,
+        name: String,
+        existing_prog_id: String,
+        incoming_prog_id: String,
+        hash_differs: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportPlan {
+    pub mode: ImportMode,
+    /// The SID of the user importing, which the bundle's captures were checked against.
+    pub target_sid: Option<String>,
+    pub rules: RuleSet,
+    pub captures: BTreeMap<String, BTreeMap<String, LatestCapture>>,
+    pub conflicts: Vec<Conflict>,
+    /// Captures exported under a different (or unknown) SID: their hash cannot verify here.
+    pub foreign_captures: Vec<(String, String)>,
+    /// Imported rules whose label has no usable capture on this machine.
+    pub needs_capture: Vec<(String, String)>,
+    pub added_rules: usize,
+    pub added_captures: usize,
+}
+
+pub fn build(
+    rules: RuleSet,
+    captures: BTreeMap<String, BTreeMap<String, LatestCapture>>,
+    config: Option<ConfigFile>,
+    source: SourceInfo,
+    created_unix_ms: u64,
+) -> Bundle {
+    let mut config = config;
+    if let Some(cfg) = config.as_mut() {
+        // Paths are machine-local; only portable settings travel.
+        cfg.paths = Default::default();
+    }
+    let mut bundle = Bundle {
+        manifest: Manifest {
+            format: BUNDLE_FORMAT.to_string(),
+            version: 1,
+            created_unix_ms,
+            source,
+            rule_count: rules.by_ext.len() + rules.by_group.len(),
+            capture_count: captures.values().map(|m| m.len()).sum(),
+        },
+        rules: rules.by_ext,
+        group_rules: rules.by_group,
+        groups: rules.groups,
+        captures,
+        config,
+        checksum: String::new(),
+    };
+    bundle.checksum = checksum(&bundle);
+    bundle
+}
+
+pub fn checksum(bundle: &Bundle) -> String {
+    checksum_value(&serde_json::to_value(bundle).unwrap_or_default())
+}
+
+fn checksum_value(value: &serde_json::Value) -> String {
+    let mut value = value.clone();
+    if let Some(obj) = value.as_object_mut() {
+        obj.remove("checksum");
+    }
+    let digest = Sha256::digest(serde_json::to_vec(&value).unwrap_or_default());
+    format!("sha256:{}", crate::integrity::encode_hex(&digest))
+}
+
+pub fn write_bundle(path: &Path, bundle: &Bundle) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let bytes = serde_json::to_vec_pretty(bundle)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    std::fs::write(path, bytes)
+}
+
+pub fn read_bundle(path: &Path) -> std::io::Result<Bundle> {
+    let bytes = std::fs::read(path)?;
+    let mut value: serde_json::Value = serde_json::from_slice(&bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    if value["manifest"]["format"].as_str() != Some(BUNDLE_FORMAT) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("not a {} file", BUNDLE_FORMAT),
+        ));
+    }
+    // Checked over the file as written, so bundles with v1 string rules still verify.
+    if value["checksum"].as_str() != Some(checksum_value(&value).as_str()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "bundle checksum mismatch (file corrupted or edited)",
+        ));
+    }
+    if let Some(rules) = value.get_mut("rules") {
+        crate::rules::upgrade_rule_map(rules);
+    }
+    serde_json::from_value(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn same_sid(a: Option<&str>, b: Option<&str>) -> bool {
+    matches!((a, b), (Some(a), Some(b)) if a.eq_ignore_ascii_case(b))
+}
+
+/// Computes the post-import rules and captures without touching disk.
+pub fn plan_import(
+    bundle: &Bundle,
+    existing_rules: &RuleSet,
+    existing_captures: &BTreeMap<String, BTreeMap<String, LatestCapture>>,
+    target_sid: Option<&str>,
+    mode: ImportMode,
+    allow_foreign: bool,
+) -> ImportPlan {
+    let mut plan = ImportPlan {
+        mode,
+        target_sid: target_sid.map(str::to_string),
+        ..ImportPlan::default()
+    };
+    let foreign = !same_sid(bundle.manifest.source.sid.as_deref(), target_sid);
+
+    let (mut rules, mut captures) = match mode {
+        ImportMode::Merge => (existing_rules.clone(), existing_captures.clone()),
+        // Profiles are local; replacing the active rules must not drop them.
+        ImportMode::Replace => (
+            RuleSet {
+                profiles: existing_rules.profiles.clone(),
+                active_profile: existing_rules.active_profile.clone(),
+                ..RuleSet::default()
+            },
+            BTreeMap::new(),
+        ),
+    };
+
+    for (ext, by_name) in bundle.captures.iter() {
+        for (name, incoming) in by_name.iter() {
+            if foreign {
+                plan.foreign_captures.push((ext.clone(), name.clone()));
+                if !allow_foreign {
+                    // Never drop a capture that is valid here for one that cannot be.
+                    if let Some(existing) = existing_captures.get(ext).and_then(|m| m.get(name)) {
+                        captures
+                            .entry(ext.clone())
+                            .or_default()
+                            .insert(name.clone(), existing.clone());
+                    }
+                    continue;
+                }
+            }
+            if let Some(existing) = existing_captures.get(ext).and_then(|m| m.get(name)) {
+                if existing.prog_id != incoming.prog_id || existing.hash != incoming.hash {
+                    plan.conflicts.push(Conflict::Capture {
+                        ext: ext.clone(),
+                        name: name.clone(),
+                        existing_prog_id: existing.prog_id.clone(),
+                        incoming_prog_id: incoming.prog_id.clone(),
+                        hash_differs: existing.hash != incoming.hash,
+                    });
+                    if mode == ImportMode::Merge {
+                        continue;
+                    }
+                }
+            } else {
+                plan.added_captures += 1;
+            }
+            captures
+                .entry(ext.clone())
+                .or_default()
+                .insert(name.clone(), incoming.clone());
+        }
+    }
+
+    let rule_maps = [
+        (&bundle.rules, &existing_rules.by_ext, &mut rules.by_ext),
+        (
+            &bundle.group_rules,
+            &existing_rules.by_group,
+            &mut rules.by_group,
+        ),
+    ];
+    for (incoming_map, existing_map, target) in rule_maps {
+        for (key, incoming) in incoming_map.iter() {
+            match existing_map.get(key) {
+                Some(existing) if existing != incoming => {
+                    plan.conflicts.push(Conflict::Rule {
+                        ext: key.clone(),
+                        existing: Box::new(existing.clone()),
+                        incoming: Box::new(incoming.clone()),
+                    });
+                    if mode == ImportMode::Merge {
+                        continue;
+                    }
+                }
+                Some(_) => {}
+                None => plan.added_rules += 1,
+            }
+            target.insert(key.clone(), incoming.clone());
+        }
+    }
+    for (group, exts) in bundle.groups.iter() {
+        if mode == ImportMode::Merge && existing_rules.groups.contains_key(group) {
+            continue;
+        }
+        rules.groups.insert(group.clone(), exts.clone());
+    }
+
+    // Follow rules resolve their label at run time.
+    for effective in rules
+        .expand()
+        .into_iter()
+        .filter(|r| r.rule.follow.is_none())
+    {
+        let (ext, label) = (&effective.ext, &effective.rule.name);
+        let has_capture = captures.get(ext).is_some_and(|m| m.contains_key(label));
+        let is_foreign = plan
+            .foreign_captures
+            .iter()
+            .any(|(e, n)| e == ext && n == label)
+            && !existing_captures
+                .get(ext)
+                .is_some_and(|m| m.contains_key(label));
+        if !has_capture || is_foreign {
+            plan.needs_capture.push((ext.clone(), label.clone()));
+        }
+    }
+
+    plan.rules = rules;
+    plan.captures = captures;
+    plan
+}
+
+/// The watch settings after importing `incoming`: the bundle's on replace, otherwise the ones
+/// already set here with the bundle filling the gaps.
+pub fn merge_watch(existing: WatchConfig, incoming: &WatchConfig, mode: ImportMode) -> WatchConfig {
+    match mode {
+        ImportMode::Replace => incoming.clone(),
+        ImportMode::Merge => WatchConfig {
+            interval_secs: existing.interval_secs.or(incoming.interval_secs),
+            monitor_only: existing.monitor_only.or(incoming.monitor_only),
+            backoff_base_secs: existing.backoff_base_secs.or(incoming.backoff_base_secs),
+            backoff_max_secs: existing.backoff_max_secs.or(incoming.backoff_max_secs),
+            backoff_policy: existing.backoff_policy.or(incoming.backoff_policy),
+            backoff_max_retries: existing
+                .backoff_max_retries
+                .or(incoming.backoff_max_retries),
+            backoff_cooldown_secs: existing
+                .backoff_cooldown_secs
+                .or(incoming.backoff_cooldown_secs),
+            flap_cycles: existing.flap_cycles.or(incoming.flap_cycles),
+            flap_window_secs: existing.flap_window_secs.or(incoming.flap_window_secs),
+            flap_quiet_secs: existing.flap_quiet_secs.or(incoming.flap_quiet_secs),
+            grace_secs: existing.grace_secs.or(incoming.grace_secs),
+            pause_processes: existing
+                .pause_processes
+                .or_else(|| incoming.pause_processes.clone()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cap(prog_id: &str, hash: &str) -> LatestCapture {
+        LatestCapture {
+            prog_id: prog_id.to_string(),
+            hash: hash.to_string(),
+            last_write_time_filetime: None,
+            prog_id_last_write_time_filetime: None,
+        }
+    }
+
+    fn sample(sid: Option<&str>) -> Bundle {
+        let mut rules = RuleSet::default();
+        rules.by_ext.insert(".mp4".to_string(), Rule::new("vlc"));
+        rules.by_ext.insert(".mkv".to_string(), Rule::new("vlc"));
+        let mut captures: BTreeMap<String, BTreeMap<String, LatestCapture>> = BTreeMap::new();
+        captures
+            .entry(".mp4".to_string())
+            .or_default()
+            .insert("vlc".to_string(), cap("VLC.mp4", "new="));
+        captures
+            .entry(".mkv".to_string())
+            .or_default()
+            .insert("vlc".to_string(), cap("VLC.mkv", "mkv="));
+        build(
+            rules,
+            captures,
+            None,
+            SourceInfo {
+                sid: sid.map(str::to_string),
+                machine: Some("PC1".to_string()),
+                hash_version: Some(1),
+            },
+            1,
+        )
+    }
+
+    #[test]
+    fn checksum_detects_edits() {
+        let mut b = sample(Some("S-1-5-21-1"));
+        assert_eq!(b.checksum, checksum(&b));
+        b.rules.insert(".avi".to_string(), Rule::new("evil"));
+        assert_ne!(b.checksum, checksum(&b));
+    }
+
+    #[test]
+    fn merge_reports_conflicts_and_keeps_existing() {
+        let b = sample(Some("S-1-5-21-1"));
+        let mut rules = RuleSet::default();
+        rules
+            .by_ext
+            .insert(".mp4".to_string(), Rule::new("potplayer"));
+        let mut caps: BTreeMap<String, BTreeMap<String, LatestCapture>> = BTreeMap::new();
+        caps.entry(".mp4".to_string())
+            .or_default()
+            .insert("vlc".to_string(), cap("VLC.mp4", "old="));
+
+        let plan = plan_import(
+            &b,
+            &rules,
+            &caps,
+            Some("s-1-5-21-1"),
+            ImportMode::Merge,
+            false,
+        );
+        assert_eq!(plan.rules.by_ext[".mp4"].name, "potplayer");
+        assert_eq!(plan.rules.by_ext[".mkv"].name, "vlc");
+        assert_eq!(plan.captures[".mp4"]["vlc"].hash, "old=");
+        assert_eq!(plan.added_rules, 1);
+        assert_eq!(plan.added_captures, 1);
+        assert_eq!(plan.conflicts.len(), 2);
+        assert!(plan.foreign_captures.is_empty());
+
+        let replaced = plan_import(
+            &b,
+            &rules,
+            &caps,
+            Some("S-1-5-21-1"),
+            ImportMode::Replace,
+            false,
+        );
+        assert_eq!(replaced.rules.by_ext, b.rules);
+        assert_eq!(replaced.captures[".mp4"]["vlc"].hash, "new=");
+    }
+
+    #[test]
+    fn foreign_sid_captures_are_flagged_and_skipped() {
+        let b = sample(Some("S-1-5-21-1"));
+        let plan = plan_import(
+            &b,
+            &RuleSet::default(),
+            &BTreeMap::new(),
+            Some("S-1-5-21-2"),
+            ImportMode::Merge,
+            false,
+        );
+        assert_eq!(plan.foreign_captures.len(), 2);
+        assert!(plan.captures.is_empty());
+        assert_eq!(plan.needs_capture.len(), 2);
+
+        let forced = plan_import(
+            &b,
+            &RuleSet::default(),
+            &BTreeMap::new(),
+            None,
+            ImportMode::Merge,
+            true,
+        );
+        assert_eq!(forced.captures.len(), 2);
+        assert_eq!(forced.needs_capture.len(), 2);
+    }
+}
@@ -0,0 +1,115 @@
+//! Parsed stores kept in memory until their files change on disk.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// What a file looked like when it was read. `None` for a missing file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+pub fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some(FileStamp {
+        modified: meta.modified().ok(),
+        len: meta.len(),
+    })
+}
+
+/// Stamps of every watched path at load time, next to what was loaded.
+type Entry<T> = (Vec<Option<FileStamp>>, Arc<T>);
+
+/// A value loaded from `paths`, reloaded when any of their stamps changes. Load errors are not
+/// cached, so a broken or tampered file is reported on every call until it is fixed.
+pub struct Cached<T> {
+    paths: Vec<PathBuf>,
+    entry: Mutex<Option<Entry<T>>>,
+}
+
+impl<T> Cached<T> {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        Self {
+            paths,
+            entry: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self, load: impl FnOnce() -> io::Result<T>) -> io::Result<Arc<T>> {
+        // Stamped before loading: a write during the load makes the next call reload.
+        let stamps: Vec<_> = self.paths.iter().map(|p| file_stamp(p)).collect();
+        let mut entry = self.entry.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((seen, value)) = entry.as_ref() {
+            if *seen == stamps {
+                return Ok(Arc::clone(value));
+            }
+        }
+        // On error the previous value stays available through `last`.
+        let value = Arc::new(load()?);
+        *entry = Some((stamps, Arc::clone(&value)));
+        Ok(value)
+    }
+
+    /// The most recently loaded value, even if the files changed since and no longer load.
+    pub fn last(&self) -> Option<Arc<T>> {
+        let entry = self.entry.lock().unwrap_or_else(|e| e.into_inner());
+        entry.as_ref().map(|(_, value)| Arc::clone(value))
+    }
+
+    /// Makes the next `get` reload, for writers whose change might not move the file's stamp.
+    /// The value stays available through `last` until then.
+    pub fn invalidate(&self) {
+        if let Some((seen, _)) = self
+            .entry
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_mut()
+        {
+            seen.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn reloads_only_after_the_file_changes() {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("fag-cache-{}.json", nanos));
+        let cache = Cached::new(vec![path.clone()]);
+        let loads = Cell::new(0);
+        let load = || {
+            loads.set(loads.get() + 1);
+            std::fs::read_to_string(&path).or_else(|_| Ok(String::new()))
+        };
+
+        assert_eq!(*cache.get(load).unwrap(), "");
+        assert_eq!(*cache.get(load).unwrap(), "");
+        assert_eq!(loads.get(), 1);
+
+        std::fs::write(&path, "v1").unwrap();
+        assert_eq!(*cache.get(load).unwrap(), "v1");
+        assert_eq!(*cache.get(load).unwrap(), "v1");
+        assert_eq!(loads.get(), 2);
+
+        cache.invalidate();
+        assert_eq!(*cache.get(load).unwrap(), "v1");
+        assert_eq!(loads.get(), 3);
+
+        std::fs::remove_file(&path).unwrap();
+        let bad = || Err(io::Error::new(io::ErrorKind::InvalidData, "bad"));
+        assert!(cache.get(bad).is_err());
+        assert!(cache.get(bad).is_err());
+        assert_eq!(cache.last().as_deref().map(String::as_str), Some("v1"));
+        assert_eq!(*cache.get(load).unwrap(), "");
+    }
+}
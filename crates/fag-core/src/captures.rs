@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatestCapture {
+    pub prog_id: String,
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_write_time_filetime: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prog_id_last_write_time_filetime: Option<u64>,
+}
+
+/// Captures by extension, then by label.
+pub type ByExt = BTreeMap<String, BTreeMap<String, LatestCapture>>;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CaptureStore {
+    version: u32,
+    #[serde(default)]
+    by_ext: BTreeMap<String, BTreeMap<String, LatestCapture>>,
+}
+
+pub fn load_store(
+    path: &Path,
+    key_path: &Path,
+) -> std::io::Result<BTreeMap<String, BTreeMap<String, LatestCapture>>> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let value = crate::integrity::verify_store_bytes(path, key_path, &bytes)?;
+    let store: CaptureStore = serde_json::from_value(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(store.by_ext)
+}
+
+pub fn save_store(
+    path: &Path,
+    key_path: &Path,
+    by_ext: &BTreeMap<String, BTreeMap<String, LatestCapture>>,
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let store = CaptureStore {
+        version: 1,
+        by_ext: by_ext.clone(),
+    };
+    let bytes = crate::integrity::seal_store(path, key_path, &store)?;
+    std::fs::write(path, bytes)
+}
+
+pub fn upsert_latest_capture(
+    path: &Path,
+    key_path: &Path,
+    ext: &str,
+    name: &str,
+    cap: LatestCapture,
+) -> std::io::Result<()> {
+    let mut by_ext = load_store(path, key_path)?;
+    by_ext
+        .entry(ext.to_string())
+        .or_default()
+        .insert(name.to_string(), cap);
+    save_store(path, key_path, &by_ext)
+}
+
+pub fn get_latest_capture(
+    path: &Path,
+    key_path: &Path,
+    ext: &str,
+    name: &str,
+) -> std::io::Result<Option<LatestCapture>> {
+    let by_ext = load_store(path, key_path)?;
+    Ok(by_ext.get(ext).and_then(|m| m.get(name)).cloned())
+}
+
+pub fn list_capture_names(path: &Path, key_path: &Path, ext: &str) -> std::io::Result<Vec<String>> {
+    let by_ext = load_store(path, key_path)?;
+    let mut out = by_ext
+        .get(ext)
+        .map(|m| m.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    out.sort();
+    Ok(out)
+}
+
+/// How a follower extension resolves against its leader's current ProgId.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FollowTarget {
+    /// The follower's capture to enforce, and its label.
+    Found(String, LatestCapture),
+    /// No capture of the leader has this ProgId, so its label is unknown.
+    LeaderUncaptured,
+    /// The leader's current app is known by this label but the follower has no capture for it.
+    FollowerUncaptured(String),
+}
+
+/// Prefers a follower capture with the very same ProgId (apps that register one ProgId for all
+/// types), then the follower capture sharing the label of the leader's matching capture.
+pub fn follow_target(
+    by_ext: &BTreeMap<String, BTreeMap<String, LatestCapture>>,
+    leader_ext: &str,
+    leader_progid: &str,
+    follower_ext: &str,
+) -> FollowTarget {
+    let follower = by_ext.get(follower_ext);
+    if let Some((label, cap)) = follower
+        .into_iter()
+        .flatten()
+        .find(|(_, c)| c.prog_id == leader_progid)
+    {
+        return FollowTarget::Found(label.clone(), cap.clone());
+    }
+    let Some(label) = by_ext.get(leader_ext).and_then(|m| {
+        m.iter()
+            .find(|(_, c)| c.prog_id == leader_progid)
+            .map(|(label, _)| label.clone())
+    }) else {
+        return FollowTarget::LeaderUncaptured;
+    };
+    match follower.and_then(|m| m.get(&label)) {
+        Some(cap) => FollowTarget::Found(label, cap.clone()),
+        None => FollowTarget::FollowerUncaptured(label),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A directory of its own per test, for the store and its integrity key.
+    fn temp_home(name: &str) -> PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let home = std::env::temp_dir().join(format!("fag-captures-{}-{}", name, nanos));
+        std::fs::create_dir_all(&home).unwrap();
+        home
+    }
+
+    #[test]
+    fn store_roundtrip_upsert_get_list() {
+        let home = temp_home("roundtrip");
+        let (path, key) = (home.join("captures.json"), home.join("integrity.key"));
+
+        let cap1 = LatestCapture {
+            prog_id: "VLC.mp4".to_string(),
+            hash: "abc=".to_string(),
+            last_write_time_filetime: Some(123),
+            prog_id_last_write_time_filetime: None,
+        };
+        upsert_latest_capture(&path, &key, ".mp4", "vlc", cap1.clone()).unwrap();
+
+        let cap2 = LatestCapture {
+            prog_id: "PotPlayerMini64.mp4".to_string(),
+            hash: "def=".to_string(),
+            last_write_time_filetime: None,
+            prog_id_last_write_time_filetime: Some(456),
+        };
+        upsert_latest_capture(&path, &key, ".mp4", "potplayer", cap2.clone()).unwrap();
+
+        assert_eq!(
+            get_latest_capture(&path, &key, ".mp4", "vlc").unwrap(),
+            Some(cap1)
+        );
+        assert_eq!(
+            get_latest_capture(&path, &key, ".mp4", "potplayer").unwrap(),
+            Some(cap2)
+        );
+
+        let names = list_capture_names(&path, &key, ".mp4").unwrap();
+        assert_eq!(names, vec!["potplayer".to_string(), "vlc".to_string()]);
+
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn follow_target_maps_leader_progid_through_labels() {
+        let cap = |prog_id: &str| LatestCapture {
+            prog_id: prog_id.to_string(),
+            hash: "h=".to_string(),
+            last_write_time_filetime: None,
+            prog_id_last_write_time_filetime: None,
+        };
+        let mut by_ext: BTreeMap<String, BTreeMap<String, LatestCapture>> = BTreeMap::new();
+        let mp4 = by_ext.entry(".mp4".to_string()).or_default();
+        mp4.insert("vlc".to_string(), cap("VLC.mp4"));
+        mp4.insert("potplayer".to_string(), cap("PotPlayerMini64.MP4"));
+        mp4.insert("photos".to_string(), cap("AppX43hnxtbyyps"));
+        let mkv = by_ext.entry(".mkv".to_string()).or_default();
+        mkv.insert("vlc".to_string(), cap("VLC.mkv"));
+        mkv.insert("films".to_string(), cap("AppX43hnxtbyyps"));
+
+        assert_eq!(
+            follow_target(&by_ext, ".mp4", "VLC.mp4", ".mkv"),
+            FollowTarget::Found("vlc".to_string(), cap("VLC.mkv"))
+        );
+        assert_eq!(
+            follow_target(&by_ext, ".mp4", "AppX43hnxtbyyps", ".mkv"),
+            FollowTarget::Found("films".to_string(), cap("AppX43hnxtbyyps"))
+        );
+        assert_eq!(
+            follow_target(&by_ext, ".mp4", "PotPlayerMini64.MP4", ".mkv"),
+            FollowTarget::FollowerUncaptured("potplayer".to_string())
+        );
+        assert_eq!(
+            follow_target(&by_ext, ".mp4", "Other.mp4", ".mkv"),
+            FollowTarget::LeaderUncaptured
+        );
+    }
+}
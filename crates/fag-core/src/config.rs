@@ -0,0 +1,761 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::rules::BackoffPolicy;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub portable: bool,
+    #[serde(default)]
+    pub paths: PathsConfig,
+    #[serde(default)]
+    pub watch: WatchConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathsConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captures: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monitor_only: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_base_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_max_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_policy: Option<BackoffPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_max_retries: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_cooldown_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flap_cycles: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flap_window_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flap_quiet_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grace_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pause_processes: Option<Vec<String>>,
+}
+
+pub const DEFAULT_INTERVAL_SECS: u64 = 5;
+pub const DEFAULT_BACKOFF_BASE_SECS: u64 = 30;
+pub const DEFAULT_BACKOFF_MAX_SECS: u64 = 600;
+pub const DEFAULT_FLAP_CYCLES: u32 = 5;
+pub const DEFAULT_FLAP_WINDOW_SECS: u64 = 600;
+pub const DEFAULT_FLAP_QUIET_SECS: u64 = 900;
+
+/// Where a resolved setting came from (reported by `fag config show`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    Config,
+    Env,
+    /// A global option on the command line (`--portable`).
+    Cli,
+}
+
+impl Source {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Config => "config",
+            Self::Env => "env",
+            Self::Cli => "cli",
+        }
+    }
+}
+
+/// Options taken from the command line before the subcommand (`fag --config x.json check`).
+#[derive(Debug, Clone, Default)]
+pub struct GlobalOptions {
+    pub config: Option<PathBuf>,
+    pub portable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub config_path: PathBuf,
+    pub home: PathBuf,
+    pub portable: (bool, Source),
+    pub rules_path: (PathBuf, Source),
+    pub captures_path: (PathBuf, Source),
+    pub log_path: (PathBuf, Source),
+    pub key_path: PathBuf,
+    /// Machine policy layered over rules.json; `None` when there is nowhere to look.
+    pub policy_path: Option<PathBuf>,
+    pub interval_secs: (u64, Source),
+    pub monitor_only: (bool, Source),
+    pub backoff_base_secs: (u64, Source),
+    pub backoff_max_secs: (u64, Source),
+    pub backoff_policy: (BackoffPolicy, Source),
+    pub backoff_max_retries: (u32, Source),
+    /// 0 = no cool-down: a rule that used up its retries stays monitor-only until seen OK.
+    pub backoff_cooldown_secs: (u64, Source),
+    /// 0 = flapping detection off.
+    pub flap_cycles: (u32, Source),
+    pub flap_window_secs: (u64, Source),
+    pub flap_quiet_secs: (u64, Source),
+    /// 0 = restore as soon as a change is seen.
+    pub grace_secs: (u64, Source),
+    /// Process names (`msiexec.exe`, `setup*.exe`) that switch every rule to observe-only while
+    /// any of them runs.
+    pub pause_processes: (Vec<String>, Source),
+}
+
+impl Settings {
+    pub fn rules_path(&self) -> &Path {
+        &self.rules_path.0
+    }
+
+    pub fn captures_path(&self) -> &Path {
+        &self.captures_path.0
+    }
+
+    pub fn log_path(&self) -> &Path {
+        &self.log_path.0
+    }
+
+    /// Where `watch-rules` keeps its state between runs; always next to the config.
+    pub fn state_path(&self) -> PathBuf {
+        self.home.join("state.json")
+    }
+
+    /// The approval queue for rules in `approve` mode; see [`crate::pending`].
+    pub fn pending_path(&self) -> PathBuf {
+        self.home.join("pending.json")
+    }
+
+    /// The single-instance lock shared by `watch` and `watch-rules`.
+    pub fn lock_path(&self) -> PathBuf {
+        self.home.join("watch.lock")
+    }
+}
+
+/// Settings keys understood by `fag config get/set`.
+pub const KEYS: &[&str] = &[
+    "portable",
+    "paths.rules",
+    "paths.captures",
+    "paths.log",
+    "watch.interval_secs",
+    "watch.monitor_only",
+    "watch.backoff_base_secs",
+    "watch.backoff_max_secs",
+    "watch.backoff_policy",
+    "watch.backoff_max_retries",
+    "watch.backoff_cooldown_secs",
+    "watch.flap_cycles",
+    "watch.flap_window_secs",
+    "watch.flap_quiet_secs",
+    "watch.grace_secs",
+    "watch.pause_processes",
+];
+
+pub fn default_home() -> PathBuf {
+    if let Some(appdata) = std::env::var_os("APPDATA") {
+        return PathBuf::from(appdata).join("FileAssocGuard");
+    }
+    PathBuf::from(".")
+}
+
+fn exe_dir() -> Option<PathBuf> {
+    std::env::current_exe()
+        .ok()
+        .and_then(|p| p.parent().map(Path::to_path_buf))
+}
+
+/// Picks the config file: `--config`, then `FAG_HOME`, then portable (next to the exe), then `%APPDATA%`.
+pub fn locate_config(opts: &GlobalOptions) -> (PathBuf, (bool, Source)) {
+    let forced_portable = if opts.portable {
+        Some(Source::Cli)
+    } else if matches!(
+        std::env::var("FAG_PORTABLE").as_deref(),
+        Ok("1") | Ok("true")
+    ) {
+        Some(Source::Env)
+    } else {
+        None
+    };
+    let portable_at = |path: &Path| match (forced_portable, sniff_portable(path)) {
+        (Some(source), _) => (true, source),
+        (None, Some(portable)) => (portable, Source::Config),
+        (None, None) => (false, Source::Default),
+    };
+
+    let explicit = opts.config.clone().or_else(|| {
+        std::env::var_os("FAG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(|home| PathBuf::from(home).join("config.json"))
+    });
+    if let Some(path) = explicit {
+        let portable = portable_at(&path);
+        return (path, portable);
+    }
+
+    if let Some(dir) = exe_dir() {
+        let candidate = dir.join("config.json");
+        let portable = portable_at(&candidate);
+        if portable.0 {
+            return (candidate, portable);
+        }
+    }
+    (default_home().join("config.json"), (false, Source::Default))
+}
+
+/// Reads only the `portable` flag, before the integrity key location is known.
+fn sniff_portable(path: &Path) -> Option<bool> {
+    std::fs::read(path)
+        .ok()
+        .and_then(|b| serde_json::from_slice::<serde_json::Value>(&b).ok())
+        .and_then(|v| v.get("portable").and_then(|p| p.as_bool()))
+}
+
+/// Portable installs keep the key with everything else; otherwise it goes under the user's
+/// LocalAppData (asked of the shell, like ProgramData), out of the config folder.
+pub fn key_path(home: &Path, portable: bool, local_app_data: Option<&Path>) -> PathBuf {
+    match local_app_data {
+        Some(local) if !portable => local.join("FileAssocGuard").join("integrity.key"),
+        _ => home.join("integrity.key"),
+    }
+}
+
+/// `%ProgramData%\FileAssocGuard\policy.json`. ProgramData is asked of the shell, not read from
+/// the environment, which any user can point elsewhere. `None` where there is no such folder
+/// (not Windows).
+pub fn policy_path() -> std::io::Result<Option<PathBuf>> {
+    Ok(program_data()?.map(|d| d.join("FileAssocGuard").join("policy.json")))
+}
+
+#[cfg(windows)]
+#[repr(C)]
+struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+#[cfg(windows)]
+fn program_data() -> std::io::Result<Option<PathBuf>> {
+    // {62AB5D82-FDC1-4DC3-A9DD-070D1D495D97}
+    const FOLDERID_PROGRAM_DATA: Guid = Guid {
+        data1: 0x62AB_5D82,
+        data2: 0xFDC1,
+        data3: 0x4DC3,
+        data4: [0xA9, 0xDD, 0x07, 0x0D, 0x1D, 0x49, 0x5D, 0x97],
+    };
+    known_folder(&FOLDERID_PROGRAM_DATA, "ProgramData").map(Some)
+}
+
+#[cfg(windows)]
+fn local_app_data() -> std::io::Result<Option<PathBuf>> {
+    // {F1B32785-6FBA-4FCF-9D55-7B8E7F157091}
+    const FOLDERID_LOCAL_APP_DATA: Guid = Guid {
+        data1: 0xF1B3_2785,
+        data2: 0x6FBA,
+        data3: 0x4FCF,
+        data4: [0x9D, 0x55, 0x7B, 0x8E, 0x7F, 0x15, 0x70, 0x91],
+    };
+    known_folder(&FOLDERID_LOCAL_APP_DATA, "LocalAppData").map(Some)
+}
+
+#[cfg(windows)]
+fn known_folder(id: &Guid, name: &str) -> std::io::Result<PathBuf> {
+    use std::ffi::c_void;
+    use std::os::windows::ffi::OsStringExt;
+
+    #[link(name = "Shell32")]
+    extern "system" {
+        fn SHGetKnownFolderPath(
+            rfid: *const Guid,
+            dwFlags: u32,
+            hToken: *mut c_void,
+            ppszPath: *mut *mut u16,
+        ) -> i32;
+    }
+
+    #[link(name = "Ole32")]
+    extern "system" {
+        fn CoTaskMemFree(pv: *mut c_void);
+    }
+
+    let mut raw: *mut u16 = std::ptr::null_mut();
+    let hr = unsafe { SHGetKnownFolderPath(id, 0, std::ptr::null_mut(), &mut raw) };
+    let path = if hr >= 0 && !raw.is_null() {
+        let len = (0..).take_while(|&i| unsafe { *raw.add(i) } != 0).count();
+        let wide = unsafe { std::slice::from_raw_parts(raw, len) };
+        Ok(PathBuf::from(std::ffi::OsString::from_wide(wide)))
+    } else {
+        Err(std::io::Error::other(format!(
+            "cannot resolve {} (SHGetKnownFolderPath: 0x{:08X})",
+            name, hr as u32
+        )))
+    };
+    // Freed even on failure, as the API asks.
+    unsafe { CoTaskMemFree(raw.cast()) };
+    path
+}
+
+#[cfg(not(windows))]
+fn program_data() -> std::io::Result<Option<PathBuf>> {
+    Ok(None)
+}
+
+#[cfg(not(windows))]
+fn local_app_data() -> std::io::Result<Option<PathBuf>> {
+    Ok(None)
+}
+
+fn home_of(config_path: &Path) -> PathBuf {
+    config_path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+pub fn read_config_file(path: &Path, key_path: &Path) -> std::io::Result<Option<ConfigFile>> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let value = crate::integrity::verify_store_bytes(path, key_path, &bytes)?;
+    let cfg: ConfigFile = serde_json::from_value(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(Some(cfg))
+}
+
+pub fn save_config_file(path: &Path, key_path: &Path, cfg: &ConfigFile) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut cfg = cfg.clone();
+    cfg.version = 1;
+    let bytes = crate::integrity::seal_store(path, key_path, &cfg)?;
+    std::fs::write(path, bytes)
+}
+
+pub fn load_settings(opts: &GlobalOptions) -> std::io::Result<Settings> {
+    load_settings_inner(opts, true)
+}
+
+/// Same as `load_settings`, but trusts an unverified config (only for `fag integrity`, so a
+/// tampered config can still be inspected and resealed).
+pub fn load_settings_unverified(opts: &GlobalOptions) -> std::io::Result<Settings> {
+    load_settings_inner(opts, false)
+}
+
+/// Re-reads the config file `current` came from, so a watcher picks up `fag config set` without a
+/// restart. The config location and portable mode stay as they were.
+pub fn reload_settings(current: &Settings) -> std::io::Result<Settings> {
+    let env = |k: &str| std::env::var(k).ok();
+    let cfg = read_config_file(&current.config_path, &current.key_path)?;
+    Ok(resolve(
+        &current.config_path,
+        current.portable,
+        current.policy_path.clone(),
+        current.key_path.clone(),
+        &cfg.unwrap_or_default(),
+        &env,
+    ))
+}
+
+fn load_settings_inner(opts: &GlobalOptions, verify: bool) -> std::io::Result<Settings> {
+    let env = |k: &str| std::env::var(k).ok();
+    let (config_path, portable) = locate_config(opts);
+    let local = local_app_data()?;
+    let key = key_path(&home_of(&config_path), portable.0, local.as_deref());
+    let cfg = if verify {
+        read_config_file(&config_path, &key)?
+    } else {
+        read_config_file_unverified(&config_path)?
+    };
+    Ok(resolve(
+        &config_path,
+        portable,
+        policy_path()?,
+        key,
+        &cfg.unwrap_or_default(),
+        &env,
+    ))
+}
+
+fn read_config_file_unverified(path: &Path) -> std::io::Result<Option<ConfigFile>> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_slice(&bytes)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn resolve(
+    config_path: &Path,
+    portable: (bool, Source),
+    policy_path: Option<PathBuf>,
+    key_path: PathBuf,
+    cfg: &ConfigFile,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Settings {
+    let home = home_of(config_path);
+
+    let path_setting = |configured: &Option<PathBuf>, file: &str| match configured {
+        Some(p) if p.is_absolute() => (p.clone(), Source::Config),
+        Some(p) => (home.join(p), Source::Config),
+        None => (home.join(file), Source::Default),
+    };
+
+    // A hand-written rules.toml next to the config is picked up without configuring paths.rules.
+    let rules_path = match path_setting(&cfg.paths.rules, "rules.toml") {
+        (p, Source::Default) if !p.exists() => path_setting(&None, "rules.json"),
+        other => other,
+    };
+
+    let interval_secs = match env("FAG_WATCH_INTERVAL").and_then(|v| v.parse::<u64>().ok()) {
+        Some(n) if n > 0 => (n, Source::Env),
+        _ => match cfg.watch.interval_secs {
+            Some(n) if n > 0 => (n, Source::Config),
+            _ => (DEFAULT_INTERVAL_SECS, Source::Default),
+        },
+    };
+    let monitor_only = match env("FAG_MONITOR_ONLY").as_deref() {
+        Some("1") | Some("true") => (true, Source::Env),
+        Some("0") | Some("false") => (false, Source::Env),
+        _ => match cfg.watch.monitor_only {
+            Some(v) => (v, Source::Config),
+            None => (false, Source::Default),
+        },
+    };
+    let backoff_base_secs = match cfg.watch.backoff_base_secs {
+        Some(n) if n > 0 => (n, Source::Config),
+        _ => (DEFAULT_BACKOFF_BASE_SECS, Source::Default),
+    };
+    let backoff_max_secs = match cfg.watch.backoff_max_secs {
+        Some(n) if n > 0 => (n, Source::Config),
+        _ => (DEFAULT_BACKOFF_MAX_SECS, Source::Default),
+    };
+    let backoff_policy = match cfg.watch.backoff_policy {
+        Some(p) => (p, Source::Config),
+        None => (BackoffPolicy::default(), Source::Default),
+    };
+    let backoff_max_retries = match cfg.watch.backoff_max_retries {
+        Some(n) => (n, Source::Config),
+        None => (0, Source::Default),
+    };
+    let backoff_cooldown_secs = match cfg.watch.backoff_cooldown_secs {
+        Some(n) => (n, Source::Config),
+        None => (0, Source::Default),
+    };
+    let flap_cycles = match cfg.watch.flap_cycles {
+        Some(n) => (n, Source::Config),
+        None => (DEFAULT_FLAP_CYCLES, Source::Default),
+    };
+    let flap_window_secs = match cfg.watch.flap_window_secs {
+        Some(n) if n > 0 => (n, Source::Config),
+        _ => (DEFAULT_FLAP_WINDOW_SECS, Source::Default),
+    };
+    let flap_quiet_secs = match cfg.watch.flap_quiet_secs {
+        Some(n) if n > 0 => (n, Source::Config),
+        _ => (DEFAULT_FLAP_QUIET_SECS, Source::Default),
+    };
+    let grace_secs = match cfg.watch.grace_secs {
+        Some(n) => (n, Source::Config),
+        None => (0, Source::Default),
+    };
+    let pause_processes = match &cfg.watch.pause_processes {
+        Some(names) if !names.is_empty() => (names.clone(), Source::Config),
+        _ => (Vec::new(), Source::Default),
+    };
+
+    Settings {
+        config_path: config_path.to_path_buf(),
+        home: home.clone(),
+        portable,
+        rules_path,
+        captures_path: path_setting(&cfg.paths.captures, "captures.json"),
+        log_path: path_setting(&cfg.paths.log, "guard.log"),
+        key_path,
+        policy_path,
+        interval_secs,
+        monitor_only,
+        backoff_base_secs,
+        backoff_max_secs,
+        backoff_policy,
+        backoff_max_retries,
+        backoff_cooldown_secs,
+        flap_cycles,
+        flap_window_secs,
+        flap_quiet_secs,
+        grace_secs,
+        pause_processes,
+    }
+}
+
+/// Returns the effective value of a setting key as a display string.
+pub fn get_value(settings: &Settings, key: &str) -> Option<(String, Source)> {
+    let path = |p: &(PathBuf, Source)| (p.0.to_string_lossy().into_owned(), p.1);
+    Some(match key {
+        "portable" => (settings.portable.0.to_string(), settings.portable.1),
+        "paths.rules" => path(&settings.rules_path),
+        "paths.captures" => path(&settings.captures_path),
+        "paths.log" => path(&settings.log_path),
+        "watch.interval_secs" => (
+            settings.interval_secs.0.to_string(),
+            settings.interval_secs.1,
+        ),
+        "watch.monitor_only" => (settings.monitor_only.0.to_string(), settings.monitor_only.1),
+        "watch.backoff_base_secs" => (
+            settings.backoff_base_secs.0.to_string(),
+            settings.backoff_base_secs.1,
+        ),
+        "watch.backoff_max_secs" => (
+            settings.backoff_max_secs.0.to_string(),
+            settings.backoff_max_secs.1,
+        ),
+        "watch.backoff_policy" => (
+            settings.backoff_policy.0.as_str().to_string(),
+            settings.backoff_policy.1,
+        ),
+        "watch.backoff_max_retries" => (
+            settings.backoff_max_retries.0.to_string(),
+            settings.backoff_max_retries.1,
+        ),
+        "watch.backoff_cooldown_secs" => (
+            settings.backoff_cooldown_secs.0.to_string(),
+            settings.backoff_cooldown_secs.1,
+        ),
+        "watch.flap_cycles" => (settings.flap_cycles.0.to_string(), settings.flap_cycles.1),
+        "watch.flap_window_secs" => (
+            settings.flap_window_secs.0.to_string(),
+            settings.flap_window_secs.1,
+        ),
+        "watch.flap_quiet_secs" => (
+            settings.flap_quiet_secs.0.to_string(),
+            settings.flap_quiet_secs.1,
+        ),
+        "watch.grace_secs" => (settings.grace_secs.0.to_string(), settings.grace_secs.1),
+        "watch.pause_processes" => (
+            settings.pause_processes.0.join(","),
+            settings.pause_processes.1,
+        ),
+        _ => return None,
+    })
+}
+
+/// Applies `fag config set <key> <value>` to a config file model. An empty value unsets the key.
+pub fn set_value(cfg: &mut ConfigFile, key: &str, value: &str) -> Result<(), String> {
+    let value = value.trim();
+    let parse_u64 = |v: &str| -> Result<Option<u64>, String> {
+        if v.is_empty() {
+            return Ok(None);
+        }
+        match v.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(format!("{} must be a positive integer", key)),
+        }
+    };
+    let parse_bool = |v: &str| -> Result<Option<bool>, String> {
+        match v {
+            "" => Ok(None),
+            "true" | "1" => Ok(Some(true)),
+            "false" | "0" => Ok(Some(false)),
+            _ => Err(format!("{} must be true or false", key)),
+        }
+    };
+    // Counts and durations where 0 is meaningful ("no retries", "no cool-down").
+    let parse_count = |v: &str| -> Result<Option<u64>, String> {
+        if v.is_empty() {
+            return Ok(None);
+        }
+        v.parse::<u64>()
+            .map(Some)
+            .map_err(|_| format!("{} must be a non-negative integer", key))
+    };
+    let parse_path = |v: &str| (!v.is_empty()).then(|| PathBuf::from(v));
+
+    match key {
+        "portable" => cfg.portable = parse_bool(value)?.unwrap_or(false),
+        "paths.rules" => cfg.paths.rules = parse_path(value),
+        "paths.captures" => cfg.paths.captures = parse_path(value),
+        "paths.log" => cfg.paths.log = parse_path(value),
+        "watch.interval_secs" => cfg.watch.interval_secs = parse_u64(value)?,
+        "watch.monitor_only" => cfg.watch.monitor_only = parse_bool(value)?,
+        "watch.backoff_base_secs" => cfg.watch.backoff_base_secs = parse_u64(value)?,
+        "watch.backoff_max_secs" => cfg.watch.backoff_max_secs = parse_u64(value)?,
+        "watch.backoff_policy" => {
+            cfg.watch.backoff_policy = match value {
+                "" => None,
+                v => Some(BackoffPolicy::parse(v).ok_or_else(|| {
+                    format!("{} must be exponential, linear, fixed or never", key)
+                })?),
+            }
+        }
+        "watch.backoff_max_retries" => {
+            cfg.watch.backoff_max_retries = parse_count(value)?
+                .map(|n| u32::try_from(n).map_err(|_| format!("{} is too large", key)))
+                .transpose()?
+        }
+        "watch.backoff_cooldown_secs" => cfg.watch.backoff_cooldown_secs = parse_count(value)?,
+        "watch.flap_cycles" => {
+            cfg.watch.flap_cycles = parse_count(value)?
+                .map(|n| u32::try_from(n).map_err(|_| format!("{} is too large", key)))
+                .transpose()?
+        }
+        "watch.flap_window_secs" => cfg.watch.flap_window_secs = parse_u64(value)?,
+        "watch.flap_quiet_secs" => cfg.watch.flap_quiet_secs = parse_u64(value)?,
+        "watch.grace_secs" => cfg.watch.grace_secs = parse_count(value)?,
+        "watch.pause_processes" => {
+            cfg.watch.pause_processes = match value {
+                "" => None,
+                v => Some(
+                    v.split(',')
+                        .map(crate::processes::normalize_pattern)
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| format!("{}: {}", key, e))?,
+                ),
+            }
+        }
+        _ => {
+            return Err(format!(
+                "unknown key '{}'. known keys: {}",
+                key,
+                KEYS.join(", ")
+            ))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn resolve_defaults_relative_to_config_dir() {
+        let cfg = ConfigFile::default();
+        let s = resolve(
+            Path::new("/fag/home/config.json"),
+            (false, Source::Default),
+            None,
+            key_path(Path::new("/fag/home"), false, None),
+            &cfg,
+            &no_env,
+        );
+        assert_eq!(s.home, PathBuf::from("/fag/home"));
+        assert_eq!(s.rules_path(), Path::new("/fag/home/rules.json"));
+        assert_eq!(s.captures_path.1, Source::Default);
+        assert_eq!(s.interval_secs, (DEFAULT_INTERVAL_SECS, Source::Default));
+        assert_eq!(s.key_path, PathBuf::from("/fag/home/integrity.key"));
+        assert_eq!(s.policy_path, None);
+    }
+
+    #[test]
+    fn resolve_config_then_env_precedence() {
+        let mut cfg = ConfigFile::default();
+        set_value(&mut cfg, "paths.log", "logs/guard.log").unwrap();
+        set_value(&mut cfg, "watch.interval_secs", "10").unwrap();
+        set_value(&mut cfg, "watch.monitor_only", "true").unwrap();
+        assert!(set_value(&mut cfg, "watch.interval_secs", "0").is_err());
+        assert!(set_value(&mut cfg, "nope", "1").is_err());
+        set_value(&mut cfg, "watch.backoff_policy", "Linear").unwrap();
+        set_value(&mut cfg, "watch.backoff_max_retries", "0").unwrap();
+        assert!(set_value(&mut cfg, "watch.backoff_policy", "random").is_err());
+        set_value(&mut cfg, "watch.pause_processes", "MsiExec.exe, setup*.exe").unwrap();
+        assert!(set_value(&mut cfg, "watch.pause_processes", r"C:\x\setup.exe").is_err());
+
+        let env = |k: &str| match k {
+            "FAG_WATCH_INTERVAL" => Some("2".to_string()),
+            "LOCALAPPDATA" => Some("/elsewhere".to_string()),
+            _ => None,
+        };
+        let s = resolve(
+            Path::new("/h/config.json"),
+            (false, Source::Default),
+            Some(PathBuf::from("/pd/FileAssocGuard/policy.json")),
+            key_path(Path::new("/h"), false, Some(Path::new("/local"))),
+            &cfg,
+            &env,
+        );
+        assert_eq!(
+            s.log_path,
+            (PathBuf::from("/h/logs/guard.log"), Source::Config)
+        );
+        assert_eq!(s.interval_secs, (2, Source::Env));
+        assert_eq!(s.monitor_only, (true, Source::Config));
+        assert_eq!(s.backoff_policy, (BackoffPolicy::Linear, Source::Config));
+        assert_eq!(s.backoff_max_retries, (0, Source::Config));
+        assert_eq!(s.backoff_cooldown_secs, (0, Source::Default));
+        assert_eq!(
+            get_value(&s, "watch.pause_processes"),
+            Some(("msiexec.exe,setup*.exe".to_string(), Source::Config))
+        );
+        assert_eq!(
+            s.key_path,
+            PathBuf::from("/local/FileAssocGuard/integrity.key")
+        );
+        assert_eq!(
+            s.policy_path,
+            Some(PathBuf::from("/pd/FileAssocGuard/policy.json"))
+        );
+
+        let portable = resolve(
+            Path::new("/usb/config.json"),
+            (true, Source::Cli),
+            None,
+            key_path(Path::new("/usb"), true, Some(Path::new("/local"))),
+            &cfg,
+            &env,
+        );
+        assert_eq!(portable.key_path, PathBuf::from("/usb/integrity.key"));
+        assert_eq!(
+            get_value(&portable, "portable"),
+            Some(("true".to_string(), Source::Cli))
+        );
+    }
+
+    #[test]
+    fn portable_reports_where_it_was_turned_on() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let home = std::env::temp_dir().join(format!("fag-config-{}", nanos));
+        std::fs::create_dir_all(&home).unwrap();
+        let path = home.join("config.json");
+        let opts = |portable| GlobalOptions {
+            config: Some(path.clone()),
+            portable,
+        };
+
+        assert_eq!(locate_config(&opts(false)).1, (false, Source::Default));
+        assert_eq!(locate_config(&opts(true)).1, (true, Source::Cli));
+        std::fs::write(&path, r#"{"version":1,"portable":true}"#).unwrap();
+        assert_eq!(locate_config(&opts(false)).1, (true, Source::Config));
+        assert_eq!(locate_config(&opts(true)).1, (true, Source::Cli));
+        let _ = std::fs::remove_dir_all(&home);
+    }
+}
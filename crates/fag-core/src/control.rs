@@ -0,0 +1,448 @@
+//! Control channel for a running `watch-rules`: a Unix socket in the fag home, or a named pipe
+//! on Windows, taking one JSON request per connection.
+//!
+//! Requests are `{"cmd":"status"}`, `{"cmd":"pause","for_secs":600}` (`for_secs` optional),
+//! `{"cmd":"resume"}`, `{"cmd":"reload"}`, `{"cmd":"check-now"}` and `{"cmd":"stop"}`. Each gets a
+//! single JSON line back, `{"ok":true,...}` or `{"ok":false,"error":"..."}`. The watcher builds
+//! the replies; this module only carries them.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Settings;
+use crate::notify::{ChangeNotifier, Wait};
+
+/// How long a connection waits for the watcher to answer (it may be inside a slow registry call).
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest wait between looks at the request queue while the watcher is idle.
+const SLICE: Duration = Duration::from_millis(200);
+/// Requests are a few dozen bytes; anything longer is not ours.
+const MAX_REQUEST: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Command {
+    /// Per-rule state, backoff and pause state.
+    Status,
+    /// Stop checking (and restoring) until `resume`, or for `for_secs`.
+    Pause {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        for_secs: Option<u64>,
+    },
+    Resume,
+    /// Re-read rules, captures and config now instead of at the next tick.
+    Reload,
+    /// Check every rule now, whatever its interval.
+    CheckNow,
+    Stop,
+}
+
+/// A request waiting for the watcher's reply.
+pub struct Request {
+    pub command: Command,
+    reply: mpsc::Sender<String>,
+    written: mpsc::Receiver<()>,
+}
+
+impl Request {
+    /// Sends `response` (one JSON object, no newline) back to the client.
+    pub fn reply(self, response: &str) {
+        let _ = self.reply.send(response.to_string());
+    }
+
+    /// Like `reply`, but returns only once the reply was written (or after a second), for a
+    /// watcher that is about to exit.
+    pub fn reply_and_wait(self, response: &str) {
+        let _ = self.reply.send(response.to_string());
+        let _ = self.written.recv_timeout(Duration::from_secs(1));
+    }
+}
+
+/// What ended [`ControlServer::wait`].
+pub enum Woke {
+    Notifier(Wait),
+    Request(Request),
+}
+
+/// Where the watcher for `settings` listens: `control.sock` in the fag home, or on Windows a pipe
+/// named after the home, so portable copies do not answer for each other.
+pub fn endpoint(settings: &Settings) -> PathBuf {
+    #[cfg(windows)]
+    {
+        use sha2::{Digest, Sha256};
+        let digest = Sha256::digest(settings.home.to_string_lossy().to_lowercase().as_bytes());
+        PathBuf::from(format!(
+            r"\\.\pipe\fag-control-{}",
+            &crate::integrity::encode_hex(&digest)[..16]
+        ))
+    }
+
+    #[cfg(not(windows))]
+    {
+        settings.home.join("control.sock")
+    }
+}
+
+pub struct ControlServer {
+    endpoint: PathBuf,
+    rx: mpsc::Receiver<Request>,
+}
+
+impl ControlServer {
+    /// Starts listening on `endpoint`. Fails with `AddrInUse` when another watcher already does.
+    pub fn bind(endpoint: &Path) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        platform::listen(endpoint, tx)?;
+        Ok(Self {
+            endpoint: endpoint.to_path_buf(),
+            rx,
+        })
+    }
+
+    pub fn endpoint(&self) -> &Path {
+        &self.endpoint
+    }
+
+    /// The next queued request, without blocking.
+    pub fn try_next(&self) -> Option<Request> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Waits like `notifier.wait(timeout)`, but returns early with the first control request.
+    pub fn wait(&self, notifier: &mut dyn ChangeNotifier, timeout: Duration) -> Woke {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(request) = self.try_next() {
+                return Woke::Request(request);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Woke::Notifier(Wait::Timeout);
+            }
+            if notifier.wait(left.min(SLICE)) == Wait::Changed {
+                return Woke::Notifier(Wait::Changed);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.endpoint);
+    }
+}
+
+/// Sends `command` to the watcher listening on `endpoint` and returns its reply line.
+pub fn send(endpoint: &Path, command: &Command) -> io::Result<String> {
+    let mut stream = platform::connect(endpoint)?;
+    let mut line = serde_json::to_string(command)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    stream.flush()?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    let reply = reply.trim_end();
+    if reply.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the watcher closed the connection without replying",
+        ));
+    }
+    Ok(reply.to_string())
+}
+
+/// Reads one request from `stream`, hands it to the watch loop and writes back the reply.
+/// `flush` runs after the write, before the watch loop learns the reply went out.
+fn serve<S: Read + Write>(mut stream: S, tx: &mpsc::Sender<Request>, flush: impl FnOnce(&mut S)) {
+    let mut line = String::new();
+    if BufReader::new((&mut stream).take(MAX_REQUEST))
+        .read_line(&mut line)
+        .is_err()
+    {
+        return;
+    }
+    let (reply, written) = match serde_json::from_str::<Command>(line.trim()) {
+        Ok(command) => {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            let (written_tx, written_rx) = mpsc::channel();
+            let request = Request {
+                command,
+                reply: reply_tx,
+                written: written_rx,
+            };
+            let reply = match tx.send(request) {
+                Ok(()) => reply_rx.recv_timeout(REPLY_TIMEOUT).unwrap_or_else(|_| {
+                    error_reply("the watcher did not answer in time (busy or stopping)")
+                }),
+                Err(_) => error_reply("the watcher is stopping"),
+            };
+            (reply, Some(written_tx))
+        }
+        Err(err) => (error_reply(&format!("bad request: {}", err)), None),
+    };
+    let _ = stream.write_all(format!("{}\n", reply).as_bytes());
+    let _ = stream.flush();
+    flush(&mut stream);
+    if let Some(written) = written {
+        let _ = written.send(());
+    }
+}
+
+fn error_reply(message: &str) -> String {
+    serde_json::json!({ "ok": false, "error": message }).to_string()
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::{serve, Request};
+
+    pub fn listen(endpoint: &Path, tx: mpsc::Sender<Request>) -> io::Result<()> {
+        if endpoint.exists() {
+            if UnixStream::connect(endpoint).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!(
+                        "another watcher is listening on {}",
+                        endpoint.to_string_lossy()
+                    ),
+                ));
+            }
+            // Left behind by a watcher that did not shut down cleanly.
+            std::fs::remove_file(endpoint)?;
+        }
+        let listener = UnixListener::bind(endpoint)?;
+        std::fs::set_permissions(endpoint, std::fs::Permissions::from_mode(0o600))?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                    serve(stream, &tx, |_| {})
+                });
+            }
+        });
+        Ok(())
+    }
+
+    pub fn connect(endpoint: &Path) -> io::Result<UnixStream> {
+        let stream = UnixStream::connect(endpoint)?;
+        stream.set_read_timeout(Some(super::REPLY_TIMEOUT + Duration::from_secs(5)))?;
+        Ok(stream)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::{serve, Request};
+    use crate::registry::windows_last_error;
+
+    type HANDLE = isize;
+    type BOOL = i32;
+
+    const INVALID_HANDLE_VALUE: HANDLE = -1;
+    const PIPE_ACCESS_DUPLEX: u32 = 0x3;
+    const FILE_FLAG_FIRST_PIPE_INSTANCE: u32 = 0x0008_0000;
+    const PIPE_TYPE_BYTE: u32 = 0x0;
+    const PIPE_WAIT: u32 = 0x0;
+    const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x8;
+    const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+    const ERROR_ACCESS_DENIED: u32 = 5;
+    const ERROR_PIPE_BUSY: i32 = 231;
+    const ERROR_PIPE_CONNECTED: u32 = 535;
+
+    #[link(name = "Kernel32")]
+    extern "system" {
+        fn CreateNamedPipeW(
+            lpName: *const u16,
+            dwOpenMode: u32,
+            dwPipeMode: u32,
+            nMaxInstances: u32,
+            nOutBufferSize: u32,
+            nInBufferSize: u32,
+            nDefaultTimeOut: u32,
+            lpSecurityAttributes: *mut core::ffi::c_void,
+        ) -> HANDLE;
+        fn ConnectNamedPipe(hNamedPipe: HANDLE, lpOverlapped: *mut core::ffi::c_void) -> BOOL;
+        fn DisconnectNamedPipe(hNamedPipe: HANDLE) -> BOOL;
+        fn FlushFileBuffers(hFile: HANDLE) -> BOOL;
+        fn CloseHandle(hObject: HANDLE) -> BOOL;
+    }
+
+    /// One pipe instance. The first is created with `FILE_FLAG_FIRST_PIPE_INSTANCE` so a second
+    /// watcher for the same home fails instead of sharing the name. The default security
+    /// descriptor only lets the creating user (and administrators) write to the pipe.
+    fn create(name: &[u16], first: bool) -> io::Result<HANDLE> {
+        let mode = PIPE_ACCESS_DUPLEX
+            | if first {
+                FILE_FLAG_FIRST_PIPE_INSTANCE
+            } else {
+                0
+            };
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                mode,
+                PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                4096,
+                4096,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            let code = unsafe { windows_last_error() };
+            if first && code == ERROR_ACCESS_DENIED {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another watcher is listening on this pipe",
+                ));
+            }
+            return Err(io::Error::from_raw_os_error(code as i32));
+        }
+        Ok(handle)
+    }
+
+    pub fn listen(endpoint: &Path, tx: mpsc::Sender<Request>) -> io::Result<()> {
+        let name: Vec<u16> = OsStr::new(endpoint)
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+        let mut next = create(&name, true)?;
+        std::thread::spawn(move || loop {
+            let connected = unsafe { ConnectNamedPipe(next, std::ptr::null_mut()) } != 0
+                || unsafe { windows_last_error() } == ERROR_PIPE_CONNECTED;
+            if !connected {
+                unsafe { CloseHandle(next) };
+            } else {
+                let pipe = unsafe { File::from_raw_handle(next as _) };
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    let handle = pipe.as_raw_handle() as HANDLE;
+                    // Blocks until the client has read the reply, so a watcher exiting right after
+                    // `stop` does not take the reply with it.
+                    serve(&pipe, &tx, |_| unsafe {
+                        FlushFileBuffers(handle);
+                    });
+                    unsafe { DisconnectNamedPipe(handle) };
+                });
+            }
+            next = match create(&name, false) {
+                Ok(h) => h,
+                Err(_) => {
+                    std::thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            };
+        });
+        Ok(())
+    }
+
+    pub fn connect(endpoint: &Path) -> io::Result<File> {
+        let mut attempts = 0;
+        loop {
+            match std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(endpoint)
+            {
+                // Every instance is serving someone; a new one is created right after.
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) && attempts < 20 => {
+                    attempts += 1;
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::ChannelNotifier;
+
+    #[test]
+    fn commands_use_the_documented_json() {
+        let pause = Command::Pause { for_secs: Some(60) };
+        assert_eq!(
+            serde_json::to_string(&pause).unwrap(),
+            r#"{"cmd":"pause","for_secs":60}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"cmd":"pause"}"#).unwrap(),
+            Command::Pause { for_secs: None }
+        );
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"cmd":"check-now"}"#).unwrap(),
+            Command::CheckNow
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_request_wakes_the_watcher_and_gets_its_reply() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("fag-ctl-{}.sock", nanos));
+        let server = ControlServer::bind(&path).unwrap();
+        assert_eq!(
+            ControlServer::bind(&path).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+
+        let client = {
+            let path = path.clone();
+            std::thread::spawn(move || send(&path, &Command::Status).unwrap())
+        };
+        let (_tx, mut notifier) = ChannelNotifier::new();
+        let started = Instant::now();
+        let Woke::Request(request) = server.wait(&mut notifier, Duration::from_secs(60)) else {
+            panic!("expected a request");
+        };
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(request.command, Command::Status);
+        request.reply(r#"{"ok":true,"paused":false}"#);
+        assert_eq!(client.join().unwrap(), r#"{"ok":true,"paused":false}"#);
+
+        let bad = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let mut stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+                stream.write_all(b"{\"cmd\":\"explode\"}\n").unwrap();
+                let mut reply = String::new();
+                BufReader::new(stream).read_line(&mut reply).unwrap();
+                reply
+            })
+        };
+        assert!(bad.join().unwrap().contains("\"ok\":false"));
+
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -56,7 +56,7 @@ pub fn query_all_feature_configurations(
     #[cfg(not(windows))]
     {
         let _ = config_type;
-        return Err(FeatureError::WindowsOnly);
+        Err(FeatureError::WindowsOnly)
     }
 
     #[cfg(windows)]
@@ -110,7 +110,7 @@ pub fn set_feature_state(
     #[cfg(not(windows))]
     {
         let _ = (feature_id, config_type, enabled_state);
-        return Err(FeatureError::WindowsOnly);
+        Err(FeatureError::WindowsOnly)
     }
 
     #[cfg(windows)]
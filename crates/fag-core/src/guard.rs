@@ -0,0 +1,1199 @@
+//! Per-rule guard state machine shared by `fag watch` and `fag watch-rules`.
+//!
+//! Each check runs one step: OK when the effective ProgId is an accepted target, otherwise
+//! TAMPERED, then (unless monitor-only) an apply that ends APPLIED or REJECTED. A rejected rule
+//! stays monitor-only through its backoff, then is retried or (out of retries) left monitor-only
+//! until it is seen OK again or its cool-down ends; see [`Backoff`]. A rule restored too often in
+//! a short time is FLAPPING and stays monitor-only until the other program goes quiet; see
+//! [`FlapLimit`]. With a grace period, a tampered value is only restored once it has stayed the
+//! same that long. Registry, clock and capture store are traits so every transition can be driven
+//! in tests.
+
+use std::collections::BTreeMap;
+
+use crate::registry::AssocStamp;
+use crate::rules::BackoffPolicy;
+
+/// How long a cached effective ProgId is trusted even with an unchanged stamp: the association
+/// also depends on keys outside UserChoice (HKCR ProgIds, policies) that the stamp does not cover.
+pub const EFFECTIVE_MAX_AGE_MS: u128 = 5 * 60 * 1000;
+
+pub trait Registry {
+    fn effective_progid(&self, ext: &str) -> Result<Option<String>, String>;
+    fn apply(&self, ext: &str, prog_id: &str, hash: &str) -> Result<(), String>;
+
+    /// A cheap fingerprint of `ext`'s user choice. While it stays the same the engine reuses the
+    /// last `effective_progid` answer; `None` (unknown) means always query.
+    fn stamp(&self, _ext: &str) -> Option<AssocStamp> {
+        None
+    }
+}
+
+pub trait Clock {
+    fn now_ms(&self) -> u128;
+}
+
+/// Resolves capture labels (used by allow lists) to ProgIds.
+pub trait CaptureStore {
+    fn prog_id(&self, ext: &str, label: &str) -> Option<String>;
+}
+
+/// The live Windows registry (UserChoiceLatest replay).
+pub struct SystemRegistry;
+
+impl Registry for SystemRegistry {
+    fn effective_progid(&self, ext: &str) -> Result<Option<String>, String> {
+        crate::registry::effective_progid_for_ext(ext).map_err(|e| e.to_string())
+    }
+
+    fn apply(&self, ext: &str, prog_id: &str, hash: &str) -> Result<(), String> {
+        crate::registry::set_user_choice_latest_replay(ext, prog_id, hash)
+            .map_err(|e| e.to_string())
+    }
+
+    fn stamp(&self, ext: &str) -> Option<AssocStamp> {
+        crate::registry::assoc_stamp(ext).ok()
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u128 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default()
+    }
+}
+
+/// What happens after a rejected apply. The n-th rejection in a row waits `seconds(n)`; once that
+/// wait is over the rule is applied again while retries are left, and otherwise stays
+/// monitor-only until it is seen OK. With a cool-down, a rule whose last rejection is that long
+/// ago starts over in AUTO_RESTORE.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Backoff {
+    pub policy: BackoffPolicy,
+    pub base_secs: u64,
+    pub max_secs: u64,
+    /// Re-applies after the first rejection. Ignored by [`BackoffPolicy::Never`].
+    pub max_retries: u32,
+    pub cooldown_secs: Option<u64>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            policy: BackoffPolicy::Exponential,
+            base_secs: crate::config::DEFAULT_BACKOFF_BASE_SECS,
+            max_secs: crate::config::DEFAULT_BACKOFF_MAX_SECS,
+            max_retries: 0,
+            cooldown_secs: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// The wait after the `failures`-th rejection in a row. Exponential doubles at most 4 times.
+    pub fn seconds(self, failures: u32) -> u64 {
+        if failures == 0 {
+            return 0;
+        }
+        match self.policy {
+            BackoffPolicy::Exponential => {
+                let shift = failures.saturating_sub(1).min(4);
+                self.base_secs
+                    .saturating_mul(1u64 << shift)
+                    .min(self.max_secs)
+            }
+            BackoffPolicy::Linear => self
+                .base_secs
+                .saturating_mul(u64::from(failures))
+                .min(self.max_secs),
+            BackoffPolicy::Fixed => self.base_secs,
+            BackoffPolicy::Never => 0,
+        }
+    }
+
+    /// Automatic re-applies still allowed after `failures` rejections in a row.
+    pub fn retries_left(self, failures: u32) -> u32 {
+        match self.policy {
+            BackoffPolicy::Never => 0,
+            _ => self.max_retries.saturating_sub(failures.saturating_sub(1)),
+        }
+    }
+}
+
+/// When restoring stops because another program keeps taking the association back: after
+/// `cycles` restores within `window_secs`, the next tamper puts the rule in FLAPPING (monitor-only)
+/// until the effective ProgId has stayed the same for `quiet_secs`. `cycles == 0` turns it off.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FlapLimit {
+    pub cycles: u32,
+    pub window_secs: u64,
+    pub quiet_secs: u64,
+}
+
+impl Default for FlapLimit {
+    fn default() -> Self {
+        Self {
+            cycles: crate::config::DEFAULT_FLAP_CYCLES,
+            window_secs: crate::config::DEFAULT_FLAP_WINDOW_SECS,
+            quiet_secs: crate::config::DEFAULT_FLAP_QUIET_SECS,
+        }
+    }
+}
+
+/// What one rule should look like right now.
+#[derive(Debug, Clone)]
+pub struct Check<'a> {
+    /// Stable identity for state and event de-duplication.
+    pub key: &'a str,
+    pub ext: &'a str,
+    /// Label of the capture being restored.
+    pub target: &'a str,
+    pub prog_id: &'a str,
+    pub hash: &'a str,
+    /// Other labels or literal ProgIds that also count as OK.
+    pub allow: &'a [String],
+    pub monitor_only: bool,
+    pub backoff: Backoff,
+    pub flapping: FlapLimit,
+    /// How long a tampered value must stay unchanged before it is restored; 0 restores at once.
+    pub grace_secs: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    Ok,
+    /// Tampered and the apply could not be attempted (registry write error).
+    Tampered,
+    Applied,
+    Rejected,
+    /// Rejected earlier; waiting out the backoff without applying.
+    Backoff,
+    /// Tampered, but the rule (or an earlier rejection with no retries left) forbids applying.
+    MonitorOnly,
+    /// Another program keeps re-taking the association; monitor-only until it goes quiet.
+    Flapping,
+    /// Tampered, and waiting for the new value to settle before restoring.
+    Grace,
+}
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Tampered => "TAMPERED",
+            Self::Applied => "APPLIED",
+            Self::Rejected => "REJECTED",
+            Self::Backoff => "BACKOFF",
+            Self::MonitorOnly => "MONITOR_ONLY",
+            Self::Flapping => "FLAPPING",
+            Self::Grace => "GRACE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// `matched` is the target label or the allow entry the effective ProgId satisfies.
+    Ok {
+        matched: String,
+    },
+    Tampered {
+        monitor_only: bool,
+    },
+    Applied,
+    /// `retries_left` 0 means the rule stays monitor-only after the backoff.
+    Rejected {
+        backoff_secs: u64,
+        retries_left: u32,
+    },
+    /// The rule just started flapping after `cycles` restores in the window; the event's
+    /// `effective` is the competing ProgId. Reported once per episode.
+    Flapping {
+        cycles: u32,
+    },
+    /// The registry write itself failed. Not de-duplicated.
+    ApplyFailed(String),
+    /// Reading the effective ProgId failed; the step treated it as unset. Not de-duplicated.
+    QueryFailed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub effective: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub phase: Phase,
+    /// Events worth reporting: repeats of the last status with the same effective ProgId are
+    /// filtered out.
+    pub events: Vec<Event>,
+}
+
+/// Backoff bookkeeping for a rule whose apply was rejected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RejectState {
+    pub failures: u32,
+    pub rejected_at_ms: u128,
+    pub retry_at_ms: u128,
+}
+
+/// What a watcher carries over a restart: rejection backoff, and the last status reported per
+/// rule so an unchanged state is not reported again. See `crate::state` for the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SavedState {
+    pub rejected: BTreeMap<String, RejectState>,
+    /// `(status, effective)` by key, as in [`EventFilter`].
+    pub last_emitted: BTreeMap<String, (String, Option<String>)>,
+}
+
+/// Remembers the last `(status, effective)` per key so unchanged states are reported once.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    last: BTreeMap<String, (String, Option<String>)>,
+}
+
+impl EventFilter {
+    pub fn should_emit(&mut self, key: &str, status: &str, effective: &Option<String>) -> bool {
+        match self.last.get(key) {
+            Some((prev_status, prev_effective))
+                if prev_status == status && prev_effective == effective =>
+            {
+                false
+            }
+            _ => {
+                self.last
+                    .insert(key.to_string(), (status.to_string(), effective.clone()));
+                true
+            }
+        }
+    }
+
+    pub fn forget(&mut self, key: &str) {
+        self.last.remove(key);
+    }
+
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.last.retain(|k, _| keep(k));
+    }
+}
+
+pub struct Engine<R, C, S> {
+    pub registry: R,
+    pub clock: C,
+    pub store: S,
+    pub filter: EventFilter,
+    rejected: BTreeMap<String, RejectState>,
+    effective: BTreeMap<String, CachedEffective>,
+    /// Recent successful restores per key, for flapping detection.
+    restores: BTreeMap<String, Vec<u128>>,
+    flapping: BTreeMap<String, LastSeen>,
+    /// Tampered rules still in their grace period.
+    settling: BTreeMap<String, LastSeen>,
+}
+
+/// For a rule in FLAPPING or in its grace period: the ProgId last seen and when it last changed.
+struct LastSeen {
+    effective: Option<String>,
+    changed_ms: u128,
+}
+
+struct CachedEffective {
+    stamp: AssocStamp,
+    prog_id: Option<String>,
+    queried_ms: u128,
+}
+
+impl<R: Registry, C: Clock, S: CaptureStore> Engine<R, C, S> {
+    pub fn new(registry: R, clock: C, store: S) -> Self {
+        Self {
+            registry,
+            clock,
+            store,
+            filter: EventFilter::default(),
+            rejected: BTreeMap::new(),
+            effective: BTreeMap::new(),
+            restores: BTreeMap::new(),
+            flapping: BTreeMap::new(),
+            settling: BTreeMap::new(),
+        }
+    }
+
+    pub fn reject_state(&self, key: &str) -> Option<RejectState> {
+        self.rejected.get(key).copied()
+    }
+
+    /// Drops a rule's rejection and flapping history, e.g. because its target changed and earlier
+    /// rejections no longer apply.
+    pub fn forget(&mut self, key: &str) {
+        self.rejected.remove(key);
+        self.restores.remove(key);
+        self.flapping.remove(key);
+        self.settling.remove(key);
+    }
+
+    /// Rejections and reported statuses, for saving across a restart. Store-level entries
+    /// (`config|...`) are left out so a broken store is reported again after the restart.
+    pub fn saved_state(&self) -> SavedState {
+        SavedState {
+            rejected: self.rejected.clone(),
+            last_emitted: self
+                .filter
+                .last
+                .iter()
+                .filter(|(k, _)| !k.starts_with("config|"))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        }
+    }
+
+    /// Picks up where a previous watcher stopped. Backoff deadlines are wall-clock times, so a
+    /// rejection whose backoff ran out during the downtime is retried on the first check.
+    pub fn restore_state(&mut self, state: SavedState) {
+        self.rejected.extend(state.rejected);
+        self.filter.last.extend(state.last_emitted);
+    }
+
+    /// Keeps only the rejections whose rule key passes `keep`.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.rejected.retain(|k, _| keep(k));
+        self.restores.retain(|k, _| keep(k));
+        self.flapping.retain(|k, _| keep(k));
+        self.settling.retain(|k, _| keep(k));
+    }
+
+    /// Drops all rejections and flapping history (the rule set was replaced) and cached registry
+    /// answers.
+    pub fn reset(&mut self) {
+        self.rejected.clear();
+        self.effective.clear();
+        self.restores.clear();
+        self.flapping.clear();
+        self.settling.clear();
+    }
+
+    pub fn step(&mut self, check: &Check) -> Step {
+        let mut events = Vec::new();
+        let effective = self.effective_progid(check.ext, &mut events);
+
+        let matched = matched_target(check.target, effective.as_deref(), check.allow, |l| {
+            if l == check.target {
+                return Some(check.prog_id.to_string());
+            }
+            self.store.prog_id(check.ext, l)
+        });
+        if let Some(matched) = matched {
+            self.rejected.remove(check.key);
+            self.flapping.remove(check.key);
+            self.settling.remove(check.key);
+            self.push(
+                check.key,
+                "OK",
+                EventKind::Ok { matched },
+                &effective,
+                &mut events,
+            );
+            return Step {
+                phase: Phase::Ok,
+                events,
+            };
+        }
+
+        let now = self.clock.now_ms();
+        if let Some(flap) = self.flapping.get_mut(check.key) {
+            if flap.effective != effective {
+                flap.effective = effective.clone();
+                flap.changed_ms = now;
+            }
+            let quiet_ms = u128::from(check.flapping.quiet_secs) * 1000;
+            if now < flap.changed_ms.saturating_add(quiet_ms) {
+                self.push(
+                    check.key,
+                    "TAMPERED",
+                    EventKind::Tampered { monitor_only: true },
+                    &effective,
+                    &mut events,
+                );
+                return Step {
+                    phase: Phase::Flapping,
+                    events,
+                };
+            }
+            // The other program went quiet: enforce again, counting cycles from zero.
+            self.flapping.remove(check.key);
+            self.restores.remove(check.key);
+        }
+
+        let mut rejected = self.rejected.get(check.key).copied();
+        if let (Some(st), Some(cooldown)) = (rejected, check.backoff.cooldown_secs) {
+            if now
+                >= st
+                    .rejected_at_ms
+                    .saturating_add(u128::from(cooldown) * 1000)
+            {
+                // Quiet long enough: start over as if it had never been rejected.
+                self.rejected.remove(check.key);
+                rejected = None;
+            }
+        }
+        let retry = rejected
+            .is_some_and(|st| now >= st.retry_at_ms && check.backoff.retries_left(st.failures) > 0);
+        let mut monitor_only = check.monitor_only || (rejected.is_some() && !retry);
+        let flapping = (!monitor_only)
+            .then(|| self.recent_restores(check.key, check.flapping, now))
+            .filter(|n| check.flapping.cycles > 0 && *n >= check.flapping.cycles);
+        monitor_only |= flapping.is_some();
+        self.push(
+            check.key,
+            "TAMPERED",
+            EventKind::Tampered { monitor_only },
+            &effective,
+            &mut events,
+        );
+        if let Some(cycles) = flapping {
+            self.flapping.insert(
+                check.key.to_string(),
+                LastSeen {
+                    effective: effective.clone(),
+                    changed_ms: now,
+                },
+            );
+            events.push(Event {
+                kind: EventKind::Flapping { cycles },
+                effective,
+            });
+            return Step {
+                phase: Phase::Flapping,
+                events,
+            };
+        }
+        if let Some(st) = rejected.filter(|_| !retry) {
+            let phase = if now < st.retry_at_ms {
+                Phase::Backoff
+            } else {
+                Phase::MonitorOnly
+            };
+            return Step { phase, events };
+        }
+        if monitor_only {
+            self.settling.remove(check.key);
+            return Step {
+                phase: Phase::MonitorOnly,
+                events,
+            };
+        }
+        if check.grace_secs > 0 {
+            let seen = self
+                .settling
+                .entry(check.key.to_string())
+                .or_insert_with(|| LastSeen {
+                    effective: effective.clone(),
+                    changed_ms: now,
+                });
+            if seen.effective != effective {
+                seen.effective = effective.clone();
+                seen.changed_ms = now;
+            }
+            if now
+                < seen
+                    .changed_ms
+                    .saturating_add(u128::from(check.grace_secs) * 1000)
+            {
+                // An installer or the Settings app may still be mid-change.
+                return Step {
+                    phase: Phase::Grace,
+                    events,
+                };
+            }
+        }
+        self.settling.remove(check.key);
+
+        if let Err(err) = self.registry.apply(check.ext, check.prog_id, check.hash) {
+            events.push(Event {
+                kind: EventKind::ApplyFailed(err),
+                effective,
+            });
+            return Step {
+                phase: Phase::Tampered,
+                events,
+            };
+        }
+
+        let after = self.registry.effective_progid(check.ext).ok().flatten();
+        if after.as_deref() == Some(check.prog_id) {
+            self.rejected.remove(check.key);
+            self.restores
+                .entry(check.key.to_string())
+                .or_default()
+                .push(self.clock.now_ms());
+            self.push(
+                check.key,
+                "APPLIED",
+                EventKind::Applied,
+                &after,
+                &mut events,
+            );
+            return Step {
+                phase: Phase::Applied,
+                events,
+            };
+        }
+
+        let failures = rejected.map(|s| s.failures).unwrap_or(0) + 1;
+        let backoff_secs = check.backoff.seconds(failures);
+        let retries_left = check.backoff.retries_left(failures);
+        let now = self.clock.now_ms();
+        self.rejected.insert(
+            check.key.to_string(),
+            RejectState {
+                failures,
+                rejected_at_ms: now,
+                retry_at_ms: now.saturating_add(u128::from(backoff_secs) * 1000),
+            },
+        );
+        self.push(
+            check.key,
+            "REJECTED",
+            EventKind::Rejected {
+                backoff_secs,
+                retries_left,
+            },
+            &after,
+            &mut events,
+        );
+        Step {
+            phase: Phase::Rejected,
+            events,
+        }
+    }
+
+    /// Restores of `key` within the flapping window, forgetting older ones.
+    fn recent_restores(&mut self, key: &str, limit: FlapLimit, now: u128) -> u32 {
+        let Some(times) = self.restores.get_mut(key) else {
+            return 0;
+        };
+        let window_ms = u128::from(limit.window_secs) * 1000;
+        times.retain(|t| now.saturating_sub(*t) < window_ms);
+        u32::try_from(times.len()).unwrap_or(u32::MAX)
+    }
+
+    /// The effective ProgId, from cache while the user-choice stamp is unchanged. The stamp is
+    /// read before querying, so a write racing the query shows up as a new stamp next time.
+    fn effective_progid(&mut self, ext: &str, events: &mut Vec<Event>) -> Option<String> {
+        let now = self.clock.now_ms();
+        let stamp = self.registry.stamp(ext);
+        if let (Some(stamp), Some(cached)) = (stamp, self.effective.get(ext)) {
+            if cached.stamp == stamp && now.saturating_sub(cached.queried_ms) < EFFECTIVE_MAX_AGE_MS
+            {
+                return cached.prog_id.clone();
+            }
+        }
+        match self.registry.effective_progid(ext) {
+            Ok(prog_id) => {
+                match stamp {
+                    Some(stamp) => {
+                        self.effective.insert(
+                            ext.to_string(),
+                            CachedEffective {
+                                stamp,
+                                prog_id: prog_id.clone(),
+                                queried_ms: now,
+                            },
+                        );
+                    }
+                    None => {
+                        self.effective.remove(ext);
+                    }
+                }
+                prog_id
+            }
+            Err(err) => {
+                self.effective.remove(ext);
+                events.push(Event {
+                    kind: EventKind::QueryFailed(err),
+                    effective: None,
+                });
+                None
+            }
+        }
+    }
+
+    fn push(
+        &mut self,
+        key: &str,
+        status: &str,
+        kind: EventKind,
+        effective: &Option<String>,
+        events: &mut Vec<Event>,
+    ) {
+        if self.filter.should_emit(key, status, effective) {
+            events.push(Event {
+                kind,
+                effective: effective.clone(),
+            });
+        }
+    }
+}
+
+/// Which accepted entry `effective` satisfies: `target`, then each `allow` entry. A label is
+/// resolved to its captured ProgId via `progid_for_label`; otherwise the entry is compared as a
+/// ProgId.
+pub fn matched_target(
+    target: &str,
+    effective: Option<&str>,
+    allow: &[String],
+    progid_for_label: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    let effective = effective?;
+    std::iter::once(target)
+        .chain(allow.iter().map(String::as_str))
+        .find(
+            |entry| match progid_for_label(&entry.to_ascii_lowercase()) {
+                Some(prog_id) => prog_id == effective,
+                None => entry.eq_ignore_ascii_case(effective),
+            },
+        )
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    /// Holds the effective ProgId; `sticky` simulates Windows rolling a write back.
+    #[derive(Default)]
+    struct FakeRegistry {
+        current: RefCell<Option<String>>,
+        sticky: Cell<bool>,
+        fail_apply: Cell<bool>,
+        applies: Cell<u32>,
+        stamped: Cell<bool>,
+        writes: Cell<u64>,
+        queries: Cell<u32>,
+    }
+
+    impl Registry for FakeRegistry {
+        fn effective_progid(&self, _ext: &str) -> Result<Option<String>, String> {
+            self.queries.set(self.queries.get() + 1);
+            Ok(self.current.borrow().clone())
+        }
+
+        fn stamp(&self, _ext: &str) -> Option<AssocStamp> {
+            self.stamped.get().then(|| AssocStamp {
+                user_choice_latest: Some(self.writes.get()),
+                user_choice_latest_prog_id: None,
+                user_choice: None,
+            })
+        }
+
+        fn apply(&self, _ext: &str, prog_id: &str, _hash: &str) -> Result<(), String> {
+            if self.fail_apply.get() {
+                return Err("access denied".to_string());
+            }
+            self.applies.set(self.applies.get() + 1);
+            self.writes.set(self.writes.get() + 1);
+            if !self.sticky.get() {
+                *self.current.borrow_mut() = Some(prog_id.to_string());
+            }
+            Ok(())
+        }
+    }
+
+    struct FakeClock(Cell<u128>);
+
+    impl Clock for FakeClock {
+        fn now_ms(&self) -> u128 {
+            self.0.get()
+        }
+    }
+
+    struct FakeStore;
+
+    impl CaptureStore for FakeStore {
+        fn prog_id(&self, _ext: &str, label: &str) -> Option<String> {
+            (label == "potplayer").then(|| "PotPlayer.mp4".to_string())
+        }
+    }
+
+    fn guard() -> Engine<FakeRegistry, FakeClock, FakeStore> {
+        let g = Engine::new(
+            FakeRegistry::default(),
+            FakeClock(Cell::new(1_000)),
+            FakeStore,
+        );
+        *g.registry.current.borrow_mut() = Some("VLC.mp4".to_string());
+        g
+    }
+
+    fn set_effective(g: &Engine<FakeRegistry, FakeClock, FakeStore>, prog_id: &str) {
+        *g.registry.current.borrow_mut() = Some(prog_id.to_string());
+        g.registry.writes.set(g.registry.writes.get() + 1);
+    }
+
+    const ALLOW: &[String] = &[];
+
+    fn check(monitor_only: bool) -> Check<'static> {
+        Check {
+            key: ".mp4|vlc",
+            ext: ".mp4",
+            target: "vlc",
+            prog_id: "VLC.mp4",
+            hash: "h",
+            allow: ALLOW,
+            monitor_only,
+            backoff: Backoff {
+                base_secs: 30,
+                max_secs: 300,
+                ..Backoff::default()
+            },
+            flapping: FlapLimit::default(),
+            grace_secs: 0,
+        }
+    }
+
+    fn kinds(step: &Step) -> Vec<EventKind> {
+        step.events.iter().map(|e| e.kind.clone()).collect()
+    }
+
+    #[test]
+    fn ok_is_reported_once() {
+        let mut g = guard();
+        let step = g.step(&check(false));
+        assert_eq!(step.phase, Phase::Ok);
+        assert_eq!(
+            kinds(&step),
+            vec![EventKind::Ok {
+                matched: "vlc".to_string()
+            }]
+        );
+        let step = g.step(&check(false));
+        assert_eq!(step.phase, Phase::Ok);
+        assert!(step.events.is_empty());
+    }
+
+    #[test]
+    fn tampered_then_applied() {
+        let mut g = guard();
+        g.step(&check(false));
+        set_effective(&g, "Hijack.mp4");
+        let step = g.step(&check(false));
+        assert_eq!(step.phase, Phase::Applied);
+        assert_eq!(
+            kinds(&step),
+            vec![
+                EventKind::Tampered {
+                    monitor_only: false
+                },
+                EventKind::Applied
+            ]
+        );
+        assert_eq!(step.events[0].effective.as_deref(), Some("Hijack.mp4"));
+        assert_eq!(step.events[1].effective.as_deref(), Some("VLC.mp4"));
+        assert_eq!(g.registry.applies.get(), 1);
+        // Back to OK on the next check.
+        assert_eq!(g.step(&check(false)).phase, Phase::Ok);
+    }
+
+    #[test]
+    fn rejected_then_backoff_then_monitor_only_until_ok() {
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        g.registry.sticky.set(true);
+
+        let step = g.step(&check(false));
+        assert_eq!(step.phase, Phase::Rejected);
+        assert_eq!(
+            kinds(&step)[1],
+            EventKind::Rejected {
+                backoff_secs: 30,
+                retries_left: 0
+            }
+        );
+        assert_eq!(
+            g.reject_state(".mp4|vlc"),
+            Some(RejectState {
+                failures: 1,
+                rejected_at_ms: 1_000,
+                retry_at_ms: 31_000
+            })
+        );
+
+        // No further writes while rejected: reported once as monitor-only, then quiet.
+        let step = g.step(&check(false));
+        assert_eq!(step.phase, Phase::Backoff);
+        assert_eq!(
+            kinds(&step),
+            vec![EventKind::Tampered { monitor_only: true }]
+        );
+        assert!(g.step(&check(false)).events.is_empty());
+        g.clock.0.set(31_000);
+        assert_eq!(g.step(&check(false)).phase, Phase::MonitorOnly);
+        assert_eq!(g.registry.applies.get(), 1);
+
+        // The user fixes it by hand: OK clears the rejection and auto-restore resumes.
+        set_effective(&g, "VLC.mp4");
+        assert_eq!(g.step(&check(false)).phase, Phase::Ok);
+        assert_eq!(g.reject_state(".mp4|vlc"), None);
+        g.registry.sticky.set(false);
+        set_effective(&g, "Hijack.mp4");
+        assert_eq!(g.step(&check(false)).phase, Phase::Applied);
+    }
+
+    fn with_backoff(backoff: Backoff) -> Check<'static> {
+        Check {
+            backoff,
+            ..check(false)
+        }
+    }
+
+    #[test]
+    fn policies_set_the_wait() {
+        let b = |policy| Backoff {
+            policy,
+            base_secs: 30,
+            max_secs: 100,
+            max_retries: 3,
+            cooldown_secs: None,
+        };
+        let waits = |b: Backoff| (1..=4).map(|n| b.seconds(n)).collect::<Vec<_>>();
+        assert_eq!(waits(b(BackoffPolicy::Exponential)), vec![30, 60, 100, 100]);
+        assert_eq!(waits(b(BackoffPolicy::Linear)), vec![30, 60, 90, 100]);
+        assert_eq!(waits(b(BackoffPolicy::Fixed)), vec![30, 30, 30, 30]);
+        assert_eq!(waits(b(BackoffPolicy::Never)), vec![0, 0, 0, 0]);
+        assert_eq!(b(BackoffPolicy::Linear).retries_left(1), 3);
+        assert_eq!(b(BackoffPolicy::Linear).retries_left(4), 0);
+        assert_eq!(b(BackoffPolicy::Never).retries_left(1), 0);
+    }
+
+    #[test]
+    fn retries_after_each_backoff_until_out_of_retries() {
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        g.registry.sticky.set(true);
+        let c = with_backoff(Backoff {
+            policy: BackoffPolicy::Linear,
+            base_secs: 10,
+            max_secs: 600,
+            max_retries: 2,
+            cooldown_secs: None,
+        });
+
+        let rejected = |step: &Step| {
+            step.events.iter().find_map(|e| match e.kind {
+                EventKind::Rejected {
+                    backoff_secs,
+                    retries_left,
+                } => Some((backoff_secs, retries_left)),
+                _ => None,
+            })
+        };
+        assert_eq!(rejected(&g.step(&c)), Some((10, 2)));
+        g.clock.0.set(10_999);
+        assert_eq!(g.step(&c).phase, Phase::Backoff);
+        g.clock.0.set(11_000);
+        let step = g.step(&c);
+        assert_eq!(step.phase, Phase::Rejected);
+        assert_eq!(rejected(&step), Some((20, 1)));
+        g.clock.0.set(31_000);
+        assert_eq!(rejected(&g.step(&c)), Some((30, 0)));
+        assert_eq!(g.registry.applies.get(), 3);
+
+        // Out of retries: monitor-only after the last backoff, no more writes.
+        g.clock.0.set(61_000);
+        assert_eq!(g.step(&c).phase, Phase::MonitorOnly);
+        g.clock.0.set(600_000);
+        assert_eq!(g.step(&c).phase, Phase::MonitorOnly);
+        assert_eq!(g.registry.applies.get(), 3);
+    }
+
+    #[test]
+    fn a_successful_retry_clears_the_rejection() {
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        g.registry.sticky.set(true);
+        let c = with_backoff(Backoff {
+            policy: BackoffPolicy::Fixed,
+            base_secs: 10,
+            max_secs: 600,
+            max_retries: 1,
+            cooldown_secs: None,
+        });
+        assert_eq!(g.step(&c).phase, Phase::Rejected);
+        g.registry.sticky.set(false);
+        g.clock.0.set(11_000);
+        assert_eq!(g.step(&c).phase, Phase::Applied);
+        assert_eq!(g.reject_state(".mp4|vlc"), None);
+    }
+
+    #[test]
+    fn cooldown_returns_to_auto_restore() {
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        g.registry.sticky.set(true);
+        let c = with_backoff(Backoff {
+            policy: BackoffPolicy::Never,
+            base_secs: 30,
+            max_secs: 600,
+            max_retries: 5,
+            cooldown_secs: Some(120),
+        });
+        let step = g.step(&c);
+        assert_eq!(
+            kinds(&step)[1],
+            EventKind::Rejected {
+                backoff_secs: 0,
+                retries_left: 0
+            }
+        );
+        g.clock.0.set(120_999);
+        assert_eq!(g.step(&c).phase, Phase::MonitorOnly);
+        assert_eq!(g.registry.applies.get(), 1);
+
+        // A fresh start: the apply is attempted again, and a new rejection counts from one.
+        g.clock.0.set(121_000);
+        assert_eq!(g.step(&c).phase, Phase::Rejected);
+        assert_eq!(g.reject_state(".mp4|vlc").map(|s| s.failures), Some(1));
+        g.registry.sticky.set(false);
+        g.clock.0.set(241_000);
+        assert_eq!(g.step(&c).phase, Phase::Applied);
+    }
+
+    #[test]
+    fn flapping_stops_restoring_until_quiet() {
+        let mut g = guard();
+        let c = Check {
+            flapping: FlapLimit {
+                cycles: 3,
+                window_secs: 60,
+                quiet_secs: 30,
+            },
+            ..check(false)
+        };
+        let tick = |g: &Engine<FakeRegistry, FakeClock, FakeStore>, secs: u128| {
+            g.clock.0.set(g.clock.0.get() + secs * 1000)
+        };
+        for _ in 0..3 {
+            set_effective(&g, "Hijack.mp4");
+            assert_eq!(g.step(&c).phase, Phase::Applied);
+            tick(&g, 5);
+        }
+
+        set_effective(&g, "Hijack.mp4");
+        let step = g.step(&c);
+        assert_eq!(step.phase, Phase::Flapping);
+        assert_eq!(
+            kinds(&step),
+            vec![
+                EventKind::Tampered { monitor_only: true },
+                EventKind::Flapping { cycles: 3 }
+            ]
+        );
+        assert_eq!(step.events[1].effective.as_deref(), Some("Hijack.mp4"));
+        assert!(g.step(&c).events.is_empty());
+        assert_eq!(g.registry.applies.get(), 3);
+
+        // Still fighting: a new value restarts the quiet period.
+        tick(&g, 20);
+        set_effective(&g, "Other.mp4");
+        assert_eq!(g.step(&c).phase, Phase::Flapping);
+        tick(&g, 29);
+        assert_eq!(g.step(&c).phase, Phase::Flapping);
+        tick(&g, 1);
+        assert_eq!(g.step(&c).phase, Phase::Applied);
+        assert_eq!(g.registry.applies.get(), 4);
+    }
+
+    #[test]
+    fn restores_outside_the_window_are_not_flapping() {
+        let mut g = guard();
+        let c = Check {
+            flapping: FlapLimit {
+                cycles: 2,
+                window_secs: 60,
+                quiet_secs: 30,
+            },
+            ..check(false)
+        };
+        for _ in 0..5 {
+            set_effective(&g, "Hijack.mp4");
+            assert_eq!(g.step(&c).phase, Phase::Applied);
+            g.clock.0.set(g.clock.0.get() + 31_000);
+        }
+        let off = Check {
+            flapping: FlapLimit {
+                cycles: 0,
+                ..c.flapping
+            },
+            ..c.clone()
+        };
+        for _ in 0..5 {
+            set_effective(&g, "Hijack.mp4");
+            assert_eq!(g.step(&off).phase, Phase::Applied);
+        }
+    }
+
+    #[test]
+    fn grace_period_waits_for_a_stable_value() {
+        let mut g = guard();
+        let c = Check {
+            grace_secs: 10,
+            ..check(false)
+        };
+        set_effective(&g, "Step1.mp4");
+        let step = g.step(&c);
+        assert_eq!(step.phase, Phase::Grace);
+        assert_eq!(
+            kinds(&step),
+            vec![EventKind::Tampered {
+                monitor_only: false
+            }]
+        );
+        // The installer writes again: the wait starts over.
+        g.clock.0.set(8_000);
+        set_effective(&g, "Step2.mp4");
+        assert_eq!(g.step(&c).phase, Phase::Grace);
+        g.clock.0.set(17_999);
+        assert_eq!(g.step(&c).phase, Phase::Grace);
+        assert_eq!(g.registry.applies.get(), 0);
+        g.clock.0.set(18_000);
+        assert_eq!(g.step(&c).phase, Phase::Applied);
+
+        // Put back by hand within the grace period: nothing to restore, and the next tamper
+        // gets a full grace period of its own.
+        set_effective(&g, "Other.mp4");
+        assert_eq!(g.step(&c).phase, Phase::Grace);
+        set_effective(&g, "VLC.mp4");
+        assert_eq!(g.step(&c).phase, Phase::Ok);
+        g.clock.0.set(40_000);
+        set_effective(&g, "Other.mp4");
+        assert_eq!(g.step(&c).phase, Phase::Grace);
+        assert_eq!(g.registry.applies.get(), 1);
+    }
+
+    #[test]
+    fn monitor_only_never_writes() {
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        let step = g.step(&check(true));
+        assert_eq!(step.phase, Phase::MonitorOnly);
+        assert_eq!(
+            kinds(&step),
+            vec![EventKind::Tampered { monitor_only: true }]
+        );
+        // A different hijacker is a new event.
+        set_effective(&g, "Other.mp4");
+        assert_eq!(g.step(&check(true)).events.len(), 1);
+        assert_eq!(g.registry.applies.get(), 0);
+    }
+
+    #[test]
+    fn apply_errors_leave_rule_tampered_and_retry() {
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        g.registry.fail_apply.set(true);
+        let step = g.step(&check(false));
+        assert_eq!(step.phase, Phase::Tampered);
+        assert_eq!(
+            kinds(&step)[1],
+            EventKind::ApplyFailed("access denied".to_string())
+        );
+        g.registry.fail_apply.set(false);
+        assert_eq!(g.step(&check(false)).phase, Phase::Applied);
+    }
+
+    #[test]
+    fn forget_clears_a_rejection() {
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        g.registry.sticky.set(true);
+        g.step(&check(false));
+        g.forget(".mp4|vlc");
+        g.registry.sticky.set(false);
+        assert_eq!(g.step(&check(false)).phase, Phase::Applied);
+    }
+
+    #[test]
+    fn restored_backoff_survives_a_restart() {
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        g.registry.sticky.set(true);
+        g.step(&check(false));
+        g.step(&check(false));
+        let saved = g.saved_state();
+
+        // A new watcher: still backing off, and the monitor-only report is not repeated.
+        let mut g = guard();
+        set_effective(&g, "Hijack.mp4");
+        g.restore_state(saved);
+        let step = g.step(&check(false));
+        assert_eq!(step.phase, Phase::Backoff);
+        assert!(step.events.is_empty());
+        assert_eq!(g.registry.applies.get(), 0);
+    }
+
+    #[test]
+    fn allow_list_accepts_labels_and_progids() {
+        let mut g = guard();
+        let allow = vec!["potplayer".to_string(), "MPC-HC.mp4".to_string()];
+        let c = Check {
+            allow: &allow,
+            ..check(false)
+        };
+        set_effective(&g, "PotPlayer.mp4");
+        assert_eq!(
+            kinds(&g.step(&c)),
+            vec![EventKind::Ok {
+                matched: "potplayer".to_string()
+            }]
+        );
+        set_effective(&g, "mpc-hc.MP4");
+        assert_eq!(g.step(&c).phase, Phase::Ok);
+        assert_eq!(g.registry.applies.get(), 0);
+        assert_eq!(
+            Backoff {
+                base_secs: 30,
+                max_secs: 300,
+                ..Backoff::default()
+            }
+            .seconds(5),
+            300
+        );
+    }
+
+    #[test]
+    fn unchanged_stamp_skips_the_registry_query() {
+        let mut g = guard();
+        g.registry.stamped.set(true);
+        for _ in 0..3 {
+            assert_eq!(g.step(&check(false)).phase, Phase::Ok);
+        }
+        assert_eq!(g.registry.queries.get(), 1);
+
+        set_effective(&g, "Hijack.mp4");
+        let step = g.step(&check(false));
+        assert_eq!(
+            kinds(&step),
+            vec![
+                EventKind::Tampered {
+                    monitor_only: false
+                },
+                EventKind::Applied
+            ]
+        );
+        // Our own write changed the stamp: one query to see it, then cached again.
+        let queries = g.registry.queries.get();
+        g.step(&check(false));
+        g.step(&check(false));
+        assert_eq!(g.registry.queries.get(), queries + 1);
+
+        g.clock.0.set(g.clock.0.get() + EFFECTIVE_MAX_AGE_MS);
+        g.step(&check(false));
+        assert_eq!(g.registry.queries.get(), queries + 2);
+    }
+}
@@ -0,0 +1,280 @@
+//! One watcher per fag directory. `watch` and `watch-rules` take an OS file lock on watch.lock
+//! (`flock` / `LockFileEx`) and write their PID, command and start time into it; a second watcher
+//! is refused with those details. The OS drops the lock when the holder exits, however it exits,
+//! so a lock left by a crashed watcher is simply taken over. A clean exit empties the file, which
+//! is how a takeover is told apart from a normal restart.
+//!
+//! [`lock_exclusive`] takes the same lock but waits for it, for the short read-modify-write of a
+//! store that more than one process updates (pending.json).
+
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Who holds (or last held) the lock.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Holder {
+    pub pid: u32,
+    pub command: String,
+    pub started_unix_ms: u64,
+}
+
+impl Holder {
+    fn describe(&self) -> String {
+        format!(
+            "pid {}, fag {}, started_unix_ms {}",
+            self.pid, self.command, self.started_unix_ms
+        )
+    }
+}
+
+/// Held until dropped.
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+    /// Set when the previous holder did not exit cleanly.
+    pub took_over: Option<Holder>,
+}
+
+impl InstanceLock {
+    /// Takes the lock for `command` (`"watch"` / `"watch-rules"`). Fails with `AlreadyExists`,
+    /// naming the running watcher, when another process holds it.
+    pub fn acquire(path: &Path, command: &str) -> io::Result<InstanceLock> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if !platform::try_lock(&file)? {
+            let holder = read_holder(&mut file)
+                .map(|h| format!(" ({})", h.describe()))
+                .unwrap_or_default();
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "another watcher is already running{}; stop it first (fag ctl stop) or watch it with fag ctl status",
+                    holder
+                ),
+            ));
+        }
+        let took_over = read_holder(&mut file);
+        let me = Holder {
+            pid: std::process::id(),
+            command: command.to_string(),
+            started_unix_ms: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+                .unwrap_or_default(),
+        };
+        let json = serde_json::to_string(&me).map_err(io::Error::other)?;
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(json.as_bytes())?;
+        file.flush()?;
+        Ok(InstanceLock {
+            file,
+            path: path.to_path_buf(),
+            took_over,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // The file stays: deleting it would let a third process lock a new file while a second
+        // one still waits on this one. The lock itself goes with the handle.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Waits until this process holds the OS lock on `path` (created if needed). It is released when
+/// the returned file is dropped, or when the process dies.
+pub fn lock_exclusive(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    platform::lock(&file)?;
+    Ok(file)
+}
+
+fn read_holder(file: &mut File) -> Option<Holder> {
+    let mut text = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut text).ok()?;
+    serde_json::from_str(text.trim()).ok()
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    const LOCK_EX: i32 = 2;
+    const LOCK_NB: i32 = 4;
+
+    extern "C" {
+        fn flock(fd: i32, operation: i32) -> i32;
+    }
+
+    pub fn lock(file: &File) -> io::Result<()> {
+        loop {
+            if unsafe { flock(file.as_raw_fd(), LOCK_EX) } == 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// `Ok(false)` when another open file description holds the lock.
+    pub fn try_lock(file: &File) -> io::Result<bool> {
+        if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            Ok(false)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+
+    use crate::registry::windows_last_error;
+
+    type BOOL = i32;
+
+    const LOCKFILE_FAIL_IMMEDIATELY: u32 = 0x1;
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 0x2;
+    const ERROR_LOCK_VIOLATION: u32 = 33;
+
+    #[repr(C)]
+    struct OVERLAPPED {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        h_event: isize,
+    }
+
+    #[link(name = "Kernel32")]
+    extern "system" {
+        fn LockFileEx(
+            hFile: isize,
+            dwFlags: u32,
+            dwReserved: u32,
+            nNumberOfBytesToLockLow: u32,
+            nNumberOfBytesToLockHigh: u32,
+            lpOverlapped: *mut OVERLAPPED,
+        ) -> BOOL;
+    }
+
+    /// Locks one byte far past the end of the file, so the holder details stay readable: a
+    /// Windows byte-range lock also blocks reads of the locked range.
+    fn lock_far_byte(file: &File, flags: u32) -> BOOL {
+        let mut overlapped = OVERLAPPED {
+            internal: 0,
+            internal_high: 0,
+            offset: u32::MAX,
+            offset_high: 0,
+            h_event: 0,
+        };
+        unsafe {
+            LockFileEx(
+                file.as_raw_handle() as isize,
+                flags,
+                0,
+                1,
+                0,
+                &mut overlapped,
+            )
+        }
+    }
+
+    pub fn try_lock(file: &File) -> io::Result<bool> {
+        if lock_far_byte(file, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY) != 0 {
+            return Ok(true);
+        }
+        match unsafe { windows_last_error() } {
+            ERROR_LOCK_VIOLATION => Ok(false),
+            code => Err(io::Error::from_raw_os_error(code as i32)),
+        }
+    }
+
+    /// Waits for the lock; the file is opened for synchronous I/O, so this blocks.
+    pub fn lock(file: &File) -> io::Result<()> {
+        if lock_far_byte(file, LOCKFILE_EXCLUSIVE_LOCK) != 0 {
+            return Ok(());
+        }
+        Err(io::Error::from_raw_os_error(
+            unsafe { windows_last_error() } as i32,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_holder_is_refused_and_a_crash_is_taken_over() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("fag-lock-{}", nanos));
+
+        let first = InstanceLock::acquire(&path, "watch-rules").unwrap();
+        assert_eq!(first.took_over, None);
+        // flock locks belong to the open file, so a second open in this process conflicts too.
+        let err = InstanceLock::acquire(&path, "watch").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(err
+            .to_string()
+            .contains(&format!("pid {}", std::process::id())));
+
+        // A clean exit leaves nothing to take over.
+        drop(first);
+        let second = InstanceLock::acquire(&path, "watch").unwrap();
+        assert_eq!(second.took_over, None);
+
+        // A crash leaves the holder in the file, but the OS has released its lock.
+        drop(second);
+        let crashed = Holder {
+            pid: 4242,
+            command: "watch".to_string(),
+            started_unix_ms: 1_792_000_000_000,
+        };
+        std::fs::write(&path, serde_json::to_string(&crashed).unwrap()).unwrap();
+        let third = InstanceLock::acquire(&path, "watch-rules").unwrap();
+        assert_eq!(third.took_over, Some(crashed));
+        drop(third);
+        let _ = std::fs::remove_file(&path);
+    }
+}
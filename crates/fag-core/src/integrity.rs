@@ -0,0 +1,322 @@
+use std::path::{Path, PathBuf};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAC_FIELD: &str = "mac";
+const KEY_LEN: usize = 32;
+
+/// Raised (wrapped in an `io::Error` of kind `InvalidData`) when a store's MAC does not verify.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreTampered {
+    pub path: PathBuf,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for StoreTampered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "store integrity check failed for {} ({}); if you edited it yourself, run: fag integrity reseal",
+            self.path.to_string_lossy(),
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for StoreTampered {}
+
+pub fn as_tampered(err: &std::io::Error) -> Option<&StoreTampered> {
+    err.get_ref()
+        .and_then(|e| e.downcast_ref::<StoreTampered>())
+}
+
+pub fn read_key(path: &Path) -> std::io::Result<Option<Vec<u8>>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key = decode_hex(text.trim()).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "integrity key is not valid hex",
+        )
+    })?;
+    if key.len() != KEY_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "integrity key has wrong length",
+        ));
+    }
+    Ok(Some(key))
+}
+
+pub fn load_or_create_key(path: &Path) -> std::io::Result<Vec<u8>> {
+    if let Some(key) = read_key(path)? {
+        return Ok(key);
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut key = vec![0u8; KEY_LEN];
+    getrandom::getrandom(&mut key).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    use std::io::Write;
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(mut f) => {
+            f.write_all(encode_hex(&key).as_bytes())?;
+            Ok(key)
+        }
+        // Another process won the race; use its key.
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            read_key(path)?.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "integrity key vanished")
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses store bytes and checks the embedded MAC.
+///
+/// Unsigned stores are never accepted, with or without a key: pre-MAC files are signed once by
+/// `fag integrity reseal`, never on the fly (deleting the key must not get an edit re-signed).
+pub fn verify_store_bytes(
+    path: &Path,
+    key_path: &Path,
+    bytes: &[u8],
+) -> std::io::Result<serde_json::Value> {
+    let value = parse_store(path, bytes)?;
+    let key = read_key(key_path)?;
+    verify_value(path, &value, key.as_deref())?;
+    Ok(value)
+}
+
+/// Store contents as JSON. TOML stores (see `toml_store`) are converted, so the MAC covers their
+/// data but not comments or layout.
+pub fn parse_store(path: &Path, bytes: &[u8]) -> std::io::Result<serde_json::Value> {
+    if crate::toml_store::is_toml(path) {
+        return Ok(crate::toml_store::to_json(&crate::toml_store::parse(
+            bytes,
+        )?));
+    }
+    serde_json::from_slice(bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+pub fn verify_value(
+    path: &Path,
+    value: &serde_json::Value,
+    key: Option<&[u8]>,
+) -> std::io::Result<()> {
+    let tampered = |reason| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            StoreTampered {
+                path: path.to_path_buf(),
+                reason,
+            },
+        )
+    };
+
+    let mac = value.get(MAC_FIELD).and_then(|v| v.as_str());
+    match (key, mac) {
+        (None, None) => Err(tampered("store is not signed")),
+        (None, Some(_)) => Err(tampered("signed store but integrity key is missing")),
+        (Some(_), None) => Err(tampered("MAC missing")),
+        (Some(key), Some(mac)) => {
+            let Some(expected) = decode_hex(mac) else {
+                return Err(tampered("MAC malformed"));
+            };
+            let mut m = new_mac(key);
+            m.update(&canonical_payload(value));
+            m.verify_slice(&expected)
+                .map_err(|_| tampered("MAC mismatch"))
+        }
+    }
+}
+
+/// Serializes a store with a fresh MAC, creating the key at `key_path` on first use.
+pub fn seal_store<T: serde::Serialize>(
+    path: &Path,
+    key_path: &Path,
+    store: &T,
+) -> std::io::Result<Vec<u8>> {
+    let key = sealing_key(path, key_path)?;
+    let mut value = serde_json::to_value(store)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    if crate::toml_store::is_toml(path) {
+        // Edit the existing document in place so hand-written comments survive.
+        let mut doc = std::fs::read(path)
+            .ok()
+            .and_then(|b| crate::toml_store::parse(&b).ok())
+            .unwrap_or_default();
+        crate::toml_store::sync(&mut doc, &value);
+        seal_toml(&mut doc, &key);
+        return Ok(doc.to_string().into_bytes());
+    }
+    seal_value(&mut value, &key);
+    serde_json::to_vec_pretty(&value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// The key to sign `path` with. A new key is only created while the file on disk is unsigned (or
+/// absent): replacing a key that already signed it would quietly bless whatever it now contains.
+fn sealing_key(path: &Path, key_path: &Path) -> std::io::Result<Vec<u8>> {
+    if let Some(key) = read_key(key_path)? {
+        return Ok(key);
+    }
+    let signed = std::fs::read(path)
+        .ok()
+        .and_then(|bytes| parse_store(path, &bytes).ok())
+        .is_some_and(|v| v.get(MAC_FIELD).is_some());
+    if signed {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            StoreTampered {
+                path: path.to_path_buf(),
+                reason: "signed store but integrity key is missing",
+            },
+        ));
+    }
+    load_or_create_key(key_path)
+}
+
+/// Signs what the document reads back as, so verification sees exactly the sealed data.
+fn seal_toml(doc: &mut toml_edit::DocumentMut, key: &[u8]) {
+    let mut value = crate::toml_store::to_json(doc);
+    seal_value(&mut value, key);
+    crate::toml_store::sync(doc, &value);
+}
+
+pub fn seal_value(value: &mut serde_json::Value, key: &[u8]) {
+    let mut m = new_mac(key);
+    m.update(&canonical_payload(value));
+    let mac = encode_hex(&m.finalize().into_bytes());
+    if let Some(obj) = value.as_object_mut() {
+        obj.insert(MAC_FIELD.to_string(), serde_json::Value::String(mac));
+    }
+}
+
+/// Re-signs an existing store file as-is (after a deliberate manual edit, or to sign a pre-MAC
+/// store once). Creates the key if there is none, so only run it on the user's say-so.
+pub fn reseal_file(path: &Path, key_path: &Path) -> std::io::Result<bool> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let key = load_or_create_key(key_path)?;
+    if crate::toml_store::is_toml(path) {
+        let mut doc = crate::toml_store::parse(&bytes)?;
+        seal_toml(&mut doc, &key);
+        std::fs::write(path, doc.to_string())?;
+        return Ok(true);
+    }
+    let mut value: serde_json::Value = serde_json::from_slice(&bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    seal_value(&mut value, &key);
+    let bytes = serde_json::to_vec_pretty(&value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    std::fs::write(path, bytes)?;
+    Ok(true)
+}
+
+fn new_mac(key: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+fn canonical_payload(value: &serde_json::Value) -> Vec<u8> {
+    let mut payload = value.clone();
+    if let Some(obj) = payload.as_object_mut() {
+        obj.remove(MAC_FIELD);
+    }
+    // serde_json's map is ordered by key, so compact output is canonical.
+    serde_json::to_vec(&payload).unwrap_or_default()
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+    out
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_then_verify_and_detect_edit() {
+        let key = [7u8; KEY_LEN];
+        let path = Path::new("rules.json");
+        let mut value = serde_json::json!({"version": 1, "by_ext": {".mp4": "vlc"}});
+        seal_value(&mut value, &key);
+        assert!(verify_value(path, &value, Some(&key)).is_ok());
+
+        value["by_ext"][".mp4"] = serde_json::Value::String("evil".to_string());
+        let err = verify_value(path, &value, Some(&key)).unwrap_err();
+        assert_eq!(as_tampered(&err).map(|t| t.reason), Some("MAC mismatch"));
+
+        let mut stripped = serde_json::json!({"version": 1, "by_ext": {}});
+        let err = verify_value(path, &stripped, Some(&key)).unwrap_err();
+        assert_eq!(as_tampered(&err).map(|t| t.reason), Some("MAC missing"));
+
+        seal_value(&mut stripped, &key);
+        let err = verify_value(path, &stripped, None).unwrap_err();
+        assert_eq!(
+            as_tampered(&err).map(|t| t.reason),
+            Some("signed store but integrity key is missing")
+        );
+        // Without a key, an unsigned store is refused too rather than signed on the spot.
+        let err = verify_value(path, &serde_json::json!({"version": 1}), None).unwrap_err();
+        assert_eq!(
+            as_tampered(&err).map(|t| t.reason),
+            Some("store is not signed")
+        );
+    }
+
+    #[test]
+    fn missing_key_is_not_recreated_over_a_signed_store() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("fag-integrity-{}", nanos));
+        let (path, key_path) = (dir.join("rules.json"), dir.join("integrity.key"));
+        let store = serde_json::json!({"version": 2, "by_ext": {}});
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, seal_store(&path, &key_path, &store).unwrap()).unwrap();
+
+        std::fs::remove_file(&key_path).unwrap();
+        let err = seal_store(&path, &key_path, &store).unwrap_err();
+        assert!(as_tampered(&err).is_some());
+        assert!(!key_path.exists());
+
+        // Only an explicit reseal signs it again, under a new key.
+        assert!(reseal_file(&path, &key_path).unwrap());
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(verify_store_bytes(&path, &key_path, &bytes).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
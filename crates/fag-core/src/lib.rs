@@ -1,4 +1,28 @@
 pub mod hash;
+pub mod api;
+pub mod bundle;
+pub mod cache;
+pub mod captures;
+pub mod config;
+pub mod control;
 pub mod features;
+pub mod guard;
+pub mod instance;
+pub mod integrity;
+pub mod localtime;
+pub mod logging;
+pub mod notify;
+pub mod pending;
+pub mod processes;
 pub mod registry;
+pub mod reload;
+pub mod rules;
+pub mod schedule;
+pub mod shutdown;
+pub mod state;
 pub mod sysinfo;
+pub mod toml_store;
+pub mod watcher;
+
+pub use api::{Error, Guard};
+pub use watcher::Watcher;
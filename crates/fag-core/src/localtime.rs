@@ -0,0 +1,58 @@
+/// Offset of local time from UTC in seconds (east positive), including daylight saving.
+///
+/// Outside Windows this is always 0, i.e. local time is treated as UTC.
+pub fn utc_offset_secs() -> i32 {
+    #[cfg(not(windows))]
+    {
+        0
+    }
+
+    #[cfg(windows)]
+    unsafe {
+        #[repr(C)]
+        struct SystemTime {
+            w_year: u16,
+            w_month: u16,
+            w_day_of_week: u16,
+            w_day: u16,
+            w_hour: u16,
+            w_minute: u16,
+            w_second: u16,
+            w_milliseconds: u16,
+        }
+
+        #[repr(C)]
+        struct TimeZoneInformation {
+            bias: i32,
+            standard_name: [u16; 32],
+            standard_date: SystemTime,
+            standard_bias: i32,
+            daylight_name: [u16; 32],
+            daylight_date: SystemTime,
+            daylight_bias: i32,
+        }
+
+        const TIME_ZONE_ID_STANDARD: u32 = 1;
+        const TIME_ZONE_ID_DAYLIGHT: u32 = 2;
+        const TIME_ZONE_ID_INVALID: u32 = 0xFFFF_FFFF;
+
+        #[link(name = "Kernel32")]
+        extern "system" {
+            fn GetTimeZoneInformation(lpTimeZoneInformation: *mut TimeZoneInformation) -> u32;
+        }
+
+        let mut tzi: TimeZoneInformation = std::mem::zeroed();
+        let rc = GetTimeZoneInformation(&mut tzi);
+        if rc == TIME_ZONE_ID_INVALID {
+            return 0;
+        }
+        // Bias is "UTC = local + bias" in minutes.
+        let bias = tzi.bias
+            + match rc {
+                TIME_ZONE_ID_STANDARD => tzi.standard_bias,
+                TIME_ZONE_ID_DAYLIGHT => tzi.daylight_bias,
+                _ => 0,
+            };
+        -bias * 60
+    }
+}
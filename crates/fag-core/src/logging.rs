@@ -0,0 +1,44 @@
+use std::path::Path;
+
+pub fn append_line(path: &Path, line: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut s = String::with_capacity(line.len() + 1);
+    s.push_str(line);
+    if !s.ends_with('\n') {
+        s.push('\n');
+    }
+    use std::io::Write;
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    f.write_all(s.as_bytes())
+}
+
+/// Complete lines after byte `offset` and the offset just past them. A missing file has no lines;
+/// a file shorter than `offset` was truncated and is read from the start.
+pub fn read_since(path: &Path, offset: u64) -> std::io::Result<(Vec<String>, u64)> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(err) => return Err(err),
+    };
+    let start = if offset > bytes.len() as u64 {
+        0
+    } else {
+        offset as usize
+    };
+    // A line still being written is left for the next call.
+    let end = bytes[start..]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(start, |i| start + i + 1);
+    let lines = String::from_utf8_lossy(&bytes[start..end])
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    Ok((lines, end as u64))
+}
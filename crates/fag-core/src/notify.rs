@@ -0,0 +1,330 @@
+//! Wake-ups for the watch loops: a registry change notification where the platform has one,
+//! plain interval polling otherwise.
+//!
+//! Windows signals any write under `HKCU\...\Explorer\FileExts` (where UserChoice and
+//! UserChoiceLatest live), so a hijack is seen within milliseconds instead of at the next tick.
+//! The interval stays as the upper bound between checks either way.
+
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wait {
+    /// Something under the watched key changed; re-check every rule now.
+    Changed,
+    Timeout,
+}
+
+pub trait ChangeNotifier {
+    /// Blocks until a change is reported or `timeout` passes.
+    fn wait(&mut self, timeout: Duration) -> Wait;
+
+    /// Shown in the watch banner (`notify=...`).
+    fn name(&self) -> &'static str;
+}
+
+/// Never reports a change: every wait is a full interval.
+pub struct PollingNotifier;
+
+impl ChangeNotifier for PollingNotifier {
+    fn wait(&mut self, timeout: Duration) -> Wait {
+        std::thread::sleep(timeout);
+        Wait::Timeout
+    }
+
+    fn name(&self) -> &'static str {
+        "polling"
+    }
+}
+
+/// Reports a change for every message sent on the paired sender. Lets other threads (and tests)
+/// wake a watch loop; a dropped sender degrades to polling.
+pub struct ChannelNotifier {
+    rx: mpsc::Receiver<()>,
+}
+
+impl ChannelNotifier {
+    pub fn new() -> (mpsc::Sender<()>, Self) {
+        let (tx, rx) = mpsc::channel();
+        (tx, Self { rx })
+    }
+}
+
+impl ChangeNotifier for ChannelNotifier {
+    fn wait(&mut self, timeout: Duration) -> Wait {
+        match self.rx.recv_timeout(timeout) {
+            Ok(()) => {
+                // One wake-up for a burst of writes.
+                while self.rx.try_recv().is_ok() {}
+                Wait::Changed
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Wait::Timeout,
+            Err(mpsc::RecvTimeoutError::Disconnected) => PollingNotifier.wait(timeout),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "channel"
+    }
+}
+
+/// The registry notifier on Windows, polling elsewhere. `Err` explains why notifications are not
+/// available; callers fall back to [`PollingNotifier`].
+pub fn system_notifier() -> Result<Box<dyn ChangeNotifier>, String> {
+    #[cfg(windows)]
+    {
+        win::RegistryNotifier::open()
+            .map(|n| Box::new(n) as Box<dyn ChangeNotifier>)
+            .map_err(|e| format!("{} failed with {}", e.api, e.code))
+    }
+
+    #[cfg(not(windows))]
+    {
+        Ok(Box::new(PollingNotifier))
+    }
+}
+
+#[cfg(windows)]
+mod win {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::time::Duration;
+
+    use super::{ChangeNotifier, PollingNotifier, Wait};
+    use crate::registry::{windows_last_error, WinApiError};
+
+    type HKEY = isize;
+    type HANDLE = isize;
+    type BOOL = i32;
+
+    const HKEY_CURRENT_USER: HKEY = 0x8000_0001_u32 as isize;
+    const KEY_NOTIFY: u32 = 0x0010;
+    const REG_NOTIFY_CHANGE_NAME: u32 = 0x1;
+    const REG_NOTIFY_CHANGE_LAST_SET: u32 = 0x4;
+    const ERROR_SUCCESS: u32 = 0;
+    const WAIT_OBJECT_0: u32 = 0;
+    const FILE_EXTS: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Explorer\\FileExts";
+
+    #[link(name = "Advapi32")]
+    extern "system" {
+        fn RegOpenKeyExW(
+            hKey: HKEY,
+            lpSubKey: *const u16,
+            ulOptions: u32,
+            samDesired: u32,
+            phkResult: *mut HKEY,
+        ) -> u32;
+        fn RegCloseKey(hKey: HKEY) -> u32;
+        fn RegNotifyChangeKeyValue(
+            hKey: HKEY,
+            bWatchSubtree: BOOL,
+            dwNotifyFilter: u32,
+            hEvent: HANDLE,
+            fAsynchronous: BOOL,
+        ) -> u32;
+    }
+
+    #[link(name = "Kernel32")]
+    extern "system" {
+        fn CreateEventW(
+            lpEventAttributes: *mut core::ffi::c_void,
+            bManualReset: BOOL,
+            bInitialState: BOOL,
+            lpName: *const u16,
+        ) -> HANDLE;
+        fn WaitForSingleObject(hHandle: HANDLE, dwMilliseconds: u32) -> u32;
+        fn CloseHandle(hObject: HANDLE) -> BOOL;
+    }
+
+    /// `RegNotifyChangeKeyValue` on the whole `FileExts` subtree. The registration belongs to the
+    /// thread that made it, so the notifier must stay on the watch loop's thread.
+    pub struct RegistryNotifier {
+        key: HKEY,
+        event: HANDLE,
+        /// Set when re-arming failed; from then on this notifier only polls.
+        broken: bool,
+    }
+
+    impl RegistryNotifier {
+        pub fn open() -> Result<Self, WinApiError> {
+            let subkey: Vec<u16> = OsStr::new(FILE_EXTS)
+                .encode_wide()
+                .chain(std::iter::once(0))
+                .collect();
+            let mut key: HKEY = 0;
+            let rc = unsafe {
+                RegOpenKeyExW(HKEY_CURRENT_USER, subkey.as_ptr(), 0, KEY_NOTIFY, &mut key)
+            };
+            if rc != ERROR_SUCCESS {
+                return Err(WinApiError {
+                    api: "RegOpenKeyExW",
+                    code: rc,
+                });
+            }
+            let event = unsafe { CreateEventW(std::ptr::null_mut(), 0, 0, std::ptr::null()) };
+            if event == 0 {
+                let code = unsafe { windows_last_error() };
+                unsafe { RegCloseKey(key) };
+                return Err(WinApiError {
+                    api: "CreateEventW",
+                    code,
+                });
+            }
+            let mut notifier = Self {
+                key,
+                event,
+                broken: false,
+            };
+            notifier.arm()?;
+            Ok(notifier)
+        }
+
+        /// One registration reports one change; it is renewed right after each signal so writes
+        /// made while the loop is checking are not lost.
+        fn arm(&mut self) -> Result<(), WinApiError> {
+            let rc = unsafe {
+                RegNotifyChangeKeyValue(
+                    self.key,
+                    1,
+                    REG_NOTIFY_CHANGE_NAME | REG_NOTIFY_CHANGE_LAST_SET,
+                    self.event,
+                    1,
+                )
+            };
+            if rc != ERROR_SUCCESS {
+                return Err(WinApiError {
+                    api: "RegNotifyChangeKeyValue",
+                    code: rc,
+                });
+            }
+            Ok(())
+        }
+    }
+
+    impl ChangeNotifier for RegistryNotifier {
+        fn wait(&mut self, timeout: Duration) -> Wait {
+            if self.broken {
+                return PollingNotifier.wait(timeout);
+            }
+            let ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX - 1);
+            if unsafe { WaitForSingleObject(self.event, ms) } != WAIT_OBJECT_0 {
+                return Wait::Timeout;
+            }
+            if self.arm().is_err() {
+                self.broken = true;
+            }
+            Wait::Changed
+        }
+
+        fn name(&self) -> &'static str {
+            if self.broken {
+                "polling"
+            } else {
+                "registry"
+            }
+        }
+    }
+
+    impl Drop for RegistryNotifier {
+        fn drop(&mut self) {
+            unsafe {
+                RegCloseKey(self.key);
+                CloseHandle(self.event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guard::{
+        Backoff, CaptureStore, Check, Clock, Engine, EventKind, FlapLimit, Phase, Registry,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    #[derive(Clone, Default)]
+    struct SharedRegistry(Arc<Mutex<Option<String>>>);
+
+    impl Registry for SharedRegistry {
+        fn effective_progid(&self, _ext: &str) -> Result<Option<String>, String> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn apply(&self, _ext: &str, prog_id: &str, _hash: &str) -> Result<(), String> {
+            *self.0.lock().unwrap() = Some(prog_id.to_string());
+            Ok(())
+        }
+    }
+
+    struct ZeroClock;
+
+    impl Clock for ZeroClock {
+        fn now_ms(&self) -> u128 {
+            0
+        }
+    }
+
+    struct NoCaptures;
+
+    impl CaptureStore for NoCaptures {
+        fn prog_id(&self, _ext: &str, _label: &str) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn a_write_is_reverted_without_waiting_for_the_interval() {
+        let registry = SharedRegistry::default();
+        *registry.0.lock().unwrap() = Some("VLC.mp4".to_string());
+        let mut engine = Engine::new(registry.clone(), ZeroClock, NoCaptures);
+        let (tx, mut notifier) = ChannelNotifier::new();
+        let check = Check {
+            key: ".mp4|vlc",
+            ext: ".mp4",
+            target: "VLC.mp4",
+            prog_id: "VLC.mp4",
+            hash: "h",
+            allow: &[],
+            monitor_only: false,
+            backoff: Backoff::default(),
+            flapping: FlapLimit::default(),
+            grace_secs: 0,
+        };
+        assert_eq!(engine.step(&check).phase, Phase::Ok);
+
+        let hijacker = {
+            let registry = registry.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                *registry.0.lock().unwrap() = Some("Hijack.mp4".to_string());
+                tx.send(()).unwrap();
+            })
+        };
+        let started = Instant::now();
+        assert_eq!(notifier.wait(Duration::from_secs(60)), Wait::Changed);
+        let kinds: Vec<_> = engine
+            .step(&check)
+            .events
+            .into_iter()
+            .map(|e| e.kind)
+            .collect();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Tampered {
+                    monitor_only: false
+                },
+                EventKind::Applied
+            ]
+        );
+        assert_eq!(registry.0.lock().unwrap().as_deref(), Some("VLC.mp4"));
+        hijacker.join().unwrap();
+
+        // Without further writes the wait runs to its timeout; a gone sender means polling.
+        assert_eq!(notifier.wait(Duration::from_millis(10)), Wait::Timeout);
+        assert_eq!(notifier.wait(Duration::from_millis(10)), Wait::Timeout);
+    }
+}
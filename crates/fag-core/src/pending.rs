@@ -0,0 +1,235 @@
+//! pending.json: the approval queue. A rule in `approve` mode does not restore by itself; each new
+//! tampered value waits here for `fag pending approve|accept|dismiss`. `watch-rules` adds items and
+//! carries out approvals, the CLI records decisions. Sealed like the other stores, since a forged
+//! approval would restore without asking. Both sides go through [`update`] / [`read_queue`], which
+//! hold an OS lock on pending.lock next to it, so their writes cannot interleave.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingQueue {
+    #[serde(default)]
+    pub next_id: u64,
+    #[serde(default)]
+    pub items: Vec<PendingItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingItem {
+    /// Assigned by [`PendingQueue::push`].
+    #[serde(default)]
+    pub id: u64,
+    /// The key `watch-rules` tracks the rule under (`.mp4|vlc`).
+    pub key: String,
+    pub ext: String,
+    /// The label a restore goes back to.
+    pub target: String,
+    pub target_progid: String,
+    /// What the association was changed to; `None` when it was removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_progid: Option<String>,
+    pub detected_unix_ms: u64,
+    /// Set by `fag pending approve`; the watcher restores and drops the item.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub approved: bool,
+}
+
+fn is_false(v: &bool) -> bool {
+    !*v
+}
+
+impl PendingQueue {
+    /// Queues a detection and returns its id. The same change already waiting keeps its id; an
+    /// older change of the same rule is superseded, so each rule has at most one item.
+    pub fn push(&mut self, mut item: PendingItem) -> (u64, bool) {
+        if let Some(same) = self
+            .items
+            .iter()
+            .find(|i| i.key == item.key && i.effective_progid == item.effective_progid)
+        {
+            return (same.id, false);
+        }
+        self.items.retain(|i| i.key != item.key);
+        self.next_id += 1;
+        item.id = self.next_id;
+        item.approved = false;
+        self.items.push(item);
+        (self.next_id, true)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&PendingItem> {
+        self.items.iter().find(|i| i.id == id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<PendingItem> {
+        let at = self.items.iter().position(|i| i.id == id)?;
+        Some(self.items.remove(at))
+    }
+
+    /// Marks an item approved; `false` when there is no such item.
+    pub fn approve(&mut self, id: u64) -> bool {
+        match self.items.iter_mut().find(|i| i.id == id) {
+            Some(item) => {
+                item.approved = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes and returns the approved item for `key`, if any.
+    pub fn take_approved(&mut self, key: &str) -> Option<PendingItem> {
+        let at = self.items.iter().position(|i| i.key == key && i.approved)?;
+        Some(self.items.remove(at))
+    }
+
+    /// Drops whatever waits for `key`, e.g. once the association is back on target by itself.
+    pub fn clear_key(&mut self, key: &str) -> Vec<PendingItem> {
+        let (gone, kept) = std::mem::take(&mut self.items)
+            .into_iter()
+            .partition(|i| i.key == key);
+        self.items = kept;
+        gone
+    }
+}
+
+/// The queue; empty when there is no file yet. Callers hold the lock.
+fn load_queue(path: &Path, key_path: &Path) -> io::Result<PendingQueue> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(PendingQueue::default()),
+        Err(e) => return Err(e),
+    };
+    let value = crate::integrity::verify_store_bytes(path, key_path, &bytes)?;
+    serde_json::from_value(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn save_queue(path: &Path, key_path: &Path, queue: &PendingQueue) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let bytes = crate::integrity::seal_store(path, key_path, queue)?;
+    std::fs::write(path, bytes)
+}
+
+/// The queue as of now, read under the lock so a write in progress is never seen half done.
+pub fn read_queue(path: &Path, key_path: &Path) -> io::Result<PendingQueue> {
+    let _lock = crate::instance::lock_exclusive(&lock_path(path))?;
+    load_queue(path, key_path)
+}
+
+/// Reads the queue, lets `f` change it and writes it back if it did, all under the lock: a
+/// decision recorded by the CLI while the watcher queues a detection waits for the other to finish.
+pub fn update<T>(
+    path: &Path,
+    key_path: &Path,
+    f: impl FnOnce(&mut PendingQueue) -> T,
+) -> io::Result<T> {
+    let _lock = crate::instance::lock_exclusive(&lock_path(path))?;
+    let mut queue = load_queue(path, key_path)?;
+    let before = queue.clone();
+    let out = f(&mut queue);
+    if queue != before {
+        save_queue(path, key_path, &queue)?;
+    }
+    Ok(out)
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    path.with_extension("lock")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(key: &str, effective: &str) -> PendingItem {
+        PendingItem {
+            id: 0,
+            key: key.to_string(),
+            ext: ".mp4".to_string(),
+            target: "vlc".to_string(),
+            target_progid: "VLC.mp4".to_string(),
+            effective_progid: Some(effective.to_string()),
+            detected_unix_ms: 1_792_000_000_000,
+            approved: false,
+        }
+    }
+
+    #[test]
+    fn one_item_per_rule_and_approvals_are_taken_once() {
+        let mut queue = PendingQueue::default();
+        assert_eq!(queue.push(item(".mp4|vlc", "Hijack.mp4")), (1, true));
+        assert_eq!(queue.push(item(".mp4|vlc", "Hijack.mp4")), (1, false));
+        assert_eq!(queue.push(item(".mkv|vlc", "Hijack.mkv")), (2, true));
+        // Changed again before anyone decided: the newer value replaces the older one.
+        assert_eq!(queue.push(item(".mp4|vlc", "Other.mp4")), (3, true));
+        assert_eq!(queue.get(1), None);
+        assert_eq!(queue.items.len(), 2);
+
+        assert_eq!(queue.take_approved(".mp4|vlc"), None);
+        assert!(queue.approve(3));
+        assert!(!queue.approve(1));
+        assert_eq!(queue.take_approved(".mp4|vlc").map(|i| i.id), Some(3));
+        assert_eq!(queue.take_approved(".mp4|vlc"), None);
+        assert_eq!(queue.clear_key(".mkv|vlc").len(), 1);
+        assert!(queue.items.is_empty());
+        // Ids are never reused.
+        assert_eq!(queue.push(item(".mkv|vlc", "Hijack.mkv")), (4, true));
+    }
+
+    #[test]
+    fn update_round_trips_and_refuses_edits() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let home = std::env::temp_dir().join(format!("fag-pending-{}", nanos));
+        std::fs::create_dir_all(&home).unwrap();
+        let (path, key) = (home.join("pending.json"), home.join("integrity.key"));
+        assert_eq!(read_queue(&path, &key).unwrap(), PendingQueue::default());
+
+        let id = update(&path, &key, |q| q.push(item(".mp4|vlc", "Hijack.mp4")).0).unwrap();
+        assert!(update(&path, &key, |q| q.approve(id)).unwrap());
+        assert!(read_queue(&path, &key).unwrap().items[0].approved);
+
+        let edited = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("Hijack.mp4", "Other.mp4");
+        std::fs::write(&path, edited).unwrap();
+        let err = read_queue(&path, &key).unwrap_err();
+        assert!(crate::integrity::as_tampered(&err).is_some());
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let home = std::env::temp_dir().join(format!("fag-pending-race-{}", nanos));
+        std::fs::create_dir_all(&home).unwrap();
+        let (path, key) = (home.join("pending.json"), home.join("integrity.key"));
+
+        // Each update opens the lock file itself, so threads contend like separate processes.
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let (path, key) = (&path, &key);
+                scope.spawn(move || {
+                    for n in 0..10 {
+                        let rule = format!(".x{}{}|vlc", t, n);
+                        update(path, key, |q| q.push(item(&rule, "Hijack"))).unwrap();
+                    }
+                });
+            }
+        });
+        let queue = read_queue(&path, &key).unwrap();
+        assert_eq!(queue.items.len(), 40);
+        assert_eq!(queue.next_id, 40);
+        let _ = std::fs::remove_dir_all(&home);
+    }
+}
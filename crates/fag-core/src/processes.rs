@@ -0,0 +1,340 @@
+//! Running processes, for holding off restores while an installer (or any named program) runs.
+//!
+//! Patterns are executable names with `*` / `?` wildcards, compared case-insensitively:
+//! `msiexec.exe`, `setup*.exe`.
+
+use std::collections::BTreeMap;
+use std::io;
+
+pub trait ProcessList {
+    /// Executable names (`msiexec.exe`) of the processes running now. Repeats are fine.
+    fn names(&self) -> io::Result<Vec<String>>;
+}
+
+/// The processes of this machine.
+pub struct SystemProcesses;
+
+impl ProcessList for SystemProcesses {
+    fn names(&self) -> io::Result<Vec<String>> {
+        platform::names()
+    }
+}
+
+/// Whether `name` matches `pattern` (`*` = any run, `?` = any one character), ignoring case.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.to_lowercase().chars().collect();
+    let n: Vec<char> = name.to_lowercase().chars().collect();
+    // Classic two-pointer glob: on a mismatch, let the last `*` swallow one more character.
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// The running processes matching any of `patterns`, lowercased, sorted and without repeats.
+pub fn running_matches(list: &dyn ProcessList, patterns: &[String]) -> io::Result<Vec<String>> {
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut found: Vec<String> = list
+        .names()?
+        .into_iter()
+        .filter(|name| patterns.iter().any(|p| matches(p, name)))
+        .map(|name| name.to_lowercase())
+        .collect();
+    found.sort();
+    found.dedup();
+    Ok(found)
+}
+
+/// Which of the already-matched `running` names match `patterns`.
+pub fn matching<'a>(running: &'a [String], patterns: &[String]) -> Vec<&'a str> {
+    running
+        .iter()
+        .filter(|name| patterns.iter().any(|p| matches(p, name)))
+        .map(String::as_str)
+        .collect()
+}
+
+/// A start or end of holding off, for one scope: `""` for every rule (`watch.pause_processes`),
+/// otherwise a rule key (its `pause_for`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HoldChange {
+    Started {
+        scope: String,
+        processes: Vec<String>,
+    },
+    Ended {
+        scope: String,
+    },
+}
+
+/// Which scopes are held off right now, and because of which processes.
+#[derive(Debug, Default)]
+pub struct ProcessHold {
+    held: BTreeMap<String, Vec<String>>,
+}
+
+impl ProcessHold {
+    /// Re-evaluates every scope against `running` (see [`running_matches`]). A scope whose
+    /// processes merely change stays held without a new [`HoldChange::Started`].
+    pub fn update(
+        &mut self,
+        running: &[String],
+        scopes: &[(String, Vec<String>)],
+    ) -> Vec<HoldChange> {
+        let mut changes = Vec::new();
+        let mut next = BTreeMap::new();
+        for (scope, patterns) in scopes {
+            let found: Vec<String> = matching(running, patterns)
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+            if found.is_empty() {
+                continue;
+            }
+            if !self.held.contains_key(scope) {
+                changes.push(HoldChange::Started {
+                    scope: scope.clone(),
+                    processes: found.clone(),
+                });
+            }
+            next.insert(scope.clone(), found);
+        }
+        for scope in self.held.keys().filter(|s| !next.contains_key(*s)) {
+            changes.push(HoldChange::Ended {
+                scope: scope.clone(),
+            });
+        }
+        self.held = next;
+        changes
+    }
+
+    /// The processes holding off `scope`, if it is held.
+    pub fn held(&self, scope: &str) -> Option<&[String]> {
+        self.held.get(scope).map(Vec::as_slice)
+    }
+
+    /// Every running process that holds something off.
+    pub fn processes(&self) -> Vec<&str> {
+        let mut all: Vec<&str> = self.held.values().flatten().map(String::as_str).collect();
+        all.sort();
+        all.dedup();
+        all
+    }
+}
+
+/// Checks a pattern given on the command line or in config.json.
+pub fn normalize_pattern(pattern: &str) -> Result<String, String> {
+    let p = pattern.trim().to_lowercase();
+    if p.is_empty() {
+        return Err("process name is empty".to_string());
+    }
+    if p.contains(['/', '\\']) {
+        return Err(format!(
+            "'{}' is a path; use the executable name (e.g. msiexec.exe)",
+            pattern.trim()
+        ));
+    }
+    Ok(p)
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+
+    use crate::registry::windows_last_error;
+
+    type HANDLE = isize;
+    type BOOL = i32;
+
+    const INVALID_HANDLE_VALUE: HANDLE = -1;
+    const TH32CS_SNAPPROCESS: u32 = 0x2;
+    const ERROR_NO_MORE_FILES: u32 = 18;
+
+    #[repr(C)]
+    #[allow(non_snake_case)]
+    struct PROCESSENTRY32W {
+        dwSize: u32,
+        cntUsage: u32,
+        th32ProcessID: u32,
+        th32DefaultHeapID: usize,
+        th32ModuleID: u32,
+        cntThreads: u32,
+        th32ParentProcessID: u32,
+        pcPriClassBase: i32,
+        dwFlags: u32,
+        szExeFile: [u16; 260],
+    }
+
+    #[link(name = "Kernel32")]
+    extern "system" {
+        fn CreateToolhelp32Snapshot(dwFlags: u32, th32ProcessID: u32) -> HANDLE;
+        fn Process32FirstW(hSnapshot: HANDLE, lppe: *mut PROCESSENTRY32W) -> BOOL;
+        fn Process32NextW(hSnapshot: HANDLE, lppe: *mut PROCESSENTRY32W) -> BOOL;
+        fn CloseHandle(hObject: HANDLE) -> BOOL;
+    }
+
+    pub fn names() -> io::Result<Vec<String>> {
+        let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Err(io::Error::from_raw_os_error(
+                unsafe { windows_last_error() } as i32,
+            ));
+        }
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            cntUsage: 0,
+            th32ProcessID: 0,
+            th32DefaultHeapID: 0,
+            th32ModuleID: 0,
+            cntThreads: 0,
+            th32ParentProcessID: 0,
+            pcPriClassBase: 0,
+            dwFlags: 0,
+            szExeFile: [0; 260],
+        };
+        let mut names = Vec::new();
+        let mut ok = unsafe { Process32FirstW(snapshot, &mut entry) };
+        while ok != 0 {
+            let len = entry
+                .szExeFile
+                .iter()
+                .position(|c| *c == 0)
+                .unwrap_or(entry.szExeFile.len());
+            names.push(String::from_utf16_lossy(&entry.szExeFile[..len]));
+            ok = unsafe { Process32NextW(snapshot, &mut entry) };
+        }
+        let code = unsafe { windows_last_error() };
+        unsafe { CloseHandle(snapshot) };
+        if code != ERROR_NO_MORE_FILES && names.is_empty() {
+            return Err(io::Error::from_raw_os_error(code as i32));
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::io;
+
+    /// Reads `/proc/<pid>/comm`; processes that exit while being listed are skipped.
+    pub fn names() -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir("/proc")? {
+            let entry = entry?;
+            let is_pid = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|s| s.bytes().all(|b| b.is_ascii_digit()));
+            if !is_pid {
+                continue;
+            }
+            if let Ok(comm) = std::fs::read_to_string(entry.path().join("comm")) {
+                names.push(comm.trim_end().to_string());
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub struct FakeProcesses(pub Vec<&'static str>);
+
+    impl ProcessList for FakeProcesses {
+        fn names(&self) -> io::Result<Vec<String>> {
+            Ok(self.0.iter().map(|s| s.to_string()).collect())
+        }
+    }
+
+    #[test]
+    fn glob_patterns_ignore_case() {
+        assert!(matches("msiexec.exe", "MsiExec.exe"));
+        assert!(matches("setup*.exe", "Setup_VLC-3.0.exe"));
+        assert!(matches("setup*.exe", "setup.exe"));
+        assert!(matches("*install?r*", "vlc-installer.exe"));
+        assert!(!matches("setup*.exe", "mysetup.exe"));
+        assert!(!matches("setup*.exe", "setup.exe.bak"));
+        assert!(!matches("msiexec.exe", "msiexec"));
+        assert!(normalize_pattern(r"C:\Windows\msiexec.exe").is_err());
+    }
+
+    #[test]
+    fn running_matches_lists_each_name_once() {
+        let list = FakeProcesses(vec![
+            "explorer.exe",
+            "msiexec.exe",
+            "MSIEXEC.EXE",
+            "Setup.exe",
+        ]);
+        let patterns = vec!["msiexec.exe".to_string(), "setup*.exe".to_string()];
+        let running = running_matches(&list, &patterns).unwrap();
+        assert_eq!(running, vec!["msiexec.exe", "setup.exe"]);
+        assert_eq!(
+            matching(&running, &["setup*.exe".to_string()]),
+            vec!["setup.exe"]
+        );
+        assert!(running_matches(&list, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hold_starts_and_ends_per_scope() {
+        let scopes = vec![
+            (String::new(), vec!["msiexec.exe".to_string()]),
+            (".pdf|acrobat".to_string(), vec!["acro*.exe".to_string()]),
+        ];
+        let patterns: Vec<String> = scopes.iter().flat_map(|(_, p)| p.clone()).collect();
+        let mut hold = ProcessHold::default();
+
+        let running = running_matches(&FakeProcesses(vec!["AcroSetup.exe"]), &patterns).unwrap();
+        assert_eq!(
+            hold.update(&running, &scopes),
+            vec![HoldChange::Started {
+                scope: ".pdf|acrobat".to_string(),
+                processes: vec!["acrosetup.exe".to_string()]
+            }]
+        );
+        assert_eq!(hold.held(""), None);
+
+        let fake = FakeProcesses(vec!["acrosetup.exe", "acrord32.exe", "msiexec.exe"]);
+        let running = running_matches(&fake, &patterns).unwrap();
+        let changes = hold.update(&running, &scopes);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(hold.held(""), Some(&["msiexec.exe".to_string()][..]));
+        assert_eq!(
+            hold.processes(),
+            vec!["acrord32.exe", "acrosetup.exe", "msiexec.exe"]
+        );
+
+        let changes = hold.update(&[], &scopes);
+        assert_eq!(
+            changes,
+            vec![
+                HoldChange::Ended {
+                    scope: String::new()
+                },
+                HoldChange::Ended {
+                    scope: ".pdf|acrobat".to_string()
+                }
+            ]
+        );
+        assert!(hold.processes().is_empty());
+    }
+}
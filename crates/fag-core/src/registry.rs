@@ -133,6 +133,9 @@ pub fn list_open_with_progids(ext: &str) -> Result<Vec<String>, ReadUserChoiceEr
         }
     }
 
+    #[cfg(not(windows))]
+    let _ = ext;
+
     out.sort();
     out.dedup();
     Ok(out)
@@ -152,10 +155,10 @@ pub fn set_user_choice(
     {
         let _ = ext;
         let _ = prog_id;
-        return Err(SetUserChoiceError::WindowsApiError {
+        Err(SetUserChoiceError::WindowsApiError {
             api: "windows-only",
             code: 0,
-        });
+        })
     }
 
     #[cfg(windows)]
@@ -232,10 +235,10 @@ pub fn set_user_choice_latest_replay(
         let _ = ext;
         let _ = prog_id;
         let _ = hash;
-        return Err(SetUserChoiceLatestError::WindowsApiError {
+        Err(SetUserChoiceLatestError::WindowsApiError {
             api: "windows-only",
             code: 0,
-        });
+        })
     }
 
     #[cfg(windows)]
@@ -309,6 +312,65 @@ pub fn effective_progid_for_ext(ext: &str) -> Result<Option<String>, ReadUserCho
     }
 }
 
+/// Last-write times of the keys a user's choice for an extension lives in. Reading them is much
+/// cheaper than asking the shell for the effective association, so an unchanged stamp lets a
+/// watcher skip that query.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AssocStamp {
+    pub user_choice_latest: Option<u64>,
+    pub user_choice_latest_prog_id: Option<u64>,
+    pub user_choice: Option<u64>,
+}
+
+pub fn assoc_stamp(ext: &str) -> Result<AssocStamp, ReadUserChoiceError> {
+    let latest = read_user_choice_latest(ext)?;
+    let user_choice = read_user_choice(ext)?;
+    Ok(AssocStamp {
+        user_choice_latest: latest
+            .as_ref()
+            .and_then(|l| l.last_write_time)
+            .map(FileTime::as_u64),
+        user_choice_latest_prog_id: latest
+            .as_ref()
+            .and_then(|l| l.prog_id_last_write_time)
+            .map(FileTime::as_u64),
+        user_choice: user_choice
+            .and_then(|u| u.last_write_time)
+            .map(FileTime::as_u64),
+    })
+}
+
+/// Whether `HKCR\<ProgId>` exists, i.e. the application behind it is still installed.
+/// `Applications\vlc.exe` ("Open with → choose another app") is a subkey path and checked as one.
+pub fn progid_registered(prog_id: &str) -> Result<bool, ReadUserChoiceError> {
+    let Some(prog_id) = progid_subkey(prog_id) else {
+        return Ok(false);
+    };
+
+    #[cfg(windows)]
+    unsafe {
+        hkcr_key_exists(prog_id).map_err(|e| ReadUserChoiceError::WindowsApiError {
+            api: e.api,
+            code: e.code,
+        })
+    }
+
+    #[cfg(not(windows))]
+    {
+        let _ = prog_id;
+        Err(ReadUserChoiceError::WindowsApiError {
+            api: "windows-only",
+            code: 0,
+        })
+    }
+}
+
+/// The HKCR subkey for `prog_id`, or `None` for a value that cannot name one.
+fn progid_subkey(prog_id: &str) -> Option<&str> {
+    let prog_id = prog_id.trim();
+    (!prog_id.is_empty() && !prog_id.contains(['/', '\0'])).then_some(prog_id)
+}
+
 impl std::fmt::Debug for ReadUserChoiceError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -1135,6 +1197,51 @@ unsafe fn enum_value_names(root: isize, subkey: &str) -> Result<Vec<String>, Win
     Ok(out)
 }
 
+#[cfg(windows)]
+unsafe fn hkcr_key_exists(subkey: &str) -> Result<bool, WinApiError> {
+    type HKEY = isize;
+    const HKEY_CLASSES_ROOT: HKEY = 0x8000_0000_u32 as isize;
+    const KEY_READ: u32 = 0x20019;
+    const ERROR_SUCCESS: u32 = 0;
+    const ERROR_FILE_NOT_FOUND: u32 = 2;
+
+    #[link(name = "Advapi32")]
+    extern "system" {
+        fn RegOpenKeyExW(
+            hKey: HKEY,
+            lpSubKey: *const u16,
+            ulOptions: u32,
+            samDesired: u32,
+            phkResult: *mut HKEY,
+        ) -> u32;
+        fn RegCloseKey(hKey: HKEY) -> u32;
+    }
+
+    fn to_wide(s: &str) -> Vec<u16> {
+        use std::ffi::OsStr;
+        use std::os::windows::ffi::OsStrExt;
+        OsStr::new(s)
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect()
+    }
+
+    let subkey_w = to_wide(subkey);
+    let mut hkey: HKEY = 0;
+    let rc = RegOpenKeyExW(HKEY_CLASSES_ROOT, subkey_w.as_ptr(), 0, KEY_READ, &mut hkey);
+    if rc == ERROR_FILE_NOT_FOUND {
+        return Ok(false);
+    }
+    if rc != ERROR_SUCCESS {
+        return Err(WinApiError {
+            api: "RegOpenKeyExW(HKCR ProgId)",
+            code: rc,
+        });
+    }
+    let _ = RegCloseKey(hkey);
+    Ok(true)
+}
+
 #[cfg(not(windows))]
 unsafe fn read_user_choice_from_subkey(
     _subkey: &str,
@@ -1145,10 +1252,31 @@ unsafe fn read_user_choice_from_subkey(
     })
 }
 
+pub fn clamp_filetime_to_minute(filetime: u64) -> u64 {
+    const MINUTE_100NS: u64 = 600_000_000;
+    filetime - (filetime % MINUTE_100NS)
+}
+
+pub fn filetime_to_regdate_hex(filetime: u64) -> String {
+    format!("{:016x}", filetime)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn applications_progids_are_looked_up_as_subkeys() {
+        assert_eq!(
+            progid_subkey(" Applications\\vlc.exe "),
+            Some("Applications\\vlc.exe")
+        );
+        assert_eq!(progid_subkey("VLC.mp4"), Some("VLC.mp4"));
+        assert_eq!(progid_subkey("  "), None);
+        assert_eq!(progid_subkey("a/b"), None);
+        assert_eq!(progid_subkey("a\0b"), None);
+    }
+
     #[test]
     fn normalize_ext_accepts_dot_prefixed() {
         assert_eq!(normalize_ext(".mp4").unwrap(), ".mp4");
@@ -1216,12 +1344,3 @@ mod tests {
         assert_eq!(filetime_to_regdate_hex(0xabcdef), "0000000000abcdef");
     }
 }
-
-pub fn clamp_filetime_to_minute(filetime: u64) -> u64 {
-    const MINUTE_100NS: u64 = 600_000_000;
-    filetime - (filetime % MINUTE_100NS)
-}
-
-pub fn filetime_to_regdate_hex(filetime: u64) -> String {
-    format!("{:016x}", filetime)
-}
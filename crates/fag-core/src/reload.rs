@@ -0,0 +1,478 @@
+//! Hot reload for the watch loops: notices that rules, captures or config.json changed on disk,
+//! validates the new contents and reports what changed.
+//!
+//! A store that no longer loads keeps its last good contents, so a typo in rules.json never stops
+//! a running guard. A tampered store is reported the same way but also suspends restoring, as it
+//! does everywhere else.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use crate::api::{Error, Guard};
+use crate::cache::{file_stamp, FileStamp};
+use crate::captures::ByExt;
+use crate::config::{self, Settings};
+use crate::guard::FlapLimit;
+use crate::rules::{BackoffPolicy, EffectiveRule, LayeredRules};
+
+/// The config.json values a watcher applies without restarting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchOptions {
+    pub interval_secs: u64,
+    pub monitor_only: bool,
+    pub backoff_base_secs: u64,
+    pub backoff_max_secs: u64,
+    pub backoff_policy: BackoffPolicy,
+    pub backoff_max_retries: u32,
+    /// 0 = none.
+    pub backoff_cooldown_secs: u64,
+    pub flapping: FlapLimit,
+    pub grace_secs: u64,
+    pub pause_processes: Vec<String>,
+}
+
+impl WatchOptions {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            interval_secs: settings.interval_secs.0,
+            monitor_only: settings.monitor_only.0,
+            backoff_base_secs: settings.backoff_base_secs.0,
+            backoff_max_secs: settings.backoff_max_secs.0,
+            backoff_policy: settings.backoff_policy.0,
+            backoff_max_retries: settings.backoff_max_retries.0,
+            backoff_cooldown_secs: settings.backoff_cooldown_secs.0,
+            flapping: FlapLimit {
+                cycles: settings.flap_cycles.0,
+                window_secs: settings.flap_window_secs.0,
+                quiet_secs: settings.flap_quiet_secs.0,
+            },
+            grace_secs: settings.grace_secs.0,
+            pause_processes: settings.pause_processes.0.clone(),
+        }
+    }
+}
+
+/// What a reload changed. Rules are named by extension.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// The rule itself or one of its extension's captures changed.
+    pub changed: Vec<String>,
+    /// config.json keys whose new value is now in effect.
+    pub options: Vec<&'static str>,
+    /// config.json keys that changed but only take effect after a restart (store paths).
+    pub restart_required: Vec<&'static str>,
+    /// Enabled rules among the added and changed ones with no capture for any label in their
+    /// chain, as `ext|label`. They are skipped until captured.
+    pub missing_captures: Vec<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.options.is_empty()
+            && self.restart_required.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub enum Reload {
+    /// The first successful load; every rule is in `added`.
+    Loaded(Changes),
+    Reloaded(Changes),
+    /// `store` (`rules`, `captures` or `config`) no longer loads and its previous contents stay in
+    /// use. Reported once per distinct error.
+    Failed {
+        store: &'static str,
+        error: Error,
+    },
+}
+
+pub struct Reloader {
+    /// Rules given up front instead of read from the rules store (`fag watch --ext`).
+    fixed: Option<Vec<EffectiveRule>>,
+    layered: Option<Arc<LayeredRules>>,
+    captures: Option<Arc<ByExt>>,
+    expanded: Vec<EffectiveRule>,
+    settings: Settings,
+    config_stamp: Option<FileStamp>,
+    loaded: bool,
+    failed: BTreeMap<&'static str, String>,
+    tampered: BTreeSet<&'static str>,
+}
+
+impl Reloader {
+    /// Follows the rules, captures and config behind `guard`.
+    pub fn new(guard: &Guard) -> Self {
+        Self::with_rules(guard, None)
+    }
+
+    /// Follows captures and config for a fixed rule list.
+    pub fn fixed(guard: &Guard, rules: Vec<EffectiveRule>) -> Self {
+        Self::with_rules(guard, Some(rules))
+    }
+
+    fn with_rules(guard: &Guard, fixed: Option<Vec<EffectiveRule>>) -> Self {
+        let settings = guard.settings().clone();
+        Self {
+            fixed,
+            layered: None,
+            captures: None,
+            expanded: Vec::new(),
+            config_stamp: file_stamp(&settings.config_path),
+            settings,
+            loaded: false,
+            failed: BTreeMap::new(),
+            tampered: BTreeSet::new(),
+        }
+    }
+
+    /// The rules in effect: the last set that loaded.
+    pub fn rules(&self) -> &[EffectiveRule] {
+        &self.expanded
+    }
+
+    /// The layered rule store in effect; `None` for a fixed rule list or before the first load.
+    pub fn layered(&self) -> Option<&LayeredRules> {
+        self.layered.as_deref()
+    }
+
+    pub fn options(&self) -> WatchOptions {
+        WatchOptions::from_settings(&self.settings)
+    }
+
+    /// Stores whose latest contents do not load, so older contents are in use.
+    pub fn failing(&self) -> Vec<&'static str> {
+        self.failed.keys().copied().collect()
+    }
+
+    /// Makes the next `poll` re-read every store and report its current errors again, as if the
+    /// files had all changed (`fag ctl reload`).
+    pub fn force(&mut self, guard: &Guard) {
+        guard.invalidate();
+        self.config_stamp = None;
+        self.failed.clear();
+    }
+
+    /// Whether `store` currently fails its integrity check.
+    pub fn is_tampered(&self, store: &str) -> bool {
+        self.tampered.contains(store)
+    }
+
+    /// Picks up whatever changed on disk since the last call. Cheap when nothing did: the stores
+    /// are only re-read when their size or mtime moved.
+    pub fn poll(&mut self, guard: &Guard) -> Vec<Reload> {
+        let mut out = Vec::new();
+        let mut changes = Changes::default();
+
+        let stamp = file_stamp(&self.settings.config_path);
+        if stamp != self.config_stamp {
+            // Stamped either way: a broken config is reported once, not on every tick.
+            self.config_stamp = stamp;
+            match config::reload_settings(&self.settings) {
+                Ok(settings) => {
+                    self.succeeded("config");
+                    (changes.options, changes.restart_required) =
+                        diff_settings(&self.settings, &settings);
+                    let paths = (
+                        self.settings.rules_path.clone(),
+                        self.settings.captures_path.clone(),
+                        self.settings.log_path.clone(),
+                    );
+                    self.settings = settings;
+                    // The guard keeps reading the old paths until restarted.
+                    (
+                        self.settings.rules_path,
+                        self.settings.captures_path,
+                        self.settings.log_path,
+                    ) = paths;
+                }
+                Err(source) => {
+                    let error = Error::Store {
+                        store: "config",
+                        source,
+                    };
+                    self.failed(&mut out, "config", error);
+                }
+            }
+        }
+
+        let old_rules = self.expanded.clone();
+        let old_captures = self.captures.clone();
+        let mut rules_moved = false;
+        if self.fixed.is_none() {
+            match guard.load_rules() {
+                Ok(layered) => {
+                    self.succeeded("rules");
+                    if !same(&self.layered, &layered) {
+                        self.expanded = layered.expand();
+                        self.layered = Some(layered);
+                        rules_moved = true;
+                    }
+                }
+                Err(error) => self.failed(&mut out, "rules", error),
+            }
+        } else if !self.loaded {
+            self.expanded = self.fixed.clone().unwrap_or_default();
+            rules_moved = true;
+        }
+        let mut captures_moved = false;
+        match guard.load_captures() {
+            Ok(captures) => {
+                self.succeeded("captures");
+                if !same(&self.captures, &captures) {
+                    self.captures = Some(captures);
+                    captures_moved = true;
+                }
+            }
+            Err(error) => self.failed(&mut out, "captures", error),
+        }
+
+        let have_rules = self.fixed.is_some() || self.layered.is_some();
+        if !self.loaded {
+            if !have_rules || self.captures.is_none() {
+                return out;
+            }
+            self.loaded = true;
+            changes.added = self.expanded.iter().map(|r| r.ext.clone()).collect();
+            changes.missing_captures = self.missing_captures(&changes.added);
+            out.push(Reload::Loaded(changes));
+            return out;
+        }
+
+        if rules_moved || captures_moved {
+            let empty = ByExt::new();
+            let old_captures = old_captures.as_deref().unwrap_or(&empty);
+            let new_captures = self.captures.as_deref().unwrap_or(&empty);
+            let old: BTreeMap<&str, &EffectiveRule> =
+                old_rules.iter().map(|r| (r.ext.as_str(), r)).collect();
+            for r in &self.expanded {
+                match old.get(r.ext.as_str()) {
+                    None => changes.added.push(r.ext.clone()),
+                    Some(prev)
+                        if *prev != r || old_captures.get(&r.ext) != new_captures.get(&r.ext) =>
+                    {
+                        changes.changed.push(r.ext.clone())
+                    }
+                    Some(_) => {}
+                }
+            }
+            let now: BTreeSet<&str> = self.expanded.iter().map(|r| r.ext.as_str()).collect();
+            changes.removed = old_rules
+                .iter()
+                .filter(|r| !now.contains(r.ext.as_str()))
+                .map(|r| r.ext.clone())
+                .collect();
+            let touched: Vec<String> = changes
+                .added
+                .iter()
+                .chain(changes.changed.iter())
+                .cloned()
+                .collect();
+            changes.missing_captures = self.missing_captures(&touched);
+        }
+        if !changes.is_empty() {
+            out.push(Reload::Reloaded(changes));
+        }
+        out
+    }
+
+    fn missing_captures(&self, exts: &[String]) -> Vec<String> {
+        let empty = ByExt::new();
+        let captures = self.captures.as_deref().unwrap_or(&empty);
+        self.expanded
+            .iter()
+            .filter(|r| exts.contains(&r.ext) && r.rule.enabled && r.rule.follow.is_none())
+            .filter(|r| {
+                let labels = captures.get(&r.ext);
+                !r.rule
+                    .chain()
+                    .any(|l| labels.is_some_and(|m| m.contains_key(l)))
+            })
+            .map(|r| format!("{}|{}", r.ext, r.rule.name))
+            .collect()
+    }
+
+    fn succeeded(&mut self, store: &'static str) {
+        self.failed.remove(store);
+        self.tampered.remove(store);
+    }
+
+    fn failed(&mut self, out: &mut Vec<Reload>, store: &'static str, error: Error) {
+        if error.tampered().is_some() {
+            self.tampered.insert(store);
+        }
+        let reason = error.to_string();
+        if self.failed.get(store) != Some(&reason) {
+            self.failed.insert(store, reason);
+            out.push(Reload::Failed { store, error });
+        }
+    }
+}
+
+fn same<T>(held: &Option<Arc<T>>, loaded: &Arc<T>) -> bool {
+    held.as_ref().is_some_and(|h| Arc::ptr_eq(h, loaded))
+}
+
+/// The `fag config` keys that differ, split into those applied live and those needing a restart.
+fn diff_settings(old: &Settings, new: &Settings) -> (Vec<&'static str>, Vec<&'static str>) {
+    let mut live = Vec::new();
+    let mut restart = Vec::new();
+    if old.interval_secs.0 != new.interval_secs.0 {
+        live.push("watch.interval_secs");
+    }
+    if old.monitor_only.0 != new.monitor_only.0 {
+        live.push("watch.monitor_only");
+    }
+    if old.backoff_base_secs.0 != new.backoff_base_secs.0 {
+        live.push("watch.backoff_base_secs");
+    }
+    if old.backoff_max_secs.0 != new.backoff_max_secs.0 {
+        live.push("watch.backoff_max_secs");
+    }
+    if old.backoff_policy.0 != new.backoff_policy.0 {
+        live.push("watch.backoff_policy");
+    }
+    if old.backoff_max_retries.0 != new.backoff_max_retries.0 {
+        live.push("watch.backoff_max_retries");
+    }
+    if old.backoff_cooldown_secs.0 != new.backoff_cooldown_secs.0 {
+        live.push("watch.backoff_cooldown_secs");
+    }
+    if old.flap_cycles.0 != new.flap_cycles.0 {
+        live.push("watch.flap_cycles");
+    }
+    if old.flap_window_secs.0 != new.flap_window_secs.0 {
+        live.push("watch.flap_window_secs");
+    }
+    if old.flap_quiet_secs.0 != new.flap_quiet_secs.0 {
+        live.push("watch.flap_quiet_secs");
+    }
+    if old.grace_secs.0 != new.grace_secs.0 {
+        live.push("watch.grace_secs");
+    }
+    if old.pause_processes.0 != new.pause_processes.0 {
+        live.push("watch.pause_processes");
+    }
+    if old.rules_path.0 != new.rules_path.0 {
+        restart.push("paths.rules");
+    }
+    if old.captures_path.0 != new.captures_path.0 {
+        restart.push("paths.captures");
+    }
+    if old.log_path.0 != new.log_path.0 {
+        restart.push("paths.log");
+    }
+    (live, restart)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::captures::{self, LatestCapture};
+    use crate::config::Source;
+    use std::path::Path;
+
+    fn guard_at(home: &Path) -> Guard {
+        let at = |file: &str| (home.join(file), Source::Default);
+        Guard::new(Settings {
+            config_path: home.join("config.json"),
+            home: home.to_path_buf(),
+            portable: (false, Source::Default),
+            rules_path: at("rules.json"),
+            captures_path: at("captures.json"),
+            log_path: at("guard.log"),
+            key_path: home.join("integrity.key"),
+            policy_path: None,
+            interval_secs: (5, Source::Default),
+            monitor_only: (false, Source::Default),
+            backoff_base_secs: (30, Source::Default),
+            backoff_max_secs: (600, Source::Default),
+            backoff_policy: (BackoffPolicy::Exponential, Source::Default),
+            backoff_max_retries: (0, Source::Default),
+            backoff_cooldown_secs: (0, Source::Default),
+            flap_cycles: (5, Source::Default),
+            flap_window_secs: (600, Source::Default),
+            flap_quiet_secs: (900, Source::Default),
+            grace_secs: (0, Source::Default),
+            pause_processes: (Vec::new(), Source::Default),
+        })
+    }
+
+    fn capture(guard: &Guard, ext: &str, label: &str, prog_id: &str) {
+        let cap = LatestCapture {
+            prog_id: prog_id.to_string(),
+            hash: "h".to_string(),
+            last_write_time_filetime: None,
+            prog_id_last_write_time_filetime: None,
+        };
+        captures::upsert_latest_capture(
+            guard.settings().captures_path(),
+            &guard.settings().key_path,
+            ext,
+            label,
+            cap,
+        )
+        .unwrap();
+    }
+
+    fn reloaded(events: Vec<Reload>) -> Changes {
+        match events.as_slice() {
+            [Reload::Reloaded(c)] => c.clone(),
+            other => panic!("expected one reload, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_rule_changes_and_keeps_the_last_good_rules() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let home = std::env::temp_dir().join(format!("fag-reload-{}", nanos));
+        std::fs::create_dir_all(&home).unwrap();
+        let guard = guard_at(&home);
+        capture(&guard, ".mp4", "vlc", "VLC.mp4");
+        capture(&guard, ".mkv", "vlc", "VLC.mkv");
+        guard.add_rule(".mp4", "vlc").unwrap();
+        guard.add_rule(".mkv", "vlc").unwrap();
+
+        let mut reloader = Reloader::new(&guard);
+        match reloader.poll(&guard).as_slice() {
+            [Reload::Loaded(c)] => assert_eq!(c.added, vec![".mkv", ".mp4"]),
+            other => panic!("expected the initial load, got {:?}", other),
+        }
+        assert!(reloader.poll(&guard).is_empty());
+
+        capture(&guard, ".avi", "vlc", "VLC.avi");
+        guard.add_rule(".avi", "vlc").unwrap();
+        guard.remove_rule(".mkv").unwrap();
+        guard
+            .update_rule(".mp4", |r| r.name = "mpv".into())
+            .unwrap();
+        let changes = reloaded(reloader.poll(&guard));
+        assert_eq!(changes.added, vec![".avi"]);
+        assert_eq!(changes.removed, vec![".mkv"]);
+        assert_eq!(changes.changed, vec![".mp4"]);
+        assert_eq!(changes.missing_captures, vec![".mp4|mpv"]);
+
+        // A re-capture changes the rule's target.
+        capture(&guard, ".avi", "vlc", "VLC.avi.2");
+        assert_eq!(reloaded(reloader.poll(&guard)).changed, vec![".avi"]);
+
+        // A broken file is reported once and the previous rules stay in force.
+        std::fs::write(guard.settings().rules_path(), "{ not json").unwrap();
+        let events = reloader.poll(&guard);
+        assert!(matches!(
+            events.as_slice(),
+            [Reload::Failed { store: "rules", .. }]
+        ));
+        assert!(reloader.poll(&guard).is_empty());
+        assert_eq!(reloader.rules().len(), 2);
+        assert_eq!(guard.rules().unwrap().user.by_ext.len(), 2);
+        let _ = std::fs::remove_dir_all(&home);
+    }
+}
@@ -0,0 +1,924 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::schedule::ScheduleWindow;
+
+pub const RULES_VERSION: u32 = 2;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RulesStore {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    by_ext: BTreeMap<String, Rule>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    by_group: BTreeMap<String, Rule>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    profiles: BTreeMap<String, Profile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    active_profile: Option<String>,
+}
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// A stored (inactive) rule set. Group definitions are shared by all profiles.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub by_ext: BTreeMap<String, Rule>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub by_group: BTreeMap<String, Rule>,
+}
+
+/// Extension groups available without defining them. A user group with the same name replaces one.
+pub const BUILTIN_GROUPS: &[(&str, &[&str])] = &[
+    (
+        "video",
+        &[
+            ".mp4", ".mkv", ".avi", ".mov", ".wmv", ".flv", ".webm", ".m4v", ".mpg", ".mpeg",
+            ".ts", ".m2ts", ".3gp",
+        ],
+    ),
+    (
+        "audio",
+        &[
+            ".mp3", ".flac", ".wav", ".aac", ".m4a", ".ogg", ".opus", ".wma", ".ape",
+        ],
+    ),
+    (
+        "image",
+        &[
+            ".jpg", ".jpeg", ".png", ".gif", ".bmp", ".webp", ".tif", ".tiff", ".heic",
+        ],
+    ),
+];
+
+/// Everything in rules.json. Rule keys starting with `.` are extensions, anything else is a group.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleSet {
+    pub by_ext: BTreeMap<String, Rule>,
+    pub by_group: BTreeMap<String, Rule>,
+    /// User-defined groups (group name -> extensions).
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Inactive profiles. The active profile's rules are `by_ext` / `by_group`.
+    pub profiles: BTreeMap<String, Profile>,
+    /// `None` is the implicit `default` profile.
+    pub active_profile: Option<String>,
+}
+
+/// One extension's rule after group expansion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectiveRule {
+    pub ext: String,
+    pub rule: Rule,
+    /// The group this came from; `None` for a per-extension rule.
+    pub group: Option<String>,
+    /// Comes from the machine policy rather than the user's rules.json.
+    pub managed: bool,
+}
+
+/// The user's rules with the machine policy layered on top. Policy entries win per extension.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayeredRules {
+    pub user: RuleSet,
+    pub policy: RuleSet,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    /// Capture label to enforce. Empty for follow rules.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// Follow rule: enforce whatever app this extension (the leader) currently uses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow: Option<String>,
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub enabled: bool,
+    /// `None` follows the global `watch.monitor_only` setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<RuleMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<BackoffOverride>,
+    /// Seconds a tampered value must stay unchanged before it is restored. `None` follows
+    /// `watch.grace_secs`; `Some(0)` restores at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grace_secs: Option<u64>,
+    /// Process names that switch this rule to observe-only while any of them runs, on top of
+    /// `watch.pause_processes`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pause_for: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Other capture labels or literal ProgIds that also count as compliant. `name` stays the
+    /// restore target.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// Labels to restore to, in order, when `name`'s application is no longer installed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<String>,
+    /// Time windows that swap in a different label; see `schedule`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<ScheduleWindow>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleMode {
+    AutoRestore,
+    MonitorOnly,
+    /// Queue each tampered value in pending.json and restore only once approved.
+    Approve,
+}
+
+impl RuleMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::AutoRestore => "auto_restore",
+            Self::MonitorOnly => "monitor_only",
+            Self::Approve => "approve",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" | "auto_restore" | "auto-restore" => Some(Self::AutoRestore),
+            "monitor" | "monitor_only" | "monitor-only" => Some(Self::MonitorOnly),
+            "approve" => Some(Self::Approve),
+            _ => None,
+        }
+    }
+}
+
+/// How long a rule waits after a rejected apply; see `guard::Backoff`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackoffPolicy {
+    /// `base * 2^(failures-1)`, capped at `max`.
+    #[default]
+    Exponential,
+    /// `base * failures`, capped at `max`.
+    Linear,
+    /// Always `base`.
+    Fixed,
+    /// No automatic retries: monitor-only until seen OK or the cool-down ends.
+    Never,
+}
+
+impl BackoffPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Exponential => "exponential",
+            Self::Linear => "linear",
+            Self::Fixed => "fixed",
+            Self::Never => "never",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "exponential" | "exp" => Some(Self::Exponential),
+            "linear" => Some(Self::Linear),
+            "fixed" => Some(Self::Fixed),
+            "never" | "never_retry" | "never-retry" => Some(Self::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Per-rule backoff settings; each `None` falls back to the `watch.backoff_*` setting.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackoffOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<BackoffPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_secs: Option<u64>,
+    /// Automatic re-applies after the first rejection, each after its backoff.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// Once this long has passed since the last rejection, the rule starts over in
+    /// AUTO_RESTORE. `Some(0)` turns an inherited cool-down off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown_secs: Option<u64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn is_true(v: &bool) -> bool {
+    *v
+}
+
+impl Rule {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            follow: None,
+            enabled: true,
+            mode: None,
+            interval_secs: None,
+            backoff: None,
+            grace_secs: None,
+            pause_for: Vec::new(),
+            description: None,
+            allow: Vec::new(),
+            fallback: Vec::new(),
+            schedule: Vec::new(),
+        }
+    }
+
+    pub fn follow(leader_ext: &str) -> Self {
+        Self {
+            follow: Some(leader_ext.to_string()),
+            ..Self::new("")
+        }
+    }
+
+    /// `name` followed by the fallback labels.
+    pub fn chain(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str()).chain(self.fallback.iter().map(String::as_str))
+    }
+
+    /// The first label in the chain that `usable` accepts, with its position (0 = `name`).
+    pub fn select_target(&self, usable: impl Fn(&str) -> bool) -> Option<(usize, &str)> {
+        self.chain().enumerate().find(|(_, label)| usable(label))
+    }
+
+    /// Which accepted target `effective` satisfies: `target` (normally `name`, or a fallback
+    /// label), then each `allow` entry. A label is resolved to its captured ProgId via
+    /// `progid_for_label`; otherwise the entry is compared as a ProgId.
+    pub fn matched_target(
+        &self,
+        target: &str,
+        effective: Option<&str>,
+        progid_for_label: impl Fn(&str) -> Option<String>,
+    ) -> Option<String> {
+        crate::guard::matched_target(target, effective, &self.allow, progid_for_label)
+    }
+}
+
+/// v1 stored `".mp4": "vlc"`; v2 stores rule objects. Rewrites v1 entries in place.
+pub fn upgrade_rule_map(by_ext: &mut serde_json::Value) -> bool {
+    let mut changed = false;
+    if let Some(map) = by_ext.as_object_mut() {
+        for v in map.values_mut() {
+            if let Some(label) = v.as_str() {
+                *v = serde_json::json!({ "name": label });
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+pub fn is_group_key(key: &str) -> bool {
+    !key.starts_with('.')
+}
+
+pub fn normalize_name(name: &str) -> Result<String, String> {
+    let name = name.trim().to_ascii_lowercase();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("invalid name (use letters, digits, - or _)".to_string());
+    }
+    Ok(name)
+}
+
+/// `mp4` and `.mp4` both become `.mp4`, the form used as a key in every store.
+pub fn normalize_ext(ext: &str) -> Result<String, String> {
+    let ext = ext.trim();
+    let ext = ext.strip_prefix('.').unwrap_or(ext);
+    if ext.is_empty() || ext.contains(['\\', '/', '\0']) {
+        return Err("invalid extension".to_string());
+    }
+    Ok(format!(".{}", ext))
+}
+
+impl RuleSet {
+    fn map_for(&mut self, key: &str) -> &mut BTreeMap<String, Rule> {
+        if is_group_key(key) {
+            &mut self.by_group
+        } else {
+            &mut self.by_ext
+        }
+    }
+
+    pub fn active_profile_name(&self) -> &str {
+        self.active_profile.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
+
+    /// Profile names with their rule counts, active one included.
+    pub fn profile_summaries(&self) -> Vec<(String, usize, bool)> {
+        let mut out: BTreeMap<String, (usize, bool)> = self
+            .profiles
+            .iter()
+            .map(|(name, p)| (name.clone(), (p.by_ext.len() + p.by_group.len(), false)))
+            .collect();
+        out.insert(
+            self.active_profile_name().to_string(),
+            (self.by_ext.len() + self.by_group.len(), true),
+        );
+        out.into_iter()
+            .map(|(name, (count, active))| (name, count, active))
+            .collect()
+    }
+
+    /// Stores the active rules under their profile name and activates `name`.
+    /// Returns `false` if there is no such profile.
+    pub fn switch_profile(&mut self, name: &str) -> bool {
+        if name == self.active_profile_name() {
+            return true;
+        }
+        let Some(next) = self.profiles.remove(name) else {
+            return false;
+        };
+        let current = Profile {
+            by_ext: std::mem::replace(&mut self.by_ext, next.by_ext),
+            by_group: std::mem::replace(&mut self.by_group, next.by_group),
+        };
+        self.profiles
+            .insert(self.active_profile_name().to_string(), current);
+        self.active_profile = Some(name.to_string());
+        true
+    }
+
+    /// Members of a user-defined group, else of the built-in group with that name.
+    pub fn group_members(&self, group: &str) -> Option<Vec<String>> {
+        if let Some(exts) = self.groups.get(group) {
+            return Some(exts.clone());
+        }
+        BUILTIN_GROUPS
+            .iter()
+            .find(|(name, _)| *name == group)
+            .map(|(_, exts)| exts.iter().map(|e| e.to_string()).collect())
+    }
+
+    /// All group names, user-defined and built-in, with their members and whether they are built in.
+    pub fn all_groups(&self) -> Vec<(String, Vec<String>, bool)> {
+        let mut out: BTreeMap<String, (Vec<String>, bool)> = BUILTIN_GROUPS
+            .iter()
+            .map(|(name, exts)| {
+                (
+                    name.to_string(),
+                    (exts.iter().map(|e| e.to_string()).collect(), true),
+                )
+            })
+            .collect();
+        for (name, exts) in self.groups.iter() {
+            out.insert(name.clone(), (exts.clone(), false));
+        }
+        out.into_iter()
+            .map(|(name, (exts, builtin))| (name, exts, builtin))
+            .collect()
+    }
+
+    /// Per-extension rules win over group rules; when groups overlap, the first group by name wins.
+    pub fn expand(&self) -> Vec<EffectiveRule> {
+        let mut out: BTreeMap<String, EffectiveRule> = self
+            .by_ext
+            .iter()
+            .map(|(ext, rule)| {
+                (
+                    ext.clone(),
+                    EffectiveRule {
+                        ext: ext.clone(),
+                        rule: rule.clone(),
+                        group: None,
+                        managed: false,
+                    },
+                )
+            })
+            .collect();
+        for (group, rule) in self.by_group.iter() {
+            for ext in self.group_members(group).unwrap_or_default() {
+                out.entry(ext.clone()).or_insert_with(|| EffectiveRule {
+                    ext,
+                    rule: rule.clone(),
+                    group: Some(group.clone()),
+                    managed: false,
+                });
+            }
+        }
+        out.into_values().collect()
+    }
+}
+
+impl LayeredRules {
+    /// Policy rules replace user rules for every extension they cover (after group expansion).
+    pub fn expand(&self) -> Vec<EffectiveRule> {
+        let mut out: BTreeMap<String, EffectiveRule> = self
+            .user
+            .expand()
+            .into_iter()
+            .map(|r| (r.ext.clone(), r))
+            .collect();
+        for mut r in self.policy.expand() {
+            r.managed = true;
+            out.insert(r.ext.clone(), r);
+        }
+        out.into_values().collect()
+    }
+
+    /// Whether the policy owns `key`: a policy group rule, or an extension any policy rule covers.
+    pub fn is_managed(&self, key: &str) -> bool {
+        if is_group_key(key) {
+            return self.policy.by_group.contains_key(key);
+        }
+        self.policy.expand().iter().any(|r| r.ext == key)
+    }
+}
+
+/// Reads the machine policy. Same shape as rules.json or rules.toml (profiles are ignored), but
+/// unsigned: administrators write it and the integrity key is per user. A missing file is an empty policy.
+pub fn load_policy(path: &Path) -> std::io::Result<RuleSet> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(RuleSet::default()),
+        Err(e) => return Err(e),
+    };
+    let mut value = crate::integrity::parse_store(path, &bytes)?;
+    for key in ["by_ext", "by_group"] {
+        if let Some(map) = value.get_mut(key) {
+            upgrade_rule_map(map);
+        }
+    }
+    let store: RulesStore = serde_json::from_value(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(RuleSet {
+        by_ext: store.by_ext,
+        by_group: store.by_group,
+        groups: store.groups,
+        ..RuleSet::default()
+    })
+}
+
+/// The user's rules plus the policy at `policy_path`, if any.
+pub fn load_layered(
+    path: &Path,
+    key_path: &Path,
+    policy_path: Option<&Path>,
+) -> std::io::Result<LayeredRules> {
+    let user = load_rules(path, key_path)?;
+    let policy = match policy_path {
+        Some(p) => load_policy(p).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("policy {}: {}", p.to_string_lossy(), e))
+        })?,
+        None => RuleSet::default(),
+    };
+    Ok(LayeredRules { user, policy })
+}
+
+pub fn load_rules(path: &Path, key_path: &Path) -> std::io::Result<RuleSet> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(RuleSet::default()),
+        Err(e) => return Err(e),
+    };
+
+    let mut value = crate::integrity::verify_store_bytes(path, key_path, &bytes)
+        .map_err(|err| unsigned_v1_hint(path, &bytes, err))?;
+    let legacy =
+        value.get("version").and_then(|v| v.as_u64()).unwrap_or(0) < u64::from(RULES_VERSION);
+    if let Some(by_ext) = value.get_mut("by_ext") {
+        upgrade_rule_map(by_ext);
+    }
+    let store: RulesStore = serde_json::from_value(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let set = RuleSet {
+        by_ext: store.by_ext,
+        by_group: store.by_group,
+        groups: store.groups,
+        profiles: store.profiles,
+        active_profile: store.active_profile,
+    };
+    if legacy {
+        save_rules(path, key_path, &set)?;
+    }
+    Ok(set)
+}
+
+/// A version 1 rules.json predates signing, so it is unsigned like an edited one. Upgrading still
+/// takes a `fag integrity reseal`; the error says so instead of just "store is not signed".
+fn unsigned_v1_hint(path: &Path, bytes: &[u8], err: std::io::Error) -> std::io::Error {
+    let unsigned =
+        crate::integrity::as_tampered(&err).is_some_and(|t| t.reason == "store is not signed");
+    let version = crate::integrity::parse_store(path, bytes)
+        .ok()
+        .and_then(|v| v.get("version").and_then(|v| v.as_u64()))
+        .unwrap_or(0);
+    if !unsigned || version >= u64::from(RULES_VERSION) {
+        return err;
+    }
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        crate::integrity::StoreTampered {
+            path: path.to_path_buf(),
+            reason: "unsigned version 1 rules store; run fag integrity reseal once to upgrade it",
+        },
+    )
+}
+
+pub fn save_rules(path: &Path, key_path: &Path, set: &RuleSet) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let store = RulesStore {
+        version: RULES_VERSION,
+        by_ext: set.by_ext.clone(),
+        by_group: set.by_group.clone(),
+        groups: set.groups.clone(),
+        profiles: set.profiles.clone(),
+        active_profile: set.active_profile.clone(),
+    };
+    let bytes = crate::integrity::seal_store(path, key_path, &store)?;
+    std::fs::write(path, bytes)
+}
+
+/// Points an extension or group at a capture label, keeping the rule's other settings if it
+/// already exists.
+pub fn upsert_rule(path: &Path, key_path: &Path, key: &str, name: &str) -> std::io::Result<()> {
+    let mut set = load_rules(path, key_path)?;
+    set.map_for(key)
+        .entry(key.to_string())
+        .and_modify(|r| {
+            r.name = name.to_string();
+            r.follow = None;
+        })
+        .or_insert_with(|| Rule::new(name));
+    save_rules(path, key_path, &set)
+}
+
+/// Makes an extension or group follow `leader_ext`, keeping the rule's other settings.
+pub fn upsert_follow_rule(
+    path: &Path,
+    key_path: &Path,
+    key: &str,
+    leader_ext: &str,
+) -> std::io::Result<()> {
+    let mut set = load_rules(path, key_path)?;
+    set.map_for(key)
+        .entry(key.to_string())
+        .and_modify(|r| {
+            r.name.clear();
+            r.fallback.clear();
+            r.schedule.clear();
+            r.follow = Some(leader_ext.to_string());
+        })
+        .or_insert_with(|| Rule::follow(leader_ext));
+    save_rules(path, key_path, &set)
+}
+
+/// Applies `f` to an existing rule. Returns `false` if there is no rule for `key`.
+pub fn update_rule(
+    path: &Path,
+    key_path: &Path,
+    key: &str,
+    f: impl FnOnce(&mut Rule),
+) -> std::io::Result<bool> {
+    let mut set = load_rules(path, key_path)?;
+    let Some(rule) = set.map_for(key).get_mut(key) else {
+        return Ok(false);
+    };
+    f(rule);
+    save_rules(path, key_path, &set)?;
+    Ok(true)
+}
+
+pub fn remove_rule(path: &Path, key_path: &Path, key: &str) -> std::io::Result<bool> {
+    let mut set = load_rules(path, key_path)?;
+    let removed = set.map_for(key).remove(key).is_some();
+    save_rules(path, key_path, &set)?;
+    Ok(removed)
+}
+
+/// Copies the active rules into profile `name` (replacing it). Saving under the active name is a no-op.
+pub fn save_profile(path: &Path, key_path: &Path, name: &str) -> std::io::Result<()> {
+    let mut set = load_rules(path, key_path)?;
+    if name == set.active_profile_name() {
+        return Ok(());
+    }
+    let snapshot = Profile {
+        by_ext: set.by_ext.clone(),
+        by_group: set.by_group.clone(),
+    };
+    set.profiles.insert(name.to_string(), snapshot);
+    save_rules(path, key_path, &set)
+}
+
+/// Switches the active profile in a single store write. `None` if the profile does not exist.
+pub fn use_profile(path: &Path, key_path: &Path, name: &str) -> std::io::Result<Option<RuleSet>> {
+    let mut set = load_rules(path, key_path)?;
+    if !set.switch_profile(name) {
+        return Ok(None);
+    }
+    save_rules(path, key_path, &set)?;
+    Ok(Some(set))
+}
+
+/// Deletes an inactive profile. Returns `false` if there is no such inactive profile.
+pub fn delete_profile(path: &Path, key_path: &Path, name: &str) -> std::io::Result<bool> {
+    let mut set = load_rules(path, key_path)?;
+    let removed = set.profiles.remove(name).is_some();
+    if removed {
+        save_rules(path, key_path, &set)?;
+    }
+    Ok(removed)
+}
+
+/// Defines (or with `None`, deletes) a user group.
+pub fn set_group(
+    path: &Path,
+    key_path: &Path,
+    group: &str,
+    exts: Option<Vec<String>>,
+) -> std::io::Result<bool> {
+    let mut set = load_rules(path, key_path)?;
+    let changed = match exts {
+        Some(exts) => {
+            set.groups.insert(group.to_string(), exts);
+            true
+        }
+        None => set.groups.remove(group).is_some(),
+    };
+    save_rules(path, key_path, &set)?;
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A directory of its own per test, for the store and its integrity key.
+    fn temp_home(name: &str) -> PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let home = std::env::temp_dir().join(format!("fag-rules-{}-{}", name, nanos));
+        std::fs::create_dir_all(&home).unwrap();
+        home
+    }
+
+    #[test]
+    fn rules_roundtrip_upsert_remove_list() {
+        let home = temp_home("roundtrip");
+        let (path, key) = (home.join("rules.json"), home.join("integrity.key"));
+
+        upsert_rule(&path, &key, ".mp4", "vlc").unwrap();
+        upsert_rule(&path, &key, ".mkv", "potplayer").unwrap();
+
+        let items: Vec<_> = load_rules(&path, &key)
+            .unwrap()
+            .by_ext
+            .into_iter()
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                (".mkv".to_string(), Rule::new("potplayer")),
+                (".mp4".to_string(), Rule::new("vlc"))
+            ]
+        );
+
+        assert!(remove_rule(&path, &key, ".mp4").unwrap());
+        assert!(!remove_rule(&path, &key, ".mp4").unwrap());
+        upsert_rule(&path, &key, "video", "vlc").unwrap();
+        let set = load_rules(&path, &key).unwrap();
+        assert_eq!(
+            set.by_ext.into_iter().collect::<Vec<_>>(),
+            vec![(".mkv".to_string(), Rule::new("potplayer"))]
+        );
+        assert_eq!(set.by_group.get("video"), Some(&Rule::new("vlc")));
+
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn v1_rules_are_migrated_and_settings_survive_relabel() {
+        let home = temp_home("v1");
+        let (path, key) = (home.join("rules.json"), home.join("integrity.key"));
+        let mut v1 = serde_json::json!({"version": 1, "by_ext": {".mp4": "vlc"}});
+        let secret = crate::integrity::load_or_create_key(&key).unwrap();
+        crate::integrity::seal_value(&mut v1, &secret);
+        std::fs::write(&path, serde_json::to_vec(&v1).unwrap()).unwrap();
+
+        let rules = load_rules(&path, &key).unwrap();
+        assert_eq!(rules.by_ext.get(".mp4"), Some(&Rule::new("vlc")));
+        let on_disk: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(on_disk["version"], 2);
+        assert_eq!(on_disk["by_ext"][".mp4"]["name"], "vlc");
+
+        assert!(update_rule(&path, &key, ".mp4", |r| {
+            r.enabled = false;
+            r.mode = Some(RuleMode::MonitorOnly);
+            r.interval_secs = Some(30);
+        })
+        .unwrap());
+        assert!(!update_rule(&path, &key, ".avi", |r| r.enabled = false).unwrap());
+
+        upsert_rule(&path, &key, ".mp4", "potplayer").unwrap();
+        let rule = load_rules(&path, &key)
+            .unwrap()
+            .by_ext
+            .remove(".mp4")
+            .unwrap();
+        assert_eq!(rule.name, "potplayer");
+        assert!(!rule.enabled);
+        assert_eq!(rule.mode, Some(RuleMode::MonitorOnly));
+        assert_eq!(rule.interval_secs, Some(30));
+
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn unsigned_v1_rules_wait_for_a_reseal_then_migrate() {
+        let home = temp_home("v1-unsigned");
+        let (path, key) = (home.join("rules.json"), home.join("integrity.key"));
+        let v1 = br#"{"version": 1, "by_ext": {".mp4": "vlc"}}"#;
+        std::fs::write(&path, v1).unwrap();
+
+        let err = load_rules(&path, &key).unwrap_err();
+        let reason = crate::integrity::as_tampered(&err).unwrap().reason;
+        assert!(reason.contains("fag integrity reseal"), "{}", reason);
+        assert_eq!(std::fs::read(&path).unwrap(), v1);
+        assert!(!key.exists());
+
+        assert!(crate::integrity::reseal_file(&path, &key).unwrap());
+        let rules = load_rules(&path, &key).unwrap();
+        assert_eq!(rules.by_ext.get(".mp4"), Some(&Rule::new("vlc")));
+        let on_disk: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(on_disk["version"], 2);
+        assert!(load_rules(&path, &key).is_ok());
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn groups_expand_with_per_ext_overrides() {
+        let mut set = RuleSet::default();
+        set.by_group.insert("video".to_string(), Rule::new("vlc"));
+        set.by_ext
+            .insert(".mkv".to_string(), Rule::new("potplayer"));
+        set.groups.insert(
+            "raw".to_string(),
+            vec![".cr2".to_string(), ".mp4".to_string()],
+        );
+        set.by_group
+            .insert("raw".to_string(), Rule::new("darktable"));
+
+        let expanded = set.expand();
+        let find = |ext: &str| expanded.iter().find(|r| r.ext == ext).cloned().unwrap();
+        assert_eq!(find(".mkv").rule.name, "potplayer");
+        assert_eq!(find(".mkv").group, None);
+        assert_eq!(find(".avi").rule.name, "vlc");
+        assert_eq!(find(".avi").group.as_deref(), Some("video"));
+        // ".mp4" is in both groups: "raw" sorts first.
+        assert_eq!(find(".mp4").rule.name, "darktable");
+        assert_eq!(find(".cr2").group.as_deref(), Some("raw"));
+
+        set.groups
+            .insert("video".to_string(), vec![".webm".to_string()]);
+        let expanded = set.expand();
+        assert!(expanded.iter().any(|r| r.ext == ".webm"));
+        assert!(!expanded.iter().any(|r| r.ext == ".avi"));
+
+        assert_eq!(normalize_name(" Video ").unwrap(), "video");
+        assert!(normalize_name(".mp4").is_err());
+    }
+
+    #[test]
+    fn toml_rules_keep_hand_written_comments() {
+        let home = temp_home("toml");
+        let (path, key) = (home.join("rules.toml"), home.join("integrity.key"));
+        upsert_rule(&path, &key, ".mp4", "vlc").unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("# chosen by IT\n{}", text)).unwrap();
+
+        // Comments are not part of the signed data.
+        upsert_rule(&path, &key, ".mkv", "potplayer").unwrap();
+        update_rule(&path, &key, ".mp4", |r| r.interval_secs = Some(30)).unwrap();
+        let set = load_rules(&path, &key).unwrap();
+        assert_eq!(set.by_ext.len(), 2);
+        assert_eq!(set.by_ext[".mp4"].interval_secs, Some(30));
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# chosen by IT\n"));
+        assert!(text.contains("[by_ext.\".mkv\"]\nname = \"potplayer\""));
+
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn policy_rules_win_over_user_rules() {
+        let home = temp_home("policy");
+        let (path, key) = (home.join("rules.json"), home.join("integrity.key"));
+        let policy_path = home.join("policy.json");
+        std::fs::write(
+            &policy_path,
+            br#"{"by_ext": {".mp4": "vlc"}, "by_group": {"audio": {"name": "foobar"}}}"#,
+        )
+        .unwrap();
+        upsert_rule(&path, &key, ".mp4", "potplayer").unwrap();
+        upsert_rule(&path, &key, ".mkv", "potplayer").unwrap();
+
+        let layered = load_layered(&path, &key, Some(&policy_path)).unwrap();
+        let expanded = layered.expand();
+        let find = |ext: &str| expanded.iter().find(|r| r.ext == ext).cloned().unwrap();
+        assert_eq!(find(".mp4").rule.name, "vlc");
+        assert!(find(".mp4").managed);
+        assert_eq!(find(".mkv").rule.name, "potplayer");
+        assert!(!find(".mkv").managed);
+        assert_eq!(find(".flac").rule.name, "foobar");
+
+        assert!(layered.is_managed(".mp4"));
+        assert!(layered.is_managed(".flac"));
+        assert!(layered.is_managed("audio"));
+        assert!(!layered.is_managed(".mkv"));
+        assert!(!layered.is_managed("video"));
+
+        let missing = load_layered(&path, &key, Some(&home.join("none.json"))).unwrap();
+        assert!(!missing.is_managed(".mp4"));
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn profiles_swap_active_rules() {
+        let home = temp_home("profiles");
+        let (path, key) = (home.join("rules.json"), home.join("integrity.key"));
+        upsert_rule(&path, &key, "video", "vlc").unwrap();
+        save_profile(&path, &key, "review").unwrap();
+        assert!(use_profile(&path, &key, "missing").unwrap().is_none());
+
+        let set = use_profile(&path, &key, "review").unwrap().unwrap();
+        assert_eq!(set.active_profile_name(), "review");
+        upsert_rule(&path, &key, "video", "potplayer").unwrap();
+
+        let set = use_profile(&path, &key, DEFAULT_PROFILE).unwrap().unwrap();
+        assert_eq!(set.by_group["video"].name, "vlc");
+        assert_eq!(set.profiles["review"].by_group["video"].name, "potplayer");
+        assert_eq!(
+            set.profile_summaries(),
+            vec![
+                ("default".to_string(), 1, true),
+                ("review".to_string(), 1, false)
+            ]
+        );
+
+        assert!(!delete_profile(&path, &key, DEFAULT_PROFILE).unwrap());
+        assert!(delete_profile(&path, &key, "review").unwrap());
+
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn allow_list_matches_labels_and_progids() {
+        let mut rule = Rule::new("vlc");
+        rule.allow = vec!["potplayer".to_string(), "MPC-HC.mp4".to_string()];
+        let captured = |label: &str| match label {
+            "vlc" => Some("VLC.mp4".to_string()),
+            "potplayer" => Some("PotPlayerMini64.MP4".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            rule.matched_target("vlc", Some("VLC.mp4"), captured)
+                .as_deref(),
+            Some("vlc")
+        );
+        assert_eq!(
+            rule.matched_target("vlc", Some("PotPlayerMini64.MP4"), captured)
+                .as_deref(),
+            Some("potplayer")
+        );
+        assert_eq!(
+            rule.matched_target("vlc", Some("mpc-hc.MP4"), captured)
+                .as_deref(),
+            Some("MPC-HC.mp4")
+        );
+        assert_eq!(rule.matched_target("vlc", Some("AppX123"), captured), None);
+        assert_eq!(rule.matched_target("vlc", None, captured), None);
+    }
+
+    #[test]
+    fn fallback_chain_skips_unusable_targets() {
+        let mut rule = Rule::new("vlc");
+        rule.fallback = vec!["potplayer".to_string(), "mpc-hc".to_string()];
+        assert_eq!(rule.select_target(|_| true), Some((0, "vlc")));
+        assert_eq!(rule.select_target(|l| l != "vlc"), Some((1, "potplayer")));
+        assert_eq!(rule.select_target(|l| l == "mpc-hc"), Some((2, "mpc-hc")));
+        assert_eq!(rule.select_target(|_| false), None);
+    }
+}
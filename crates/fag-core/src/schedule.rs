@@ -0,0 +1,311 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rules::Rule;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+pub const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// While active, the rule enforces `name` instead of its own label.
+///
+/// `days` is a list such as `mon-fri`, `sat,sun` or `*`. `from`/`to` are local `HH:MM`; a window
+/// with `from` after `to` runs overnight and belongs to the day it starts on. `from == to` covers
+/// the whole day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleWindow {
+    pub days: String,
+    pub from: String,
+    pub to: String,
+    pub name: String,
+}
+
+/// A point in time plus the local UTC offset it should be read in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LocalTime {
+    pub unix_secs: i64,
+    pub utc_offset_secs: i32,
+}
+
+impl LocalTime {
+    fn local_secs(self) -> i64 {
+        self.unix_secs + i64::from(self.utc_offset_secs)
+    }
+
+    /// 0 = Monday.
+    pub fn weekday(self) -> usize {
+        // 1970-01-01 was a Thursday.
+        (self.local_secs().div_euclid(86_400) + 3).rem_euclid(7) as usize
+    }
+
+    pub fn minute_of_day(self) -> u32 {
+        (self.local_secs().rem_euclid(86_400) / 60) as u32
+    }
+
+    pub fn plus_minutes(self, minutes: i64) -> Self {
+        Self {
+            unix_secs: self.unix_secs + minutes * 60,
+            ..self
+        }
+    }
+
+    /// `YYYY-MM-DD HH:MM ddd` in local time.
+    pub fn format(self) -> String {
+        let days = self.local_secs().div_euclid(86_400);
+        let (y, m, d) = civil_from_days(days);
+        let minute = self.minute_of_day();
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02} {}",
+            y,
+            m,
+            d,
+            minute / 60,
+            minute % 60,
+            DAY_NAMES[self.weekday()]
+        )
+    }
+}
+
+pub trait Clock {
+    fn now(&self) -> LocalTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> LocalTime {
+        LocalTime {
+            unix_secs: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+            utc_offset_secs: crate::localtime::utc_offset_secs(),
+        }
+    }
+}
+
+pub fn parse_days(spec: &str) -> Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    for part in spec.split(',').map(|p| p.trim().to_ascii_lowercase()) {
+        if matches!(part.as_str(), "*" | "all" | "daily") {
+            days = [true; 7];
+            continue;
+        }
+        let day = |s: &str| {
+            DAY_NAMES
+                .iter()
+                .position(|d| s.starts_with(d))
+                .ok_or_else(|| format!("unknown day {:?} (use mon..sun, mon-fri, *)", s))
+        };
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let (start, end) = (day(a.trim())?, day(b.trim())?);
+                let mut i = start;
+                loop {
+                    days[i] = true;
+                    if i == end {
+                        break;
+                    }
+                    i = (i + 1) % 7;
+                }
+            }
+            None => days[day(&part)?] = true,
+        }
+    }
+    if !days.contains(&true) {
+        return Err("no days given".to_string());
+    }
+    Ok(days)
+}
+
+pub fn parse_hhmm(s: &str) -> Result<u32, String> {
+    let err = || format!("invalid time {:?} (use HH:MM)", s);
+    let (h, m) = s.trim().split_once(':').ok_or_else(err)?;
+    let (h, m): (u32, u32) = (h.parse().map_err(|_| err())?, m.parse().map_err(|_| err())?);
+    if m >= 60 || h > 24 || (h == 24 && m != 0) {
+        return Err(err());
+    }
+    Ok(h * 60 + m)
+}
+
+impl ScheduleWindow {
+    pub fn validate(&self) -> Result<(), String> {
+        parse_days(&self.days)?;
+        parse_hhmm(&self.from)?;
+        parse_hhmm(&self.to)?;
+        Ok(())
+    }
+
+    /// Invalid windows (hand-edited rules.json) never match.
+    pub fn contains(&self, t: LocalTime) -> bool {
+        let (Ok(days), Ok(from), Ok(to)) = (
+            parse_days(&self.days),
+            parse_hhmm(&self.from),
+            parse_hhmm(&self.to),
+        ) else {
+            return false;
+        };
+        let (day, minute) = (t.weekday(), t.minute_of_day());
+        let yesterday = (day + 6) % 7;
+        match from.cmp(&to) {
+            std::cmp::Ordering::Equal => days[day],
+            std::cmp::Ordering::Less => days[day] && (from..to).contains(&minute),
+            std::cmp::Ordering::Greater => {
+                (days[day] && minute >= from) || (days[yesterday] && minute < to)
+            }
+        }
+    }
+}
+
+/// The label the rule enforces at `t`: the first matching window, else the rule's own label.
+pub fn active_label(rule: &Rule, t: LocalTime) -> &str {
+    rule.schedule
+        .iter()
+        .find(|w| w.contains(t))
+        .map(|w| w.name.as_str())
+        .unwrap_or(&rule.name)
+}
+
+/// The rule with its label replaced by the one active at `t`.
+pub fn apply(rule: &Rule, t: LocalTime) -> Rule {
+    let mut scheduled = rule.clone();
+    if rule.follow.is_none() {
+        scheduled.name = active_label(rule, t).to_string();
+    }
+    scheduled
+}
+
+/// Label changes within `horizon_minutes` after `start`, as `(time, from, to)`. Uses the offset of
+/// `start` throughout, so a DST change inside the horizon shifts later entries by an hour.
+pub fn transitions(
+    rule: &Rule,
+    start: LocalTime,
+    horizon_minutes: u32,
+) -> Vec<(LocalTime, String, String)> {
+    let mut out = Vec::new();
+    let mut current = active_label(rule, start).to_string();
+    // Align to whole minutes so transitions land on the boundary itself.
+    let first = LocalTime {
+        unix_secs: start.unix_secs - start.unix_secs.rem_euclid(60),
+        ..start
+    };
+    for i in 1..=i64::from(horizon_minutes) {
+        let t = first.plus_minutes(i);
+        let label = active_label(rule, t);
+        if label != current {
+            out.push((t, current, label.to_string()));
+            current = label.to_string();
+        }
+    }
+    out
+}
+
+// Howard Hinnant's days-to-civil algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedClock(LocalTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> LocalTime {
+            self.0
+        }
+    }
+
+    // 2026-10-19 is a Monday; 00:00 UTC.
+    const MONDAY: i64 = 1_792_368_000;
+
+    fn at(day: i64, hh: i64, mm: i64) -> LocalTime {
+        LocalTime {
+            unix_secs: MONDAY + day * 86_400 + hh * 3600 + mm * 60,
+            utc_offset_secs: 0,
+        }
+    }
+
+    fn lab_rule() -> Rule {
+        let mut rule = Rule::new("vlc");
+        rule.schedule = vec![
+            ScheduleWindow {
+                days: "mon-fri".to_string(),
+                from: "08:00".to_string(),
+                to: "18:00".to_string(),
+                name: "potplayer".to_string(),
+            },
+            ScheduleWindow {
+                days: "fri".to_string(),
+                from: "22:00".to_string(),
+                to: "02:00".to_string(),
+                name: "mpc-hc".to_string(),
+            },
+        ];
+        rule
+    }
+
+    #[test]
+    fn windows_pick_labels_by_weekday_and_time() {
+        let rule = lab_rule();
+        assert_eq!(at(0, 0, 0).format(), "2026-10-19 00:00 mon");
+        assert_eq!(active_label(&rule, at(0, 7, 59)), "vlc");
+        assert_eq!(active_label(&rule, at(0, 8, 0)), "potplayer");
+        assert_eq!(active_label(&rule, at(0, 18, 0)), "vlc");
+        // Friday overnight window spills into Saturday morning.
+        assert_eq!(active_label(&rule, at(4, 23, 0)), "mpc-hc");
+        assert_eq!(active_label(&rule, at(5, 1, 59)), "mpc-hc");
+        assert_eq!(active_label(&rule, at(5, 2, 0)), "vlc");
+        assert_eq!(active_label(&rule, at(5, 12, 0)), "vlc");
+
+        // Local offset shifts the window: 08:30 at UTC+8 is 00:30 UTC.
+        let shifted = LocalTime {
+            unix_secs: at(0, 0, 30).unix_secs,
+            utc_offset_secs: 8 * 3600,
+        };
+        assert_eq!(active_label(&rule, shifted), "potplayer");
+    }
+
+    #[test]
+    fn transitions_follow_the_clock() {
+        let rule = lab_rule();
+        let clock = FixedClock(at(0, 7, 30));
+        let upcoming = transitions(&rule, clock.now(), MINUTES_PER_DAY);
+        let got: Vec<_> = upcoming
+            .iter()
+            .map(|(t, from, to)| (t.format(), from.as_str(), to.as_str()))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                ("2026-10-19 08:00 mon".to_string(), "vlc", "potplayer"),
+                ("2026-10-19 18:00 mon".to_string(), "potplayer", "vlc"),
+            ]
+        );
+        assert_eq!(apply(&rule, at(0, 9, 0)).name, "potplayer");
+    }
+
+    #[test]
+    fn day_and_time_specs() {
+        assert_eq!(
+            parse_days("sat,sun").unwrap(),
+            [false, false, false, false, false, true, true]
+        );
+        assert_eq!(
+            parse_days("fri-mon").unwrap(),
+            [true, false, false, false, true, true, true]
+        );
+        assert_eq!(parse_days("*").unwrap(), [true; 7]);
+        assert!(parse_days("funday").is_err());
+        assert_eq!(parse_hhmm("24:00").unwrap(), 1440);
+        assert!(parse_hhmm("7:60").is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Ctrl+C, closing the console window, SIGTERM and SIGHUP as a flag the watch loops poll, so they
+//! can save their state and log a summary instead of dying mid-tick.
+//!
+//! A second Ctrl+C while the first is still being handled exits at once.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static FINISHED: AtomicBool = AtomicBool::new(false);
+
+/// Routes the stop signals to [`requested`]. Until this is called they kill the process as usual.
+pub fn install() -> Result<(), String> {
+    platform::install()
+}
+
+/// Whether the process was asked to stop.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Asks the watch loop to stop, as a signal would.
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Tells a pending console-close handler that state is saved and the process may end.
+pub fn finished() {
+    FINISHED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::REQUESTED;
+    use std::sync::atomic::Ordering;
+
+    const SIGHUP: i32 = 1;
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    const SIG_ERR: usize = usize::MAX;
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+
+    /// Only async-signal-safe work here: an atomic swap, or `_exit` for the second signal.
+    extern "C" fn on_signal(signum: i32) {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            unsafe { _exit(128 + signum) };
+        }
+    }
+
+    pub fn install() -> Result<(), String> {
+        for signum in [SIGHUP, SIGINT, SIGTERM] {
+            if unsafe { signal(signum, on_signal) } == SIG_ERR {
+                return Err(format!("signal({}) failed", signum));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{FINISHED, REQUESTED};
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    type BOOL = i32;
+
+    const CTRL_C_EVENT: u32 = 0;
+    const CTRL_BREAK_EVENT: u32 = 1;
+
+    #[link(name = "Kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> BOOL>,
+            add: BOOL,
+        ) -> BOOL;
+    }
+
+    /// Runs on its own thread. For Ctrl+C/Ctrl+Break the loop stops by itself; for console close,
+    /// logoff and shutdown Windows ends the process as soon as this returns, so it waits (within
+    /// the system's ~5 s allowance) for the loop to save its state.
+    unsafe extern "system" fn on_ctrl(ctrl_type: u32) -> BOOL {
+        let again = REQUESTED.swap(true, Ordering::SeqCst);
+        match ctrl_type {
+            // A second Ctrl+C falls through to the default handler, which exits.
+            CTRL_C_EVENT | CTRL_BREAK_EVENT => i32::from(!again),
+            _ => {
+                let started = Instant::now();
+                while !FINISHED.load(Ordering::SeqCst) && started.elapsed() < Duration::from_secs(4)
+                {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                1
+            }
+        }
+    }
+
+    pub fn install() -> Result<(), String> {
+        if unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) } == 0 {
+            let code = unsafe { crate::registry::windows_last_error() };
+            return Err(format!("SetConsoleCtrlHandler failed with {}", code));
+        }
+        Ok(())
+    }
+}
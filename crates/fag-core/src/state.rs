@@ -0,0 +1,151 @@
+//! state.json: what `watch-rules` carries over a restart (see [`SavedState`]). Sealed like the
+//! other stores, since a forged backoff would quietly stop restores.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::guard::{RejectState, SavedState};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    #[serde(default)]
+    saved_unix_ms: u64,
+    #[serde(default)]
+    rejected: BTreeMap<String, SavedRejection>,
+    #[serde(default)]
+    last_emitted: BTreeMap<String, SavedStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedRejection {
+    failures: u32,
+    /// Missing in files written before cool-downs existed; the retry time stands in for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rejected_at_unix_ms: Option<u64>,
+    retry_at_unix_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedStatus {
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    effective_progid: Option<String>,
+}
+
+/// The saved state; empty when there is no file yet.
+pub fn load_state(path: &Path, key_path: &Path) -> std::io::Result<SavedState> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(SavedState::default()),
+        Err(e) => return Err(e),
+    };
+    let value = crate::integrity::verify_store_bytes(path, key_path, &bytes)?;
+    let file: StateFile = serde_json::from_value(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(SavedState {
+        rejected: file
+            .rejected
+            .into_iter()
+            .map(|(k, r)| {
+                let rejected_at = r.rejected_at_unix_ms.unwrap_or(r.retry_at_unix_ms);
+                let state = RejectState {
+                    failures: r.failures,
+                    rejected_at_ms: u128::from(rejected_at),
+                    retry_at_ms: u128::from(r.retry_at_unix_ms),
+                };
+                (k, state)
+            })
+            .collect(),
+        last_emitted: file
+            .last_emitted
+            .into_iter()
+            .map(|(k, s)| (k, (s.status, s.effective_progid)))
+            .collect(),
+    })
+}
+
+pub fn save_state(
+    path: &Path,
+    key_path: &Path,
+    state: &SavedState,
+    now_ms: u128,
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = StateFile {
+        version: 1,
+        saved_unix_ms: u64::try_from(now_ms).unwrap_or(u64::MAX),
+        rejected: state
+            .rejected
+            .iter()
+            .map(|(k, r)| {
+                let unix_ms = |ms: u128| u64::try_from(ms).unwrap_or(u64::MAX);
+                let saved = SavedRejection {
+                    failures: r.failures,
+                    rejected_at_unix_ms: Some(unix_ms(r.rejected_at_ms)),
+                    retry_at_unix_ms: unix_ms(r.retry_at_ms),
+                };
+                (k.clone(), saved)
+            })
+            .collect(),
+        last_emitted: state
+            .last_emitted
+            .iter()
+            .map(|(k, (status, effective))| {
+                let saved = SavedStatus {
+                    status: status.clone(),
+                    effective_progid: effective.clone(),
+                };
+                (k.clone(), saved)
+            })
+            .collect(),
+    };
+    let bytes = crate::integrity::seal_store(path, key_path, &file)?;
+    std::fs::write(path, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_refuses_edits() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let home = std::env::temp_dir().join(format!("fag-state-{}", nanos));
+        std::fs::create_dir_all(&home).unwrap();
+        let (path, key) = (home.join("state.json"), home.join("integrity.key"));
+        assert_eq!(load_state(&path, &key).unwrap(), SavedState::default());
+
+        let mut state = SavedState::default();
+        state.rejected.insert(
+            ".mp4|vlc".to_string(),
+            RejectState {
+                failures: 2,
+                rejected_at_ms: 1_792_000_000_000,
+                retry_at_ms: 1_792_000_060_000,
+            },
+        );
+        state.last_emitted.insert(
+            ".mp4|vlc".to_string(),
+            ("REJECTED".to_string(), Some("Hijack.mp4".to_string())),
+        );
+        save_state(&path, &key, &state, 1_792_000_000_000).unwrap();
+        assert_eq!(load_state(&path, &key).unwrap(), state);
+
+        // Pushing the retry out by hand breaks the seal.
+        let edited = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("1792000060000", "9792000060000");
+        std::fs::write(&path, edited).unwrap();
+        let err = load_state(&path, &key).unwrap_err();
+        assert!(crate::integrity::as_tampered(&err).is_some());
+        let _ = std::fs::remove_dir_all(&home);
+    }
+}
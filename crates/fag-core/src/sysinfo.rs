@@ -28,7 +28,7 @@ impl std::error::Error for SysinfoError {}
 pub fn read_sysinfo() -> Result<Sysinfo, SysinfoError> {
     #[cfg(not(windows))]
     {
-        return Err(SysinfoError::WindowsOnly);
+        Err(SysinfoError::WindowsOnly)
     }
 
     #[cfg(windows)]
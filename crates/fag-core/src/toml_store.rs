@@ -0,0 +1,174 @@
+use std::path::Path;
+
+use serde_json::{Map, Value};
+use toml_edit::{DocumentMut, Item, Table, TableLike};
+
+/// Stores whose path ends in `.toml` are read and written as TOML; everything else is JSON.
+pub fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+}
+
+pub fn parse(bytes: &[u8]) -> std::io::Result<DocumentMut> {
+    let text = std::str::from_utf8(bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    text.parse::<DocumentMut>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// The document's data as JSON, ignoring comments and layout.
+pub fn to_json(doc: &DocumentMut) -> Value {
+    table_to_json(doc.as_table())
+}
+
+/// Makes `doc` hold `value` (a JSON object), keeping comments and formatting of entries whose
+/// data did not change. New entries are laid out as `[section."key"]` tables two levels deep and
+/// inline below that.
+pub fn sync(doc: &mut DocumentMut, value: &Value) {
+    if let Some(map) = value.as_object() {
+        sync_table(doc.as_table_mut(), map, 0);
+    }
+}
+
+fn table_to_json(table: &dyn TableLike) -> Value {
+    Value::Object(
+        table
+            .iter()
+            .filter_map(|(k, item)| Some((k.to_string(), item_to_json(item)?)))
+            .collect(),
+    )
+}
+
+fn item_to_json(item: &Item) -> Option<Value> {
+    match item {
+        Item::None => None,
+        Item::Value(v) => Some(value_to_json(v)),
+        Item::Table(t) => Some(table_to_json(t)),
+        Item::ArrayOfTables(a) => Some(Value::Array(a.iter().map(|t| table_to_json(t)).collect())),
+    }
+}
+
+fn value_to_json(v: &toml_edit::Value) -> Value {
+    match v {
+        toml_edit::Value::String(s) => Value::String(s.value().clone()),
+        toml_edit::Value::Integer(i) => Value::from(*i.value()),
+        toml_edit::Value::Float(f) => serde_json::Number::from_f64(*f.value())
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        toml_edit::Value::Boolean(b) => Value::Bool(*b.value()),
+        toml_edit::Value::Datetime(d) => Value::String(d.value().to_string()),
+        toml_edit::Value::Array(a) => Value::Array(a.iter().map(value_to_json).collect()),
+        toml_edit::Value::InlineTable(t) => table_to_json(t),
+    }
+}
+
+fn sync_table(table: &mut dyn TableLike, value: &Map<String, Value>, depth: usize) {
+    let stale: Vec<String> = table
+        .iter()
+        .map(|(k, _)| k.to_string())
+        .filter(|k| value.get(k).is_none_or(Value::is_null))
+        .collect();
+    for k in stale {
+        table.remove(&k);
+    }
+    // Scalars first, so a fresh document starts with `version` rather than a section.
+    let ordered = value
+        .iter()
+        .filter(|(_, v)| !v.is_object())
+        .chain(value.iter().filter(|(_, v)| v.is_object()));
+    for (k, v) in ordered {
+        if v.is_null() {
+            continue;
+        }
+        match table.get_mut(k) {
+            Some(item) if item_to_json(item).as_ref() == Some(v) => {}
+            Some(item) if item.is_table_like() && v.is_object() => {
+                if let (Some(t), Some(map)) = (item.as_table_like_mut(), v.as_object()) {
+                    sync_table(t, map, depth + 1);
+                }
+            }
+            Some(Item::Value(old)) if !v.is_object() => {
+                let decor = old.decor().clone();
+                *old = json_to_value(v);
+                *old.decor_mut() = decor;
+            }
+            Some(item) => *item = new_item(v, depth + 1),
+            None => {
+                table.insert(k, new_item(v, depth + 1));
+            }
+        }
+    }
+}
+
+fn new_item(v: &Value, depth: usize) -> Item {
+    match v.as_object() {
+        Some(map) if depth <= 2 => {
+            let mut table = Table::new();
+            // `[by_ext]` alone adds nothing when every entry gets its own `[by_ext."…"]` header.
+            table.set_implicit(!map.is_empty() && map.values().all(Value::is_object));
+            sync_table(&mut table, map, depth);
+            Item::Table(table)
+        }
+        _ => Item::Value(json_to_value(v)),
+    }
+}
+
+fn json_to_value(v: &Value) -> toml_edit::Value {
+    match v {
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().unwrap_or_default().into(),
+        },
+        Value::String(s) => s.as_str().into(),
+        Value::Array(a) => toml_edit::Value::Array(a.iter().map(json_to_value).collect()),
+        Value::Object(map) => toml_edit::Value::InlineTable(
+            map.iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.as_str(), json_to_value(v)))
+                .collect(),
+        ),
+        // TOML has no null; callers skip null entries.
+        Value::Null => "".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_keeps_comments_on_untouched_entries() {
+        let text = r#"# FileAssocGuard rules
+version = 2
+
+# Work laptop: always VLC
+[by_ext.".mp4"]
+name = "vlc" # do not change
+interval_secs = 30
+
+[by_ext.".mkv"]
+name = "vlc"
+"#;
+        let mut doc = parse(text.as_bytes()).unwrap();
+        let mut value = to_json(&doc);
+        assert_eq!(value["by_ext"][".mp4"]["interval_secs"], 30);
+
+        value["by_ext"][".mp4"]["interval_secs"] = 60.into();
+        value["by_ext"][".mkv"] = serde_json::json!({"name": "potplayer", "allow": ["vlc"]});
+        value["by_ext"][".avi"] = serde_json::json!({"name": "vlc", "backoff": {"max_secs": 60}});
+        sync(&mut doc, &value);
+
+        let out = doc.to_string();
+        assert!(out.starts_with("# FileAssocGuard rules\nversion = 2\n"));
+        assert!(out.contains("# Work laptop: always VLC\n[by_ext.\".mp4\"]"));
+        assert!(out.contains("name = \"vlc\" # do not change\ninterval_secs = 60\n"));
+        assert!(out.contains("[by_ext.\".avi\"]\nname = \"vlc\"\nbackoff = { max_secs = 60 }\n"));
+        assert_eq!(to_json(&parse(out.as_bytes()).unwrap()), value);
+
+        let mut fresh = DocumentMut::new();
+        sync(&mut fresh, &value);
+        assert_eq!(to_json(&fresh), value);
+        assert!(fresh.to_string().starts_with("version = 2\n"));
+    }
+}
@@ -0,0 +1,1600 @@
+//! The watch loop behind `fag watch-rules` and `fag watch`: reloads the stores, applies schedules,
+//! follow rules, fallbacks, process holds and the approval queue, steps the [`Engine`] for every
+//! rule that is due, and answers `fag ctl` requests between ticks.
+//!
+//! Everything the loop has to say comes out as [`Output`]: [`WatchEvent`]s, which serialize to
+//! the JSON lines `fag` prints and are written to the event log as they happen, and warnings
+//! about the watcher itself for stderr.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::api::{FollowUnresolved, Guard, Resolved};
+use crate::control::{self, Command, ControlServer, Request, Woke};
+use crate::guard::{
+    Backoff, Check, Clock, Engine, EventKind, Phase, Registry, SavedState, SystemClock,
+    SystemRegistry,
+};
+use crate::instance::InstanceLock;
+use crate::integrity::StoreTampered;
+use crate::notify::{self, ChangeNotifier, PollingNotifier, Wait};
+use crate::pending::{self, PendingItem, PendingQueue};
+use crate::processes::{self, HoldChange, ProcessHold};
+use crate::reload::{Changes, Reload, Reloader, WatchOptions};
+use crate::rules::{BackoffOverride, EffectiveRule, Rule, RuleMode};
+use crate::schedule::{self, LocalTime};
+use crate::{shutdown, state};
+
+const REJECTED_HINT: &str = "系统拒绝/回滚了写入：后续改为只提示不自动改。建议去 Windows 设置里手动改回默认程序，然后再运行 fag capture-latest（可更新抓取）";
+const REJECTED_RETRY_HINT: &str = "系统拒绝/回滚了写入：退避结束后会自动再试；一直失败的话同样建议去 Windows 设置里手动改回默认程序";
+const FLAPPING_HINT: &str = "另一个程序在反复抢回关联：先只提示不自动改，它安静 quiet_secs 秒后恢复守护。建议找出并关掉它（competing_progid）";
+const TARGET_UNAVAILABLE_HINT: &str =
+    "首选程序的 ProgId 已不在 HKCR（可能已卸载）：暂时改用后备目标；重新安装后会自动切回";
+const CONFIG_TAMPERED_HINT: &str =
+    "配置文件可能被其他程序改写：已停止按该文件执行恢复。确认是你自己改的后运行 fag integrity reseal";
+const CONFIG_RELOAD_FAILED_HINT: &str =
+    "新内容无法读取：继续按上一次有效的内容守护；改好文件后会自动重新加载";
+
+const OK_REPLY: &str = "{\"ok\":true}";
+
+/// One status line: `{"time_unix_ms":...,"status":"TAMPERED",...}` once serialized.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WatchEvent {
+    pub time_unix_ms: u128,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+/// What a TAMPERED line says happens next, and where a REJECTED or FLAPPING rule goes from here.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Mode {
+    AutoRestore,
+    MonitorOnly,
+    /// Waiting in the approval queue for `fag pending`.
+    Approve,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Status {
+    /// On target; `matched` is the label or allow entry it satisfies. Not logged: it is the
+    /// steady state.
+    Ok {
+        ext: String,
+        name: String,
+        effective_progid: Option<String>,
+        target_progid: String,
+        matched: String,
+    },
+    Tampered {
+        ext: String,
+        name: String,
+        effective_progid: Option<String>,
+        target_progid: String,
+        mode: Mode,
+        /// The approval queue item, in [`Mode::Approve`].
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pending_id: Option<u64>,
+        /// How long the new value must stay before it is restored.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        grace_secs: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hint: Option<String>,
+    },
+    Applied {
+        ext: String,
+        name: String,
+        effective_progid: Option<String>,
+        target_progid: String,
+    },
+    Rejected {
+        ext: String,
+        name: String,
+        effective_progid: Option<String>,
+        target_progid: String,
+        backoff_seconds: u64,
+        retries_left: u32,
+        next_mode: Mode,
+        hint: String,
+    },
+    Flapping {
+        ext: String,
+        name: String,
+        competing_progid: Option<String>,
+        target_progid: String,
+        cycles: u32,
+        window_secs: u64,
+        quiet_secs: u64,
+        next_mode: Mode,
+        hint: String,
+    },
+    /// `fag ctl pause`; `None` until resumed.
+    Paused {
+        until_unix_ms: Option<u128>,
+    },
+    /// `by` is `ctl` or `timer`.
+    Resumed {
+        by: String,
+    },
+    ConfigReloaded {
+        added: Vec<String>,
+        removed: Vec<String>,
+        changed: Vec<String>,
+        options: Vec<String>,
+        restart_required: Vec<String>,
+        missing_captures: Vec<String>,
+    },
+    ConfigTampered {
+        store: String,
+        path: String,
+        reason: String,
+        hint: String,
+    },
+    ConfigReloadFailed {
+        store: String,
+        path: String,
+        reason: String,
+        hint: String,
+    },
+    ProfileChanged {
+        from: String,
+        to: String,
+    },
+    ScheduleSwitched {
+        ext: String,
+        from: String,
+        to: String,
+    },
+    FollowUnresolved {
+        ext: String,
+        follow: String,
+        leader_progid: Option<String>,
+        reason: String,
+        hint: String,
+    },
+    /// The rule's own target is back after running on a fallback.
+    TargetAvailable {
+        ext: String,
+        name: String,
+        target: String,
+        target_progid: String,
+    },
+    /// `unavailable` are the labels skipped; `target` is `None` when the whole chain is.
+    TargetUnavailable {
+        ext: String,
+        name: String,
+        unavailable: Vec<String>,
+        target: Option<String>,
+        target_progid: Option<String>,
+        hint: String,
+    },
+    /// `key` is `None` for `watch.pause_processes`, which covers every rule.
+    ObserveOnly {
+        key: Option<String>,
+        processes: Vec<String>,
+    },
+    ObserveEnded {
+        key: Option<String>,
+    },
+    /// `reason` is `ctl` or `signal`; the counts are for this run.
+    Shutdown {
+        reason: String,
+        uptime_secs: u128,
+        tampered: u64,
+        restored: u64,
+    },
+    /// The single-watcher lock was left behind by a watcher that crashed.
+    InstanceTakenOver {
+        lock: String,
+        previous_pid: u32,
+        previous_command: String,
+        previous_started_unix_ms: u64,
+    },
+}
+
+impl WatchEvent {
+    pub fn now(status: Status) -> Self {
+        Self {
+            time_unix_ms: unix_time_ms(),
+            status,
+        }
+    }
+
+    pub fn config_tampered(store: &str, t: &StoreTampered) -> Self {
+        Self::now(Status::ConfigTampered {
+            store: store.to_string(),
+            path: t.path.to_string_lossy().into_owned(),
+            reason: t.reason.to_string(),
+            hint: CONFIG_TAMPERED_HINT.to_string(),
+        })
+    }
+
+    pub fn follow_unresolved(ext: &str, u: &FollowUnresolved) -> Self {
+        Self::now(Status::FollowUnresolved {
+            ext: ext.to_string(),
+            follow: u.leader.clone(),
+            leader_progid: u.leader_progid.clone(),
+            reason: u.reason.clone(),
+            hint: u.hint.clone(),
+        })
+    }
+
+    /// `resolved` is the fallback in use, or `None` when no label in the chain is usable.
+    pub fn target_unavailable(ext: &str, rule: &Rule, resolved: Option<&Resolved>) -> Self {
+        Self::now(Status::TargetUnavailable {
+            ext: ext.to_string(),
+            name: rule.name.clone(),
+            unavailable: rule
+                .chain()
+                .take(resolved.map(|r| r.position).unwrap_or(usize::MAX))
+                .map(str::to_string)
+                .collect(),
+            target: resolved.map(|r| r.label.clone()),
+            target_progid: resolved.map(|r| r.capture.prog_id.clone()),
+            hint: TARGET_UNAVAILABLE_HINT.to_string(),
+        })
+    }
+
+    /// INSTANCE_TAKEN_OVER when `lock` was taken from a watcher that crashed.
+    pub fn instance_taken_over(lock: &InstanceLock) -> Option<Self> {
+        let previous = lock.took_over.as_ref()?;
+        Some(Self::now(Status::InstanceTakenOver {
+            lock: lock.path().to_string_lossy().into_owned(),
+            previous_pid: previous.pid,
+            previous_command: previous.command.clone(),
+            previous_started_unix_ms: previous.started_unix_ms,
+        }))
+    }
+
+    /// Whether the event belongs in the event log; OK lines are only printed.
+    pub fn is_logged(&self) -> bool {
+        !matches!(self.status, Status::Ok { .. })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("events serialize")
+    }
+}
+
+/// What a [`Watcher`] hands its host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// Print it; it is already in the event log if [`WatchEvent::is_logged`].
+    Event(WatchEvent),
+    /// Something wrong with the watcher's own setup (no notifier, unreadable state, ...).
+    Warning(String),
+    /// A rule skipped or an apply that failed, worth a line on stderr.
+    Notice(String),
+}
+
+/// Command-line values that win over config.json, which is otherwise reloaded live.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WatchOverrides {
+    /// Pins the interval.
+    pub interval_secs: Option<u64>,
+    /// Every rule monitor-only, whatever its own mode.
+    pub monitor_only: bool,
+    /// Every rule's tampered values wait for `fag pending`; `monitor_only` still wins.
+    pub approve: bool,
+}
+
+/// What the watcher last saw for one rule, for `fag ctl status`.
+struct RuleState {
+    /// An engine phase (`OK`, `BACKOFF`, ...) or why the rule was skipped (`NO_CAPTURE`, ...).
+    phase: &'static str,
+    target_progid: Option<String>,
+    checked_ms: u128,
+}
+
+impl RuleState {
+    fn new(phase: &'static str, target_progid: Option<String>, checked_ms: u128) -> Self {
+        Self {
+            phase,
+            target_progid,
+            checked_ms,
+        }
+    }
+}
+
+/// What a [`Watcher`] works against besides the stores: the registry it checks and restores,
+/// the clock its intervals, backoff and pauses run on, the local time schedules are read at, and
+/// what wakes it between ticks.
+pub struct Host<R, C, L> {
+    pub registry: R,
+    pub clock: C,
+    pub schedule_clock: L,
+    pub notifier: Box<dyn ChangeNotifier>,
+}
+
+impl Host<SystemRegistry, SystemClock, schedule::SystemClock> {
+    /// The live registry and clock, woken by registry change notifications where the platform
+    /// has them. `Err` carries the polling host and why notifications are unavailable.
+    pub fn system() -> Result<Self, (Self, String)> {
+        let host = |notifier| Self {
+            registry: SystemRegistry,
+            clock: SystemClock,
+            schedule_clock: schedule::SystemClock,
+            notifier,
+        };
+        notify::system_notifier()
+            .map(host)
+            .map_err(|err| (host(Box::new(PollingNotifier)), err))
+    }
+}
+
+pub struct Watcher<'g, R = SystemRegistry, C = SystemClock, L = schedule::SystemClock> {
+    guard: &'g Guard,
+    overrides: WatchOverrides,
+    engine: Engine<R, C, &'g Guard>,
+    schedule_clock: L,
+    reloader: Reloader,
+    notifier: Box<dyn ChangeNotifier>,
+    control: Option<ControlServer>,
+    /// state.json, or `None` for `fag watch`: the file belongs to the whole rule set.
+    state_path: Option<PathBuf>,
+    saved: SavedState,
+    started_ms: u128,
+    next_check_ms: BTreeMap<String, u128>,
+    chain_position: BTreeMap<String, Option<usize>>,
+    follow_label: BTreeMap<String, String>,
+    scheduled_label: BTreeMap<String, String>,
+    rule_state: BTreeMap<String, RuleState>,
+    active_profile: Option<String>,
+    /// `None` = running; `Some(None)` = paused until resumed.
+    paused: Option<Option<u128>>,
+    requests: Vec<Request>,
+    /// For the SHUTDOWN summary.
+    tampered: u64,
+    restored: u64,
+    /// Rules held in observe-only while a `pause_processes` / `pause_for` process runs.
+    hold: ProcessHold,
+    process_list_failed: bool,
+    pending_failed: bool,
+    out: Vec<Output>,
+}
+
+impl<'g> Watcher<'g> {
+    /// [`Self::rules_on`] the system [`Host`] (`fag watch-rules`).
+    pub fn rules(guard: &'g Guard, overrides: WatchOverrides) -> Self {
+        let (host, warning) = system_host();
+        let mut watcher = Self::rules_on(guard, overrides, host);
+        watcher.out.splice(0..0, warning);
+        watcher
+    }
+
+    /// [`Self::single_on`] the system [`Host`] (`fag watch`).
+    pub fn single(guard: &'g Guard, ext: &str, label: &str, overrides: WatchOverrides) -> Self {
+        let (host, warning) = system_host();
+        let mut watcher = Self::single_on(guard, ext, label, overrides, host);
+        watcher.out.splice(0..0, warning);
+        watcher
+    }
+}
+
+fn system_host() -> (
+    Host<SystemRegistry, SystemClock, schedule::SystemClock>,
+    Option<Output>,
+) {
+    match Host::system() {
+        Ok(host) => (host, None),
+        Err((host, err)) => (
+            host,
+            Some(Output::Warning(format!(
+                "registry change notifications unavailable ({}); polling only",
+                err
+            ))),
+        ),
+    }
+}
+
+impl<'g, R: Registry, C: Clock, L: schedule::Clock> Watcher<'g, R, C, L> {
+    /// Guards every rule in the rules store, with backoff carried over in state.json.
+    pub fn rules_on(guard: &'g Guard, overrides: WatchOverrides, host: Host<R, C, L>) -> Self {
+        let mut watcher = Self::new(guard, Reloader::new(guard), overrides, host);
+        let path = guard.settings().state_path();
+        match state::load_state(&path, &guard.settings().key_path) {
+            Ok(saved) => {
+                watcher.engine.restore_state(saved.clone());
+                watcher.saved = saved;
+            }
+            Err(err) => watcher.warn(format!(
+                "ignoring saved state {} ({}); starting fresh",
+                path.to_string_lossy(),
+                err
+            )),
+        }
+        watcher.state_path = Some(path);
+        watcher
+    }
+
+    /// Guards `ext` against the capture `label` alone; a re-capture moves the target.
+    pub fn single_on(
+        guard: &'g Guard,
+        ext: &str,
+        label: &str,
+        overrides: WatchOverrides,
+        host: Host<R, C, L>,
+    ) -> Self {
+        let rule = EffectiveRule {
+            ext: ext.to_string(),
+            rule: Rule::new(label),
+            group: None,
+            managed: false,
+        };
+        Self::new(guard, Reloader::fixed(guard, vec![rule]), overrides, host)
+    }
+
+    fn new(
+        guard: &'g Guard,
+        reloader: Reloader,
+        overrides: WatchOverrides,
+        host: Host<R, C, L>,
+    ) -> Self {
+        let mut out = Vec::new();
+        if let Err(err) = shutdown::install() {
+            out.push(Output::Warning(format!(
+                "{}; Ctrl+C will stop without saving state",
+                err
+            )));
+        }
+        let control = match ControlServer::bind(&control::endpoint(guard.settings())) {
+            Ok(c) => Some(c),
+            Err(err) => {
+                out.push(Output::Warning(format!(
+                    "control channel unavailable ({}); fag ctl cannot reach this watcher",
+                    err
+                )));
+                None
+            }
+        };
+        Self {
+            guard,
+            overrides,
+            engine: Engine::new(host.registry, host.clock, guard),
+            schedule_clock: host.schedule_clock,
+            reloader,
+            notifier: host.notifier,
+            control,
+            state_path: None,
+            saved: SavedState::default(),
+            started_ms: unix_time_ms(),
+            next_check_ms: BTreeMap::new(),
+            chain_position: BTreeMap::new(),
+            follow_label: BTreeMap::new(),
+            scheduled_label: BTreeMap::new(),
+            rule_state: BTreeMap::new(),
+            active_profile: None,
+            paused: None,
+            requests: Vec::new(),
+            tampered: 0,
+            restored: 0,
+            hold: ProcessHold::default(),
+            process_list_failed: false,
+            pending_failed: false,
+            out,
+        }
+    }
+
+    /// The notifier's name: `registry` or `polling` on the system [`Host`].
+    pub fn notify_name(&self) -> &str {
+        self.notifier.name()
+    }
+
+    /// Where `fag ctl` reaches this watcher; `None` when the channel could not be opened.
+    pub fn control_endpoint(&self) -> Option<&Path> {
+        self.control.as_ref().map(|c| c.endpoint())
+    }
+
+    /// Watches until `fag ctl stop` or a stop signal, handing everything to `out` as it happens.
+    /// Ends with SHUTDOWN and a last state save.
+    pub fn run(&mut self, mut out: impl FnMut(Output)) {
+        let reason = loop {
+            let next = self.tick();
+            self.out.drain(..).for_each(&mut out);
+            match next {
+                Err(reason) => break reason,
+                Ok(timeout) => self.sleep(timeout),
+            }
+        };
+        self.emit(WatchEvent::now(Status::Shutdown {
+            reason: reason.to_string(),
+            uptime_secs: unix_time_ms().saturating_sub(self.started_ms) / 1000,
+            tampered: self.tampered,
+            restored: self.restored,
+        }));
+        self.save_state();
+        self.out.drain(..).for_each(&mut out);
+    }
+
+    /// One pass: requests, reloads, then every rule that is due. Returns how long to wait for
+    /// the next one, or why to stop.
+    fn tick(&mut self) -> Result<Duration, &'static str> {
+        let (reloads, stop) = self.answer_requests();
+        if stop {
+            return Err("ctl");
+        }
+        if shutdown::requested() {
+            return Err("signal");
+        }
+        if self
+            .paused
+            .is_some_and(|until| until.is_some_and(|t| self.engine.clock.now_ms() >= t))
+        {
+            self.paused = None;
+            self.resumed("timer");
+        }
+
+        self.poll_stores();
+        for request in reloads {
+            let failing = serde_json::to_string(&self.reloader.failing()).expect("names serialize");
+            request.reply(&format!("{{\"ok\":true,\"failing\":{}}}", failing));
+        }
+        let options = self.reloader.options();
+        let interval_secs = self
+            .overrides
+            .interval_secs
+            .unwrap_or(options.interval_secs);
+        let interval = Duration::from_secs(interval_secs);
+        // Paused: stores are still reloaded and `status` answered; nothing is checked. A tampered
+        // rules store was reported above; nothing is restored until it is resealed.
+        if self.paused.is_some() || self.reloader.is_tampered("rules") {
+            return Ok(interval);
+        }
+        if let Some(profile) = self
+            .reloader
+            .layered()
+            .map(|l| l.user.active_profile_name().to_string())
+        {
+            self.engine.filter.forget("config|rules");
+            self.switch_profile(profile);
+        }
+        // Empty when the rules never loaded; the reason was reported above.
+        let rules = self.reloader.rules().to_vec();
+        if rules.is_empty() {
+            self.notice("no rules found".to_string());
+            return Ok(interval);
+        }
+
+        // Wake up often enough for the fastest enabled rule.
+        let tick = rules
+            .iter()
+            .filter(|r| r.rule.enabled)
+            .map(|r| r.rule.interval_secs.unwrap_or(interval_secs))
+            .min()
+            .unwrap_or(interval_secs)
+            .max(1);
+        self.update_hold(&rules, &options);
+
+        let now_ms = self.engine.clock.now_ms();
+        let now_local = self.schedule_clock.now();
+        let mut captures_ok = true;
+        for r in &rules {
+            if !self.check_rule(&r.ext, &r.rule, &options, interval_secs, now_ms, now_local) {
+                captures_ok = false;
+                break;
+            }
+        }
+        if captures_ok {
+            self.engine.filter.forget("config|captures");
+        }
+        self.save_state();
+        Ok(Duration::from_secs(tick))
+    }
+
+    /// Answers the queued `fag ctl` requests. Reload requests are returned, to be answered once
+    /// the stores were re-read; the flag is set when one of them asked to stop.
+    fn answer_requests(&mut self) -> (Vec<Request>, bool) {
+        let mut reloads = Vec::new();
+        let mut stop = false;
+        for request in std::mem::take(&mut self.requests) {
+            let reply = match request.command {
+                Command::Status => self.status_json(),
+                Command::Pause { for_secs } => {
+                    let now = self.engine.clock.now_ms();
+                    let until = for_secs.map(|s| now.saturating_add(u128::from(s) * 1000));
+                    self.paused = Some(until);
+                    self.emit(WatchEvent::now(Status::Paused {
+                        until_unix_ms: until,
+                    }));
+                    OK_REPLY.to_string()
+                }
+                Command::Resume => {
+                    if self.paused.take().is_some() {
+                        self.resumed("ctl");
+                    }
+                    OK_REPLY.to_string()
+                }
+                Command::Reload => {
+                    self.reloader.force(self.guard);
+                    reloads.push(request);
+                    continue;
+                }
+                Command::CheckNow if self.paused.is_some() => {
+                    "{\"ok\":false,\"error\":\"paused; run fag ctl resume first\"}".to_string()
+                }
+                Command::CheckNow => {
+                    self.next_check_ms.clear();
+                    OK_REPLY.to_string()
+                }
+                Command::Stop => {
+                    stop = true;
+                    request.reply_and_wait(OK_REPLY);
+                    continue;
+                }
+            };
+            request.reply(&reply);
+        }
+        (reloads, stop)
+    }
+
+    fn resumed(&mut self, by: &str) {
+        self.emit(WatchEvent::now(Status::Resumed { by: by.to_string() }));
+        self.next_check_ms.clear();
+    }
+
+    fn poll_stores(&mut self) {
+        for event in self.reloader.poll(self.guard) {
+            match event {
+                Reload::Loaded(changes) => {
+                    // Saved state for rules removed while the watcher was down is dropped.
+                    let keys: Vec<String> = self
+                        .reloader
+                        .rules()
+                        .iter()
+                        .map(|r| rule_key(&r.ext, &r.rule))
+                        .collect();
+                    let keep = |k: &str| keys.iter().any(|key| key == k);
+                    self.engine.retain(keep);
+                    self.engine
+                        .filter
+                        .retain(|k| k.starts_with("config|") || keep(k));
+                    for key in changes.missing_captures {
+                        self.notice(format!(
+                            "capture missing for {} (skipped until captured)",
+                            key
+                        ));
+                    }
+                }
+                Reload::Reloaded(changes) => {
+                    self.emit(WatchEvent::now(config_reloaded(&changes)));
+                    // Rules that changed, or whose captures did, start over: no carried-over
+                    // backoff, chain position or schedule label.
+                    let touched: Vec<String> = changes
+                        .added
+                        .iter()
+                        .chain(&changes.removed)
+                        .chain(&changes.changed)
+                        .map(|ext| format!("{}|", ext))
+                        .collect();
+                    let keep = |k: &str| !touched.iter().any(|p| k.starts_with(p.as_str()));
+                    self.engine.retain(keep);
+                    self.engine.filter.retain(keep);
+                    self.next_check_ms.retain(|k, _| keep(k));
+                    self.rule_state.retain(|k, _| keep(k));
+                    self.chain_position.retain(|k, _| keep(k));
+                    self.follow_label.retain(|k, _| keep(k));
+                    self.scheduled_label.retain(|k, _| keep(k));
+                }
+                Reload::Failed { store, error } => {
+                    // CONFIG_TAMPERED is reported once, shared with the other tamper checks.
+                    let event = match error.tampered() {
+                        Some((store, t)) => {
+                            let key = format!("config|{}", store);
+                            if !self
+                                .engine
+                                .filter
+                                .should_emit(&key, "CONFIG_TAMPERED", &None)
+                            {
+                                continue;
+                            }
+                            WatchEvent::config_tampered(store, t)
+                        }
+                        None => {
+                            let settings = self.guard.settings();
+                            let path = match store {
+                                "rules" => settings.rules_path(),
+                                "captures" => settings.captures_path(),
+                                _ => settings.config_path.as_path(),
+                            };
+                            WatchEvent::now(Status::ConfigReloadFailed {
+                                store: store.to_string(),
+                                path: path.to_string_lossy().into_owned(),
+                                reason: error.to_string(),
+                                hint: CONFIG_RELOAD_FAILED_HINT.to_string(),
+                            })
+                        }
+                    };
+                    self.emit(event);
+                }
+            }
+        }
+    }
+
+    /// `fag profile use` switched the rule set: start from a clean slate.
+    fn switch_profile(&mut self, profile: String) {
+        if let Some(prev) = self.active_profile.as_deref().filter(|p| *p != profile) {
+            let event = WatchEvent::now(Status::ProfileChanged {
+                from: prev.to_string(),
+                to: profile.clone(),
+            });
+            self.emit(event);
+            self.engine.reset();
+            self.next_check_ms.clear();
+            self.rule_state.clear();
+            self.chain_position.clear();
+            self.follow_label.clear();
+            self.scheduled_label.clear();
+            self.engine.filter.retain(|k| k.starts_with("config|"));
+        }
+        self.active_profile = Some(profile);
+    }
+
+    /// Installers rewrite associations on purpose: watch them but keep out of the way, then
+    /// check everything they may have touched once they are gone.
+    fn update_hold(&mut self, rules: &[EffectiveRule], options: &WatchOptions) {
+        let scopes: Vec<(String, Vec<String>)> =
+            std::iter::once((String::new(), options.pause_processes.clone()))
+                .chain(
+                    rules
+                        .iter()
+                        .filter(|r| r.rule.enabled)
+                        .map(|r| (rule_key(&r.ext, &r.rule), r.rule.pause_for.clone())),
+                )
+                .filter(|(_, names)| !names.is_empty())
+                .collect();
+        let patterns: Vec<String> = scopes.iter().flat_map(|(_, names)| names.clone()).collect();
+        let running = match processes::running_matches(&processes::SystemProcesses, &patterns) {
+            Ok(running) => {
+                self.process_list_failed = false;
+                running
+            }
+            Err(err) => {
+                if !self.process_list_failed {
+                    self.warn(format!(
+                        "cannot list processes ({}); pause_processes is ignored until it works again",
+                        err
+                    ));
+                }
+                self.process_list_failed = true;
+                Vec::new()
+            }
+        };
+        let key = |scope: String| (!scope.is_empty()).then_some(scope);
+        for change in self.hold.update(&running, &scopes) {
+            let status = match change {
+                HoldChange::Started { scope, processes } => Status::ObserveOnly {
+                    key: key(scope),
+                    processes,
+                },
+                HoldChange::Ended { scope } => {
+                    if scope.is_empty() {
+                        self.next_check_ms.clear();
+                    } else {
+                        self.next_check_ms.remove(&scope);
+                    }
+                    Status::ObserveEnded { key: key(scope) }
+                }
+            };
+            self.emit(WatchEvent::now(status));
+        }
+    }
+
+    /// Checks one rule if it is due. `false` when the captures store is tampered, which ends the
+    /// pass: no rule is restored until it is resealed.
+    fn check_rule(
+        &mut self,
+        ext: &str,
+        rule: &Rule,
+        options: &WatchOptions,
+        interval_secs: u64,
+        now_ms: u128,
+        now_local: LocalTime,
+    ) -> bool {
+        let key = rule_key(ext, rule);
+        if !rule.enabled {
+            self.next_check_ms.remove(&key);
+            self.rule_state.remove(&key);
+            return true;
+        }
+        if self.next_check_ms.get(&key).is_some_and(|t| now_ms < *t) {
+            return true;
+        }
+        let rule_interval = rule.interval_secs.unwrap_or(interval_secs).max(1);
+        // Small slack so a rule on the same interval as the tick is not skipped by jitter.
+        self.next_check_ms.insert(
+            key.clone(),
+            now_ms.saturating_add(u128::from(rule_interval) * 1000 - 500),
+        );
+
+        let rule = schedule::apply(rule, now_local);
+        if !rule.schedule.is_empty() {
+            if let Some(prev) = self
+                .scheduled_label
+                .insert(key.clone(), rule.name.clone())
+                .filter(|prev| *prev != rule.name)
+            {
+                self.emit(WatchEvent::now(Status::ScheduleSwitched {
+                    ext: ext.to_string(),
+                    from: prev,
+                    to: rule.name.clone(),
+                }));
+                // A new window means a new target: do not carry over its backoff.
+                self.engine.forget(&key);
+            }
+        }
+        let rule = match self.guard.resolve_follow(ext, &rule) {
+            Ok(r) => r,
+            Err(unresolved) => {
+                if self.engine.filter.should_emit(
+                    &key,
+                    "FOLLOW_UNRESOLVED",
+                    &unresolved.leader_progid,
+                ) {
+                    self.emit(WatchEvent::follow_unresolved(ext, &unresolved));
+                }
+                self.rule_state
+                    .insert(key, RuleState::new("FOLLOW_UNRESOLVED", None, now_ms));
+                return true;
+            }
+        };
+        if rule.follow.is_some()
+            && self
+                .follow_label
+                .insert(key.clone(), rule.name.clone())
+                .is_some_and(|prev| prev != rule.name)
+        {
+            // The leader moved to another app: earlier rejections were for the old one.
+            self.engine.forget(&key);
+        }
+        let label = &rule.name;
+
+        let cap = match self.guard.capture_for(ext, label) {
+            Ok(Some(c)) => c,
+            Err(err) if err.tampered().is_some() => {
+                if self
+                    .engine
+                    .filter
+                    .should_emit("config|captures", "CONFIG_TAMPERED", &None)
+                {
+                    if let Some((store, t)) = err.tampered() {
+                        self.emit(WatchEvent::config_tampered(store, t));
+                    }
+                }
+                return false;
+            }
+            _ => {
+                self.notice(format!("capture missing ext={} name={} (skip)", ext, label));
+                self.rule_state
+                    .insert(key, RuleState::new("NO_CAPTURE", None, now_ms));
+                return true;
+            }
+        };
+
+        let resolved = self.guard.resolve_target(ext, &rule);
+        let position = resolved.as_ref().map(|r| r.position);
+        let previous = self.chain_position.insert(key.clone(), position);
+        if previous != Some(position) {
+            let event = match position {
+                Some(0) if previous.is_some() => Some(WatchEvent::now(Status::TargetAvailable {
+                    ext: ext.to_string(),
+                    name: label.clone(),
+                    target: label.clone(),
+                    target_progid: cap.prog_id.clone(),
+                })),
+                Some(0) => None,
+                _ => Some(WatchEvent::target_unavailable(
+                    ext,
+                    &rule,
+                    resolved.as_ref(),
+                )),
+            };
+            if let Some(event) = event {
+                self.emit(event);
+                // A different target means earlier rejections no longer apply.
+                self.engine.forget(&key);
+            }
+        }
+        let Some(Resolved {
+            label: target,
+            capture: cap,
+            ..
+        }) = resolved
+        else {
+            // Nothing installed to restore to; re-checked every interval.
+            self.rule_state
+                .insert(key, RuleState::new("TARGET_UNAVAILABLE", None, now_ms));
+            return true;
+        };
+
+        let observing = self.overrides.monitor_only
+            || self.hold.held("").is_some()
+            || self.hold.held(&key).is_some();
+        // Approval mode restores only what `fag pending approve` let through, and at once.
+        let approve =
+            !observing && (self.overrides.approve || rule.mode == Some(RuleMode::Approve));
+        let monitor_only = observing
+            || (!approve
+                && rule
+                    .mode
+                    .map(|m| m == RuleMode::MonitorOnly)
+                    .unwrap_or(options.monitor_only));
+        let approved = if approve {
+            self.queue(|q| q.take_approved(&key)).flatten()
+        } else {
+            None
+        };
+        if approved.is_some() {
+            // The user asked for this restore: earlier rejections do not hold it back.
+            self.engine.forget(&key);
+        }
+        let awaiting = approve && approved.is_none();
+        let grace_secs = rule.grace_secs.unwrap_or(options.grace_secs);
+        let step = self.engine.step(&Check {
+            key: &key,
+            ext,
+            target: &target,
+            prog_id: &cap.prog_id,
+            hash: &cap.hash,
+            allow: &rule.allow,
+            monitor_only: monitor_only || awaiting,
+            backoff: backoff_for(rule.backoff.as_ref(), options),
+            flapping: options.flapping,
+            grace_secs: if approved.is_some() { 0 } else { grace_secs },
+        });
+        let phase = match step.phase {
+            Phase::MonitorOnly if awaiting => "AWAITING_APPROVAL",
+            phase => phase.as_str(),
+        };
+        self.rule_state.insert(
+            key.clone(),
+            RuleState::new(phase, Some(cap.prog_id.clone()), now_ms),
+        );
+        if approve && step.phase == Phase::Ok {
+            // Back on target by itself: nothing left to decide.
+            self.queue(|q| {
+                q.clear_key(&key);
+            });
+        }
+
+        for event in step.events {
+            let effective_progid = event.effective;
+            let (ext, name, target_progid) = (ext.to_string(), label.clone(), cap.prog_id.clone());
+            let status = match event.kind {
+                EventKind::Ok { matched } => Status::Ok {
+                    ext,
+                    name,
+                    effective_progid,
+                    target_progid,
+                    matched,
+                },
+                EventKind::Tampered { .. } if awaiting => {
+                    self.tampered += 1;
+                    let item = PendingItem {
+                        id: 0,
+                        key: key.clone(),
+                        ext: ext.clone(),
+                        target: target.clone(),
+                        target_progid: target_progid.clone(),
+                        effective_progid: effective_progid.clone(),
+                        detected_unix_ms: u64::try_from(now_ms).unwrap_or(u64::MAX),
+                        approved: false,
+                    };
+                    let id = self.queue(|q| q.push(item).0);
+                    Status::Tampered {
+                        ext,
+                        name,
+                        effective_progid,
+                        target_progid,
+                        mode: Mode::Approve,
+                        pending_id: id,
+                        grace_secs: None,
+                        hint: id.map(|id| pending_hint(id, &target)),
+                    }
+                }
+                EventKind::Tampered { monitor_only } => {
+                    self.tampered += 1;
+                    let grace = (!monitor_only && grace_secs > 0).then_some(grace_secs);
+                    Status::Tampered {
+                        hint: grace.map(|secs| grace_hint(&ext, secs)),
+                        ext,
+                        name,
+                        effective_progid,
+                        target_progid,
+                        mode: if monitor_only {
+                            Mode::MonitorOnly
+                        } else {
+                            Mode::AutoRestore
+                        },
+                        pending_id: None,
+                        grace_secs: grace,
+                    }
+                }
+                EventKind::Applied => {
+                    self.restored += 1;
+                    Status::Applied {
+                        ext,
+                        name,
+                        effective_progid,
+                        target_progid,
+                    }
+                }
+                EventKind::Rejected {
+                    backoff_secs,
+                    retries_left,
+                } => Status::Rejected {
+                    ext,
+                    name,
+                    effective_progid,
+                    target_progid,
+                    backoff_seconds: backoff_secs,
+                    retries_left,
+                    next_mode: if retries_left > 0 {
+                        Mode::AutoRestore
+                    } else {
+                        Mode::MonitorOnly
+                    },
+                    hint: if retries_left > 0 {
+                        REJECTED_RETRY_HINT
+                    } else {
+                        REJECTED_HINT
+                    }
+                    .to_string(),
+                },
+                EventKind::Flapping { cycles } => Status::Flapping {
+                    ext,
+                    name,
+                    competing_progid: effective_progid,
+                    target_progid,
+                    cycles,
+                    window_secs: options.flapping.window_secs,
+                    quiet_secs: options.flapping.quiet_secs,
+                    next_mode: Mode::MonitorOnly,
+                    hint: FLAPPING_HINT.to_string(),
+                },
+                EventKind::ApplyFailed(err) => {
+                    self.notice(format!("apply failed ext={} name={}: {}", ext, name, err));
+                    continue;
+                }
+                // The step treated the association as unset; the next check queries again.
+                EventKind::QueryFailed(_) => continue,
+            };
+            self.emit(WatchEvent::now(status));
+        }
+        true
+    }
+
+    /// Runs `f` on the approval queue. `None` while pending.json is unusable, which is reported
+    /// once until it works again; approve-mode rules then only report.
+    fn queue<T>(&mut self, f: impl FnOnce(&mut PendingQueue) -> T) -> Option<T> {
+        let path = self.guard.settings().pending_path();
+        match pending::update(&path, &self.guard.settings().key_path, f) {
+            Ok(out) => {
+                self.pending_failed = false;
+                Some(out)
+            }
+            Err(err) => {
+                if !self.pending_failed {
+                    self.warn(format!(
+                        "approval queue {} unusable ({}); approve-mode rules only report",
+                        path.to_string_lossy(),
+                        err
+                    ));
+                }
+                self.pending_failed = true;
+                None
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the next tick. A registry write wakes the loop at once and every
+    /// rule is re-checked, whatever its own interval.
+    fn sleep(&mut self, timeout: Duration) {
+        if self.wait(timeout) == Wait::Changed {
+            self.next_check_ms.clear();
+        }
+    }
+
+    /// Waits for the next tick. A control request ends the wait early and is queued;
+    /// Ctrl+C or a stop signal ends it within half a second.
+    fn wait(&mut self, timeout: Duration) -> Wait {
+        const SLICE: Duration = Duration::from_millis(500);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || shutdown::requested() {
+                return Wait::Timeout;
+            }
+            match &self.control {
+                None => {
+                    if self.notifier.wait(left.min(SLICE)) == Wait::Changed {
+                        return Wait::Changed;
+                    }
+                }
+                Some(control) => match control.wait(self.notifier.as_mut(), left.min(SLICE)) {
+                    Woke::Notifier(Wait::Changed) => return Wait::Changed,
+                    Woke::Notifier(Wait::Timeout) => {}
+                    Woke::Request(request) => {
+                        self.requests.push(request);
+                        return Wait::Timeout;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Writes state.json when the engine's state moved since the last write.
+    fn save_state(&mut self) {
+        let Some(path) = self.state_path.clone() else {
+            return;
+        };
+        let state = self.engine.saved_state();
+        if state == self.saved {
+            return;
+        }
+        let key_path = &self.guard.settings().key_path;
+        if let Err(err) = state::save_state(&path, key_path, &state, unix_time_ms()) {
+            self.warn(format!(
+                "could not save state to {}: {}",
+                path.to_string_lossy(),
+                err
+            ));
+        }
+        // Not retried until the state moves again, so a read-only home warns once per change.
+        self.saved = state;
+    }
+
+    /// The `fag ctl status` reply: pause state plus one entry per rule in effect.
+    fn status_json(&self) -> String {
+        #[derive(Serialize)]
+        struct RuleStatus<'a> {
+            key: String,
+            ext: &'a str,
+            name: &'a str,
+            phase: &'static str,
+            target_progid: Option<&'a str>,
+            checked_unix_ms: Option<u128>,
+            next_check_unix_ms: Option<u128>,
+            failures: u32,
+            backoff_seconds: Option<u128>,
+        }
+        #[derive(Serialize)]
+        struct Reply<'a> {
+            ok: bool,
+            pid: u32,
+            started_unix_ms: u128,
+            paused: bool,
+            paused_until_unix_ms: Option<u128>,
+            interval_secs: u64,
+            notify: &'a str,
+            profile: Option<&'a str>,
+            failing: Vec<&'static str>,
+            observe_only_for: Vec<&'a str>,
+            rules: Vec<RuleStatus<'a>>,
+        }
+
+        let now = self.engine.clock.now_ms();
+        let rules = self
+            .reloader
+            .rules()
+            .iter()
+            .map(|r| {
+                let key = rule_key(&r.ext, &r.rule);
+                let state = self.rule_state.get(&key);
+                let rejection = self.engine.reject_state(&key);
+                RuleStatus {
+                    ext: &r.ext,
+                    name: &r.rule.name,
+                    phase: match state {
+                        Some(s) => s.phase,
+                        None if !r.rule.enabled => "DISABLED",
+                        None => "PENDING",
+                    },
+                    target_progid: state.and_then(|s| s.target_progid.as_deref()),
+                    checked_unix_ms: state.map(|s| s.checked_ms),
+                    next_check_unix_ms: self.next_check_ms.get(&key).copied(),
+                    failures: rejection.map(|j| j.failures).unwrap_or(0),
+                    backoff_seconds: rejection
+                        .map(|j| j.retry_at_ms.saturating_sub(now).div_ceil(1000)),
+                    key,
+                }
+            })
+            .collect();
+        let reply = Reply {
+            ok: true,
+            pid: std::process::id(),
+            started_unix_ms: self.started_ms,
+            paused: self.paused.is_some(),
+            paused_until_unix_ms: self.paused.flatten(),
+            interval_secs: self
+                .overrides
+                .interval_secs
+                .unwrap_or(self.reloader.options().interval_secs),
+            notify: self.notifier.name(),
+            profile: self
+                .reloader
+                .layered()
+                .map(|l| l.user.active_profile_name()),
+            failing: self.reloader.failing(),
+            observe_only_for: self.hold.processes(),
+            rules,
+        };
+        serde_json::to_string(&reply).expect("status serializes")
+    }
+
+    /// Logs `event` (unless it is an OK) and queues it for the host.
+    fn emit(&mut self, event: WatchEvent) {
+        if event.is_logged() {
+            let _ = self.guard.record(&event.to_json());
+        }
+        self.out.push(Output::Event(event));
+    }
+
+    fn warn(&mut self, message: String) {
+        self.out.push(Output::Warning(message));
+    }
+
+    fn notice(&mut self, message: String) {
+        self.out.push(Output::Notice(message));
+    }
+}
+
+/// The key a rule's state is tracked under: `.mp4|vlc`, or `.mkv|follow:.mp4` for a follow rule.
+fn rule_key(ext: &str, rule: &Rule) -> String {
+    match rule.follow.as_deref() {
+        Some(leader) => format!("{}|follow:{}", ext, leader),
+        None => format!("{}|{}", ext, rule.name),
+    }
+}
+
+/// A rule's backoff: its own settings where it has them, the `watch.backoff_*` ones otherwise.
+fn backoff_for(rule: Option<&BackoffOverride>, options: &WatchOptions) -> Backoff {
+    let o = rule.cloned().unwrap_or_default();
+    let cooldown = o.cooldown_secs.unwrap_or(options.backoff_cooldown_secs);
+    Backoff {
+        policy: o.policy.unwrap_or(options.backoff_policy),
+        base_secs: o.base_secs.unwrap_or(options.backoff_base_secs),
+        max_secs: o.max_secs.unwrap_or(options.backoff_max_secs),
+        max_retries: o.max_retries.unwrap_or(options.backoff_max_retries),
+        cooldown_secs: (cooldown > 0).then_some(cooldown),
+    }
+}
+
+fn config_reloaded(changes: &Changes) -> Status {
+    let keys = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+    Status::ConfigReloaded {
+        added: changes.added.clone(),
+        removed: changes.removed.clone(),
+        changed: changes.changed.clone(),
+        options: keys(&changes.options),
+        restart_required: keys(&changes.restart_required),
+        missing_captures: changes.missing_captures.clone(),
+    }
+}
+
+/// What to run for a TAMPERED value waiting in the approval queue.
+fn pending_hint(id: u64, target: &str) -> String {
+    format!(
+        "等待确认：fag pending approve {} 恢复为 {}；fag pending accept {} --name <标签> 保留新的关联；fag pending dismiss {} 忽略这次更改",
+        id, target, id, id
+    )
+}
+
+/// The wait before a restore, and how to keep the new association instead.
+fn grace_hint(ext: &str, grace_secs: u64) -> String {
+    format!(
+        "新的关联连续 {} 秒不再变化后才会恢复；如果这是有意的更改，可以运行 fag capture-latest --ext {} --name <标签>，再用 fag rules set --ext {} --name <标签> 改用它",
+        grace_secs, ext, ext
+    )
+}
+
+fn unix_time_ms() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::captures::LatestCapture;
+    use crate::config::GlobalOptions;
+    use crate::notify::ChannelNotifier;
+    use std::sync::{Arc, Mutex};
+
+    fn at(status: Status) -> String {
+        WatchEvent {
+            time_unix_ms: 1_792_000_000_000,
+            status,
+        }
+        .to_json()
+    }
+
+    #[test]
+    fn events_serialize_to_the_printed_lines() {
+        assert_eq!(
+            at(Status::Ok {
+                ext: ".mp4".into(),
+                name: "vlc".into(),
+                effective_progid: Some("VLC.mp4".into()),
+                target_progid: "VLC.mp4".into(),
+                matched: "vlc".into(),
+            }),
+            r#"{"time_unix_ms":1792000000000,"status":"OK","ext":".mp4","name":"vlc","effective_progid":"VLC.mp4","target_progid":"VLC.mp4","matched":"vlc"}"#
+        );
+        // Optional TAMPERED fields are left out, not null; a removed association is null.
+        assert_eq!(
+            at(Status::Tampered {
+                ext: ".mp4".into(),
+                name: "vlc".into(),
+                effective_progid: None,
+                target_progid: "VLC.mp4".into(),
+                mode: Mode::AutoRestore,
+                pending_id: None,
+                grace_secs: None,
+                hint: None,
+            }),
+            r#"{"time_unix_ms":1792000000000,"status":"TAMPERED","ext":".mp4","name":"vlc","effective_progid":null,"target_progid":"VLC.mp4","mode":"AUTO_RESTORE"}"#
+        );
+        assert_eq!(
+            at(Status::ObserveEnded { key: None }),
+            r#"{"time_unix_ms":1792000000000,"status":"OBSERVE_ENDED","key":null}"#
+        );
+        assert_eq!(
+            at(Status::Paused {
+                until_unix_ms: None
+            }),
+            r#"{"time_unix_ms":1792000000000,"status":"PAUSED","until_unix_ms":null}"#
+        );
+    }
+
+    #[test]
+    fn fallback_lines_name_the_skipped_labels() {
+        let mut rule = Rule::new("potplayer");
+        rule.fallback = vec!["vlc".into(), "mpv".into()];
+        let resolved = Resolved {
+            position: 1,
+            label: "vlc".into(),
+            capture: crate::captures::LatestCapture {
+                prog_id: "VLC.mp4".into(),
+                hash: "h".into(),
+                last_write_time_filetime: None,
+                prog_id_last_write_time_filetime: None,
+            },
+        };
+        let json: serde_json::Value = serde_json::from_str(
+            &WatchEvent::target_unavailable(".mp4", &rule, Some(&resolved)).to_json(),
+        )
+        .unwrap();
+        assert_eq!(json["status"], "TARGET_UNAVAILABLE");
+        assert_eq!(json["unavailable"], serde_json::json!(["potplayer"]));
+        assert_eq!(json["target_progid"], "VLC.mp4");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&WatchEvent::target_unavailable(".mp4", &rule, None).to_json())
+                .unwrap();
+        assert_eq!(
+            json["unavailable"],
+            serde_json::json!(["potplayer", "vlc", "mpv"])
+        );
+        assert_eq!(json["target"], serde_json::Value::Null);
+        assert!(!WatchEvent::now(Status::Ok {
+            ext: ".mp4".into(),
+            name: "vlc".into(),
+            effective_progid: None,
+            target_progid: "VLC.mp4".into(),
+            matched: "vlc".into(),
+        })
+        .is_logged());
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedRegistry(Arc<Mutex<Option<String>>>);
+
+    impl SharedRegistry {
+        fn effective(&self) -> Option<String> {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn set(&self, prog_id: &str) {
+            *self.0.lock().unwrap() = Some(prog_id.to_string());
+        }
+    }
+
+    impl Registry for SharedRegistry {
+        fn effective_progid(&self, _ext: &str) -> Result<Option<String>, String> {
+            Ok(self.effective())
+        }
+
+        fn apply(&self, _ext: &str, prog_id: &str, _hash: &str) -> Result<(), String> {
+            self.set(prog_id);
+            Ok(())
+        }
+    }
+
+    /// Time stands still, so a rule only comes due again when something clears its next check.
+    struct ZeroClock;
+
+    impl Clock for ZeroClock {
+        fn now_ms(&self) -> u128 {
+            0
+        }
+    }
+
+    /// Both clocks at once, in UTC, moved by the test.
+    #[derive(Clone)]
+    struct SharedTime(Arc<Mutex<i64>>);
+
+    impl SharedTime {
+        fn set(&self, unix_secs: i64) {
+            *self.0.lock().unwrap() = unix_secs;
+        }
+    }
+
+    impl Clock for SharedTime {
+        fn now_ms(&self) -> u128 {
+            *self.0.lock().unwrap() as u128 * 1000
+        }
+    }
+
+    impl schedule::Clock for SharedTime {
+        fn now(&self) -> LocalTime {
+            LocalTime {
+                unix_secs: *self.0.lock().unwrap(),
+                utc_offset_secs: 0,
+            }
+        }
+    }
+
+    /// A portable home with `.mp4` guarded by the capture `vlc` (VLC.mp4).
+    fn guarded_home(name: &str) -> (PathBuf, Guard) {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let home = std::env::temp_dir().join(format!("fag-watcher-{}-{}", name, nanos));
+        std::fs::create_dir_all(&home).unwrap();
+        let guard = Guard::open(&GlobalOptions {
+            config: Some(home.join("config.json")),
+            portable: true,
+        })
+        .unwrap();
+        let key_path = &guard.settings().key_path;
+        crate::captures::upsert_latest_capture(
+            guard.settings().captures_path(),
+            key_path,
+            ".mp4",
+            "vlc",
+            LatestCapture {
+                prog_id: "VLC.mp4".into(),
+                hash: "h".into(),
+                last_write_time_filetime: None,
+                prog_id_last_write_time_filetime: None,
+            },
+        )
+        .unwrap();
+        crate::rules::upsert_rule(guard.settings().rules_path(), key_path, ".mp4", "vlc").unwrap();
+        (home, guard)
+    }
+
+    fn statuses<R, C, L>(watcher: &mut Watcher<'_, R, C, L>) -> Vec<String> {
+        watcher
+            .out
+            .drain(..)
+            .filter_map(|out| match out {
+                Output::Event(event) => serde_json::to_value(&event).ok(),
+                _ => None,
+            })
+            .map(|v| v["status"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn a_notifier_signal_rechecks_before_the_interval() {
+        let (home, guard) = guarded_home("notify");
+        let registry = SharedRegistry::default();
+        registry.set("VLC.mp4");
+        let (tx, notifier) = ChannelNotifier::new();
+        let overrides = WatchOverrides {
+            interval_secs: Some(3600),
+            ..Default::default()
+        };
+        let host = Host {
+            registry: registry.clone(),
+            clock: ZeroClock,
+            schedule_clock: schedule::SystemClock,
+            notifier: Box::new(notifier),
+        };
+        let mut watcher = Watcher::rules_on(&guard, overrides, host);
+        assert_eq!(watcher.notify_name(), "channel");
+        watcher.tick().unwrap();
+        assert_eq!(statuses(&mut watcher), ["OK"]);
+
+        // Not due for another hour: the hijack stands.
+        registry.set("Hijack.mp4");
+        watcher.tick().unwrap();
+        assert!(statuses(&mut watcher).is_empty());
+        assert_eq!(registry.effective().as_deref(), Some("Hijack.mp4"));
+
+        tx.send(()).unwrap();
+        let started = Instant::now();
+        watcher.sleep(Duration::from_secs(3600));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        watcher.tick().unwrap();
+        assert_eq!(statuses(&mut watcher), ["TAMPERED", "APPLIED"]);
+        assert_eq!(registry.effective().as_deref(), Some("VLC.mp4"));
+        drop(watcher);
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn a_tick_switches_targets_across_a_schedule_boundary() {
+        let (home, guard) = guarded_home("schedule");
+        let key_path = &guard.settings().key_path;
+        crate::captures::upsert_latest_capture(
+            guard.settings().captures_path(),
+            key_path,
+            ".mp4",
+            "potplayer",
+            LatestCapture {
+                prog_id: "PotPlayer.mp4".into(),
+                hash: "p".into(),
+                last_write_time_filetime: None,
+                prog_id_last_write_time_filetime: None,
+            },
+        )
+        .unwrap();
+        guard
+            .set_schedule(
+                ".mp4",
+                Some(schedule::ScheduleWindow {
+                    days: "mon-fri".into(),
+                    from: "09:00".into(),
+                    to: "17:00".into(),
+                    name: "potplayer".into(),
+                }),
+            )
+            .unwrap();
+
+        // Monday 2026-10-19, 08:59 UTC.
+        const MONDAY: i64 = 1_792_368_000;
+        let time = SharedTime(Arc::new(Mutex::new(MONDAY + 8 * 3600 + 59 * 60)));
+        let registry = SharedRegistry::default();
+        registry.set("VLC.mp4");
+        let host = Host {
+            registry: registry.clone(),
+            clock: time.clone(),
+            schedule_clock: time.clone(),
+            notifier: Box::new(crate::notify::PollingNotifier),
+        };
+        let overrides = WatchOverrides {
+            interval_secs: Some(60),
+            ..Default::default()
+        };
+        let mut watcher = Watcher::rules_on(&guard, overrides, host);
+        watcher.tick().unwrap();
+        assert_eq!(statuses(&mut watcher), ["OK"]);
+
+        time.set(MONDAY + 9 * 3600 + 60);
+        watcher.tick().unwrap();
+        assert_eq!(
+            statuses(&mut watcher),
+            ["SCHEDULE_SWITCHED", "TAMPERED", "APPLIED"]
+        );
+        assert_eq!(registry.effective().as_deref(), Some("PotPlayer.mp4"));
+
+        time.set(MONDAY + 17 * 3600 + 60);
+        watcher.tick().unwrap();
+        assert_eq!(
+            statuses(&mut watcher),
+            ["SCHEDULE_SWITCHED", "TAMPERED", "APPLIED"]
+        );
+        assert_eq!(registry.effective().as_deref(), Some("VLC.mp4"));
+        drop(watcher);
+        let _ = std::fs::remove_dir_all(&home);
+    }
+}